@@ -0,0 +1,326 @@
+use std::path::PathBuf;
+use gameboy_emu::game_boy::apu::AudioChannel;
+use gameboy_emu::game_boy::model::Model;
+
+pub const USAGE: &str = "\
+usage: gameboy_emu [options] <rom>
+       gameboy_emu info <rom>
+       gameboy_emu disasm <rom>    print the ROM as RGBDS source
+       gameboy_emu dap             serve the Debug Adapter Protocol on stdin and stdout for editors,
+                                   the launch request names the ROM
+
+options:
+  --headless           run without a window, for --frames N frames or until --play-movie ends
+  --debug              run in a command line debugger instead of a window, see its help command
+  --gdb PORT           wait for GDB on localhost PORT instead of opening a window (target remote :PORT)
+  --frames N           stop after N frames
+  --scale N            window scale factor (default 4)
+  --boot-rom PATH      run PATH as the boot ROM before the cartridge
+  --model MODEL        hardware to emulate: dmg0, dmg, mgb, sgb, cgb or agb
+                       (default cgb for CGB games, sgb for SGB games, dmg otherwise)
+  --palette NAME|FILE  DMG colors: dmg, pocket, light, gbc or a palette file
+                       (default gbc on CGB models, pocket otherwise; P cycles them)
+  --save-dir DIR       keep battery saves and save states in DIR instead of next to the ROM
+  --load-state PATH    start from a save state, ours or BESS from another emulator
+  --record-movie PATH  record the joypad input to a movie, starting from the battery save or --load-state
+  --play-movie PATH    replay a movie recorded with --record-movie, or a BizHawk BK2 or
+                       VisualBoyAdvance VBM movie, on the same ROM
+  --expect-frame-hash HASH
+                       with --headless, fail unless the last frame hashes to HASH (hex)
+  --trace              log the CPU state before every instruction to stderr, like Gameboy Doctor
+  --trace-file PATH    log to PATH instead, implies --trace
+  --trace-pc START-END only log instructions at addresses from START to END (hex)
+  --trace-bank N       only log instructions in ROM bank N
+  --trace-last N       only log the last N instructions, and only when the emulator crashes
+                       or the CPU locks up on an illegal opcode
+  --trace-labels       write a Label: line before the instructions at labels from <rom>.sym,
+                       which Gameboy Doctor does not expect
+  --coverage           add which ROM bytes ran as code or were read as data to <rom>.cov on exit,
+                       and write a report per bank to <rom>-coverage.txt
+  --profile PATH       count the cycles spent per address, bank, symbol and call stack, write the
+                       call stacks to PATH folded for flamegraph.pl and a summary to stderr on exit
+  --screenshot-at N    write frame N to <rom>-frame<N>.png
+  --wav PATH           with --headless, record the audio to PATH as a WAV file
+  --audio-channels LIST
+                       only mix the channels in LIST, e.g. 1,2 for the pulse channels
+                       (1 and 2 pulse, 3 wave, 4 noise; default all)
+  -h, --help           print this help
+
+labels from <rom>.sym, as rgblink writes it, show up in the debugger, profiles and disassembly,
+and in traces with --trace-labels.
+The DAP server also maps them to lines of the .asm and .inc files next to the ROM.
+The disassembly takes bytes <rom>.cov saw read only as data for data rather than code.
+
+keys:
+  1-9                  pick a save state slot
+  F5, F9               save to the slot, load from it
+  F6                   export the slot as BESS for other emulators
+  R                    rewind while held";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RunOptions {
+    pub rom_path: PathBuf,
+    pub headless: bool,
+    pub debug: bool,
+    pub gdb_port: Option<u16>,
+    pub frames: Option<u64>,
+    pub scale: u32,
+    pub boot_rom: Option<PathBuf>,
+    pub model: Option<Model>,
+    pub save_dir: Option<PathBuf>,
+    pub palette: Option<String>,
+    pub load_state: Option<PathBuf>,
+    pub record_movie: Option<PathBuf>,
+    pub play_movie: Option<PathBuf>,
+    pub expect_frame_hash: Option<u64>,
+    pub trace: bool,
+    pub trace_file: Option<PathBuf>,
+    pub trace_pc: Option<(u16, u16)>,
+    pub trace_bank: Option<usize>,
+    pub trace_last: Option<usize>,
+    pub trace_labels: bool,
+    pub profile: Option<PathBuf>,
+    pub coverage: bool,
+    pub screenshot_at: Option<u64>,
+    pub wav: Option<PathBuf>,
+    pub audio_channels: Vec<AudioChannel>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Run(Box<RunOptions>),
+    Info { rom_path: PathBuf },
+    Disassemble { rom_path: PathBuf },
+    Dap,
+    Help,
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.into_iter();
+    let mut options = RunOptions {
+        rom_path: PathBuf::new(),
+        headless: false,
+        debug: false,
+        gdb_port: None,
+        frames: None,
+        scale: 4,
+        boot_rom: None,
+        model: None,
+        save_dir: None,
+        palette: None,
+        load_state: None,
+        record_movie: None,
+        play_movie: None,
+        expect_frame_hash: None,
+        trace: false,
+        trace_file: None,
+        trace_pc: None,
+        trace_bank: None,
+        trace_last: None,
+        trace_labels: false,
+        profile: None,
+        coverage: false,
+        screenshot_at: None,
+        wav: None,
+        audio_channels: ALL_AUDIO_CHANNELS.to_vec(),
+    };
+    let mut rom_path = None;
+    let mut subcommand = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--headless" => options.headless = true,
+            "--debug" => options.debug = true,
+            "--gdb" => options.gdb_port = Some(parse_number(&arg, args.next())?),
+            "--trace" => options.trace = true,
+            "--trace-file" => {
+                options.trace = true;
+                options.trace_file = Some(PathBuf::from(value(&arg, args.next())?));
+            }
+            "--trace-pc" => {
+                let range = value(&arg, args.next())?;
+                options.trace_pc = Some(parse_hex_range(&range).ok_or_else(|| format!("{arg} expects START-END in hex, got '{range}'"))?);
+            }
+            "--trace-bank" => options.trace_bank = Some(parse_number(&arg, args.next())?),
+            "--trace-last" => options.trace_last = Some(parse_number(&arg, args.next())?),
+            "--trace-labels" => options.trace_labels = true,
+            "--coverage" => options.coverage = true,
+            "--profile" => options.profile = Some(PathBuf::from(value(&arg, args.next())?)),
+            "--frames" => options.frames = Some(parse_number(&arg, args.next())?),
+            "--screenshot-at" => options.screenshot_at = Some(parse_number(&arg, args.next())?),
+            "--wav" => options.wav = Some(PathBuf::from(value(&arg, args.next())?)),
+            "--audio-channels" => {
+                let list = value(&arg, args.next())?;
+                options.audio_channels = parse_audio_channels(&list).ok_or_else(|| format!("{arg} expects channels 1-4 separated by commas, got '{list}'"))?;
+            }
+            "--scale" => {
+                options.scale = parse_number(&arg, args.next())?;
+                if options.scale == 0 {
+                    return Err(String::from("--scale must be at least 1"));
+                }
+            }
+            "--boot-rom" => options.boot_rom = Some(PathBuf::from(value(&arg, args.next())?)),
+            "--save-dir" => options.save_dir = Some(PathBuf::from(value(&arg, args.next())?)),
+            "--palette" => options.palette = Some(value(&arg, args.next())?),
+            "--load-state" => options.load_state = Some(PathBuf::from(value(&arg, args.next())?)),
+            "--record-movie" => options.record_movie = Some(PathBuf::from(value(&arg, args.next())?)),
+            "--play-movie" => options.play_movie = Some(PathBuf::from(value(&arg, args.next())?)),
+            "--expect-frame-hash" => {
+                let hash = value(&arg, args.next())?;
+                let parsed = u64::from_str_radix(hash.trim_start_matches("0x"), 16);
+                options.expect_frame_hash = Some(parsed.map_err(|_| format!("{arg} expects a hex number, got '{hash}'"))?);
+            }
+            "--model" => options.model = Some(value(&arg, args.next())?.parse()?),
+            "info" | "disasm" | "dap" if rom_path.is_none() && subcommand.is_none() => subcommand = Some(arg),
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{arg}'")),
+            _ if rom_path.is_none() => rom_path = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument '{arg}'")),
+        }
+    }
+
+    if subcommand.as_deref() == Some("dap") {
+        return match rom_path {
+            Some(_) => Err(String::from("dap takes the ROM from the launch request")),
+            None => Ok(Command::Dap),
+        };
+    }
+    let Some(rom_path) = rom_path else {
+        return Err(String::from("missing ROM path"));
+    };
+    match subcommand.as_deref() {
+        Some("info") => return Ok(Command::Info { rom_path }),
+        Some(_) => return Ok(Command::Disassemble { rom_path }),
+        None => {}
+    }
+
+    // a movie brings its own start condition
+    if options.play_movie.is_some() && (options.record_movie.is_some() || options.load_state.is_some()) {
+        return Err(String::from("--play-movie cannot be combined with --record-movie or --load-state"));
+    }
+
+    if options.debug && (options.record_movie.is_some() || options.play_movie.is_some()) {
+        return Err(String::from("--debug cannot record or play movies"));
+    }
+    if options.gdb_port.is_some() && (options.debug || options.record_movie.is_some() || options.play_movie.is_some()) {
+        return Err(String::from("--gdb cannot be combined with --debug or movies"));
+    }
+    if !options.trace && (options.trace_pc.is_some() || options.trace_bank.is_some() || options.trace_last.is_some() || options.trace_labels) {
+        return Err(String::from("--trace-pc, --trace-bank, --trace-last and --trace-labels need --trace or --trace-file"));
+    }
+    if options.headless && !options.debug && options.gdb_port.is_none() && options.frames.is_none() && options.play_movie.is_none() {
+        return Err(String::from("--headless needs --frames or --play-movie, there is no window to close"));
+    }
+    if options.expect_frame_hash.is_some() && !options.headless {
+        return Err(String::from("--expect-frame-hash needs --headless"));
+    }
+    if options.wav.is_some() && (!options.headless || options.debug || options.gdb_port.is_some()) {
+        return Err(String::from("--wav needs --headless without --debug or --gdb"));
+    }
+
+    options.rom_path = rom_path;
+    Ok(Command::Run(Box::new(options)))
+}
+
+fn value(option: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("{option} needs a value"))
+}
+
+/// `START-END`, both hex with an optional $ or 0x.
+fn parse_hex_range(range: &str) -> Option<(u16, u16)> {
+    let hex = |text: &str| {
+        let digits = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+        u16::from_str_radix(digits, 16).ok()
+    };
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (hex(start)?, hex(end)?);
+    (start <= end).then_some((start, end))
+}
+
+const ALL_AUDIO_CHANNELS: [AudioChannel; 4] = [AudioChannel::Pulse1, AudioChannel::Pulse2, AudioChannel::Wave, AudioChannel::Noise];
+
+/// Channel numbers like `1,2`, counted from 1 like the NRxy registers do.
+fn parse_audio_channels(list: &str) -> Option<Vec<AudioChannel>> {
+    list.split(',')
+        .map(|number| number.trim().parse::<usize>().ok()?.checked_sub(1).and_then(|idx| ALL_AUDIO_CHANNELS.get(idx).copied()))
+        .collect()
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, argument: Option<String>) -> Result<T, String> {
+    let argument = value(option, argument)?;
+    argument.parse().map_err(|_| format!("{option} expects a number, got '{argument}'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_run_options() {
+        let Ok(Command::Run(options)) = parse(args("--headless --frames 60 --model cgb game.gb --screenshot-at 30 --load-state game.ss1")) else {
+            panic!("expected a run command");
+        };
+
+        assert_eq!(options.rom_path, PathBuf::from("game.gb"));
+        assert!(options.headless);
+        assert_eq!(options.frames, Some(60));
+        assert_eq!(options.model, Some(Model::Cgb));
+        assert_eq!(options.screenshot_at, Some(30));
+        assert_eq!(options.load_state, Some(PathBuf::from("game.ss1")));
+        assert_eq!(options.scale, 4);
+
+        let Ok(Command::Run(options)) = parse(args("--headless --play-movie run.bk2 --expect-frame-hash 0xCBF29CE484222325 game.gb")) else {
+            panic!("expected a run command");
+        };
+        assert_eq!(options.play_movie, Some(PathBuf::from("run.bk2")));
+        assert_eq!(options.expect_frame_hash, Some(0xCBF2_9CE4_8422_2325));
+
+        let Ok(Command::Run(options)) = parse(args("--trace-file cpu.log --trace-pc $4000-7FFF --trace-bank 2 --profile game.folded --coverage game.gb")) else {
+            panic!("expected a run command");
+        };
+        assert!(options.trace);
+        assert_eq!(options.trace_pc, Some((0x4000, 0x7FFF)));
+        assert_eq!(options.trace_bank, Some(2));
+        assert!(!options.trace_labels);
+        assert_eq!(options.profile, Some(PathBuf::from("game.folded")));
+        assert!(options.coverage);
+        assert_eq!(options.audio_channels, ALL_AUDIO_CHANNELS);
+
+        let Ok(Command::Run(options)) = parse(args("--headless --frames 600 --wav game.wav --audio-channels 1,4 game.gb")) else {
+            panic!("expected a run command");
+        };
+        assert_eq!(options.wav, Some(PathBuf::from("game.wav")));
+        assert_eq!(options.audio_channels, [AudioChannel::Pulse1, AudioChannel::Noise]);
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        assert_eq!(parse(args("info game.gb")), Ok(Command::Info { rom_path: PathBuf::from("game.gb") }));
+        assert_eq!(parse(args("disasm game.gb")), Ok(Command::Disassemble { rom_path: PathBuf::from("game.gb") }));
+        assert_eq!(parse(args("dap")), Ok(Command::Dap));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(args("--frames")).is_err());
+        assert!(parse(args("--frames ten game.gb")).is_err());
+        assert!(parse(args("--model gba game.gb")).is_err());
+        assert!(parse(args("--fast game.gb")).is_err());
+        assert!(parse(args("")).is_err());
+        assert!(parse(args("--play-movie run.gbm --load-state game.ss1 game.gb")).is_err());
+        assert!(parse(args("--expect-frame-hash 1234 game.gb")).is_err());
+        assert!(parse(args("--headless game.gb")).is_err());
+        assert!(parse(args("--headless --record-movie run.gbm game.gb")).is_err());
+        assert!(parse(args("--trace-last 100 game.gb")).is_err());
+        assert!(parse(args("--trace-labels game.gb")).is_err());
+        assert!(parse(args("--trace --trace-pc 0200-0100 game.gb")).is_err());
+        assert!(parse(args("--gdb 2345 --debug game.gb")).is_err());
+        assert!(parse(args("--wav game.wav game.gb")).is_err());
+        assert!(parse(args("--audio-channels 0,5 game.gb")).is_err());
+        assert!(parse(args("--audio-channels pulse game.gb")).is_err());
+    }
+}
@@ -0,0 +1,660 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use gameboy_emu::game_boy::GameBoy;
+use gameboy_emu::game_boy::cpu::registers::RegisterPair;
+use gameboy_emu::game_boy::debugger::{self, Debugger, Stop};
+use gameboy_emu::game_boy::memory::watchpoints;
+use gameboy_emu::game_boy::source_map::SourceMap;
+use gameboy_emu::game_boy::symbols::Symbols;
+use crate::debugger::parse_location;
+use crate::json::Json;
+
+/// The SM83 is the only thread there is.
+const THREAD_ID: usize = 1;
+const REGISTERS: [(&str, RegisterPair); 6] = [
+    ("AF", RegisterPair::AF), ("BC", RegisterPair::BC), ("DE", RegisterPair::DE),
+    ("HL", RegisterPair::HL), ("SP", RegisterPair::SP), ("PC", RegisterPair::PC),
+];
+/// `variablesReference` of the scopes every frame has.
+const REGISTERS_REFERENCE: usize = 1;
+const IO_REFERENCE: usize = 2;
+
+/// A ROM ready to debug, with what is known about its source.
+pub struct Program {
+    pub game_boy: GameBoy,
+    pub symbols: Symbols,
+    pub sources: SourceMap,
+}
+
+struct Session {
+    game_boy: GameBoy,
+    debugger: Debugger,
+    sources: SourceMap,
+}
+
+/// Serves the Debug Adapter Protocol on `input` and `output` until the editor disconnects.
+/// `load` turns the program of a launch request into something to debug.
+pub fn run(input: impl BufRead + Send + 'static, output: impl Write, load: impl Fn(&Path) -> Result<Program, String>) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    // requests like pause have to get through while the game runs
+    thread::spawn(move || read_messages(input, sender));
+    let mut server = Server::new(output);
+    loop {
+        let request = match next_request(&receiver, server.running) {
+            Ok(request) => request,
+            Err(TryRecvError::Empty) => {
+                server.run_frame()?;
+                continue;
+            }
+            Err(TryRecvError::Disconnected) => return Ok(()),
+        };
+        if !server.handle(&request, &load)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Waits for the next request while stopped, only looks while running.
+fn next_request(receiver: &Receiver<Json>, running: bool) -> Result<Json, TryRecvError> {
+    if running {
+        receiver.try_recv()
+    } else {
+        receiver.recv().map_err(|_| TryRecvError::Disconnected)
+    }
+}
+
+/// Reads `Content-Length` framed messages until the input ends.
+fn read_messages(mut input: impl BufRead, sender: Sender<Json>) {
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            match input.read_line(&mut header) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') && name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+        let Some(length) = length else { continue };
+        let mut body = vec![0; length];
+        if input.read_exact(&mut body).is_err() {
+            return;
+        }
+        match Json::parse(&String::from_utf8_lossy(&body)) {
+            Ok(message) => {
+                if sender.send(message).is_err() {
+                    return;
+                }
+            }
+            // stdout belongs to the protocol
+            Err(error) => eprintln!("ignoring a message that is no JSON: {error}"),
+        }
+    }
+}
+
+struct Server<W: Write> {
+    output: W,
+    seq: usize,
+    session: Option<Session>,
+    running: bool,
+    stop_on_entry: bool,
+    /// Each kind of breakpoint is set as a whole, these add up to the ones of the debugger.
+    source_breakpoints: HashMap<PathBuf, Vec<(u16, Option<usize>)>>,
+    function_breakpoints: Vec<(u16, Option<usize>)>,
+    instruction_breakpoints: Vec<(u16, Option<usize>)>,
+}
+
+impl<W: Write> Server<W> {
+    fn new(output: W) -> Server<W> {
+        Server {
+            output,
+            seq: 0,
+            session: None,
+            running: false,
+            stop_on_entry: false,
+            source_breakpoints: HashMap::new(),
+            function_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+        }
+    }
+
+    /// Answers one request, false ends the session.
+    fn handle(&mut self, request: &Json, load: &impl Fn(&Path) -> Result<Program, String>) -> io::Result<bool> {
+        if request.get("type").and_then(Json::as_str) != Some("request") {
+            return Ok(true);
+        }
+        let command = request.get("command").and_then(Json::as_str).unwrap_or_default();
+        let arguments = request.get("arguments").unwrap_or(&Json::Null);
+        match command {
+            "initialize" => self.respond(request, Ok(capabilities()))?,
+            "launch" => {
+                let program = arguments.get("program").and_then(Json::as_str).ok_or_else(|| String::from("launch needs a program"));
+                match program.and_then(|program| load(Path::new(program))) {
+                    Ok(program) => {
+                        self.session = Some(Session {
+                            game_boy: program.game_boy,
+                            debugger: Debugger::with_symbols(program.symbols),
+                            sources: program.sources,
+                        });
+                        self.stop_on_entry = arguments.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+                        self.respond(request, Ok(Json::Null))?;
+                        // breakpoints come after this, now that there are symbols to resolve them with
+                        self.event("initialized", Json::Null)?;
+                    }
+                    Err(message) => self.respond(request, Err(message))?,
+                }
+            }
+            "configurationDone" => {
+                self.respond(request, Ok(Json::Null))?;
+                if self.stop_on_entry {
+                    self.stopped("entry")?;
+                } else {
+                    self.running = self.session.is_some();
+                }
+            }
+            "disconnect" => {
+                self.respond(request, Ok(Json::Null))?;
+                return Ok(false);
+            }
+            "threads" => {
+                let thread = Json::object([("id", THREAD_ID.into()), ("name", "SM83".into())]);
+                self.respond(request, Ok(Json::object([("threads", vec![thread].into())])))?;
+            }
+            "pause" => {
+                if let Some(session) = &mut self.session {
+                    session.debugger.cancel_step();
+                }
+                self.respond(request, Ok(Json::Null))?;
+                self.stopped("pause")?;
+            }
+            "setExceptionBreakpoints" => self.respond(request, Ok(Json::object([("breakpoints", Json::Array(Vec::new()))])))?,
+            _ => {
+                let result = match self.session {
+                    Some(_) => self.session_request(command, arguments),
+                    None => Err(String::from("no program has been launched")),
+                };
+                let stop = match result {
+                    Ok((body, stop)) => {
+                        self.respond(request, Ok(body))?;
+                        stop
+                    }
+                    Err(message) => {
+                        self.respond(request, Err(message))?;
+                        None
+                    }
+                };
+                if let Some(stop) = stop {
+                    self.report(stop)?;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Requests that need a program, with how execution stopped if they ran it.
+    fn session_request(&mut self, command: &str, arguments: &Json) -> Result<(Json, Option<Stop>), String> {
+        let session = self.session.as_mut().expect("checked by the caller");
+        let game_boy = &mut session.game_boy;
+        let debugger = &mut session.debugger;
+        let body = match command {
+            "setBreakpoints" => {
+                let path = arguments.get("source").and_then(|source| source.get("path")).and_then(Json::as_str).ok_or("no source path")?;
+                let path = canonical(Path::new(path));
+                let mut breakpoints = Vec::new();
+                let mut results = Vec::new();
+                for breakpoint in arguments.get("breakpoints").and_then(Json::as_array).unwrap_or_default() {
+                    let line = breakpoint.get("line").and_then(Json::as_u64).unwrap_or(0) as usize;
+                    match session.sources.location_of(&path, line) {
+                        Some((line, bank, address)) => {
+                            breakpoints.push((address, rom_bank(bank, address)));
+                            results.push(Json::object([("verified", true.into()), ("line", line.into())]));
+                        }
+                        None => results.push(Json::object([("verified", false.into()), ("message", "no code here that the .sym file and source agree on".into())])),
+                    }
+                }
+                self.source_breakpoints.insert(path, breakpoints);
+                sync_breakpoints(debugger, &self.source_breakpoints, &self.function_breakpoints, &self.instruction_breakpoints);
+                Json::object([("breakpoints", results.into())])
+            }
+            "setFunctionBreakpoints" | "setInstructionBreakpoints" => {
+                let mut breakpoints = Vec::new();
+                let mut results = Vec::new();
+                for breakpoint in arguments.get("breakpoints").and_then(Json::as_array).unwrap_or_default() {
+                    let key = if command == "setFunctionBreakpoints" { "name" } else { "instructionReference" };
+                    let name = breakpoint.get(key).and_then(Json::as_str).unwrap_or_default();
+                    let offset = breakpoint.get("offset").and_then(Json::as_i64).unwrap_or(0);
+                    match parse_location(debugger.symbols(), name) {
+                        Ok((address, bank)) => {
+                            breakpoints.push((address.wrapping_add(offset as u16), bank));
+                            results.push(Json::object([("verified", true.into())]));
+                        }
+                        Err(message) => results.push(Json::object([("verified", false.into()), ("message", message.into())])),
+                    }
+                }
+                if command == "setFunctionBreakpoints" {
+                    self.function_breakpoints = breakpoints;
+                } else {
+                    self.instruction_breakpoints = breakpoints;
+                }
+                sync_breakpoints(debugger, &self.source_breakpoints, &self.function_breakpoints, &self.instruction_breakpoints);
+                Json::object([("breakpoints", results.into())])
+            }
+            "continue" => {
+                self.running = true;
+                Json::object([("allThreadsContinued", true.into())])
+            }
+            "next" => return Ok((Json::Null, Some(debugger.step_over(game_boy, Some(1))))),
+            "stepIn" => return Ok((Json::Null, Some(debugger.step(game_boy, 1)))),
+            "stepOut" => return Ok((Json::Null, Some(debugger.step_out(game_boy, Some(1))))),
+            "stackTrace" => {
+                let mut frames = vec![stack_frame(session, 0, session.game_boy.cpu().registers().read_pc())];
+                for (idx, frame) in debugger::backtrace(&session.game_boy).iter().enumerate() {
+                    frames.push(stack_frame(session, idx + 1, frame.call_site));
+                }
+                Json::object([("totalFrames", frames.len().into()), ("stackFrames", frames.into())])
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: usize| {
+                    Json::object([("name", name.into()), ("variablesReference", reference.into()), ("expensive", false.into())])
+                };
+                Json::object([("scopes", vec![scope("Registers", REGISTERS_REFERENCE), scope("I/O registers", IO_REFERENCE)].into())])
+            }
+            "variables" => {
+                let variables = match arguments.get("variablesReference").and_then(Json::as_u64) {
+                    Some(1) => registers(game_boy),
+                    Some(2) => io_registers(game_boy),
+                    _ => Vec::new(),
+                };
+                Json::object([("variables", variables.into())])
+            }
+            "setVariable" => {
+                let name = arguments.get("name").and_then(Json::as_str).unwrap_or_default();
+                let value = arguments.get("value").and_then(Json::as_str).and_then(parse_value).ok_or("values are hex with $ or 0x, or decimal")?;
+                let variable = match arguments.get("variablesReference").and_then(Json::as_u64) {
+                    Some(1) => {
+                        let &(_, pair) = REGISTERS.iter().find(|(register, _)| *register == name).ok_or("only register pairs can be set")?;
+                        game_boy.write_register(pair, value);
+                        variable(name, game_boy.cpu().registers().read_pair(pair))
+                    }
+                    Some(2) => {
+                        let address = watchpoints::io_register(name).ok_or("no such I/O register")?;
+                        game_boy.write_memory(address, u8::try_from(value).map_err(|_| "I/O registers are bytes")?);
+                        byte_variable(name, address, game_boy.memory().peek(address))
+                    }
+                    _ => return Err(String::from("no such variable")),
+                };
+                Json::object([("value", variable.get("value").cloned().unwrap_or(Json::Null))])
+            }
+            "evaluate" => {
+                let expression = arguments.get("expression").and_then(Json::as_str).unwrap_or_default().trim();
+                if let Some(&(name, pair)) = REGISTERS.iter().find(|(register, _)| register.eq_ignore_ascii_case(expression)) {
+                    let value = variable(name, game_boy.cpu().registers().read_pair(pair));
+                    Json::object([("result", value.get("value").cloned().unwrap_or(Json::Null)), ("variablesReference", 0.into())])
+                } else {
+                    let (address, _) = parse_location(debugger.symbols(), expression)?;
+                    Json::object([
+                        ("result", format!("${:02X}", game_boy.memory().peek(address)).into()),
+                        ("memoryReference", memory_reference(address).into()),
+                        ("variablesReference", 0.into()),
+                    ])
+                }
+            }
+            "readMemory" => {
+                let (start, count) = memory_range(debugger, arguments)?;
+                let count = count.min(0x10000);
+                let readable = (start.max(0)..(start + count as i64).min(0x10000)).map(|address| address as u16);
+                let memory = game_boy.memory();
+                let data: Vec<u8> = readable.map(|address| memory.peek(address)).collect();
+                Json::object([
+                    ("address", format!("0x{:04X}", start.clamp(0, 0xFFFF)).into()),
+                    ("data", base64_encode(&data).into()),
+                    ("unreadableBytes", (count - data.len()).into()),
+                ])
+            }
+            "writeMemory" => {
+                let data = arguments.get("data").and_then(Json::as_str).and_then(base64_decode).ok_or("data is no base64")?;
+                let (start, _) = memory_range(debugger, arguments)?;
+                let mut written = 0;
+                for (offset, byte) in data.into_iter().enumerate() {
+                    if let Ok(address) = u16::try_from(start + offset as i64) {
+                        game_boy.write_memory(address, byte);
+                        written += 1;
+                    }
+                }
+                Json::object([("bytesWritten", written.into())])
+            }
+            _ => return Err(format!("{command} is not supported")),
+        };
+        Ok((body, None))
+    }
+
+    /// Runs a frame of a continue, or of a step over or out that has not got back yet.
+    fn run_frame(&mut self) -> io::Result<()> {
+        let Some(session) = &mut self.session else {
+            self.running = false;
+            return Ok(());
+        };
+        match session.debugger.resume(&mut session.game_boy, Some(1)) {
+            Stop::Done => Ok(()),
+            stop => self.report(stop),
+        }
+    }
+
+    /// Tells the editor why execution stopped, or keeps running a step that needs more frames.
+    fn report(&mut self, stop: Stop) -> io::Result<()> {
+        let stepping = self.session.as_ref().is_some_and(|session| session.debugger.is_stepping());
+        match stop {
+            Stop::Done if stepping => {
+                self.running = true;
+                Ok(())
+            }
+            Stop::Done | Stop::Returned(_) => self.stopped("step"),
+            Stop::Breakpoint(_) => self.stopped("breakpoint"),
+            Stop::Watchpoint(_) => self.stopped("data breakpoint"),
+            Stop::Locked(_) => self.stopped("exception"),
+        }
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        self.running = false;
+        let body = Json::object([("reason", reason.into()), ("threadId", THREAD_ID.into()), ("allThreadsStopped", true.into())]);
+        self.event("stopped", body)
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let request_seq = request.get("seq").cloned().unwrap_or(Json::Null);
+        let command = request.get("command").cloned().unwrap_or(Json::Null);
+        let mut message = vec![("type", "response".into()), ("request_seq", request_seq), ("command", command)];
+        match result {
+            Ok(body) => {
+                message.push(("success", true.into()));
+                if body != Json::Null {
+                    message.push(("body", body));
+                }
+            }
+            Err(error) => {
+                message.push(("success", false.into()));
+                message.push(("message", error.into()));
+            }
+        }
+        self.send(message)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        let mut message = vec![("type", "event".into()), ("event", event.into())];
+        if body != Json::Null {
+            message.push(("body", body));
+        }
+        self.send(message)
+    }
+
+    fn send(&mut self, mut message: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        message.insert(0, ("seq", self.seq.into()));
+        let text = Json::object(message).to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{text}", text.len())?;
+        self.output.flush()
+    }
+}
+
+/// Sets the breakpoints of the debugger to all kinds of DAP breakpoints together.
+fn sync_breakpoints(
+    debugger: &mut Debugger,
+    source: &HashMap<PathBuf, Vec<(u16, Option<usize>)>>,
+    function: &[(u16, Option<usize>)],
+    instruction: &[(u16, Option<usize>)],
+) {
+    let old: Vec<u16> = debugger.breakpoints().map(|(address, _)| address).collect();
+    for address in old {
+        debugger.remove_breakpoint(address);
+    }
+    for &(address, bank) in source.values().flatten().chain(function).chain(instruction) {
+        debugger.add_breakpoint(address, bank);
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object([
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsFunctionBreakpoints", true.into()),
+        ("supportsInstructionBreakpoints", true.into()),
+        ("supportsReadMemoryRequest", true.into()),
+        ("supportsWriteMemoryRequest", true.into()),
+        ("supportsSetVariable", true.into()),
+        ("supportsEvaluateForHovers", true.into()),
+    ])
+}
+
+fn stack_frame(session: &Session, id: usize, address: u16) -> Json {
+    let name = session.debugger.describe(&session.game_boy, address).unwrap_or_else(|| format!("${address:04X}"));
+    let mut frame = vec![
+        ("id", id.into()),
+        ("name", name.into()),
+        ("instructionPointerReference", memory_reference(address).into()),
+    ];
+    let line = session.game_boy.bank_at(address).and_then(|bank| session.sources.line_at(bank, address));
+    match line {
+        Some((path, line)) => {
+            let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            let source = Json::object([("name", name.into()), ("path", path.to_string_lossy().into_owned().into())]);
+            frame.extend([("source", source), ("line", line.into()), ("column", 1.into())]);
+        }
+        None => frame.extend([("line", 0.into()), ("column", 0.into())]),
+    }
+    Json::object(frame)
+}
+
+fn registers(game_boy: &GameBoy) -> Vec<Json> {
+    let registers = game_boy.cpu().registers();
+    let mut variables: Vec<Json> = REGISTERS.iter().map(|&(name, pair)| variable(name, registers.read_pair(pair))).collect();
+    let flags: String = [(registers.read_zero_flag(), 'Z'), (registers.read_subtraction_flag(), 'N'), (registers.read_half_carry_flag(), 'H'), (registers.read_carry_flag(), 'C')]
+        .iter()
+        .map(|&(set, flag)| if set { flag } else { '-' })
+        .collect();
+    variables.push(Json::object([("name", "flags".into()), ("value", flags.into()), ("variablesReference", 0.into())]));
+    variables.push(Json::object([("name", "IME".into()), ("value", game_boy.cpu().ime().to_string().into()), ("variablesReference", 0.into())]));
+    variables
+}
+
+fn io_registers(game_boy: &GameBoy) -> Vec<Json> {
+    let memory = game_boy.memory();
+    (0xFF00..=0xFF7F)
+        .filter_map(|address| Some(byte_variable(watchpoints::io_register_name(address)?, address, memory.peek(address))))
+        .collect()
+}
+
+/// A register pair, with a memory reference for what it points at.
+fn variable(name: &str, value: u16) -> Json {
+    let mut variable = vec![("name", name.into()), ("value", format!("${value:04X}").into()), ("variablesReference", 0.into())];
+    if name != "AF" {
+        variable.push(("memoryReference", memory_reference(value).into()));
+    }
+    Json::object(variable)
+}
+
+fn byte_variable(name: &str, address: u16, value: u8) -> Json {
+    Json::object([
+        ("name", name.into()),
+        ("value", format!("${value:02X}").into()),
+        ("variablesReference", 0.into()),
+        ("memoryReference", memory_reference(address).into()),
+    ])
+}
+
+fn memory_reference(address: u16) -> String {
+    format!("0x{address:04X}")
+}
+
+/// The start and length of a readMemory or writeMemory, the start outside the address space when the offset puts it there.
+fn memory_range(debugger: &Debugger, arguments: &Json) -> Result<(i64, usize), String> {
+    let reference = arguments.get("memoryReference").and_then(Json::as_str).ok_or("no memory reference")?;
+    let (address, _) = parse_location(debugger.symbols(), reference)?;
+    let offset = arguments.get("offset").and_then(Json::as_i64).unwrap_or(0);
+    let count = arguments.get("count").and_then(Json::as_u64).unwrap_or(0) as usize;
+    Ok((address as i64 + offset, count))
+}
+
+/// Labels in switchable ROM banks only stop in their bank.
+fn rom_bank(bank: usize, address: u16) -> Option<usize> {
+    (0x4000..0x8000).contains(&address).then_some(bank)
+}
+
+/// Editors send paths as the user opened them, the source map has them from the file system.
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn parse_value(text: &str) -> Option<u16> {
+    let text = text.trim();
+    match text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (idx, &byte)| bits | (byte as u32) << (16 - 8 * idx));
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                text.push(BASE64[(bits >> (18 - 6 * idx) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for char in text.bytes().filter(|&char| char != b'=') {
+        bits = bits << 6 | BASE64.iter().position(|&digit| digit == char)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use gameboy_emu::game_boy::test_rom::test_rom;
+
+    fn request(seq: usize, command: &str, arguments: &str) -> String {
+        let body = format!("{{\"seq\":{seq},\"type\":\"request\",\"command\":\"{command}\",\"arguments\":{arguments}}}");
+        format!("Content-Length: {}\r\n\r\n{body}", body.len())
+    }
+
+    #[test]
+    fn debugs_a_launched_rom() {
+        assert_eq!(base64_decode(&base64_encode(b"SM83!")).as_deref(), Some(&b"SM83!"[..]));
+
+        // 0150: ld a, $80; call $0157; jr -7; 0157: ld b, a; ret
+        let rom = test_rom(&[0x3E, 0x80, 0xCD, 0x57, 0x01, 0x18, 0xF9, 0x47, 0xC9]);
+        let source = "Main:\n    ld a, $80\n.loop\n    call Helper\n    jr Main\n\nHelper:\n    ld b, a\n    ret\n";
+        let script = [
+            request(1, "initialize", "{\"adapterID\":\"gameboy\"}"),
+            request(2, "launch", "{\"program\":\"game.gb\"}"),
+            request(3, "setBreakpoints", "{\"source\":{\"path\":\"/nowhere/main.asm\"},\"breakpoints\":[{\"line\":8},{\"line\":20}]}"),
+            request(4, "setFunctionBreakpoints", "{\"breakpoints\":[{\"name\":\"Main.loop\"}]}"),
+            request(5, "configurationDone", "{}"),
+            request(6, "next", "{\"threadId\":1}"),
+            request(7, "stackTrace", "{\"threadId\":1}"),
+            request(8, "variables", "{\"variablesReference\":1}"),
+            request(9, "setVariable", "{\"variablesReference\":1,\"name\":\"DE\",\"value\":\"$C000\"}"),
+            request(10, "writeMemory", "{\"memoryReference\":\"0xC000\",\"data\":\"q80=\"}"),
+            request(11, "readMemory", "{\"memoryReference\":\"0xC000\",\"offset\":-1,\"count\":3}"),
+            request(12, "disconnect", "{}"),
+        ];
+        let load = |path: &Path| {
+            assert_eq!(path, Path::new("game.gb"));
+            let symbols = Symbols::parse("00:0150 Main\n00:0152 Main.loop\n00:0157 Helper\n").unwrap();
+            let sources = SourceMap::new(&rom, &symbols, &[(PathBuf::from("/nowhere/main.asm"), String::from(source))]);
+            Ok(Program { game_boy: GameBoy::new(rom.clone()).unwrap(), symbols, sources })
+        };
+        // like an editor, wait for the game to stop before the next request
+        let (sender, receiver) = mpsc::channel();
+        read_messages(Cursor::new(script.concat().into_bytes()), sender);
+        let mut output = Vec::new();
+        let mut server = Server::new(&mut output);
+        for request in receiver.try_iter() {
+            assert!(server.handle(&request, &load).unwrap() || request.get("command") == Some(&Json::from("disconnect")));
+            while server.running {
+                server.run_frame().unwrap();
+            }
+        }
+
+        let output = String::from_utf8(output).unwrap();
+        let messages: Vec<Json> = output.split("Content-Length: ").skip(1)
+            .map(|message| Json::parse(message.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect();
+        let summary: Vec<String> = messages.iter().map(|message| match message.get("type").and_then(Json::as_str) {
+            Some("event") => format!("event {}", message.get("event").and_then(Json::as_str).unwrap()),
+            _ => format!("{} {}", message.get("command").and_then(Json::as_str).unwrap(), message.get("success").unwrap()),
+        }).collect();
+        assert_eq!(summary, [
+            "initialize true", "launch true", "event initialized", "setBreakpoints true", "setFunctionBreakpoints true",
+            "configurationDone true", "event stopped", "next true", "event stopped", "stackTrace true", "variables true",
+            "setVariable true", "writeMemory true", "readMemory true", "disconnect true",
+        ]);
+        let body = |idx: usize| messages[idx].get("body").unwrap().to_string();
+        assert_eq!(body(3), r#"{"breakpoints":[{"verified":true,"line":8},{"verified":false,"message":"no code here that the .sym file and source agree on"}]}"#);
+        // the function breakpoint on Main.loop, then the source one in Helper that the call runs into
+        assert_eq!(body(6), r#"{"reason":"breakpoint","threadId":1,"allThreadsStopped":true}"#);
+        assert_eq!(body(8), r#"{"reason":"breakpoint","threadId":1,"allThreadsStopped":true}"#);
+        assert!(body(9).contains(r#"{"id":0,"name":"Helper","instructionPointerReference":"0x0157","source":{"name":"main.asm","path":"/nowhere/main.asm"},"line":8,"column":1}"#));
+        assert!(body(9).contains(r#""name":"Main.loop","#));
+        assert!(body(10).contains(r#"{"name":"AF","value":"$80B0","variablesReference":0}"#));
+        assert_eq!(body(13), r#"{"address":"0xBFFF","data":"/6vN","unreadableBytes":0}"#);
+    }
+
+    #[test]
+    fn accesses_echo_ram_and_the_unusable_range() {
+        let script = [
+            request(1, "launch", "{\"program\":\"game.gb\"}"),
+            request(2, "writeMemory", "{\"memoryReference\":\"0xDFFF\",\"data\":\"q83v\"}"),
+            request(3, "readMemory", "{\"memoryReference\":\"0xDFFE\",\"count\":4}"),
+            request(4, "readMemory", "{\"memoryReference\":\"0xFE9F\",\"count\":98}"),
+            request(5, "setVariable", "{\"variablesReference\":2,\"name\":\"LY\",\"value\":\"$99\"}"),
+        ];
+        let load = |_: &Path| Ok(Program { game_boy: GameBoy::new(test_rom(&[0x18, 0xFE])).unwrap(), symbols: Symbols::default(), sources: SourceMap::default() });
+        let (sender, receiver) = mpsc::channel();
+        read_messages(Cursor::new(script.concat().into_bytes()), sender);
+        let mut output = Vec::new();
+        let mut server = Server::new(&mut output);
+        for request in receiver.try_iter() {
+            assert!(server.handle(&request, &load).unwrap());
+        }
+
+        let output = String::from_utf8(output).unwrap();
+        let bodies: Vec<String> = output.split("Content-Length: ").skip(1)
+            .map(|message| Json::parse(message.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .filter(|message| message.get("type").and_then(Json::as_str) == Some("response"))
+            .map(|message| message.get("body").map(Json::to_string).unwrap_or_default())
+            .collect();
+        // the bytes past DFFF land in echo RAM, so the work RAM they mirror reads them back
+        assert_eq!(bodies[2], r#"{"address":"0xDFFE","data":"AKvN7w==","unreadableBytes":0}"#);
+        assert_eq!(bodies[3], format!(r#"{{"address":"0xFE9F","data":"{}","unreadableBytes":0}}"#, base64_encode(&[[0x00].as_slice(), &[0xFF; 0x60], &[0xCF]].concat())));
+        assert_eq!(bodies[4], r#"{"value":"$00"}"#);
+    }
+}
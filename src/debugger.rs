@@ -0,0 +1,407 @@
+use std::io;
+use std::io::{BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use gameboy_emu::game_boy::GameBoy;
+use gameboy_emu::game_boy::debugger::{self, Debugger, Stop};
+use gameboy_emu::game_boy::memory::watchpoints::{self, Access, Condition, Watchpoint, WatchpointHit};
+use gameboy_emu::game_boy::symbols::Symbols;
+
+const HELP: &str = "\
+commands, an empty line repeats the last one:
+  step [N]              s  execute N instructions (default 1)
+  next                  n  step over calls and rsts
+  finish                f  run until the current function returns
+  continue [FRAMES]     c  run until a breakpoint, or for about FRAMES frames
+  break [ADDR]          b  set a breakpoint at ADDR, or list them
+  delete ADDR           d  remove the breakpoint at ADDR
+  watch [r|w|rw] ADDR[-END] [BYTE]
+                        wa stop when the CPU reads or writes (default w) the range, only BYTE if given
+  watch lcd-off            stop when LCDC turns the LCD off outside VBlank
+  watch                    list the watchpoints
+  unwatch ID            uw remove watchpoint ID
+  registers             r  show the CPU registers
+  memory ADDR [LEN]     x  hexdump LEN bytes (default 64)
+  write ADDR BYTE...    w  write bytes starting at ADDR
+  disassemble [ADDR]    l  disassemble around ADDR (default PC)
+  backtrace             bt show the calls on the stack
+  quit                  q
+addresses and bytes are hex, with or without a $ or 0x prefix. Addresses can also be labels from the
+.sym file, a breakpoint on a label in a switchable ROM bank only hits in its bank. I/O registers go by
+name too, like LCDC. continue without FRAMES, next and finish stop when you press Enter, a command
+typed while the game runs is carried out next";
+
+/// Commands read on their own thread, so that the game can run until one comes in.
+struct Commands {
+    lines: Receiver<io::Result<String>>,
+    /// The line that stopped the running game, it is the next command.
+    pending: Option<io::Result<String>>,
+}
+
+impl Commands {
+    fn read(input: impl BufRead + Send + 'static) -> Commands {
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in input.lines() {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Commands { lines, pending: None }
+    }
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        self.pending.take().or_else(|| self.lines.recv().ok())
+    }
+
+    /// Whether a line came in or the input ended, without waiting. An empty line only interrupts.
+    fn interrupted(&mut self) -> bool {
+        match self.lines.try_recv() {
+            Ok(Ok(line)) if line.trim().is_empty() => true,
+            Ok(line) => {
+                self.pending = Some(line);
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => true,
+        }
+    }
+}
+
+/// Reads commands from `input` until `quit` or the end of input and answers on `output`.
+pub fn run(game_boy: &mut GameBoy, symbols: Symbols, input: impl BufRead + Send + 'static, mut output: impl Write) -> io::Result<()> {
+    let mut debugger = Debugger::with_symbols(symbols);
+    let mut commands = Commands::read(input);
+    let mut last_command = String::new();
+    writeln!(output, "type help for a list of commands")?;
+    show_position(game_boy, &debugger, &mut output)?;
+
+    write!(output, "(debug) ")?;
+    output.flush()?;
+    while let Some(line) = commands.next() {
+        let line = line?;
+        let command = if line.trim().is_empty() { last_command.clone() } else { line.trim().to_string() };
+        match execute(game_boy, &mut debugger, &mut commands, &command, &mut output) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(message) => writeln!(output, "error: {message}")?,
+        }
+        last_command = command;
+        write!(output, "(debug) ")?;
+        output.flush()?;
+    }
+    writeln!(output)
+}
+
+/// Runs one command and returns whether it was `quit`. Errors in the command come back as messages.
+fn execute(game_boy: &mut GameBoy, debugger: &mut Debugger, commands: &mut Commands, command: &str, output: &mut impl Write) -> Result<bool, String> {
+    let mut words = command.split_whitespace();
+    let Some(name) = words.next() else { return Ok(false) };
+    let arguments: Vec<&str> = words.collect();
+    let out = |error: io::Error| error.to_string();
+
+    match name {
+        "step" | "s" => {
+            let count = arguments.first().map_or(Ok(1), |count| parse_count(count))?;
+            let stop = debugger.step(game_boy, count);
+            report(game_boy, debugger, Some(stop), output).map_err(out)?;
+        }
+        "next" | "n" => {
+            let stop = debugger.step_over(game_boy, Some(1));
+            let stop = keep_running(game_boy, debugger, commands, stop, None, false);
+            report(game_boy, debugger, stop, output).map_err(out)?;
+        }
+        "finish" | "f" => {
+            let stop = debugger.step_out(game_boy, Some(1));
+            let stop = keep_running(game_boy, debugger, commands, stop, None, false);
+            report(game_boy, debugger, stop, output).map_err(out)?;
+        }
+        "continue" | "c" => {
+            let frames = arguments.first().map(|frames| parse_count(frames)).transpose()?;
+            let stop = debugger.resume(game_boy, Some(frames.map_or(1, |frames| frames.min(1))));
+            let stop = keep_running(game_boy, debugger, commands, stop, frames, true);
+            report(game_boy, debugger, stop, output).map_err(out)?;
+        }
+        "break" | "b" => match arguments.first() {
+            Some(location) => {
+                let (address, bank) = parse_location(debugger.symbols(), location)?;
+                if !debugger.add_breakpoint(address, bank) {
+                    return Err(format!("there already is a breakpoint at {address:04X}"));
+                }
+            }
+            None => {
+                for (address, bank) in debugger.breakpoints() {
+                    let name = bank.and_then(|bank| debugger.symbols().describe(bank, address));
+                    match (bank, name) {
+                        (Some(bank), Some(name)) => writeln!(output, "{address:04X} in bank {bank} <{name}>"),
+                        (Some(bank), None) => writeln!(output, "{address:04X} in bank {bank}"),
+                        _ => writeln!(output, "{address:04X}"),
+                    }
+                    .map_err(out)?;
+                }
+            }
+        },
+        "delete" | "d" => {
+            let address = parse_address(debugger.symbols(), arguments.first().ok_or("delete needs an address")?)?;
+            if !debugger.remove_breakpoint(address) {
+                return Err(format!("there is no breakpoint at {address:04X}"));
+            }
+        }
+        "watch" | "wa" => match arguments.as_slice() {
+            [] => {
+                for (id, watchpoint) in game_boy.watchpoints() {
+                    writeln!(output, "{id}: {}", describe_watchpoint(&watchpoint)).map_err(out)?;
+                }
+            }
+            ["lcd-off"] => {
+                let id = game_boy.add_watchpoint(Watchpoint::lcd_off_outside_vblank());
+                writeln!(output, "watchpoint {id}").map_err(out)?;
+            }
+            arguments => {
+                let id = game_boy.add_watchpoint(parse_watchpoint(debugger.symbols(), arguments)?);
+                writeln!(output, "watchpoint {id}").map_err(out)?;
+            }
+        },
+        "unwatch" | "uw" => {
+            let id = arguments.first().ok_or("unwatch needs a watchpoint id")?;
+            let id = parse_count(id)? as usize;
+            if !game_boy.remove_watchpoint(id) {
+                return Err(format!("there is no watchpoint {id}"));
+            }
+        }
+        "registers" | "r" => show_registers(game_boy, output).map_err(out)?,
+        "memory" | "x" => {
+            let address = parse_address(debugger.symbols(), arguments.first().ok_or("memory needs an address")?)?;
+            let length = arguments.get(1).map_or(Ok(64), |length| parse_count(length))?;
+            hexdump(game_boy, address, length, output).map_err(out)?;
+        }
+        "write" | "w" => {
+            let address = parse_address(debugger.symbols(), arguments.first().ok_or("write needs an address")?)?;
+            let bytes = arguments[1..].iter().map(|byte| parse_hex(byte).and_then(byte_value)).collect::<Result<Vec<_>, _>>()?;
+            if bytes.is_empty() {
+                return Err(String::from("write needs bytes to write"));
+            }
+            for (offset, byte) in bytes.into_iter().enumerate() {
+                game_boy.write_memory(address.wrapping_add(offset as u16), byte);
+            }
+        }
+        "disassemble" | "l" => {
+            let pc = game_boy.cpu().registers().read_pc();
+            let address = arguments.first().map_or(Ok(pc), |address| parse_address(debugger.symbols(), address))?;
+            for instruction in debugger::disassemble_around(game_boy, address, 5, 10) {
+                let label = |address| game_boy.bank_at(address).and_then(|bank| debugger.symbols().name_at(bank, address));
+                if let Some(label) = label(instruction.address) {
+                    writeln!(output, "   {label}:").map_err(out)?;
+                }
+                let marker = if instruction.address == pc { "=>" } else { "  " };
+                match instruction.target.and_then(|target| debugger.describe(game_boy, target)) {
+                    Some(target) => writeln!(output, "{marker} {:04X}: {} ; {target}", instruction.address, instruction.text),
+                    None => writeln!(output, "{marker} {:04X}: {}", instruction.address, instruction.text),
+                }
+                .map_err(out)?;
+            }
+        }
+        "backtrace" | "bt" => {
+            writeln!(output, "#0 {}", location(game_boy, debugger, game_boy.cpu().registers().read_pc())).map_err(out)?;
+            for (idx, frame) in debugger::backtrace(game_boy).iter().enumerate() {
+                writeln!(
+                    output,
+                    "#{} {}, returns to {:04X} (stack {:04X})",
+                    idx + 1, location(game_boy, debugger, frame.call_site), frame.return_address, frame.stack_address,
+                )
+                .map_err(out)?;
+            }
+        }
+        "help" | "h" => writeln!(output, "{HELP}").map_err(out)?,
+        "quit" | "q" => return Ok(true),
+        _ => return Err(format!("unknown command '{name}', type help for a list")),
+    }
+    Ok(false)
+}
+
+/// Goes on from the first frame that ended in `stop` a frame at a time, while nothing stopped the game
+/// and for up to `frames` frames in all. Only a `continue` goes on without a step over or out pending.
+/// Without a frame count a command that comes in stops it, then this is None.
+fn keep_running(
+    game_boy: &mut GameBoy, debugger: &mut Debugger, commands: &mut Commands, mut stop: Stop, frames: Option<u64>, continuing: bool,
+) -> Option<Stop> {
+    let mut ran = 1;
+    while stop == Stop::Done && (continuing || debugger.is_stepping()) && frames.is_none_or(|frames| ran < frames) {
+        if frames.is_none() && commands.interrupted() {
+            debugger.cancel_step();
+            return None;
+        }
+        stop = debugger.resume(game_boy, Some(1));
+        ran += 1;
+    }
+    Some(stop)
+}
+
+/// What stopped the game and where it is now, where None is an interrupt.
+fn report(game_boy: &GameBoy, debugger: &Debugger, stop: Option<Stop>, output: &mut impl Write) -> io::Result<()> {
+    let Some(stop) = stop else {
+        writeln!(output, "interrupted")?;
+        return show_position(game_boy, debugger, output);
+    };
+    match stop {
+        Stop::Done | Stop::Returned(_) => {}
+        Stop::Breakpoint(address) => writeln!(output, "breakpoint at {}", location(game_boy, debugger, address))?,
+        Stop::Locked(address) => writeln!(
+            output,
+            "CPU locked up on illegal opcode ${:02X} at {}",
+            game_boy.memory().peek(address), location(game_boy, debugger, address),
+        )?,
+        Stop::Watchpoint(hits) => {
+            for hit in hits {
+                report_hit(game_boy, debugger, &hit, output)?;
+            }
+        }
+    }
+    show_position(game_boy, debugger, output)
+}
+
+/// `address`, followed by the label it is at or after if there is one.
+fn location(game_boy: &GameBoy, debugger: &Debugger, address: u16) -> String {
+    match debugger.describe(game_boy, address) {
+        Some(name) => format!("{address:04X} <{name}>"),
+        None => format!("{address:04X}"),
+    }
+}
+
+fn report_hit(game_boy: &GameBoy, debugger: &Debugger, hit: &WatchpointHit, output: &mut impl Write) -> io::Result<()> {
+    let target = match watchpoints::io_register_name(hit.address) {
+        Some(name) => format!("{:04X} <{name}>", hit.address),
+        None => location(game_boy, debugger, hit.address),
+    };
+    let access = match hit.access {
+        Access::Read => format!("read {:02X} from {target}", hit.value),
+        Access::Write => format!("wrote {:02X} to {target}", hit.value),
+    };
+    let instruction = debugger::instruction_at(game_boy, hit.instruction);
+    writeln!(output, "watchpoint {}: {}: {} {access}", hit.id, location(game_boy, debugger, hit.instruction), instruction.text)
+}
+
+fn show_position(game_boy: &GameBoy, debugger: &Debugger, output: &mut impl Write) -> io::Result<()> {
+    let pc = game_boy.cpu().registers().read_pc();
+    writeln!(output, "{}: {}", location(game_boy, debugger, pc), debugger::instruction_at(game_boy, pc).text)
+}
+
+fn show_registers(game_boy: &GameBoy, output: &mut impl Write) -> io::Result<()> {
+    let cpu = game_boy.cpu();
+    let reg = cpu.registers();
+    let flags = [(reg.read_zero_flag(), 'Z'), (reg.read_subtraction_flag(), 'N'), (reg.read_half_carry_flag(), 'H'), (reg.read_carry_flag(), 'C')]
+        .map(|(set, name)| if set { name } else { '-' })
+        .iter()
+        .collect::<String>();
+    writeln!(
+        output,
+        "A:{:02X} F:{flags} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} PC:{:04X} IME:{} HALT:{}",
+        reg.read_a(), reg.read_bc(), reg.read_de(), reg.read_hl(), reg.read_sp(), reg.read_pc(),
+        cpu.ime() as u8, cpu.is_halted() as u8,
+    )
+}
+
+fn hexdump(game_boy: &GameBoy, address: u16, length: u64, output: &mut impl Write) -> io::Result<()> {
+    let memory = game_boy.memory();
+    for row in (0..length).step_by(16) {
+        let row_address = address.wrapping_add(row as u16);
+        let bytes: Vec<String> = (0..16.min(length - row))
+            .map(|offset| format!("{:02X}", memory.peek(row_address.wrapping_add(offset as u16))))
+            .collect();
+        writeln!(output, "{row_address:04X}: {}", bytes.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Parses `[r|w|rw] ADDR[-END] [BYTE]`.
+fn parse_watchpoint(symbols: &Symbols, arguments: &[&str]) -> Result<Watchpoint, String> {
+    let (kind, arguments) = match arguments {
+        [kind @ ("r" | "w" | "rw"), rest @ ..] => (*kind, rest),
+        _ => ("w", arguments),
+    };
+    let (range, value) = match arguments {
+        [range] => (*range, None),
+        [range, value] => (*range, Some(parse_hex(value).and_then(byte_value)?)),
+        _ => return Err(String::from("watch needs an address or range and optionally a byte")),
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_address(symbols, start)?, parse_address(symbols, end)?),
+        None => (parse_address(symbols, range)?, parse_address(symbols, range)?),
+    };
+    if end < start {
+        return Err(format!("{end:04X} comes before {start:04X}"));
+    }
+    let watchpoint = match kind {
+        "r" => Watchpoint::read(start..=end),
+        "w" => Watchpoint::write(start..=end),
+        _ => Watchpoint::access(start..=end),
+    };
+    Ok(match value {
+        Some(value) => watchpoint.with_value(value),
+        None => watchpoint,
+    })
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let kind = match (watchpoint.reads, watchpoint.writes) {
+        (true, true) => "rw",
+        (true, false) => "r",
+        _ => "w",
+    };
+    let (start, end) = (*watchpoint.range.start(), *watchpoint.range.end());
+    let range = if start == end { format!("{start:04X}") } else { format!("{start:04X}-{end:04X}") };
+    match watchpoint.condition {
+        Condition::Always => format!("{kind} {range}"),
+        Condition::Equals(value) => format!("{kind} {range} {value:02X}"),
+        Condition::LcdOffOutsideVBlank => String::from("lcd-off"),
+    }
+}
+
+/// A label, the name of an I/O register or a hex address.
+fn parse_address(symbols: &Symbols, text: &str) -> Result<u16, String> {
+    parse_location(symbols, text).map(|(address, _)| address)
+}
+
+/// Like `parse_address`, along with the bank of labels in switchable ROM banks.
+pub(crate) fn parse_location(symbols: &Symbols, text: &str) -> Result<(u16, Option<usize>), String> {
+    if let Some((bank, address)) = symbols.location(text) {
+        return Ok((address, (0x4000..0x8000).contains(&address).then_some(bank)));
+    }
+    watchpoints::io_register(text).map_or_else(|| parse_hex(text), Ok).map(|address| (address, None))
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("'{text}' is no hex number up to FFFF"))
+}
+
+fn byte_value(value: u16) -> Result<u8, String> {
+    u8::try_from(value).map_err(|_| format!("{value:X} does not fit in a byte"))
+}
+
+fn parse_count(text: &str) -> Result<u64, String> {
+    text.parse().map_err(|_| format!("'{text}' is no number"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_arguments() {
+        assert_eq!(parse_hex("$C000"), Ok(0xC000));
+        assert_eq!(parse_hex("0xff40"), Ok(0xFF40));
+        assert_eq!(parse_hex("150"), Ok(0x0150));
+        assert!(parse_hex("10000").is_err());
+        assert!(parse_hex("100").and_then(byte_value).is_err());
+        assert_eq!(parse_count("12"), Ok(12));
+
+        let symbols = Symbols::parse("02:4100 MainLoop\n00:c000 wBuffer\n").unwrap();
+        assert_eq!(parse_address(&symbols, "lcdc"), Ok(0xFF40));
+        assert_eq!(parse_location(&symbols, "MainLoop"), Ok((0x4100, Some(2))));
+        assert_eq!(parse_location(&symbols, "4100"), Ok((0x4100, None)));
+        assert_eq!(parse_watchpoint(&symbols, &["rw", "wBuffer-C0FF", "$42"]), Ok(Watchpoint::access(0xC000..=0xC0FF).with_value(0x42)));
+        assert_eq!(parse_watchpoint(&symbols, &["LY"]), Ok(Watchpoint::write(0xFF44..=0xFF44)));
+    }
+}
@@ -0,0 +1,309 @@
+use std::fs;
+use std::path::PathBuf;
+use crate::cli::RunOptions;
+use crate::screenshot;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use gameboy_emu::game_boy::{Button, GameBoy};
+use gameboy_emu::game_boy::color::{rgb555_to_rgb888, ColorCorrection};
+use gameboy_emu::game_boy::movie::MovieSession;
+use gameboy_emu::game_boy::palette::Palette;
+use gameboy_emu::game_boy::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use gameboy_emu::game_boy::rewind::RewindBuffer;
+use gameboy_emu::game_boy::sgb::{SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+use pixels::{Pixels, SurfaceTexture};
+use winit::application::ApplicationHandler;
+use winit::dpi::PhysicalSize;
+use winit::error::EventLoopError;
+use winit::event::{ElementState, WindowEvent};
+use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
+use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::window::{Window, WindowId};
+
+/// A frame is 70224 T-cycles at 4.194304 MHz, about 59.73 Hz.
+const FRAME_DURATION: Duration = Duration::from_nanos(16_742_706);
+/// A minute of frames to rewind through.
+const REWIND_FRAMES: usize = 60 * 60;
+
+/// Desktop window that shows the LCD, feeds it keyboard input and paces emulation to real time.
+struct Frontend {
+    game_boy: GameBoy,
+    save_path: PathBuf,
+    rom_path: PathBuf,
+    scale: u32,
+    frames: Option<u64>,
+    screenshot_at: Option<u64>,
+    frame: u64,
+    /// Toggled with C, only affects CGB games.
+    color_correction: ColorCorrection,
+    /// Colors for DMG games, cycled with P.
+    palettes: Vec<Palette>,
+    palette: usize,
+    /// Save state slot 1-9, selected with the number keys. F5 saves to it and F9 loads from it.
+    state_slot: u8,
+    /// A state per frame, stepped back through while R is held.
+    rewind: RewindBuffer,
+    rewinding: bool,
+    /// A movie being recorded or played back. Loading states would break it, so F9 and rewinding are off.
+    movie: Option<MovieSession>,
+    movie_path: Option<PathBuf>,
+    window: Option<Arc<Window>>,
+    pixels: Option<Pixels<'static>>,
+    next_frame: Instant,
+}
+
+/// Runs until the window closes and hands the Game Boy back.
+pub fn run(game_boy: GameBoy, save_path: PathBuf, palettes: Vec<Palette>, movie: Option<MovieSession>, options: &RunOptions) -> Result<GameBoy, EventLoopError> {
+    let event_loop = EventLoop::new()?;
+    let mut frontend = Frontend {
+        game_boy,
+        save_path,
+        rom_path: options.rom_path.clone(),
+        scale: options.scale,
+        frames: options.frames,
+        screenshot_at: options.screenshot_at,
+        frame: 0,
+        color_correction: ColorCorrection::Gbc,
+        palettes,
+        palette: 0,
+        state_slot: 1,
+        rewind: RewindBuffer::new(REWIND_FRAMES),
+        rewinding: false,
+        movie,
+        movie_path: options.record_movie.clone(),
+        window: None,
+        pixels: None,
+        next_frame: Instant::now(),
+    };
+    event_loop.run_app(&mut frontend)?;
+    Ok(frontend.game_boy)
+}
+
+impl Frontend {
+    fn map_key(code: KeyCode) -> Option<Button> {
+        match code {
+            KeyCode::ArrowRight => Some(Button::Right),
+            KeyCode::ArrowLeft => Some(Button::Left),
+            KeyCode::ArrowUp => Some(Button::Up),
+            KeyCode::ArrowDown => Some(Button::Down),
+            KeyCode::KeyX => Some(Button::A),
+            KeyCode::KeyZ => Some(Button::B),
+            KeyCode::Backspace | KeyCode::ShiftRight => Some(Button::Select),
+            KeyCode::Enter => Some(Button::Start),
+            _ => None,
+        }
+    }
+
+    fn write_save(&self) {
+        if let Err(error) = crate::write_movie_and_save(&self.game_boy, self.movie.as_ref(), self.movie_path.as_deref(), &self.save_path) {
+            eprintln!("{error}");
+        }
+    }
+
+    /// Save states sit next to the battery save, as `.ss1` to `.ss9`.
+    fn state_path(&self) -> PathBuf {
+        self.save_path.with_extension(format!("ss{}", self.state_slot))
+    }
+
+    fn save_state(&self) {
+        let path = self.state_path();
+        match fs::write(&path, self.game_boy.save_state()) {
+            Ok(()) => println!("saved state {} to {}", self.state_slot, path.display()),
+            Err(error) => eprintln!("failed to write {}: {error}", path.display()),
+        }
+    }
+
+    /// Writes the slot as a BESS state, `.ss1.bess` to `.ss9.bess`, for loading into other emulators.
+    fn export_bess(&self) {
+        let path = self.save_path.with_extension(format!("ss{}.bess", self.state_slot));
+        match fs::write(&path, self.game_boy.export_bess()) {
+            Ok(()) => println!("exported state {} to {}", self.state_slot, path.display()),
+            Err(error) => eprintln!("failed to write {}: {error}", path.display()),
+        }
+    }
+
+    fn load_state(&mut self) {
+        if self.movie.is_some() {
+            eprintln!("states cannot be loaded during a movie");
+            return;
+        }
+        let path = self.state_path();
+        let result = fs::read(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|data| self.game_boy.load_state(&data).map_err(|error| error.to_string()));
+        match result {
+            Ok(()) => println!("loaded state {} from {}", self.state_slot, path.display()),
+            Err(error) => eprintln!("failed to load {}: {error}", path.display()),
+        }
+    }
+
+    /// Goes back one frame, staying on the oldest one once the buffer runs out.
+    fn rewind_frame(&mut self) {
+        let Some(state) = self.rewind.pop() else { return };
+        match self.game_boy.load_state(state) {
+            Ok(()) => self.game_boy.run_frame(),
+            Err(error) => {
+                eprintln!("failed to rewind: {error}");
+                self.rewind.clear();
+            }
+        }
+    }
+
+    fn state_slot_key(code: KeyCode) -> Option<u8> {
+        match code {
+            KeyCode::Digit1 => Some(1),
+            KeyCode::Digit2 => Some(2),
+            KeyCode::Digit3 => Some(3),
+            KeyCode::Digit4 => Some(4),
+            KeyCode::Digit5 => Some(5),
+            KeyCode::Digit6 => Some(6),
+            KeyCode::Digit7 => Some(7),
+            KeyCode::Digit8 => Some(8),
+            KeyCode::Digit9 => Some(9),
+            _ => None,
+        }
+    }
+
+    fn draw(&mut self) {
+        let Some(pixels) = &mut self.pixels else { return };
+
+        if let Some(framebuffer) = self.game_boy.sgb_framebuffer() {
+            for (rgba, color) in pixels.frame_mut().chunks_exact_mut(4).zip(framebuffer.iter()) {
+                let [red, green, blue] = rgb555_to_rgb888(*color, ColorCorrection::None);
+                rgba.copy_from_slice(&[red, green, blue, 0xFF]);
+            }
+        } else if self.game_boy.is_cgb_mode() {
+            for (rgba, color) in pixels.frame_mut().chunks_exact_mut(4).zip(self.game_boy.color_framebuffer()) {
+                let [red, green, blue] = rgb555_to_rgb888(*color, self.color_correction);
+                rgba.copy_from_slice(&[red, green, blue, 0xFF]);
+            }
+        } else {
+            let palette = &self.palettes[self.palette];
+            let shades = self.game_boy.framebuffer().iter().zip(self.game_boy.framebuffer_layers());
+            for (rgba, (shade, layer)) in pixels.frame_mut().chunks_exact_mut(4).zip(shades) {
+                let [red, green, blue] = palette.rgb(*layer, *shade);
+                rgba.copy_from_slice(&[red, green, blue, 0xFF]);
+            }
+        }
+        if let Err(error) = pixels.render() {
+            eprintln!("failed to render: {error}");
+        }
+    }
+}
+
+impl ApplicationHandler for Frontend {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        if self.window.is_some() {
+            return;
+        }
+
+        // the SGB picture includes its border
+        let (width, height) = if self.game_boy.is_sgb_mode() {
+            (SGB_SCREEN_WIDTH as u32, SGB_SCREEN_HEIGHT as u32)
+        } else {
+            (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+        };
+        let attributes = Window::default_attributes()
+            .with_title("gameboy_emu")
+            .with_inner_size(PhysicalSize::new(width * self.scale, height * self.scale))
+            .with_min_inner_size(PhysicalSize::new(width, height));
+        let window = Arc::new(event_loop.create_window(attributes).expect("failed to create window"));
+
+        let size = window.inner_size();
+        let surface = SurfaceTexture::new(size.width, size.height, Arc::clone(&window));
+        // pixels only scales by whole multiples and letterboxes the rest
+        let pixels = Pixels::new(width, height, surface).expect("failed to create pixel buffer");
+
+        self.window = Some(window);
+        self.pixels = Some(pixels);
+        self.next_frame = Instant::now();
+    }
+
+    fn window_event(&mut self, event_loop: &ActiveEventLoop, _window_id: WindowId, event: WindowEvent) {
+        match event {
+            WindowEvent::CloseRequested => event_loop.exit(),
+            WindowEvent::Resized(size) => {
+                if let Some(pixels) = &mut self.pixels
+                    && let Err(error) = pixels.resize_surface(size.width, size.height) {
+                    eprintln!("failed to resize surface: {error}");
+                }
+            }
+            WindowEvent::KeyboardInput { event, .. } => {
+                let PhysicalKey::Code(code) = event.physical_key else { return };
+                if code == KeyCode::Escape {
+                    event_loop.exit();
+                } else if code == KeyCode::KeyC && event.state == ElementState::Pressed && !event.repeat {
+                    self.color_correction = match self.color_correction {
+                        ColorCorrection::None => ColorCorrection::Gbc,
+                        ColorCorrection::Gbc => ColorCorrection::None,
+                    };
+                } else if code == KeyCode::KeyP && event.state == ElementState::Pressed && !event.repeat {
+                    self.palette = (self.palette + 1) % self.palettes.len();
+                    println!("palette: {}", self.palettes[self.palette].name);
+                } else if let Some(slot) = Self::state_slot_key(code) {
+                    if event.state == ElementState::Pressed && !event.repeat {
+                        self.state_slot = slot;
+                        println!("state slot: {slot}");
+                    }
+                } else if code == KeyCode::F5 && event.state == ElementState::Pressed && !event.repeat {
+                    self.save_state();
+                } else if code == KeyCode::KeyR && self.movie.is_none() {
+                    self.rewinding = event.state == ElementState::Pressed;
+                } else if code == KeyCode::F6 && event.state == ElementState::Pressed && !event.repeat {
+                    self.export_bess();
+                } else if code == KeyCode::F9 && event.state == ElementState::Pressed && !event.repeat {
+                    self.load_state();
+                } else if let Some(button) = Self::map_key(code)
+                    && !self.movie.as_ref().is_some_and(MovieSession::is_playing) {
+                    self.game_boy.set_button(button, event.state == ElementState::Pressed);
+                }
+            }
+            WindowEvent::RedrawRequested => self.draw(),
+            _ => {}
+        }
+    }
+
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        let now = Instant::now();
+        if now >= self.next_frame {
+            if self.rewinding {
+                self.rewind_frame();
+            } else {
+                if let Some(movie) = &mut self.movie {
+                    let was_playing = movie.is_playing();
+                    movie.start_frame(&mut self.game_boy);
+                    if was_playing && !movie.is_playing() {
+                        println!("movie ended after {} frames, the keyboard takes over", movie.movie().len());
+                    }
+                }
+                // taken before the frame, rewinding runs it again to draw the picture the state leaves out
+                self.rewind.push(self.game_boy.save_state_without_picture());
+                self.game_boy.run_frame();
+                self.frame += 1;
+                if self.screenshot_at == Some(self.frame) {
+                    match screenshot::save(&self.game_boy, &self.rom_path, self.frame) {
+                        Ok(path) => println!("wrote {}", path.display()),
+                        Err(error) => eprintln!("failed to write screenshot: {error}"),
+                    }
+                }
+                if self.frames == Some(self.frame) {
+                    event_loop.exit();
+                    return;
+                }
+            }
+            self.next_frame += FRAME_DURATION;
+            // after a stall, resume at normal speed instead of fast-forwarding to catch up
+            if self.next_frame < now {
+                self.next_frame = now + FRAME_DURATION;
+            }
+            if let Some(window) = &self.window {
+                window.request_redraw();
+            }
+        }
+        event_loop.set_control_flow(ControlFlow::WaitUntil(self.next_frame));
+    }
+
+    fn exiting(&mut self, _event_loop: &ActiveEventLoop) {
+        self.write_save();
+    }
+}
@@ -1,107 +1,844 @@
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell};
+use std::fmt;
+use std::io;
+use std::io::{Seek, Write};
 use std::rc::Rc;
 use cpu::CPU;
+use cpu::registers::{RegisterPair, Registers};
+use crate::game_boy::apu::{AudioChannel, APU};
+use crate::game_boy::apu::wav_writer::WavWriter;
+use crate::game_boy::bess::Bess;
+use crate::game_boy::cartridge_header::{CartridgeHeader, HeaderError};
+use crate::game_boy::coverage::Coverage;
 use crate::game_boy::memory::Memory;
-use crate::game_boy::ppu::PPU;
+use crate::game_boy::memory::cartridge::Cartridge;
+use crate::game_boy::memory::watchpoints::{Watchpoint, WatchpointHit};
+use crate::game_boy::model::Model;
+use crate::game_boy::ppu::{Layer, PPU};
+use crate::game_boy::profiler::Profiler;
+use crate::game_boy::save_state::{Sections, StateError, StateHeader, StateReader, StateWriter};
+use crate::game_boy::sgb::Sgb;
+use crate::game_boy::tracer::Tracer;
 
+pub use crate::game_boy::memory::joypad::Button;
+
+pub mod apu;
+mod bess;
+pub mod cartridge_header;
+pub mod color;
+pub mod coverage;
 pub mod cpu;
-mod memory;
+pub mod debugger;
+pub mod disassembler;
+pub mod memory;
+pub mod model;
+pub mod movie;
+pub mod palette;
 pub mod ppu;
+pub mod profiler;
+pub mod rewind;
+pub mod save_state;
+pub mod sgb;
+pub mod source_map;
+pub mod symbols;
+pub mod tracer;
+/// A ROM fixture for tests, here and in the tests of the binary.
+#[doc(hidden)]
+pub mod test_rom;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoadError {
+    Header(HeaderError),
+    WrongLogo,
+    HeaderChecksumMismatch,
+    /// Cartridges have at least two 16 KiB ROM banks.
+    RomTooSmall(usize),
+    UnsupportedCartridgeType(u8),
+    InvalidRamSize(u8),
+    BootRomSize { model: Model, size: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Header(error) => write!(f, "{error}"),
+            LoadError::WrongLogo => write!(f, "wrong logo"),
+            LoadError::HeaderChecksumMismatch => write!(f, "header checksum mismatch"),
+            LoadError::RomTooSmall(length) => write!(f, "ROM is only {length} bytes long, cartridges have at least 32 KiB"),
+            LoadError::UnsupportedCartridgeType(cartridge_type) => write!(f, "cartridge type {cartridge_type:02X} is not supported"),
+            LoadError::InvalidRamSize(code) => write!(f, "invalid RAM size code {code:02X}"),
+            LoadError::BootRomSize { model, size } => {
+                write!(f, "boot ROM is {size} bytes, a {model} boot ROM has {}", model.boot_rom_size())
+            }
+        }
+    }
+}
 
 pub struct GameBoy {
+    model: Model,
     memory: Rc<RefCell<Memory>>,
     cpu: CPU,
-    ppu: PPU
+    ppu: PPU,
+    apu: APU,
 }
 
 impl GameBoy {
-    pub fn new() -> GameBoy {
+    /// Creates a Game Boy with `cartridge_rom` inserted, ready to execute from the entry point.
+    /// CGB games get a CGB, SGB games an SGB and everything else a DMG, see `Model::for_cartridge`.
+    /// Fails for ROMs `check_rom` rejects.
+    pub fn new(cartridge_rom: Vec<u8>) -> Result<GameBoy, LoadError> {
+        let model = CartridgeHeader::parse(&cartridge_rom).map_or(Model::Dmg, |header| Model::for_cartridge(&header));
+        Self::with_model(cartridge_rom, model, None)
+    }
+
+    /// Creates a Game Boy of `model` with `cartridge_rom` inserted. With a `boot_rom` execution starts
+    /// at 0x0000 like on real hardware, without one it starts at the entry point in the state the boot ROM
+    /// of `model` leaves behind. Also fails if `boot_rom` does not have the size `model` expects.
+    pub fn with_model(cartridge_rom: Vec<u8>, model: Model, boot_rom: Option<Vec<u8>>) -> Result<GameBoy, LoadError> {
+        let header = Self::check_rom(&cartridge_rom)?;
+        if let Some(boot_rom) = &boot_rom
+            && boot_rom.len() != model.boot_rom_size() {
+            return Err(LoadError::BootRomSize { model, size: boot_rom.len() });
+        }
+
+        let cgb_game = header.cgb_flag & 0x80 != 0;
         let memory = Rc::new(RefCell::new(Memory::new()));
-        let cpu = CPU::new(Rc::clone(&memory));
+        // the CGB boot ROM always starts in CGB mode and decides on compatibility mode itself
+        memory.borrow_mut().set_cgb_mode(model.is_cgb() && (cgb_game || boot_rom.is_some()));
+        if model == Model::Sgb && header.supports_sgb() {
+            memory.borrow_mut().enable_sgb();
+        }
+
+        let registers = match boot_rom {
+            Some(boot_rom) => {
+                memory.borrow_mut().load_boot_rom(boot_rom);
+                Registers::new()
+            }
+            None => {
+                memory.borrow_mut().apply_post_boot_state(model);
+                Registers::post_boot(model, header.header_checksum, cgb_game)
+            }
+        };
+        memory.borrow_mut().load_cartridge(cartridge_rom)?;
+
+        let cpu = CPU::new(Rc::clone(&memory), registers);
         let ppu = PPU::new(Rc::clone(&memory));
-        GameBoy{ memory, cpu, ppu }
+        let apu = APU::new(Rc::clone(&memory), apu::DEFAULT_SAMPLE_RATE);
+        Ok(GameBoy{ model, memory, cpu, ppu, apu })
     }
 
-    pub fn start(&mut self, cartridge_rom: Vec<u8>) {
-        if !self.is_nintendo_logo_correct(&cartridge_rom) {
-            panic!("wrong logo")
+    /// Parses the header of a ROM this emulator can run. Like the boot ROM it checks the logo and the
+    /// header checksum but not the global checksum, which plenty of homebrew gets wrong.
+    pub fn check_rom(cartridge_rom: &[u8]) -> Result<CartridgeHeader, LoadError> {
+        let header = CartridgeHeader::parse(cartridge_rom).map_err(LoadError::Header)?;
+        if !header.logo_valid {
+            return Err(LoadError::WrongLogo);
+        }
+        if !header.is_header_checksum_valid() {
+            return Err(LoadError::HeaderChecksumMismatch);
         }
-        self.print_nintendo_logo(&cartridge_rom);
+        Cartridge::layout(cartridge_rom)?;
+        Ok(header)
+    }
 
-        let header_checksum = cartridge_rom[0x14D];
-        assert_eq!(header_checksum, Self::calculate_checksum(&cartridge_rom));
+    pub fn model(&self) -> Model {
+        self.model
+    }
 
-        let global_checksum = ((cartridge_rom[0x14E] as u16) << 8) + (cartridge_rom[0x14F] as u16);
-        assert_eq!(global_checksum, Self::calculate_global_checksum(&cartridge_rom));
+    /// Whether an SGB runs a game that supports it, which adds a border and colors.
+    pub fn is_sgb_mode(&self) -> bool {
+        self.memory.borrow().sgb().is_some()
+    }
 
-        self.memory.borrow_mut().load_cartridge(cartridge_rom);
+    /// Whether CGB features are in use, which is not the case for a DMG game on a CGB.
+    pub fn is_cgb_mode(&self) -> bool {
+        self.memory.borrow().is_cgb_mode()
+    }
 
-        loop {
-            let cycles_used = self.cpu.execute_next_instruction();
+    /// Starts logging every instruction with `tracer`, or stops with None. Returns the tracer that was in use.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        self.cpu.set_tracer(tracer)
+    }
+
+    /// Starts recording how the CPU uses each ROM byte into `coverage`, or stops with None.
+    /// Returns the coverage map that was in use.
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) -> Option<Coverage> {
+        self.memory.borrow_mut().set_coverage(coverage)
+    }
+
+    /// Starts counting the cycles spent per address and call stack with `profiler`, or stops with None.
+    /// Returns the profiler that was in use, which writes its profile when dropped.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+        self.cpu.set_profiler(profiler)
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    /// Read-only view of the address space. Holding it while stepping the emulator panics.
+    pub fn memory(&self) -> Ref<'_, Memory> {
+        self.memory.borrow()
+    }
+
+    /// The bank of the memory mapped at `address`, numbered like RGBDS and its `.sym` files do. None in the boot ROM.
+    pub fn bank_at(&self, address: u16) -> Option<usize> {
+        self.memory.borrow().bank_at(address)
+    }
+
+    /// Writes to the address space like the CPU would, for debuggers. Watchpoints do not see it.
+    pub fn write_memory(&mut self, address: u16, value: u8) {
+        self.memory.borrow_mut().poke(address, value);
+    }
+
+    /// Sets a register for debuggers. The low nibble of F stays zero like on hardware.
+    pub fn write_register(&mut self, pair: RegisterPair, value: u16) {
+        self.cpu.registers_mut().write_pair(pair, value);
+    }
+
+    /// Starts watching CPU accesses and returns an id for `remove_watchpoint` and the hits.
+    /// Hits do not stop `step`, `run_frame` or `run_cycles`, callers poll `take_watchpoint_hits`
+    /// after them, or use `Debugger` which stops right after the instruction that hit.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.memory.borrow_mut().watchpoints_mut().add(watchpoint)
+    }
+
+    /// Returns false if there is no watchpoint with `id`.
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        self.memory.borrow_mut().watchpoints_mut().remove(id)
+    }
+
+    pub fn watchpoints(&self) -> Vec<(usize, Watchpoint)> {
+        self.memory.borrow().watchpoints().list().to_vec()
+    }
+
+    /// The watched accesses since the last call, oldest first. Only the first 1024 are kept
+    /// when nobody takes them, so poll after every run.
+    pub fn take_watchpoint_hits(&mut self) -> Vec<WatchpointHit> {
+        self.memory.borrow().watchpoints().take_hits()
+    }
+
+    /// Sets the rate audio is resampled to, usually 44100 or 48000 Hz.
+    /// Samples that were not drained yet are discarded.
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn audio_sample_rate(&self) -> u32 {
+        self.apu.sample_rate()
+    }
+
+    /// Number of stereo samples waiting to be drained.
+    pub fn audio_samples_available(&self) -> usize {
+        self.apu.samples_available()
+    }
+
+    /// Moves up to `out.len() / 2` stereo samples into `out`, interleaved left then right,
+    /// and returns how many stereo samples were written.
+    pub fn read_audio_samples(&mut self, out: &mut [i16]) -> usize {
+        self.apu.read_samples(out)
+    }
+
+    /// Drains every pending stereo sample, interleaved left then right.
+    pub fn audio_samples(&mut self) -> Vec<i16> {
+        let mut samples = vec![0; 2 * self.apu.samples_available()];
+        self.apu.read_samples(&mut samples);
+        samples
+    }
+
+    /// Mutes or unmutes one APU channel in the audio output, e.g. to listen to the noise channel alone.
+    pub fn set_audio_channel_enabled(&mut self, channel: AudioChannel, enabled: bool) {
+        self.apu.set_channel_enabled(channel, enabled);
+    }
+
+    /// The last completed frame, one DMG shade from 0 (lightest) to 3 per pixel, row by row.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.ppu.framebuffer()
+    }
+
+    /// The layer each pixel of `framebuffer` was drawn by, see `palette::Palette::rgb`.
+    pub fn framebuffer_layers(&self) -> &[Layer] {
+        self.ppu.layers()
+    }
+
+    /// The last completed SGB picture of `sgb::SGB_SCREEN_WIDTH` by `sgb::SGB_SCREEN_HEIGHT` RGB555 colors,
+    /// the colorized screen inside its border. None unless in SGB mode.
+    pub fn sgb_framebuffer(&self) -> Option<Ref<'_, [u16]>> {
+        Ref::filter_map(self.memory.borrow(), |memory| memory.sgb().map(Sgb::framebuffer)).ok()
+    }
+
+    /// The last completed frame as RGB555 colors, red in the lowest bits. Only drawn in CGB mode,
+    /// see `color::rgb555_to_rgb888` to display it.
+    pub fn color_framebuffer(&self) -> &[u16] {
+        self.ppu.color_framebuffer()
+    }
 
-            self.ppu.step(cycles_used);
+    /// FNV-1a hash of the last completed picture: the SGB picture in SGB mode, the colors in CGB mode
+    /// and the shades otherwise. It stays the same across builds, to check replays against a known result.
+    pub fn frame_hash(&self) -> u64 {
+        let hash = |hash: u64, byte: u8| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3);
+        let offset_basis = 0xCBF2_9CE4_8422_2325;
+        if let Some(framebuffer) = self.sgb_framebuffer() {
+            framebuffer.iter().flat_map(|color| color.to_le_bytes()).fold(offset_basis, hash)
+        } else if self.is_cgb_mode() {
+            self.color_framebuffer().iter().flat_map(|color| color.to_le_bytes()).fold(offset_basis, hash)
+        } else {
+            self.framebuffer().iter().copied().fold(offset_basis, hash)
         }
+    }
 
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.memory.borrow_mut().set_button(button, pressed);
     }
 
-    fn is_nintendo_logo_correct(&self, cartridge_rom: &[u8]) -> bool {
-        const NINTENDO_LOGO: &[u8] = &[
-            0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
-            0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
-            0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
-        ];
+    /// The pressed buttons as a mask of `Button::mask` bits.
+    pub fn pressed_buttons(&self) -> u8 {
+        self.memory.borrow().pressed_buttons()
+    }
 
-        let nintendo_logo_content = &cartridge_rom[0x104..0x133 + 1];
-        NINTENDO_LOGO == nintendo_logo_content
+    /// Presses exactly the buttons in `mask`, see `pressed_buttons`.
+    pub fn set_buttons(&mut self, mask: u8) {
+        for button in Button::ALL {
+            self.set_button(button, mask & button.mask() != 0);
+        }
     }
 
-    fn print_nintendo_logo(&self, cartridge_rom: &[u8]) {
-        let nintendo_logo_content = &cartridge_rom[0x104..0x133 + 1];
-        self.print_half(&nintendo_logo_content[0..24]);
-        self.print_half(&nintendo_logo_content[24..48]);
+    /// Runs the cartridge clock on emulated time starting at `seconds` since the epoch instead of the host clock,
+    /// which makes runs reproducible. Set it before loading save data or states so they catch up deterministically.
+    pub fn set_rtc_time(&mut self, seconds: u64) {
+        self.memory.borrow_mut().set_rtc_emulated_time(seconds);
     }
 
-    fn print_half(&self, bytes: &[u8]) {
-        assert_eq!(bytes.len(), 24);
-        for nimble_idx in 0..4 {
-            for byte_pair in bytes.chunks(2) {
-                let first_byte = format!("{:08b}", byte_pair[0]);
-                let second_byte = format!("{:08b}", byte_pair[1]);
-                let nimble = match nimble_idx {
-                    0 => &first_byte[0..4],
-                    1 => &first_byte[4..8],
-                    2 => &second_byte[0..4],
-                    3 => &second_byte[4..8],
-                    _ => panic!("invalid nimble"),
-                };
-                for bit in nimble.chars() {
-                    print!("{}", if bit == '1' { '▮' } else { ' ' });
-                }
-            }
-            println!();
+    /// Whether the cartridge keeps its RAM on a battery, i.e. whether `save_data` needs to be persisted.
+    pub fn has_battery(&self) -> bool {
+        self.memory.borrow().has_battery()
+    }
+
+    /// Contents of the battery-backed cartridge RAM, followed by the RTC state for MBC3 clocks.
+    pub fn save_data(&self) -> Option<Vec<u8>> {
+        let mut memory = self.memory.borrow_mut();
+        if memory.has_battery() { Some(memory.save_data()) } else { None }
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.memory.borrow_mut().load_save_data(data);
+    }
+
+    /// Snapshot of the whole machine, loadable with `load_state` into a Game Boy of the same model running the same ROM.
+    pub fn save_state(&self) -> Vec<u8> {
+        self.write_state(true)
+    }
+
+    /// Like `save_state` without the picture, which is most of a state that changes every frame.
+    /// `load_state` keeps the picture on screen, running a frame draws the one that belongs to it.
+    /// This is what rewinding keeps a minute of.
+    pub fn save_state_without_picture(&self) -> Vec<u8> {
+        self.write_state(false)
+    }
+
+    fn write_state(&self, with_picture: bool) -> Vec<u8> {
+        let memory = self.memory.borrow();
+        let mut state = StateWriter::new(&StateHeader::new(self.model, memory.rom_checksum()));
+        state.section(b"CPU ", |state| self.cpu.save_state(state));
+        memory.save_state(&mut state);
+        state.section(b"PPU ", |state| self.ppu.save_state(state, with_picture));
+        state.section(b"APU ", |state| self.apu.save_state(state));
+        state.into_bytes()
+    }
+
+    /// Restores a snapshot taken by `save_state`, or imports a BESS state from another emulator.
+    /// On error the machine is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let (header, mut sections) = match StateReader::sections(data) {
+            Err(StateError::NotAState) => return self.import_bess(data),
+            result => result?,
+        };
+        let rom_checksum = self.memory.borrow().rom_checksum();
+        if header.rom_checksum != rom_checksum {
+            return Err(StateError::RomMismatch { state_checksum: header.rom_checksum, rom_checksum });
+        }
+        if header.model != self.model.to_string() {
+            return Err(StateError::ModelMismatch { state_model: header.model, model: self.model });
+        }
+
+        let backup = self.save_state();
+        let result = self.load_sections(&mut sections);
+        if result.is_err() {
+            let (_, mut sections) = StateReader::sections(&backup).expect("own state is readable");
+            self.load_sections(&mut sections).expect("own state loads");
         }
+        result
+    }
+
+    /// Exports a Best Effort Save State, which SameBoy and other emulators can load.
+    /// The PPU, APU and SGB are only described by their registers.
+    pub fn export_bess(&self) -> Vec<u8> {
+        let mut bess = Bess::new(self.model);
+        self.cpu.export_bess(&mut bess.core);
+        self.memory.borrow_mut().export_bess(&mut bess);
+        bess.write()
+    }
+
+    /// Imports a Best Effort Save State made by another emulator. Parts of the machine BESS does not
+    /// describe start over: the PPU at the start of its current mode, playing sound channels from their trigger.
+    pub fn import_bess(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let bess = Bess::parse(data)?;
+        let rom_checksum = self.memory.borrow().rom_checksum();
+        if let Some((_, state_checksum)) = bess.info
+            && state_checksum != rom_checksum {
+            return Err(StateError::RomMismatch { state_checksum, rom_checksum });
+        }
+        if !bess.is_compatible_with(self.model) {
+            return Err(StateError::ModelMismatch { state_model: bess.model_name(), model: self.model });
+        }
+
+        self.cpu.import_bess(&bess.core);
+        self.memory.borrow_mut().import_bess(&bess);
+        self.ppu.import_bess();
+        self.apu.import_bess();
+        Ok(())
     }
 
-    fn calculate_checksum(content: &[u8]) -> u8 {
-        let mut checksum: u8 = 0;
+    fn load_sections(&mut self, sections: &mut Sections) -> Result<(), StateError> {
+        self.cpu.load_state(sections.get(b"CPU ")?)?;
+        self.memory.borrow_mut().load_state(sections)?;
+        self.ppu.load_state(sections.get(b"PPU ")?)?;
+        self.apu.load_state(sections.get(b"APU ")?)
+    }
 
-        for byte in content.iter().take(0x014C + 1).skip(0x0134) {
-            checksum = checksum.wrapping_sub(byte + 1);
+    /// Runs headlessly for `frames` frames and writes the audio produced
+    /// to `writer` as a 16-bit stereo WAV file.
+    pub fn record_audio<W: Write + Seek>(&mut self, frames: u32, writer: W) -> io::Result<W> {
+        let mut wav = WavWriter::new(writer, self.apu.sample_rate())?;
+        for _ in 0..frames {
+            self.run_frame();
+            wav.write_samples(&self.audio_samples())?;
         }
 
-        checksum
+        wav.finish()
+    }
+
+    /// Runs until the PPU enters VBlank, at which point `framebuffer` holds the new frame.
+    /// Runs past watchpoint hits, see `take_watchpoint_hits`.
+    pub fn run_frame(&mut self) {
+        while !self.execute_instruction().1 {}
+    }
+
+    /// Executes a single instruction and returns the machine cycles it took.
+    pub fn step(&mut self) -> i32 {
+        self.execute_instruction().0
+    }
+
+    /// Executes instructions until at least `cycles` machine cycles have passed
+    /// and returns how many actually did, which can overshoot by part of an instruction.
+    /// Runs past watchpoint hits, see `take_watchpoint_hits`.
+    pub fn run_cycles(&mut self, cycles: u64) -> u64 {
+        let mut cycles_run = 0;
+        while cycles_run < cycles {
+            cycles_run += self.execute_instruction().0 as u64;
+        }
+        cycles_run
     }
 
-    fn calculate_global_checksum(content: &[u8]) -> u16 {
-        let mut checksum: u16 = 0;
+    /// Executes one instruction, returning the cycles it took and whether VBlank began.
+    fn execute_instruction(&mut self) -> (i32, bool) {
+        self.memory.borrow().watchpoints().set_instruction(self.cpu.registers().read_pc());
+        let mut cycles_used = self.cpu.execute_next_instruction();
+        let mut vblank_started = self.advance(cycles_used);
 
-        for (address, byte) in content.iter().enumerate() {
-            if address == 0x014E || address == 0x014F {
-                continue;
+        // VRAM DMA stops the CPU while it copies, the rest keeps running and may reach the next HBlank
+        loop {
+            let stall_cycles = {
+                let mut memory = self.memory.borrow_mut();
+                memory.run_hblank_dma(self.cpu.is_halted());
+                memory.take_dma_stall_cycles()
+            };
+            if stall_cycles == 0 {
+                break;
             }
-            checksum = checksum.wrapping_add(*byte as u16);
+            cycles_used += stall_cycles;
+            vblank_started |= self.advance(stall_cycles);
+        }
+
+        (cycles_used, vblank_started)
+    }
+
+    /// Runs everything but the CPU for `cycles` machine cycles and returns whether VBlank began.
+    fn advance(&mut self, cycles: i32) -> bool {
+        // the timer follows the CPU clock, the PPU and APU keep their pace in double speed
+        let dots = {
+            let mut memory = self.memory.borrow_mut();
+            memory.step_timer(cycles);
+            let dots = if memory.is_double_speed() { 2 * cycles } else { 4 * cycles };
+            memory.step_rtc(dots);
+            dots
+        };
+        let vblank_started = self.ppu.step(dots);
+        self.apu.step(dots);
+        if vblank_started && let Some(sgb) = self.memory.borrow_mut().sgb_mut() {
+            sgb.frame_completed(self.ppu.framebuffer());
+        }
+        vblank_started
+    }
+
+}
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::game_boy::memory::watchpoints::Access;
+
+    pub(crate) use super::test_rom::test_rom;
+
+    fn cgb_test_rom(code: &[u8]) -> Vec<u8> {
+        let mut rom = test_rom(code);
+        rom[0x143] = 0x80;
+        fix_checksums(&mut rom);
+        rom
+    }
+
+    fn fix_checksums(rom: &mut [u8]) {
+        rom[0x14D] = CartridgeHeader::calculate_checksum(rom);
+        let global_checksum = CartridgeHeader::calculate_global_checksum(rom);
+        rom[0x14E] = (global_checksum >> 8) as u8;
+        rom[0x14F] = global_checksum as u8;
+    }
+
+    /// ld a, value; ld [address], a
+    fn store(address: u16, value: u8) -> [u8; 5] {
+        [0x3E, value, 0xEA, address as u8, (address >> 8) as u8]
+    }
+
+    fn square_wave_rom() -> Vec<u8> {
+        let mut code = Vec::new();
+        for (address, value) in [(0xFF26, 0x80), (0xFF24, 0x77), (0xFF25, 0xFF), (0xFF16, 0x80), (0xFF17, 0xF0), (0xFF19, 0x87)] {
+            code.extend(store(address, value));
+        }
+        let end = 0x150 + code.len() as u16;
+        code.extend([0xC3, end as u8, (end >> 8) as u8]);
+        test_rom(&code)
+    }
+
+    #[test]
+    fn records_audio_to_wav() {
+        let mut game_boy = GameBoy::new(square_wave_rom()).unwrap();
+        let bytes = game_boy.record_audio(10, Cursor::new(Vec::new())).unwrap().into_inner();
+
+        assert_eq!(&bytes[0..4], b"RIFF");
+        let data_length = u32::from_le_bytes(bytes[40..44].try_into().unwrap()) as usize;
+        assert_eq!(bytes.len(), 44 + data_length);
+        // ten frames at 59.7 Hz are a bit over 8000 stereo samples at 48 kHz
+        assert!((7_900 * 4..8_100 * 4).contains(&data_length));
+        assert!(bytes[44..].chunks(2).any(|sample| i16::from_le_bytes([sample[0], sample[1]]) > 10_000));
+    }
+
+    #[test]
+    fn muted_channel_is_silent() {
+        let mut game_boy = GameBoy::new(square_wave_rom()).unwrap();
+        game_boy.set_audio_channel_enabled(AudioChannel::Pulse2, false);
+        // the boot ROM leaves the DAC of channel 1 on after its chime
+        game_boy.set_audio_channel_enabled(AudioChannel::Pulse1, false);
+        let bytes = game_boy.record_audio(10, Cursor::new(Vec::new())).unwrap().into_inner();
+
+        assert!(bytes[44..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn save_state_round_trips() {
+        let mut game_boy = GameBoy::new(square_wave_rom()).unwrap();
+        game_boy.run_frame();
+        let state = game_boy.save_state();
+        game_boy.run_frame();
+        let frame_after_save = game_boy.framebuffer().to_vec();
+        let pc_after_save = game_boy.cpu().registers().read_pc();
+
+        game_boy.run_frame();
+        game_boy.load_state(&state).unwrap();
+        game_boy.run_frame();
+        assert_eq!(game_boy.framebuffer(), &frame_after_save[..]);
+        assert_eq!(game_boy.cpu().registers().read_pc(), pc_after_save);
+
+        let mut fresh = GameBoy::new(square_wave_rom()).unwrap();
+        fresh.load_state(&state).unwrap();
+        fresh.run_frame();
+        assert_eq!(fresh.save_state(), game_boy.save_state());
+    }
+
+    #[test]
+    fn rejects_states_of_other_games_and_models() {
+        let state = GameBoy::new(square_wave_rom()).unwrap().save_state();
+
+        let mut other_game = GameBoy::new(test_rom(&[])).unwrap();
+        assert!(matches!(other_game.load_state(&state), Err(StateError::RomMismatch { .. })));
+        let mut other_model = GameBoy::with_model(square_wave_rom(), Model::Mgb, None).unwrap();
+        assert!(matches!(other_model.load_state(&state), Err(StateError::ModelMismatch { .. })));
+
+        // a truncated section fails while loading, after which the machine is as before
+        let mut game_boy = GameBoy::new(square_wave_rom()).unwrap();
+        game_boy.run_frame();
+        let before = game_boy.save_state();
+        let mut damaged = state.clone();
+        let apu_section = damaged.windows(4).position(|tag| tag == b"APU ").unwrap();
+        damaged.truncate(apu_section + 8);
+        damaged[apu_section + 4..].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(game_boy.load_state(&damaged), Err(StateError::Truncated));
+        assert_eq!(game_boy.save_state(), before);
+    }
+
+    #[test]
+    fn bess_export_imports_into_a_fresh_machine() {
+        let mut game_boy = GameBoy::new(square_wave_rom()).unwrap();
+        game_boy.run_frame();
+        let bess = game_boy.export_bess();
+        assert_eq!(&bess[bess.len() - 4..], b"BESS");
+
+        let mut imported = GameBoy::new(square_wave_rom()).unwrap();
+        imported.import_bess(&bess).unwrap();
+        assert_eq!(imported.export_bess(), bess);
+        assert_eq!(imported.cpu().registers().read_pc(), game_boy.cpu().registers().read_pc());
+        assert_eq!(imported.ppu().ly(), 144);
+        assert_eq!(imported.memory().read(0xFF26) & 0x0F, game_boy.memory().read(0xFF26) & 0x0F);
+
+        let mut other_game = GameBoy::new(test_rom(&[])).unwrap();
+        assert!(matches!(other_game.import_bess(&bess), Err(StateError::RomMismatch { .. })));
+        let mut other_model = GameBoy::with_model(square_wave_rom(), Model::Cgb, None).unwrap();
+        assert!(matches!(other_model.import_bess(&bess), Err(StateError::ModelMismatch { .. })));
+    }
+
+    #[test]
+    fn rejects_roms_the_boot_rom_would_refuse() {
+        let mut rom = test_rom(&[]);
+        rom[0x14E] ^= 0xFF;
+        assert!(GameBoy::new(rom.clone()).is_ok(), "the global checksum is not checked");
+        assert!(GameBoy::with_model(rom.clone(), Model::Dmg, Some(vec![0; 0x100])).is_ok());
+        assert_eq!(GameBoy::with_model(rom.clone(), Model::Cgb, Some(vec![0; 0x100])).err(), Some(LoadError::BootRomSize { model: Model::Cgb, size: 0x100 }));
+        rom[0x14D] ^= 0xFF;
+        assert_eq!(GameBoy::new(rom.clone()).err(), Some(LoadError::HeaderChecksumMismatch));
+        rom[0x104] ^= 0xFF;
+        assert_eq!(GameBoy::new(rom).err(), Some(LoadError::WrongLogo));
+        assert_eq!(GameBoy::new(vec![0; 0x100]).err(), Some(LoadError::Header(HeaderError::TooSmall(0x100))));
+    }
+
+    #[test]
+    fn rejects_cartridges_it_cannot_emulate() {
+        let mut rom = test_rom(&[]);
+        rom[0x147] = 0xFE;
+        fix_checksums(&mut rom);
+        assert_eq!(GameBoy::check_rom(&rom).err(), Some(LoadError::UnsupportedCartridgeType(0xFE)));
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x07;
+        fix_checksums(&mut rom);
+        assert_eq!(GameBoy::new(rom.clone()).err(), Some(LoadError::InvalidRamSize(0x07)));
+        rom[0x149] = 0x03;
+        fix_checksums(&mut rom);
+        assert!(GameBoy::new(rom.clone()).is_ok());
+        rom.truncate(0x4000);
+        assert_eq!(GameBoy::new(rom).err(), Some(LoadError::RomTooSmall(0x4000)));
+    }
+
+    #[test]
+    fn starts_in_post_boot_state_without_boot_rom() {
+        let game_boy = GameBoy::new(test_rom(&[])).unwrap();
+
+        assert_eq!(game_boy.cpu().registers().read_af(), 0x01B0);
+        assert_eq!(game_boy.cpu().registers().read_sp(), 0xFFFE);
+        assert_eq!(game_boy.cpu().registers().read_pc(), 0x100);
+        assert_eq!(game_boy.memory().read(0xFF40), 0x91);
+        assert_eq!(game_boy.memory().read(0xFF47), 0xFC);
+        assert_eq!(game_boy.memory().read(0xFF04), 0xAB);
+    }
+
+    #[test]
+    fn boot_rom_unmaps_itself() {
+        // ld sp, 0xFFFE; jp 0x00FC ... 0x00FC: ld a, 1; ldh [0x50], a
+        let mut boot_rom = vec![0; 0x100];
+        boot_rom[0..6].copy_from_slice(&[0x31, 0xFE, 0xFF, 0xC3, 0xFC, 0x00]);
+        boot_rom[0xFC..0x100].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let mut game_boy = GameBoy::with_model(test_rom(&[]), Model::Dmg, Some(boot_rom)).unwrap();
+
+        assert_eq!(game_boy.cpu().registers().read_pc(), 0x0000);
+        assert_eq!(game_boy.memory().read(0x0000), 0x31);
+        for _ in 0..4 {
+            game_boy.step();
+        }
+
+        assert_eq!(game_boy.cpu().registers().read_pc(), 0x100);
+        assert_eq!(game_boy.memory().read(0x0000), 0x00);
+        assert_eq!(game_boy.memory().read(0x0100), 0xC3);
+    }
+
+    #[test]
+    fn cgb_game_banks_work_ram_and_video_ram() {
+        let mut code = Vec::new();
+        for (address, value) in [(0xFF70, 2), (0xD000, 0x22), (0xFF70, 3), (0xD000, 0x33), (0xFF4F, 1), (0x8000, 0x11)] {
+            code.extend(store(address, value));
+        }
+        let mut game_boy = GameBoy::new(cgb_test_rom(&code)).unwrap();
+        assert_eq!(game_boy.model(), Model::Cgb);
+        assert!(game_boy.is_cgb_mode());
+        for _ in 0..13 {
+            game_boy.step();
         }
 
-        checksum
+        let memory = game_boy.memory();
+        assert_eq!(memory.read(0xD000), 0x33);
+        assert_eq!(memory.read(0xFF70), 0xFB);
+        assert_eq!(memory.read(0x8000), 0x11);
+        assert_eq!(memory.video_ram(0).read(0x8000), 0x00);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn dmg_game_on_cgb_has_no_banking() {
+        let mut game_boy = GameBoy::with_model(test_rom(&store(0xFF70, 2)), Model::Cgb, None).unwrap();
+        for _ in 0..3 {
+            game_boy.step();
+        }
+
+        assert!(!game_boy.is_cgb_mode());
+        assert_eq!(game_boy.memory().read(0xFF70), 0xFF);
+        assert_eq!(game_boy.memory().read(0xFF4D), 0xFF);
+    }
+
+    #[test]
+    fn vram_dma_copies_rom_into_video_ram() {
+        let mut code = Vec::new();
+        for (address, value) in [(0xFF51, 0x01), (0xFF52, 0x00), (0xFF53, 0x00), (0xFF54, 0x00), (0xFF55, 0x01)] {
+            code.extend(store(address, value));
+        }
+        // continues where the general-purpose transfer stopped
+        code.extend(store(0xFF55, 0x81));
+        let rom = cgb_test_rom(&code);
+        let mut game_boy = GameBoy::new(rom.clone()).unwrap();
+        for _ in 0..10 {
+            game_boy.step();
+        }
+
+        // two blocks stall the CPU for 8 machine cycles each
+        assert_eq!(game_boy.step(), 4 + 2 * 8);
+        assert_eq!(game_boy.memory().read(0xFF55), 0xFF);
+        assert_eq!(game_boy.memory().video_ram(0).read(0x8000), 0xC3);
+        assert_eq!(game_boy.memory().video_ram(0).read(0x801F), rom[0x11F]);
+
+        game_boy.step();
+        game_boy.step();
+        assert_eq!(game_boy.memory().read(0xFF55), 0x01);
+        game_boy.run_frame();
+        assert_eq!(game_boy.memory().read(0xFF55), 0xFF);
+        assert_eq!(game_boy.memory().video_ram(0).read(0x803F), rom[0x13F]);
+    }
+
+    #[test]
+    fn dma_copies_do_not_trigger_watchpoints() {
+        let stores = [(0xC000, 0x42), (0xFF46, 0xC0), (0xFF51, 0xC0), (0xFF52, 0x00), (0xFF53, 0x00), (0xFF54, 0x00), (0xFF55, 0x00)];
+        let code: Vec<u8> = stores.iter().flat_map(|&(address, value)| store(address, value)).collect();
+        let mut game_boy = GameBoy::new(cgb_test_rom(&code)).unwrap();
+        let id = game_boy.add_watchpoint(Watchpoint::access(0xC000..=0xC0FF));
+        game_boy.add_watchpoint(Watchpoint::write(0xFE00..=0xFE9F));
+        // the jump to 0150, then two instructions a store
+        for _ in 0..1 + 2 * stores.len() {
+            game_boy.step();
+        }
+        // only the store into C000 is a CPU access
+        assert_eq!(game_boy.take_watchpoint_hits(), [
+            WatchpointHit { id, access: Access::Write, address: 0xC000, value: 0x42, instruction: 0x0152 },
+        ]);
+        assert_eq!(game_boy.memory().peek(0xFE00), 0x42);
+        assert_eq!(game_boy.memory().video_ram(0).read(0x8000), 0x42);
+    }
+
+    #[test]
+    fn stop_switches_speed_when_armed() {
+        // ld a, 1; ldh [0x4D], a; stop
+        let mut game_boy = GameBoy::new(cgb_test_rom(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00])).unwrap();
+        for _ in 0..3 {
+            game_boy.step();
+        }
+        assert_eq!(game_boy.memory().read(0xFF4D), 0x7F);
+
+        assert_eq!(game_boy.step(), 2050);
+        assert_eq!(game_boy.memory().read(0xFF4D), 0xFE);
+        assert_eq!(game_boy.cpu().registers().read_pc(), 0x156);
+
+        // a line takes twice as many machine cycles in double speed
+        let line = game_boy.ppu().ly();
+        game_boy.run_cycles(228);
+        assert_eq!(game_boy.ppu().ly(), line + 1);
+    }
+
+    #[test]
+    fn steps_single_instructions() {
+        // ld b, 0x42; ld c, b
+        let mut game_boy = GameBoy::new(test_rom(&[0x06, 0x42, 0x48])).unwrap();
+
+        assert_eq!(game_boy.step(), 4);
+        assert_eq!(game_boy.step(), 2);
+        assert_eq!(game_boy.cpu().registers().read_b(), 0x42);
+        assert_eq!(game_boy.cpu().registers().read_pc(), 0x152);
+
+        game_boy.step();
+        assert_eq!(game_boy.cpu().registers().read_c(), 0x42);
+    }
+
+    #[test]
+    fn run_frame_returns_at_vblank() {
+        // ld a, 0x80; ld [0xFF40], a; jp 0x0155
+        let mut game_boy = GameBoy::new(test_rom(&[0x3E, 0x80, 0xEA, 0x40, 0xFF, 0xC3, 0x55, 0x01])).unwrap();
+
+        game_boy.run_frame();
+        assert_eq!(game_boy.ppu().ly(), 144);
+        assert_eq!(game_boy.ppu().mode(), 1);
+        assert_eq!(game_boy.memory().read(0xFF44), 144);
+
+        let cycles = game_boy.run_cycles(17556);
+        assert!((17556..17560).contains(&cycles));
+        assert_eq!(game_boy.ppu().ly(), 144);
+    }
+
+    #[test]
+    fn run_frame_leaves_watchpoint_hits_to_poll() {
+        // ld a, 0x80; ld [0xFF40], a; jp 0x0155
+        let mut game_boy = GameBoy::new(test_rom(&[0x3E, 0x80, 0xEA, 0x40, 0xFF, 0xC3, 0x55, 0x01])).unwrap();
+        let id = game_boy.add_watchpoint(Watchpoint::write(0xFF40..=0xFF40));
+
+        game_boy.run_frame();
+        assert_eq!(game_boy.ppu().ly(), 144);
+        assert_eq!(game_boy.take_watchpoint_hits(), [
+            WatchpointHit { id, access: Access::Write, address: 0xFF40, value: 0x80, instruction: 0x0152 },
+        ]);
+        game_boy.run_frame();
+        assert!(game_boy.take_watchpoint_hits().is_empty());
+    }
+
+    #[test]
+    fn debuggers_inspect_echo_ram_and_the_unusable_range() {
+        let mut game_boy = GameBoy::new(test_rom(&[])).unwrap();
+        game_boy.write_memory(0xE010, 0x42);
+        game_boy.write_memory(0xDDFF, 0x24);
+        game_boy.write_memory(0xFEA0, 0x12);
+        game_boy.write_memory(0xFF44, 0x99);
+        let memory = game_boy.memory();
+        assert_eq!(memory.peek(0xC010), 0x42);
+        assert_eq!(memory.peek(0xFDFF), 0x24);
+        assert_eq!(memory.peek(0xFEA0), 0xFF);
+        assert_eq!(memory.peek(0xFF44), 0x00);
+    }
+
+    #[test]
+    fn games_write_to_echo_ram_and_the_unusable_range() {
+        let mut code = Vec::new();
+        for (address, value) in [(0xE000, 0x42), (0xFEA0, 0x12), (0xFF44, 0x99)] {
+            code.extend(store(address, value));
+        }
+        let end = 0x150 + code.len() as u16;
+        code.extend([0xC3, end as u8, (end >> 8) as u8]);
+        let mut game_boy = GameBoy::new(test_rom(&code)).unwrap();
+        game_boy.run_frame();
+        game_boy.run_frame();
+        assert_eq!(game_boy.memory().peek(0xC000), 0x42);
+        assert_eq!(game_boy.memory().peek(0xFEA0), 0xFF);
+    }
+}
@@ -0,0 +1,408 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::game_boy::apu::blip_buffer::BlipBuffer;
+use crate::game_boy::apu::high_pass_filter::HighPassFilter;
+use crate::game_boy::apu::noise_channel::NoiseChannel;
+use crate::game_boy::apu::pulse_channel::PulseChannel;
+use crate::game_boy::apu::sample_ring::SampleRing;
+use crate::game_boy::apu::wave_channel::WaveChannel;
+use crate::game_boy::memory::audio_registers::AudioRegisters;
+use crate::game_boy::memory::Memory;
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+mod blip_buffer;
+mod envelope;
+mod high_pass_filter;
+mod length_timer;
+mod noise_channel;
+mod pulse_channel;
+mod sample_ring;
+mod sweep;
+mod wave_channel;
+pub mod wav_writer;
+
+/// The APU is clocked at 2 MiHz, every other T-cycle.
+pub const CLOCK_RATE: u32 = 2_097_152;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioChannel {
+    Pulse1,
+    Pulse2,
+    Wave,
+    Noise,
+}
+
+/// The frame sequencer runs at 512 Hz.
+const FRAME_SEQUENCER_PERIOD: u32 = CLOCK_RATE / 512;
+/// Samples are moved from the resampler to the output ring roughly once per millisecond.
+const RESAMPLER_FRAME_LENGTH: u32 = 2048;
+/// Four channels at full volume and maximum master volume stay within i16.
+const VOLUME_SCALE: i32 = 64;
+
+pub struct APU {
+    memory: Rc<RefCell<Memory>>,
+
+    channel1: PulseChannel,
+    channel2: PulseChannel,
+    channel3: WaveChannel,
+    channel4: NoiseChannel,
+
+    panning: u8,
+    master_volume: u8,
+    /// Channels that are mixed into the output, for isolating them while debugging.
+    channel_mask: u8,
+    frame_sequencer_timer: u32,
+    frame_sequencer_step: u8,
+
+    left_buffer: BlipBuffer,
+    right_buffer: BlipBuffer,
+    left_filter: HighPassFilter,
+    right_filter: HighPassFilter,
+    last_left: i32,
+    last_right: i32,
+    frame_time: u32,
+    sample_rate: u32,
+    samples: SampleRing,
+}
+
+impl APU {
+    pub(crate) fn new(memory: Rc<RefCell<Memory>>, sample_rate: u32) -> APU {
+        APU {
+            memory,
+            channel1: PulseChannel::new(true),
+            channel2: PulseChannel::new(false),
+            channel3: WaveChannel::new(),
+            channel4: NoiseChannel::new(),
+            panning: 0,
+            master_volume: 0,
+            channel_mask: 0x0F,
+            frame_sequencer_timer: 0,
+            frame_sequencer_step: 0,
+            left_buffer: BlipBuffer::new(CLOCK_RATE, sample_rate),
+            right_buffer: BlipBuffer::new(CLOCK_RATE, sample_rate),
+            left_filter: HighPassFilter::new(sample_rate),
+            right_filter: HighPassFilter::new(sample_rate),
+            last_left: 0,
+            last_right: 0,
+            frame_time: 0,
+            sample_rate,
+            samples: SampleRing::new(sample_rate as usize),
+        }
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Changes the output sample rate. Samples that were not drained yet are discarded.
+    pub(crate) fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.left_buffer = BlipBuffer::new(CLOCK_RATE, sample_rate);
+        self.right_buffer = BlipBuffer::new(CLOCK_RATE, sample_rate);
+        self.left_filter = HighPassFilter::new(sample_rate);
+        self.right_filter = HighPassFilter::new(sample_rate);
+        self.last_left = 0;
+        self.last_right = 0;
+        self.frame_time = 0;
+        self.sample_rate = sample_rate;
+        self.samples = SampleRing::new(sample_rate as usize);
+    }
+
+    /// The channels and frame sequencer. The mix and channel mask belong to the host and are left alone.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        self.channel1.save_state(state);
+        self.channel2.save_state(state);
+        self.channel3.save_state(state);
+        self.channel4.save_state(state);
+        state.write_u8(self.panning);
+        state.write_u8(self.master_volume);
+        state.write_u32(self.frame_sequencer_timer);
+        state.write_u8(self.frame_sequencer_step);
+    }
+
+    /// Also drops buffered output, which belongs to the timeline the state replaces.
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.channel1.load_state(state)?;
+        self.channel2.load_state(state)?;
+        self.channel3.load_state(state)?;
+        self.channel4.load_state(state)?;
+        self.panning = state.read_u8()?;
+        self.master_volume = state.read_u8()?;
+        self.frame_sequencer_timer = state.read_u32()?;
+        if self.frame_sequencer_timer >= FRAME_SEQUENCER_PERIOD {
+            return Err(StateError::Invalid("frame sequencer timer"));
+        }
+        self.frame_sequencer_step = state.read_u8()? & 0x07;
+        self.set_sample_rate(self.sample_rate);
+        Ok(())
+    }
+
+    /// Starts over with the channels the imported registers restart, see `AudioRegisters::restore`.
+    pub(crate) fn import_bess(&mut self) {
+        self.power_off();
+        self.frame_sequencer_timer = 0;
+        self.set_sample_rate(self.sample_rate);
+    }
+
+    /// Mutes or unmutes a single channel in the mix. Muted channels keep running.
+    pub(crate) fn set_channel_enabled(&mut self, channel: AudioChannel, enabled: bool) {
+        let bit = 1 << channel as u8;
+        if enabled {
+            self.channel_mask |= bit;
+        } else {
+            self.channel_mask &= !bit;
+        }
+    }
+
+    pub(crate) fn samples_available(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub(crate) fn read_samples(&mut self, out: &mut [i16]) -> usize {
+        self.samples.read(out)
+    }
+
+    /// Advances by `dots` of the 4 MiHz PPU clock, which the APU keeps following in double speed.
+    pub(crate) fn step(&mut self, dots: i32) {
+        self.sync_registers();
+
+        for _ in 0..dots / 2 {
+            self.tick();
+        }
+
+        self.write_back_registers();
+    }
+
+    fn sync_registers(&mut self) {
+        let memory = Rc::clone(&self.memory);
+        let mut memory = memory.borrow_mut();
+        let registers = memory.audio_registers_mut();
+
+        if !registers.is_powered_on() {
+            self.power_off();
+            return;
+        }
+
+        let length_written = registers.take_length_written();
+        let triggered = registers.take_triggered();
+
+        let get = |address| registers.get(address);
+        self.channel1.write_registers(
+            get(AudioRegisters::NR10), get(AudioRegisters::NR11), get(AudioRegisters::NR12),
+            get(AudioRegisters::NR13), get(AudioRegisters::NR14),
+        );
+        self.channel2.write_registers(
+            0, get(AudioRegisters::NR21), get(AudioRegisters::NR22),
+            get(AudioRegisters::NR23), get(AudioRegisters::NR24),
+        );
+        self.channel3.write_registers(
+            get(AudioRegisters::NR30), get(AudioRegisters::NR32),
+            get(AudioRegisters::NR33), get(AudioRegisters::NR34), registers.wave_ram(),
+        );
+        self.channel4.write_registers(
+            get(AudioRegisters::NR42), get(AudioRegisters::NR43), get(AudioRegisters::NR44),
+        );
+        self.panning = get(AudioRegisters::NR51);
+        self.master_volume = get(AudioRegisters::NR50);
+
+        if length_written & 0b0001 != 0 { self.channel1.reload_length(get(AudioRegisters::NR11)); }
+        if length_written & 0b0010 != 0 { self.channel2.reload_length(get(AudioRegisters::NR21)); }
+        if length_written & 0b0100 != 0 { self.channel3.reload_length(get(AudioRegisters::NR31)); }
+        if length_written & 0b1000 != 0 { self.channel4.reload_length(get(AudioRegisters::NR41)); }
+
+        if triggered & 0b0001 != 0 { self.channel1.trigger(get(AudioRegisters::NR12)); }
+        if triggered & 0b0010 != 0 { self.channel2.trigger(get(AudioRegisters::NR22)); }
+        if triggered & 0b0100 != 0 { self.channel3.trigger(); }
+        if triggered & 0b1000 != 0 { self.channel4.trigger(get(AudioRegisters::NR42)); }
+    }
+
+    fn write_back_registers(&mut self) {
+        let mut memory = self.memory.borrow_mut();
+        let registers = memory.audio_registers_mut();
+        if !registers.is_powered_on() {
+            return;
+        }
+
+        // the sweep unit writes the new frequency back into NR13/NR14
+        let frequency = self.channel1.frequency();
+        registers.set(AudioRegisters::NR13, frequency as u8);
+        let nr14 = registers.get(AudioRegisters::NR14);
+        registers.set(AudioRegisters::NR14, (nr14 & 0xF8) | (frequency >> 8) as u8);
+
+        let status = self.channel1.is_enabled() as u8
+            | (self.channel2.is_enabled() as u8) << 1
+            | (self.channel3.is_enabled() as u8) << 2
+            | (self.channel4.is_enabled() as u8) << 3;
+        registers.set_channel_status(status);
+    }
+
+    fn power_off(&mut self) {
+        self.channel1 = PulseChannel::new(true);
+        self.channel2 = PulseChannel::new(false);
+        self.channel3 = WaveChannel::new();
+        self.channel4 = NoiseChannel::new();
+        self.panning = 0;
+        self.master_volume = 0;
+        self.frame_sequencer_step = 0;
+    }
+
+    fn tick(&mut self) {
+        self.frame_sequencer_timer += 1;
+        if self.frame_sequencer_timer == FRAME_SEQUENCER_PERIOD {
+            self.frame_sequencer_timer = 0;
+            self.clock_frame_sequencer();
+        }
+
+        self.channel1.tick();
+        self.channel2.tick();
+        self.channel3.tick();
+        self.channel4.tick();
+
+        self.mix();
+
+        self.frame_time += 1;
+        if self.frame_time == RESAMPLER_FRAME_LENGTH {
+            self.end_resampler_frame();
+        }
+    }
+
+    /// Length timers run at 256 Hz, the sweep at 128 Hz and envelopes at 64 Hz.
+    fn clock_frame_sequencer(&mut self) {
+        if self.frame_sequencer_step.is_multiple_of(2) {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.channel1.clock_envelope();
+            self.channel2.clock_envelope();
+            self.channel4.clock_envelope();
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) & 0x07;
+    }
+
+    fn mix(&mut self) {
+        let outputs = [
+            self.channel1.output(),
+            self.channel2.output(),
+            self.channel3.output(),
+            self.channel4.output(),
+        ];
+
+        let mut left = 0;
+        let mut right = 0;
+        for (idx, output) in outputs.into_iter().enumerate() {
+            let Some(digital) = output else { continue };
+            if self.channel_mask & (1 << idx) == 0 {
+                continue;
+            }
+            // each DAC maps 0..=15 linearly onto an analog level around zero
+            let analog = 15 - 2 * digital as i32;
+            if self.panning & (0x10 << idx) != 0 {
+                left += analog;
+            }
+            if self.panning & (0x01 << idx) != 0 {
+                right += analog;
+            }
+        }
+        left *= (((self.master_volume >> 4) & 0x07) as i32 + 1) * VOLUME_SCALE;
+        right *= ((self.master_volume & 0x07) as i32 + 1) * VOLUME_SCALE;
+
+        if left != self.last_left {
+            self.left_buffer.add_delta(self.frame_time, left - self.last_left);
+            self.last_left = left;
+        }
+        if right != self.last_right {
+            self.right_buffer.add_delta(self.frame_time, right - self.last_right);
+            self.last_right = right;
+        }
+    }
+
+    fn end_resampler_frame(&mut self) {
+        self.left_buffer.end_frame(self.frame_time);
+        self.right_buffer.end_frame(self.frame_time);
+        self.frame_time = 0;
+
+        let mut left = [0; 64];
+        let mut right = [0; 64];
+        while self.left_buffer.samples_available() > 0 {
+            let count = self.left_buffer.read_samples(&mut left);
+            self.right_buffer.read_samples(&mut right[..count]);
+
+            for (left, right) in left[..count].iter().zip(&right[..count]) {
+                let left = self.left_filter.apply(*left);
+                let right = self.right_filter.apply(*right);
+                self.samples.push(left, right);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(memory: &Rc<RefCell<Memory>>, address: u16, value: u8) {
+        memory.borrow_mut().write(address, value);
+    }
+
+    #[test]
+    fn triggered_pulse_channel_is_audible() {
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        let mut apu = APU::new(Rc::clone(&memory), 48_000);
+
+        write(&memory, AudioRegisters::NR52, 0x80);
+        write(&memory, AudioRegisters::NR50, 0x77);
+        write(&memory, AudioRegisters::NR51, 0x22);
+        write(&memory, AudioRegisters::NR21, 0x80);
+        write(&memory, AudioRegisters::NR22, 0xF0);
+        write(&memory, AudioRegisters::NR23, 0x00);
+        write(&memory, AudioRegisters::NR24, 0x87);
+
+        // one 59.7 Hz frame worth of dots
+        apu.step(70224);
+
+        assert_eq!(memory.borrow().read(AudioRegisters::NR52), 0xF2);
+        let mut samples = vec![0; 2 * apu.samples_available()];
+        let count = apu.read_samples(&mut samples);
+        assert!((790..=810).contains(&count));
+        assert!(samples.iter().step_by(2).any(|&left| left > 10_000));
+        assert!(samples.iter().step_by(2).any(|&left| left < -10_000));
+    }
+
+    #[test]
+    fn length_timer_turns_channel_off() {
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        let mut apu = APU::new(Rc::clone(&memory), 48_000);
+
+        write(&memory, AudioRegisters::NR52, 0x80);
+        write(&memory, AudioRegisters::NR12, 0xF0);
+        write(&memory, AudioRegisters::NR11, 0x3F);
+        write(&memory, AudioRegisters::NR14, 0xC0);
+
+        apu.step(4);
+        assert_eq!(memory.borrow().read(AudioRegisters::NR52) & 0x01, 0x01);
+        // the length timer expires on the first 256 Hz clock
+        apu.step(8 * FRAME_SEQUENCER_PERIOD as i32);
+        assert_eq!(memory.borrow().read(AudioRegisters::NR52) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn rejects_states_with_the_frame_sequencer_past_its_period() {
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        let mut apu = APU::new(Rc::clone(&memory), 48_000);
+        let mut state = StateWriter::without_header();
+        apu.save_state(&mut state);
+        let mut data = state.into_bytes();
+        assert_eq!(apu.load_state(&mut StateReader::new(&data)), Ok(()));
+
+        // the timer is followed by the step
+        let timer = data.len() - 5;
+        data[timer..timer + 4].copy_from_slice(&FRAME_SEQUENCER_PERIOD.to_le_bytes());
+        assert_eq!(apu.load_state(&mut StateReader::new(&data)), Err(StateError::Invalid("frame sequencer timer")));
+    }
+}
@@ -0,0 +1,156 @@
+use std::f64::consts::PI;
+
+const PHASE_BITS: u32 = 5;
+const PHASE_COUNT: usize = 1 << PHASE_BITS;
+const HALF_WIDTH: usize = 8;
+const KERNEL_WIDTH: usize = 2 * HALF_WIDTH;
+const TIME_BITS: u32 = 32;
+const DELTA_BITS: u32 = 15;
+/// Cutoff of the step kernel relative to the output Nyquist frequency.
+const CUTOFF: f64 = 0.9;
+
+/// Band-limited step synthesis, in the spirit of blip_buf.
+///
+/// Amplitude changes are recorded at the exact clock at which they happen as
+/// band-limited steps in a buffer of differences, which is integrated when
+/// samples are read out. This resamples the 2 MiHz APU output to the host rate
+/// without the aliasing that nearest-sample decimation produces.
+pub(crate) struct BlipBuffer {
+    kernel: [[i32; KERNEL_WIDTH]; PHASE_COUNT],
+    /// Output samples per clock, as a 32.32 fixed point number.
+    factor: u64,
+    /// Position of clock 0 of the current frame, in the same fixed point format.
+    offset: u64,
+    deltas: Vec<i64>,
+    integrator: i64,
+}
+
+impl BlipBuffer {
+    pub(crate) fn new(clock_rate: u32, sample_rate: u32) -> BlipBuffer {
+        BlipBuffer {
+            kernel: Self::build_kernel(),
+            factor: ((sample_rate as u64) << TIME_BITS) / clock_rate as u64,
+            offset: 0,
+            deltas: Vec::new(),
+            integrator: 0,
+        }
+    }
+
+    fn build_kernel() -> [[i32; KERNEL_WIDTH]; PHASE_COUNT] {
+        let mut kernel = [[0; KERNEL_WIDTH]; PHASE_COUNT];
+
+        for (phase, taps) in kernel.iter_mut().enumerate() {
+            let fraction = phase as f64 / PHASE_COUNT as f64;
+
+            let mut weights = [0.0; KERNEL_WIDTH];
+            for (tap, weight) in weights.iter_mut().enumerate() {
+                let x = tap as f64 - (HALF_WIDTH - 1) as f64 - fraction;
+                let window = 0.42
+                    + 0.5 * (PI * x / HALF_WIDTH as f64).cos()
+                    + 0.08 * (2.0 * PI * x / HALF_WIDTH as f64).cos();
+                let sinc = if x == 0.0 { 1.0 } else { (PI * CUTOFF * x).sin() / (PI * CUTOFF * x) };
+                *weight = sinc * window;
+            }
+
+            let sum: f64 = weights.iter().sum();
+            let mut total = 0;
+            for (tap, weight) in taps.iter_mut().zip(weights) {
+                *tap = (weight / sum * (1 << DELTA_BITS) as f64).round() as i32;
+                total += *tap;
+            }
+            // every phase has to add up to exactly one, otherwise the integrator drifts
+            taps[HALF_WIDTH - 1] += (1 << DELTA_BITS) - total;
+        }
+
+        kernel
+    }
+
+    /// Records an amplitude change at `time` clocks from the start of the current frame.
+    pub(crate) fn add_delta(&mut self, time: u32, delta: i32) {
+        let position = self.offset + time as u64 * self.factor;
+        let index = (position >> TIME_BITS) as usize;
+        let phase = (position >> (TIME_BITS - PHASE_BITS)) as usize & (PHASE_COUNT - 1);
+
+        if self.deltas.len() < index + KERNEL_WIDTH {
+            self.deltas.resize(index + KERNEL_WIDTH, 0);
+        }
+        for (sample, tap) in self.deltas[index..].iter_mut().zip(self.kernel[phase]) {
+            *sample += tap as i64 * delta as i64;
+        }
+    }
+
+    /// Ends the current frame after `duration` clocks, making its samples available for reading.
+    pub(crate) fn end_frame(&mut self, duration: u32) {
+        self.offset += duration as u64 * self.factor;
+        let available = self.samples_available();
+        if self.deltas.len() < available + KERNEL_WIDTH {
+            self.deltas.resize(available + KERNEL_WIDTH, 0);
+        }
+    }
+
+    pub(crate) fn samples_available(&self) -> usize {
+        (self.offset >> TIME_BITS) as usize
+    }
+
+    /// Reads up to `out.len()` samples and returns how many were read.
+    pub(crate) fn read_samples(&mut self, out: &mut [i32]) -> usize {
+        let count = out.len().min(self.samples_available());
+
+        for (sample, delta) in out.iter_mut().zip(&self.deltas[..count]) {
+            self.integrator += delta;
+            *sample = (self.integrator >> DELTA_BITS) as i32;
+        }
+
+        self.deltas.copy_within(count.., 0);
+        let length = self.deltas.len();
+        self.deltas[length - count..].fill(0);
+        self.offset -= (count as u64) << TIME_BITS;
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_settles_at_its_amplitude() {
+        let mut buffer = BlipBuffer::new(2_097_152, 48_000);
+        buffer.add_delta(100, 10_000);
+        buffer.end_frame(2_097_152 / 100);
+
+        let mut samples = [0; 1024];
+        let count = buffer.read_samples(&mut samples);
+
+        assert_eq!(samples[0], 0);
+        assert_eq!(samples[count - 1], 10_000);
+    }
+
+    #[test]
+    fn produces_samples_at_output_rate() {
+        let mut buffer = BlipBuffer::new(2_097_152, 44_100);
+        let mut total = 0;
+        let mut samples = [0; 1024];
+        for _ in 0..64 {
+            buffer.end_frame(2_097_152 / 64);
+            total += buffer.read_samples(&mut samples);
+        }
+
+        assert_eq!(total, 44_100);
+    }
+
+    #[test]
+    fn alternating_steps_do_not_drift() {
+        let mut buffer = BlipBuffer::new(2_097_152, 48_000);
+        for time in 0..20_000 {
+            buffer.add_delta(time, if time % 2 == 0 { 7_000 } else { -7_000 });
+        }
+        buffer.end_frame(40_000);
+
+        let mut samples = [0; 1024];
+        let count = buffer.read_samples(&mut samples);
+
+        assert_eq!(samples[count - 1], 0);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// Volume envelope shared by the pulse and noise channels. Clocked at 64 Hz.
+pub(crate) struct Envelope {
+    volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub(crate) fn new() -> Envelope {
+        Envelope { volume: 0, increase: false, period: 0, timer: 0 }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&[self.volume, self.increase as u8, self.period, self.timer]);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut bytes = [0; 4];
+        state.read_bytes(&mut bytes)?;
+        self.volume = bytes[0] & 0x0F;
+        self.increase = bytes[1] != 0;
+        self.period = bytes[2] & 0x07;
+        self.timer = bytes[3];
+        Ok(())
+    }
+
+    /// Reloads the envelope from NRx2 when its channel is triggered.
+    pub(crate) fn trigger(&mut self, nrx2: u8) {
+        self.volume = nrx2 >> 4;
+        self.increase = nrx2 & 0x08 != 0;
+        self.period = nrx2 & 0x07;
+        self.timer = self.period;
+    }
+
+    pub(crate) fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    pub(crate) fn volume(&self) -> u8 {
+        self.volume
+    }
+}
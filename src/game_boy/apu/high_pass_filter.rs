@@ -0,0 +1,26 @@
+use crate::game_boy::apu::CLOCK_RATE;
+
+/// Per T-cycle charge factor of the DMG output capacitor.
+const DMG_CHARGE_FACTOR: f64 = 0.999958;
+
+/// Models the capacitor between the mixer and the amplifier, which removes
+/// the DC offset of the DACs.
+pub(crate) struct HighPassFilter {
+    capacitor: f64,
+    charge_factor: f64,
+}
+
+impl HighPassFilter {
+    pub(crate) fn new(sample_rate: u32) -> HighPassFilter {
+        let t_cycles_per_sample = 2.0 * CLOCK_RATE as f64 / sample_rate as f64;
+        HighPassFilter { capacitor: 0.0, charge_factor: DMG_CHARGE_FACTOR.powf(t_cycles_per_sample) }
+    }
+
+    pub(crate) fn apply(&mut self, input: i32) -> i16 {
+        let input = input as f64;
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge_factor;
+
+        output.clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    }
+}
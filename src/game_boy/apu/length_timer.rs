@@ -0,0 +1,48 @@
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// Silences a channel once its length runs out. Clocked at 256 Hz by the frame sequencer.
+pub(crate) struct LengthTimer {
+    max: u16,
+    remaining: u16,
+    enabled: bool,
+}
+
+impl LengthTimer {
+    pub(crate) fn new(max: u16) -> LengthTimer {
+        LengthTimer { max, remaining: 0, enabled: false }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.remaining);
+        state.write_bool(self.enabled);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.remaining = state.read_u16()?.min(self.max);
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+
+    pub(crate) fn load(&mut self, value: u8) {
+        self.remaining = self.max - value as u16;
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub(crate) fn trigger(&mut self) {
+        if self.remaining == 0 {
+            self.remaining = self.max;
+        }
+    }
+
+    /// Returns true when the timer expires and the channel has to be turned off.
+    pub(crate) fn clock(&mut self) -> bool {
+        if !self.enabled || self.remaining == 0 {
+            return false;
+        }
+        self.remaining -= 1;
+        self.remaining == 0
+    }
+}
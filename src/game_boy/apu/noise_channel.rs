@@ -0,0 +1,118 @@
+use crate::game_boy::apu::envelope::Envelope;
+use crate::game_boy::apu::length_timer::LengthTimer;
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// Clock divisors selected by the lower bits of NR43, in APU ticks.
+const DIVISORS: [u16; 8] = [4, 8, 16, 24, 32, 40, 48, 56];
+
+/// Pseudo-random noise from a 15-bit (or 7-bit) linear feedback shift register.
+pub(crate) struct NoiseChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    nr43: u8,
+    timer: u32,
+    lfsr: u16,
+    length: LengthTimer,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    pub(crate) fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            dac_enabled: false,
+            nr43: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+            length: LengthTimer::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        state.write_u8(self.nr43);
+        state.write_u32(self.timer);
+        state.write_u16(self.lfsr);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.nr43 = state.read_u8()?;
+        self.timer = state.read_u32()?;
+        self.lfsr = state.read_u16()? & 0x7FFF;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        Ok(())
+    }
+
+    /// Timer period in APU ticks.
+    fn period(&self) -> u32 {
+        (DIVISORS[(self.nr43 & 0x07) as usize] as u32) << (self.nr43 >> 4)
+    }
+
+    pub(crate) fn write_registers(&mut self, nr42: u8, nr43: u8, nr44: u8) {
+        self.dac_enabled = nr42 & 0xF8 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+        self.nr43 = nr43;
+        self.length.set_enabled(nr44 & 0x40 != 0);
+    }
+
+    pub(crate) fn reload_length(&mut self, nr41: u8) {
+        self.length.load(nr41 & 0x3F);
+    }
+
+    pub(crate) fn trigger(&mut self, nr42: u8) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger(nr42);
+    }
+
+    pub(crate) fn tick(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period();
+
+        // shifts of 14 and 15 stop the LFSR
+        if self.nr43 >> 4 >= 14 {
+            return;
+        }
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.nr43 & 0x08 != 0 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    pub(crate) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(crate) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Digital output between 0 and 15, or None while the DAC is off.
+    pub(crate) fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        Some(if self.enabled && self.lfsr & 1 == 0 { self.envelope.volume() } else { 0 })
+    }
+}
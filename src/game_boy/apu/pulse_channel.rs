@@ -0,0 +1,141 @@
+use crate::game_boy::apu::envelope::Envelope;
+use crate::game_boy::apu::length_timer::LengthTimer;
+use crate::game_boy::apu::sweep::Sweep;
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+/// Square wave channel. Channel 1 has a frequency sweep, channel 2 does not.
+pub(crate) struct PulseChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    timer: u16,
+    length: LengthTimer,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl PulseChannel {
+    pub(crate) fn new(with_sweep: bool) -> PulseChannel {
+        PulseChannel {
+            enabled: false,
+            dac_enabled: false,
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 0,
+            length: LengthTimer::new(64),
+            envelope: Envelope::new(),
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        state.write_u8(self.duty);
+        state.write_u8(self.duty_step);
+        state.write_u16(self.frequency);
+        state.write_u16(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(state);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.duty = state.read_u8()? & 0x03;
+        self.duty_step = state.read_u8()? & 0x07;
+        self.frequency = state.read_u16()? & 0x07FF;
+        self.timer = state.read_u16()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load_state(state)?;
+        }
+        Ok(())
+    }
+
+    /// Timer period in APU ticks.
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+
+    pub(crate) fn write_registers(&mut self, nrx0: u8, nrx1: u8, nrx2: u8, nrx3: u8, nrx4: u8) {
+        if let Some(sweep) = &mut self.sweep {
+            sweep.set_register(nrx0);
+        }
+        self.duty = nrx1 >> 6;
+        self.dac_enabled = nrx2 & 0xF8 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+        self.frequency = ((nrx4 as u16 & 0x07) << 8) | nrx3 as u16;
+        self.length.set_enabled(nrx4 & 0x40 != 0);
+    }
+
+    pub(crate) fn reload_length(&mut self, nrx1: u8) {
+        self.length.load(nrx1 & 0x3F);
+    }
+
+    pub(crate) fn trigger(&mut self, nrx2: u8) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger(nrx2);
+        if let Some(sweep) = &mut self.sweep && !sweep.trigger(self.frequency) {
+            self.enabled = false;
+        }
+    }
+
+    pub(crate) fn tick(&mut self) {
+        if self.timer <= 1 {
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub(crate) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(crate) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub(crate) fn clock_sweep(&mut self) {
+        if let Some(sweep) = &mut self.sweep {
+            match sweep.clock(self.frequency) {
+                Some(frequency) => self.frequency = frequency,
+                None => self.enabled = false,
+            }
+        }
+    }
+
+    pub(crate) fn frequency(&self) -> u16 {
+        self.frequency
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Digital output between 0 and 15, or None while the DAC is off.
+    pub(crate) fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        let high = DUTY_PATTERNS[self.duty as usize] & (0x80 >> self.duty_step) != 0;
+        Some(if self.enabled && high { self.envelope.volume() } else { 0 })
+    }
+}
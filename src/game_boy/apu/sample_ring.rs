@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+
+/// Bounded queue of stereo samples waiting to be drained by the frontend.
+/// When it is full the oldest samples are dropped, so a consumer that stops
+/// draining only loses audio instead of growing memory.
+pub(crate) struct SampleRing {
+    samples: VecDeque<(i16, i16)>,
+    capacity: usize,
+}
+
+impl SampleRing {
+    pub(crate) fn new(capacity: usize) -> SampleRing {
+        SampleRing { samples: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub(crate) fn push(&mut self, left: i16, right: i16) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((left, right));
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Moves up to `out.len() / 2` samples into `out`, interleaved left then right.
+    /// Returns the number of stereo samples written.
+    pub(crate) fn read(&mut self, out: &mut [i16]) -> usize {
+        let count = self.samples.len().min(out.len() / 2);
+        for (frame, (left, right)) in out.chunks_exact_mut(2).zip(self.samples.drain(..count)) {
+            frame[0] = left;
+            frame[1] = right;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_oldest_samples_when_full() {
+        let mut ring = SampleRing::new(2);
+        ring.push(1, -1);
+        ring.push(2, -2);
+        ring.push(3, -3);
+
+        let mut out = [0; 8];
+        assert_eq!(ring.read(&mut out), 2);
+        assert_eq!(out[..4], [2, -2, 3, -3]);
+        assert_eq!(ring.len(), 0);
+    }
+
+    #[test]
+    fn partial_read_keeps_the_rest() {
+        let mut ring = SampleRing::new(4);
+        ring.push(1, 1);
+        ring.push(2, 2);
+
+        let mut out = [0; 2];
+        assert_eq!(ring.read(&mut out), 1);
+        assert_eq!(out, [1, 1]);
+        assert_eq!(ring.len(), 1);
+    }
+}
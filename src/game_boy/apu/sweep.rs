@@ -0,0 +1,92 @@
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// Frequency sweep unit of channel 1. Clocked at 128 Hz.
+pub(crate) struct Sweep {
+    nr10: u8,
+    enabled: bool,
+    timer: u8,
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    pub(crate) fn new() -> Sweep {
+        Sweep { nr10: 0, enabled: false, timer: 0, shadow_frequency: 0 }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.nr10);
+        state.write_bool(self.enabled);
+        state.write_u8(self.timer);
+        state.write_u16(self.shadow_frequency);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.nr10 = state.read_u8()?;
+        self.enabled = state.read_bool()?;
+        self.timer = state.read_u8()?;
+        self.shadow_frequency = state.read_u16()?;
+        Ok(())
+    }
+
+    pub(crate) fn set_register(&mut self, nr10: u8) {
+        self.nr10 = nr10;
+    }
+
+    fn period(&self) -> u8 {
+        (self.nr10 >> 4) & 0x07
+    }
+
+    fn shift(&self) -> u8 {
+        self.nr10 & 0x07
+    }
+
+    fn reload_timer(&mut self) {
+        // a period of 0 is treated as 8
+        self.timer = if self.period() == 0 { 8 } else { self.period() };
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift();
+        if self.nr10 & 0x08 != 0 {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+
+    /// Returns false if the initial overflow check turns the channel off.
+    pub(crate) fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow_frequency = frequency;
+        self.reload_timer();
+        self.enabled = self.period() != 0 || self.shift() != 0;
+
+        self.shift() == 0 || self.next_frequency() <= 0x7FF
+    }
+
+    /// Returns the new channel frequency, or None if it overflowed and the channel has to be turned off.
+    pub(crate) fn clock(&mut self, frequency: u16) -> Option<u16> {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer != 0 {
+            return Some(frequency);
+        }
+        self.reload_timer();
+        if !self.enabled || self.period() == 0 {
+            return Some(frequency);
+        }
+
+        let new_frequency = self.next_frequency();
+        if new_frequency > 0x7FF {
+            return None;
+        }
+        if self.shift() == 0 {
+            return Some(frequency);
+        }
+        self.shadow_frequency = new_frequency;
+
+        // the new frequency is checked for overflow a second time without being applied
+        if self.next_frequency() > 0x7FF {
+            return None;
+        }
+        Some(new_frequency)
+    }
+}
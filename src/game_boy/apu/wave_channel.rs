@@ -0,0 +1,115 @@
+use crate::game_boy::apu::length_timer::LengthTimer;
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// Plays back the 32 4-bit samples stored in wave RAM.
+pub(crate) struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u16,
+    position: u8,
+    sample: u8,
+    wave_ram: [u8; 16],
+    length: LengthTimer,
+}
+
+impl WaveChannel {
+    pub(crate) fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            wave_ram: [0; 16],
+            length: LengthTimer::new(256),
+        }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        state.write_u8(self.volume_code);
+        state.write_u16(self.frequency);
+        state.write_u16(self.timer);
+        state.write_u8(self.position);
+        state.write_u8(self.sample);
+        state.write_bytes(&self.wave_ram);
+        self.length.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.volume_code = state.read_u8()? & 0x03;
+        self.frequency = state.read_u16()? & 0x07FF;
+        self.timer = state.read_u16()?;
+        self.position = state.read_u8()? & 0x1F;
+        self.sample = state.read_u8()? & 0x0F;
+        state.read_bytes(&mut self.wave_ram)?;
+        self.length.load_state(state)?;
+        Ok(())
+    }
+
+    /// Timer period in APU ticks.
+    fn period(&self) -> u16 {
+        2048 - self.frequency
+    }
+
+    pub(crate) fn write_registers(&mut self, nr30: u8, nr32: u8, nr33: u8, nr34: u8, wave_ram: &[u8]) {
+        self.dac_enabled = nr30 & 0x80 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+        self.volume_code = (nr32 >> 5) & 0x03;
+        self.frequency = ((nr34 as u16 & 0x07) << 8) | nr33 as u16;
+        self.length.set_enabled(nr34 & 0x40 != 0);
+        self.wave_ram.copy_from_slice(wave_ram);
+    }
+
+    pub(crate) fn reload_length(&mut self, nr31: u8) {
+        self.length.load(nr31);
+    }
+
+    pub(crate) fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    pub(crate) fn tick(&mut self) {
+        if self.timer <= 1 {
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x1F;
+            let byte = self.wave_ram[(self.position / 2) as usize];
+            self.sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub(crate) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Digital output between 0 and 15, or None while the DAC is off.
+    pub(crate) fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if !self.enabled || self.volume_code == 0 {
+            return Some(0);
+        }
+        Some(self.sample >> (self.volume_code - 1))
+    }
+}
@@ -0,0 +1,299 @@
+use crate::game_boy::model::Model;
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// Ends every BESS file, after the offset of its first block.
+const FOOTER_MAGIC: &[u8; 4] = b"BESS";
+const MAJOR_VERSION: u16 = 1;
+const MINOR_VERSION: u16 = 1;
+/// CORE up to and including its buffer table, later minor versions may append to it.
+const CORE_LENGTH: usize = 0xD0;
+const INFO_LENGTH: usize = 0x12;
+const RTC_LENGTH: usize = 0x30;
+
+/// The machine state BESS shares between emulators: registers, the I/O page and the memory buffers.
+pub(crate) struct BessCore {
+    /// Family and revision, "G" for DMG and MGB, "S" for SGB and "C" for CGB and AGB.
+    pub(crate) model: [u8; 4],
+    pub(crate) pc: u16,
+    pub(crate) af: u16,
+    pub(crate) bc: u16,
+    pub(crate) de: u16,
+    pub(crate) hl: u16,
+    pub(crate) sp: u16,
+    pub(crate) ime: bool,
+    pub(crate) interrupt_enable: u8,
+    /// 0 while running, 1 halted and 2 stopped.
+    pub(crate) execution_state: u8,
+    /// 0xFF00-0xFF7F, including registers that are only written through the buffers.
+    pub(crate) io_registers: [u8; 128],
+    pub(crate) work_ram: Vec<u8>,
+    pub(crate) video_ram: Vec<u8>,
+    pub(crate) cartridge_ram: Vec<u8>,
+    pub(crate) object_attribute_memory: Vec<u8>,
+    pub(crate) high_ram: Vec<u8>,
+    pub(crate) bg_palettes: Vec<u8>,
+    pub(crate) obj_palettes: Vec<u8>,
+}
+
+/// A Best Effort Save State, the format SameBoy, Gambatte forks and others append to their own states.
+/// Only the blocks this emulator has state for are read and written: NAME, INFO, CORE, MBC and RTC.
+pub(crate) struct Bess {
+    /// The emulator that wrote the state.
+    pub(crate) name: Option<String>,
+    /// ROM title and global checksum from the header.
+    pub(crate) info: Option<([u8; 16], u16)>,
+    pub(crate) core: BessCore,
+    /// Writes to replay to the memory bank controller.
+    pub(crate) mbc_writes: Vec<(u16, u8)>,
+    /// The MBC3 clock, in the same layout as the save file footer.
+    pub(crate) rtc: Option<[u8; RTC_LENGTH]>,
+}
+
+impl Bess {
+    pub(crate) fn new(model: Model) -> Bess {
+        Bess {
+            name: Some(format!("gameboy_emu {}", env!("CARGO_PKG_VERSION"))),
+            info: None,
+            core: BessCore {
+                model: model_code(model),
+                pc: 0,
+                af: 0,
+                bc: 0,
+                de: 0,
+                hl: 0,
+                sp: 0,
+                ime: false,
+                interrupt_enable: 0,
+                execution_state: 0,
+                io_registers: [0; 128],
+                work_ram: Vec::new(),
+                video_ram: Vec::new(),
+                cartridge_ram: Vec::new(),
+                object_attribute_memory: Vec::new(),
+                high_ram: Vec::new(),
+                bg_palettes: Vec::new(),
+                obj_palettes: Vec::new(),
+            },
+            mbc_writes: Vec::new(),
+            rtc: None,
+        }
+    }
+
+    /// Whether `model` can run a state of the model in CORE. Only CGB and non-CGB differ in memory layout.
+    pub(crate) fn is_compatible_with(&self, model: Model) -> bool {
+        (self.core.model[0] == b'C') == model.is_cgb()
+    }
+
+    pub(crate) fn model_name(&self) -> String {
+        String::from_utf8_lossy(&self.core.model).trim_end().to_string()
+    }
+
+    /// Reads the blocks the footer points to. `data` is the whole file, buffers may live anywhere in it.
+    pub(crate) fn parse(data: &[u8]) -> Result<Bess, StateError> {
+        if data.len() < 8 || &data[data.len() - 4..] != FOOTER_MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let first_block = u32::from_le_bytes(data[data.len() - 8..data.len() - 4].try_into().unwrap()) as usize;
+        let mut blocks = StateReader::new(data.get(first_block..data.len() - 8).ok_or(StateError::Truncated)?);
+
+        let mut bess = Bess::new(Model::Dmg);
+        bess.name = None;
+        let mut has_core = false;
+        loop {
+            let mut tag = [0; 4];
+            blocks.read_bytes(&mut tag)?;
+            let length = blocks.read_u32()? as usize;
+            let mut block = StateReader::new(blocks.read_slice(length)?);
+            match &tag {
+                b"NAME" => bess.name = Some(String::from_utf8_lossy(block.read_slice(length)?).into_owned()),
+                b"INFO" if length >= INFO_LENGTH => {
+                    let mut title = [0; 16];
+                    block.read_bytes(&mut title)?;
+                    // the checksum is copied from the header as it is, big-endian
+                    let checksum = u16::from_be_bytes([block.read_u8()?, block.read_u8()?]);
+                    bess.info = Some((title, checksum));
+                }
+                b"CORE" if length >= CORE_LENGTH => {
+                    bess.read_core(&mut block, data)?;
+                    has_core = true;
+                }
+                b"MBC " => {
+                    if !length.is_multiple_of(3) {
+                        return Err(StateError::Invalid("BESS MBC block"));
+                    }
+                    for _ in 0..length / 3 {
+                        bess.mbc_writes.push((block.read_u16()?, block.read_u8()?));
+                    }
+                }
+                b"RTC " if length >= RTC_LENGTH => {
+                    let mut rtc = [0; RTC_LENGTH];
+                    block.read_bytes(&mut rtc)?;
+                    bess.rtc = Some(rtc);
+                }
+                b"INFO" | b"CORE" | b"RTC " => return Err(StateError::Truncated),
+                b"END " => break,
+                _ => {}
+            }
+        }
+
+        if !has_core {
+            return Err(StateError::MissingSection(String::from("CORE")));
+        }
+        Ok(bess)
+    }
+
+    fn read_core(&mut self, block: &mut StateReader, data: &[u8]) -> Result<(), StateError> {
+        let major_version = block.read_u16()?;
+        let _minor_version = block.read_u16()?;
+        if major_version != MAJOR_VERSION {
+            let emulator_version = self.name.clone().unwrap_or_else(|| String::from("an unknown emulator"));
+            return Err(StateError::UnsupportedVersion { format_version: major_version, emulator_version });
+        }
+
+        let core = &mut self.core;
+        block.read_bytes(&mut core.model)?;
+        core.pc = block.read_u16()?;
+        core.af = block.read_u16()?;
+        core.bc = block.read_u16()?;
+        core.de = block.read_u16()?;
+        core.hl = block.read_u16()?;
+        core.sp = block.read_u16()?;
+        core.ime = block.read_bool()?;
+        core.interrupt_enable = block.read_u8()?;
+        core.execution_state = block.read_u8()?;
+        block.read_u8()?;
+        block.read_bytes(&mut core.io_registers)?;
+        for buffer in [
+            &mut core.work_ram, &mut core.video_ram, &mut core.cartridge_ram, &mut core.object_attribute_memory,
+            &mut core.high_ram, &mut core.bg_palettes, &mut core.obj_palettes,
+        ] {
+            let size = block.read_u32()? as usize;
+            let offset = block.read_u32()? as usize;
+            let end = offset.checked_add(size).filter(|&end| end <= data.len()).ok_or(StateError::Truncated)?;
+            *buffer = data[offset..end].to_vec();
+        }
+        Ok(())
+    }
+
+    /// Writes the buffers, then the blocks, then the footer.
+    pub(crate) fn write(&self) -> Vec<u8> {
+        let core = &self.core;
+        let buffers = [
+            &core.work_ram, &core.video_ram, &core.cartridge_ram, &core.object_attribute_memory,
+            &core.high_ram, &core.bg_palettes, &core.obj_palettes,
+        ];
+        let mut state = StateWriter::without_header();
+        let mut buffer_table = Vec::new();
+        for buffer in buffers {
+            buffer_table.push((buffer.len() as u32, state.len() as u32));
+            state.write_bytes(buffer);
+        }
+
+        let first_block = state.len() as u32;
+        if let Some(name) = &self.name {
+            state.section(b"NAME", |state| state.write_bytes(name.as_bytes()));
+        }
+        if let Some((title, checksum)) = &self.info {
+            state.section(b"INFO", |state| {
+                state.write_bytes(title);
+                state.write_bytes(&checksum.to_be_bytes());
+            });
+        }
+        state.section(b"CORE", |state| {
+            state.write_u16(MAJOR_VERSION);
+            state.write_u16(MINOR_VERSION);
+            state.write_bytes(&core.model);
+            for register in [core.pc, core.af, core.bc, core.de, core.hl, core.sp] {
+                state.write_u16(register);
+            }
+            state.write_bool(core.ime);
+            state.write_u8(core.interrupt_enable);
+            state.write_u8(core.execution_state);
+            state.write_u8(0);
+            state.write_bytes(&core.io_registers);
+            for (size, offset) in buffer_table {
+                state.write_u32(size);
+                state.write_u32(offset);
+            }
+        });
+        if !self.mbc_writes.is_empty() {
+            state.section(b"MBC ", |state| {
+                for &(address, value) in &self.mbc_writes {
+                    state.write_u16(address);
+                    state.write_u8(value);
+                }
+            });
+        }
+        if let Some(rtc) = &self.rtc {
+            state.section(b"RTC ", |state| state.write_bytes(rtc));
+        }
+        state.section(b"END ", |_| {});
+
+        state.write_u32(first_block);
+        state.write_bytes(FOOTER_MAGIC);
+        state.into_bytes()
+    }
+}
+
+/// The model as BESS names it, family letter first.
+fn model_code(model: Model) -> [u8; 4] {
+    *match model {
+        Model::Dmg0 => b"GD0 ",
+        Model::Dmg => b"GDB ",
+        Model::Mgb => b"GM  ",
+        Model::Sgb => b"SN  ",
+        Model::Cgb => b"CCE ",
+        Model::Agb => b"CA  ",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_what_it_writes_and_skips_unknown_blocks() {
+        let mut bess = Bess::new(Model::Cgb);
+        bess.info = Some((*b"TEST TITLE\0\0\0\0\0\0", 0xBEEF));
+        bess.core.pc = 0x0150;
+        bess.core.io_registers[0x40] = 0x91;
+        bess.core.video_ram = vec![0x11; 0x4000];
+        bess.core.high_ram = vec![0x22; 0x7F];
+        bess.mbc_writes = vec![(0x0000, 0x0A), (0x2000, 0x05)];
+        let mut data = bess.write();
+
+        // an emulator's own state before the BESS data, and a block from a newer version
+        let unknown_block = [b"XOAM".as_slice(), &4u32.to_le_bytes(), &[0; 4]].concat();
+        let footer = data.len() - 8;
+        let first_block = u32::from_le_bytes(data[footer..footer + 4].try_into().unwrap()) as usize;
+        data.splice(first_block..first_block, unknown_block);
+        let mut file = vec![0xAA; 100];
+        file.extend(&data);
+        let footer = file.len() - 8;
+        let shifted = |value: u32| value + 100;
+        let first_block = shifted(u32::from_le_bytes(file[footer..footer + 4].try_into().unwrap()));
+        file[footer..footer + 4].copy_from_slice(&first_block.to_le_bytes());
+        // buffer offsets are relative to the start of the file
+        let core = file.windows(4).position(|tag| tag == b"CORE").unwrap() + 8;
+        for idx in 0..7 {
+            let entry = core + 0x98 + idx * 8 + 4;
+            let offset = shifted(u32::from_le_bytes(file[entry..entry + 4].try_into().unwrap()));
+            file[entry..entry + 4].copy_from_slice(&offset.to_le_bytes());
+        }
+
+        let parsed = Bess::parse(&file).unwrap();
+        assert_eq!(parsed.name, bess.name);
+        assert_eq!(parsed.info, bess.info);
+        assert_eq!(parsed.model_name(), "CCE");
+        assert!(parsed.is_compatible_with(Model::Agb));
+        assert!(!parsed.is_compatible_with(Model::Sgb));
+        assert_eq!(parsed.core.pc, 0x0150);
+        assert_eq!(parsed.core.io_registers[0x40], 0x91);
+        assert_eq!(parsed.core.video_ram, bess.core.video_ram);
+        assert_eq!(parsed.core.high_ram, bess.core.high_ram);
+        assert_eq!(parsed.mbc_writes, bess.mbc_writes);
+        assert_eq!(parsed.rtc, None);
+
+        assert_eq!(Bess::parse(&file[..file.len() - 1]).err(), Some(StateError::NotAState));
+    }
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+
+/// The logo every cartridge has at 0x0104, the boot ROM refuses to start without it.
+pub const NINTENDO_LOGO: &[u8] = &[
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The ROM ends before the header does.
+    TooSmall(usize),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::TooSmall(length) => write!(f, "ROM is only {length} bytes long, too small to hold a header"),
+        }
+    }
+}
+
+/// The cartridge header at 0x0100-0x014F.
+pub struct CartridgeHeader {
+    pub entry_point: [u8; 4],
+    pub logo_valid: bool,
+    pub title: String,
+    pub manufacturer_code: String,
+    pub cgb_flag: u8,
+    pub new_licensee_code: String,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    pub destination_code: u8,
+    pub old_licensee_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub computed_header_checksum: u8,
+    pub global_checksum: u16,
+    pub computed_global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    pub fn parse(cartridge_rom: &[u8]) -> Result<CartridgeHeader, HeaderError> {
+        if cartridge_rom.len() < 0x150 {
+            return Err(HeaderError::TooSmall(cartridge_rom.len()));
+        }
+
+        let cgb_flag = cartridge_rom[0x143];
+        // newer cartridges use the last bytes of the title for the manufacturer code and CGB flag
+        let title_end = if cgb_flag & 0x80 != 0 { 0x13F } else { 0x144 };
+        let manufacturer_code = if cgb_flag & 0x80 != 0 { Self::text(&cartridge_rom[0x13F..0x143]) } else { String::new() };
+
+        Ok(CartridgeHeader {
+            entry_point: cartridge_rom[0x100..0x104].try_into().unwrap(),
+            logo_valid: Self::is_nintendo_logo_correct(cartridge_rom),
+            title: Self::text(&cartridge_rom[0x134..title_end]),
+            manufacturer_code,
+            cgb_flag,
+            new_licensee_code: Self::text(&cartridge_rom[0x144..0x146]),
+            sgb_flag: cartridge_rom[0x146],
+            cartridge_type: cartridge_rom[0x147],
+            rom_size_code: cartridge_rom[0x148],
+            ram_size_code: cartridge_rom[0x149],
+            destination_code: cartridge_rom[0x14A],
+            old_licensee_code: cartridge_rom[0x14B],
+            version: cartridge_rom[0x14C],
+            header_checksum: cartridge_rom[0x14D],
+            computed_header_checksum: Self::calculate_checksum(cartridge_rom),
+            global_checksum: ((cartridge_rom[0x14E] as u16) << 8) + (cartridge_rom[0x14F] as u16),
+            computed_global_checksum: Self::calculate_global_checksum(cartridge_rom),
+        })
+    }
+
+    fn text(bytes: &[u8]) -> String {
+        bytes.iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '?' })
+            .collect()
+    }
+
+    pub(crate) fn is_nintendo_logo_correct(cartridge_rom: &[u8]) -> bool {
+        let nintendo_logo_content = &cartridge_rom[0x104..0x133 + 1];
+        NINTENDO_LOGO == nintendo_logo_content
+    }
+
+    pub fn calculate_checksum(content: &[u8]) -> u8 {
+        let mut checksum: u8 = 0;
+
+        for byte in content.iter().take(0x014C + 1).skip(0x0134) {
+            checksum = checksum.wrapping_sub(byte + 1);
+        }
+
+        checksum
+    }
+
+    pub fn calculate_global_checksum(content: &[u8]) -> u16 {
+        let mut checksum: u16 = 0;
+
+        for (address, byte) in content.iter().enumerate() {
+            if address == 0x014E || address == 0x014F {
+                continue;
+            }
+            checksum = checksum.wrapping_add(*byte as u16);
+        }
+
+        checksum
+    }
+
+    pub fn is_header_checksum_valid(&self) -> bool {
+        self.header_checksum == self.computed_header_checksum
+    }
+
+    pub fn is_global_checksum_valid(&self) -> bool {
+        self.global_checksum == self.computed_global_checksum
+    }
+
+    pub fn cartridge_type_name(&self) -> &'static str {
+        match self.cartridge_type {
+            0x00 => "ROM ONLY",
+            0x01 => "MBC1",
+            0x02 => "MBC1+RAM",
+            0x03 => "MBC1+RAM+BATTERY",
+            0x05 => "MBC2",
+            0x06 => "MBC2+BATTERY",
+            0x08 => "ROM+RAM",
+            0x09 => "ROM+RAM+BATTERY",
+            0x0B => "MMM01",
+            0x0C => "MMM01+RAM",
+            0x0D => "MMM01+RAM+BATTERY",
+            0x0F => "MBC3+TIMER+BATTERY",
+            0x10 => "MBC3+TIMER+RAM+BATTERY",
+            0x11 => "MBC3",
+            0x12 => "MBC3+RAM",
+            0x13 => "MBC3+RAM+BATTERY",
+            0x19 => "MBC5",
+            0x1A => "MBC5+RAM",
+            0x1B => "MBC5+RAM+BATTERY",
+            0x1C => "MBC5+RUMBLE",
+            0x1D => "MBC5+RUMBLE+RAM",
+            0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+            0x20 => "MBC6",
+            0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+            0xFC => "POCKET CAMERA",
+            0xFD => "BANDAI TAMA5",
+            0xFE => "HuC3",
+            0xFF => "HuC1+RAM+BATTERY",
+            _ => "unknown",
+        }
+    }
+
+    /// ROM size in bytes, or None for an invalid size code.
+    pub fn rom_size(&self) -> Option<usize> {
+        if self.rom_size_code <= 8 { Some((32 * 1024) << self.rom_size_code) } else { None }
+    }
+
+    /// External RAM size in bytes, or None for an invalid size code.
+    pub fn ram_size(&self) -> Option<usize> {
+        match self.ram_size_code {
+            0 | 1 => Some(0),
+            2 => Some(8 * 1024),
+            3 => Some(32 * 1024),
+            4 => Some(128 * 1024),
+            5 => Some(64 * 1024),
+            _ => None,
+        }
+    }
+
+    pub fn cgb_support(&self) -> &'static str {
+        match self.cgb_flag {
+            0x80 => "CGB enhanced, DMG compatible",
+            0xC0 => "CGB only",
+            _ => "DMG only",
+        }
+    }
+
+    /// The SGB only enables its features when the old licensee code defers to the new one as well.
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
+
+    pub fn destination(&self) -> &'static str {
+        if self.destination_code == 0x00 { "Japan" } else { "Overseas" }
+    }
+
+    /// The licensee, from the new code when the old one defers to it.
+    pub fn licensee_code(&self) -> String {
+        if self.old_licensee_code == 0x33 {
+            self.new_licensee_code.clone()
+        } else {
+            format!("{:02X}", self.old_licensee_code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_header_fields() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13F].copy_from_slice(b"TESTGAME\0\0\0");
+        rom[0x13F..0x143].copy_from_slice(b"ABCD");
+        rom[0x143] = 0x80;
+        rom[0x144..0x146].copy_from_slice(b"01");
+        rom[0x146] = 0x03;
+        rom[0x147] = 0x1B;
+        rom[0x148] = 0x05;
+        rom[0x149] = 0x03;
+        rom[0x14B] = 0x33;
+        rom[0x14D] = CartridgeHeader::calculate_checksum(&rom);
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+
+        assert_eq!(header.title, "TESTGAME");
+        assert_eq!(header.manufacturer_code, "ABCD");
+        assert_eq!(header.cgb_support(), "CGB enhanced, DMG compatible");
+        assert!(header.supports_sgb());
+        assert_eq!(header.cartridge_type_name(), "MBC5+RAM+BATTERY");
+        assert_eq!(header.rom_size(), Some(1024 * 1024));
+        assert_eq!(header.ram_size(), Some(32 * 1024));
+        assert_eq!(header.licensee_code(), "01");
+        assert!(header.is_header_checksum_valid());
+        assert!(!header.logo_valid);
+    }
+
+    #[test]
+    fn rejects_truncated_rom() {
+        assert_eq!(CartridgeHeader::parse(&[0; 0x100]).err(), Some(HeaderError::TooSmall(0x100)));
+    }
+}
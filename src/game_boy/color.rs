@@ -0,0 +1,46 @@
+/// How RGB555 colors from CGB palette RAM are turned into RGB888 for a modern display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorCorrection {
+    /// Scales every channel linearly, which looks oversaturated compared to the real LCD.
+    None,
+    /// Mixes the channels and darkens the result the way the GBC screen does.
+    Gbc,
+}
+
+/// Converts an RGB555 color, red in the lowest bits, to RGB888.
+pub fn rgb555_to_rgb888(color: u16, correction: ColorCorrection) -> [u8; 3] {
+    let red = (color & 0x1F) as u32;
+    let green = ((color >> 5) & 0x1F) as u32;
+    let blue = ((color >> 10) & 0x1F) as u32;
+
+    match correction {
+        ColorCorrection::None => [red, green, blue].map(|channel| (channel << 3 | channel >> 2) as u8),
+        ColorCorrection::Gbc => {
+            // the weights of each channel add up to 32, the result is scaled down to 0-240
+            let mixed = [
+                red * 26 + green * 4 + blue * 2,
+                green * 24 + blue * 8,
+                red * 6 + green * 4 + blue * 22,
+            ];
+            mixed.map(|channel| (channel.min(960) >> 2) as u8)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_without_correction() {
+        assert_eq!(rgb555_to_rgb888(0x7FFF, ColorCorrection::None), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(rgb555_to_rgb888(0x001F, ColorCorrection::None), [0xFF, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn correction_bleeds_channels_and_darkens() {
+        let [red, green, blue] = rgb555_to_rgb888(0x001F, ColorCorrection::Gbc);
+        assert!(red < 0xFF && green == 0 && blue > 0);
+        assert_eq!(rgb555_to_rgb888(0x7FFF, ColorCorrection::Gbc), [240, 240, 240]);
+    }
+}
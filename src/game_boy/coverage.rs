@@ -0,0 +1,214 @@
+use std::fmt::{self, Write};
+use crate::game_boy::symbols::Symbols;
+
+/// Executed as the first byte of an instruction.
+pub const OPCODE: u8 = 0x01;
+/// Fetched as a later byte of an instruction, an immediate value or what follows 0xCB.
+pub const OPERAND: u8 = 0x02;
+/// Read by an instruction or a DMA, like a table `ld a, [hl+]` goes through.
+pub const DATA: u8 = 0x04;
+
+const BANK_SIZE: usize = 0x4000;
+/// Untouched ranges shorter than this are left out of the report, they are mostly alignment.
+const MIN_UNTOUCHED: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CoverageError {
+    /// 1-based, like editors count.
+    pub line: usize,
+}
+
+impl fmt::Display for CoverageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {} is not BANK:START-END FLAGS inside the ROM", self.line)
+    }
+}
+
+/// How each byte of a cartridge ROM was used, as `OPCODE`, `OPERAND` and `DATA` bits.
+///
+/// The file format has a range of bytes with the same use per line, `01:4000-4002 x`, with `x` for opcodes,
+/// `o` for operands and `d` for data. Banks and addresses are as in `.sym` files, untouched bytes are left out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coverage {
+    rom: Vec<u8>,
+}
+
+impl Coverage {
+    pub fn new(rom_size: usize) -> Coverage {
+        Coverage { rom: vec![0; rom_size] }
+    }
+
+    pub fn parse(text: &str, rom_size: usize) -> Result<Coverage, CoverageError> {
+        let mut coverage = Coverage::new(rom_size);
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || CoverageError { line: idx + 1 };
+            let (range, flags) = line.split_once(char::is_whitespace).ok_or_else(error)?;
+            let (bank, range) = range.split_once(':').ok_or_else(error)?;
+            let (start, end) = range.split_once('-').ok_or_else(error)?;
+            let bank = usize::from_str_radix(bank, 16).map_err(|_| error())?;
+            let start = coverage.offset(bank, u16::from_str_radix(start, 16).map_err(|_| error())?).ok_or_else(error)?;
+            let end = coverage.offset(bank, u16::from_str_radix(end, 16).map_err(|_| error())?).ok_or_else(error)?;
+            let mut bits = 0;
+            for flag in flags.trim().chars() {
+                bits |= match flag {
+                    'x' => OPCODE,
+                    'o' => OPERAND,
+                    'd' => DATA,
+                    _ => return Err(error()),
+                };
+            }
+            if end < start {
+                return Err(error());
+            }
+            for byte in &mut coverage.rom[start..=end] {
+                *byte |= bits;
+            }
+        }
+        Ok(coverage)
+    }
+
+    /// Where `address` in `bank` is in the ROM, None past its end or outside of 0x0000-0x7FFF.
+    fn offset(&self, bank: usize, address: u16) -> Option<usize> {
+        let offset = match (bank, address) {
+            (0, 0x0000..=0x3FFF) => address as usize,
+            (1.., 0x4000..=0x7FFF) => bank * BANK_SIZE + address as usize - 0x4000,
+            _ => return None,
+        };
+        (offset < self.rom.len()).then_some(offset)
+    }
+
+    /// Whether no byte was used at all.
+    pub fn is_empty(&self) -> bool {
+        self.rom.iter().all(|&byte| byte == 0)
+    }
+
+    /// The `OPCODE`, `OPERAND` and `DATA` bits of the byte at `offset` in the ROM, 0 past its end.
+    pub fn at(&self, offset: usize) -> u8 {
+        self.rom.get(offset).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn mark(&mut self, offset: usize, usage: u8) {
+        if let Some(byte) = self.rom.get_mut(offset) {
+            *byte |= usage;
+        }
+    }
+
+    /// Adds what `other` saw, to sum up several sessions with one ROM.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (byte, &usage) in self.rom.iter_mut().zip(&other.rom) {
+            *byte |= usage;
+        }
+    }
+
+    /// Sums up each bank as how many bytes were used how, then lists what was never touched:
+    /// the labels of `symbols` with nothing used up to the next one, or longer untouched ranges
+    /// that are not padding of one byte value.
+    pub fn report(&self, rom: &[u8], symbols: &Symbols) -> String {
+        let mut out = String::from("bank   opcodes  operands      data  untouched\n");
+        let banks = self.rom.len().div_ceil(BANK_SIZE);
+        for bank in 0..banks {
+            let bytes = &self.rom[bank * BANK_SIZE..((bank + 1) * BANK_SIZE).min(self.rom.len())];
+            let count = |usage: u8| bytes.iter().filter(|&&byte| byte & usage != 0).count();
+            let untouched = bytes.iter().filter(|&&byte| byte == 0).count();
+            writeln!(
+                out, "{bank:>4}  {:>8}  {:>8}  {:>8}  {untouched:>9} ({:.1}%)",
+                count(OPCODE), count(OPERAND), count(DATA), untouched as f64 * 100.0 / bytes.len() as f64,
+            ).unwrap();
+        }
+
+        let mut untouched = String::new();
+        let labels: Vec<(usize, u16, &str)> = symbols.iter().filter(|&(bank, address, _)| self.offset(bank, address).is_some()).collect();
+        if labels.is_empty() {
+            for bank in 0..banks {
+                let base = if bank == 0 { 0x0000 } else { 0x4000 };
+                let start = bank * BANK_SIZE;
+                let bytes = &self.rom[start..(start + BANK_SIZE).min(self.rom.len())];
+                let mut offset = 0;
+                while offset < bytes.len() {
+                    let run = bytes[offset..].iter().take_while(|&&byte| byte == 0).count();
+                    let data = &rom[start + offset..(start + offset + run).min(rom.len())];
+                    if run >= MIN_UNTOUCHED && data.iter().any(|&byte| byte != data[0]) {
+                        writeln!(untouched, "{bank:02X}:{:04X}-{:04X}", base + offset, base + offset + run - 1).unwrap();
+                    }
+                    offset += run.max(1);
+                }
+            }
+        } else {
+            for (idx, &(bank, address, name)) in labels.iter().enumerate() {
+                let start = self.offset(bank, address).unwrap_or_default();
+                let end = match labels.get(idx + 1) {
+                    Some(&(next_bank, next, _)) if next_bank == bank => self.offset(bank, next).unwrap_or(start),
+                    _ => ((bank + 1) * BANK_SIZE).min(self.rom.len()),
+                };
+                if start < end && self.rom[start..end].iter().all(|&byte| byte == 0) {
+                    writeln!(untouched, "{bank:02X}:{address:04X} {name}").unwrap();
+                }
+            }
+        }
+        if !untouched.is_empty() {
+            write!(out, "\nnever used:\n{untouched}").unwrap();
+        }
+        out
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut offset = 0;
+        while offset < self.rom.len() {
+            let usage = self.rom[offset];
+            let bank_end = (offset / BANK_SIZE + 1) * BANK_SIZE;
+            let run = self.rom[offset..bank_end.min(self.rom.len())].iter().take_while(|&&byte| byte == usage).count();
+            if usage != 0 {
+                let bank = offset / BANK_SIZE;
+                let base = if bank == 0 { 0x0000 } else { 0x4000 };
+                let start = base + offset % BANK_SIZE;
+                let flags: String = [(OPCODE, 'x'), (OPERAND, 'o'), (DATA, 'd')].iter()
+                    .filter(|&&(bit, _)| usage & bit != 0)
+                    .map(|&(_, flag)| flag)
+                    .collect();
+                writeln!(f, "{bank:02X}:{start:04X}-{:04X} {flags}", start + run - 1)?;
+            }
+            offset += run;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::GameBoy;
+    use crate::game_boy::tests::test_rom;
+
+    #[test]
+    fn records_code_operands_and_data() {
+        // 0150: ld hl, $0160; ld a, [hl]; jr -2, then a byte of data at 0160
+        let rom = test_rom(&[0x21, 0x60, 0x01, 0x7E, 0x18, 0xFD, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x42]);
+        let mut game_boy = GameBoy::new(rom.clone()).unwrap();
+        game_boy.set_coverage(Some(Coverage::new(rom.len())));
+        for _ in 0..5 {
+            game_boy.step();
+        }
+        let coverage = game_boy.set_coverage(None).unwrap();
+        assert_eq!(coverage.at(0x0100), OPCODE);
+        assert_eq!(coverage.at(0x0151), OPERAND);
+        assert_eq!(coverage.at(0x0160), DATA);
+        assert_eq!(coverage.at(0x0161), 0);
+
+        let text = coverage.to_string();
+        assert_eq!(text, "00:0100-0100 x\n00:0101-0102 o\n00:0150-0150 x\n00:0151-0152 o\n00:0153-0154 x\n00:0155-0155 o\n00:0160-0160 d\n");
+        assert_eq!(Coverage::parse(&text, rom.len()), Ok(coverage.clone()));
+        assert_eq!(Coverage::parse("01:4000-4001 x", rom.len()), Ok(Coverage::parse("01:4000-4000 x\n01:4001-4001 x", rom.len()).unwrap()));
+        assert_eq!(Coverage::parse("02:4000-4001 x", rom.len()), Err(CoverageError { line: 1 }));
+
+        let symbols = Symbols::parse("00:0150 Main\n00:0156 Unused\n00:0160 Table\n00:0161 Padding\n").unwrap();
+        let report = coverage.report(&rom, &symbols);
+        assert!(report.starts_with("bank   opcodes  operands      data  untouched\n   0         4         5         1      16374 (99.9%)\n"));
+        assert!(report.ends_with("never used:\n00:0156 Unused\n00:0161 Padding\n"));
+    }
+}
@@ -1,30 +1,19 @@
+pub(crate) mod opcode;
 pub mod registers;
 
 use std::cell::RefCell;
 use std::rc::Rc;
+use opcode::{Operation, PrefixedOperation};
 use registers::Registers;
+use crate::game_boy::bess::BessCore;
+use crate::game_boy::coverage;
 use crate::game_boy::memory::Memory;
+use crate::game_boy::profiler::Profiler;
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+use crate::game_boy::tracer::Tracer;
 
-trait InstructionMatcher {
-    fn is_match(&self, opcode: u8) -> bool;
-}
-
-impl InstructionMatcher for str {
-    fn is_match(&self, instruction: u8) -> bool {
-        for (idx, bit) in self.chars().enumerate() {
-            if bit == '.' {
-                continue
-            }
-
-            let other_bit = if (instruction & (1 << (7 - idx))) != 0 { '1' } else { '0' };
-            if other_bit != bit {
-                return false
-            }
-        }
-
-        true
-    }
-}
+/// Register code of `[hl]` in the r8 operand encoding.
+const R8_HL: u8 = 6;
 
 pub struct CPU {
     reg: Registers,
@@ -32,14 +21,95 @@ pub struct CPU {
 
     ime: bool,
     set_ime_after_instruction: bool,
+    halted: bool,
+    stopped: bool,
+    /// An illegal opcode hung the CPU, only a reset gets it going again.
+    locked: bool,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
 }
 
 impl CPU {
-    pub fn new(memory: Rc<RefCell<Memory>>) -> CPU {
-        CPU { reg: Registers::new(), memory, ime: false, set_ime_after_instruction: false }
+    pub(crate) fn new(memory: Rc<RefCell<Memory>>, reg: Registers) -> CPU {
+        CPU { reg, memory, ime: false, set_ime_after_instruction: false, halted: false, stopped: false, locked: false, tracer: None, profiler: None }
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.reg
+    }
+
+    pub(crate) fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.reg
+    }
+
+    /// Interrupt master enable flag.
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    /// Whether the CPU is waiting in HALT for an interrupt.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Whether the CPU ran into an illegal opcode and hung like the hardware does.
+    /// PC stays on that opcode and the rest of the Game Boy keeps running.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        self.reg.save_state(state);
+        state.write_bool(self.ime);
+        state.write_bool(self.set_ime_after_instruction);
+        state.write_bool(self.halted);
+        state.write_bool(self.stopped);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.reg.load_state(state)?;
+        self.ime = state.read_bool()?;
+        self.set_ime_after_instruction = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        self.stopped = state.read_bool()?;
+        // a lock-up is not saved, PC is still on the illegal opcode and runs into it again
+        self.locked = false;
+        Ok(())
+    }
+
+    pub(crate) fn export_bess(&self, core: &mut BessCore) {
+        core.pc = self.reg.read_pc();
+        core.af = self.reg.read_af();
+        core.bc = self.reg.read_bc();
+        core.de = self.reg.read_de();
+        core.hl = self.reg.read_hl();
+        core.sp = self.reg.read_sp();
+        // BESS has no place for a pending EI, it takes effect right away instead
+        core.ime = self.ime || self.set_ime_after_instruction;
+        core.execution_state = if self.stopped { 2 } else if self.halted { 1 } else { 0 };
+    }
+
+    pub(crate) fn import_bess(&mut self, core: &BessCore) {
+        self.reg.write_pc(core.pc);
+        self.reg.write_af(core.af);
+        self.reg.write_bc(core.bc);
+        self.reg.write_de(core.de);
+        self.reg.write_hl(core.hl);
+        self.reg.write_sp(core.sp);
+        self.ime = core.ime;
+        self.set_ime_after_instruction = false;
+        self.halted = core.execution_state == 1;
+        self.stopped = core.execution_state == 2;
+        self.locked = false;
     }
 
-    fn nop(&mut self) {}
+    fn read(&self, address: u16) -> u8 {
+        self.memory.borrow().read(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory.borrow_mut().write(address, value);
+    }
 
     fn decode_r8(&self, r8: u8) -> u8 {
         match r8 {
@@ -49,7 +119,7 @@ impl CPU {
             3 => self.reg.read_e(),
             4 => self.reg.read_h(),
             5 => self.reg.read_l(),
-            6 => self.memory.borrow().read(self.reg.read_hl()),
+            6 => self.read(self.reg.read_hl()),
             7 => self.reg.read_a(),
             _ => panic!("Invalid register code: {r8}")
         }
@@ -63,7 +133,7 @@ impl CPU {
             3 => self.reg.write_e(value),
             4 => self.reg.write_h(value),
             5 => self.reg.write_l(value),
-            6 => self.memory.borrow_mut().write(self.reg.read_hl(), value),
+            6 => self.write(self.reg.read_hl(), value),
             7 => self.reg.write_a(value),
             _ => panic!("Invalid register code: {r8}")
         }
@@ -89,6 +159,26 @@ impl CPU {
         };
     }
 
+    /// Resolves the r16mem operand of `ld [r16], a` and `ld a, [r16]`, where codes 2 and 3
+    /// are `[hl+]` and `[hl-]` and adjust HL after the access.
+    fn decode_r16_mem(&mut self, r16: u8) -> u16 {
+        match r16 {
+            0 => self.reg.read_bc(),
+            1 => self.reg.read_de(),
+            2 => {
+                let hl = self.reg.read_hl();
+                self.reg.write_hl(hl.wrapping_add(1));
+                hl
+            }
+            3 => {
+                let hl = self.reg.read_hl();
+                self.reg.write_hl(hl.wrapping_sub(1));
+                hl
+            }
+            _ => panic!("Invalid register code: {r16}")
+        }
+    }
+
     fn resolve_condition(&self, cond: u8) -> bool {
         match cond {
             0 => !self.reg.read_zero_flag(),
@@ -99,38 +189,29 @@ impl CPU {
         }
     }
 
-    /// add value in r8 plus the carry flag to register A
-    fn adc_a_r8(&mut self, r8: u8) {
-        let register_value = self.decode_r8(r8);
-        self.adc_a(register_value);
+    fn set_flags(&mut self, zero: bool, subtraction: bool, half_carry: bool, carry: bool) {
+        self.reg.set_zero_flag(zero);
+        self.reg.set_subtraction_flag(subtraction);
+        self.reg.set_half_carry_flag(half_carry);
+        self.reg.set_carry_flag(carry);
     }
 
     /// add byte plus carry flag to register A
     fn adc_a(&mut self, value: u8) {
         let a = self.reg.read_a();
-        let sum_result = a as u16 + value as u16 + if self.reg.read_carry_flag() { 1 } else { 0 };
+        let carry = self.reg.read_carry_flag() as u8;
+        let sum_result = a as u16 + value as u16 + carry as u16;
 
-        self.reg.set_zero_flag(sum_result == 0);
-        self.reg.set_subtraction_flag(false);
-        self.reg.set_half_carry_flag(sum_result > 0xF);
-        self.reg.set_carry_flag(sum_result > 0xFF);
+        self.set_flags(sum_result as u8 == 0, false, (a & 0xF) + (value & 0xF) + carry > 0xF, sum_result > 0xFF);
 
         self.reg.write_a(sum_result as u8);
     }
 
-    fn add_a_r8(&mut self, r8: u8) {
-        let register_value = self.decode_r8(r8);
-        self.add_a(register_value);
-    }
-
     fn add_a(&mut self, value: u8) {
         let a = self.reg.read_a();
         let sum_result = a as u16 + value as u16;
 
-        self.reg.set_zero_flag(sum_result == 0);
-        self.reg.set_subtraction_flag(false);
-        self.reg.set_half_carry_flag(sum_result > 0xF);
-        self.reg.set_carry_flag(sum_result > 0xFF);
+        self.set_flags(sum_result as u8 == 0, false, Self::detect_bit_3_overflow(a, value), sum_result > 0xFF);
 
         self.reg.write_a(sum_result as u8);
     }
@@ -146,41 +227,36 @@ impl CPU {
         let sum_result = hl as u32 + value as u32;
 
         self.reg.set_subtraction_flag(false);
-        self.reg.set_half_carry_flag(sum_result > 0xFFF);
+        self.reg.set_half_carry_flag((hl & 0xFFF) + (value & 0xFFF) > 0xFFF);
         self.reg.set_carry_flag(sum_result > 0xFFFF);
 
         self.reg.write_hl(sum_result as u16);
     }
 
-    fn add_sp(&mut self, e8: i8) {
+    /// SP plus a signed offset, with the flags computed on the low byte as an unsigned addition.
+    fn sp_plus_e8(&mut self, e8: i8) -> u16 {
         let sp = self.reg.read_sp();
-        let (sum_result, has_overflow) = sp.overflowing_add(e8 as u16);
+        let offset = e8 as u8;
 
-        self.reg.set_zero_flag(false);
-        self.reg.set_subtraction_flag(false);
-        self.reg.set_half_carry_flag(sum_result > 0xF || has_overflow);
-        self.reg.set_carry_flag(sum_result > 0xFF || has_overflow);
+        self.set_flags(false, false, Self::detect_bit_3_overflow(sp as u8, offset), (sp & 0xFF) + offset as u16 > 0xFF);
 
-        self.reg.write_sp(sum_result);
+        sp.wrapping_add(e8 as u16)
     }
 
-    fn and_a_r8(&mut self, r8: u8) {
-        let register_value = self.decode_r8(r8);
-        self.and_a(register_value);
+    fn add_sp(&mut self, e8: i8) {
+        let sum_result = self.sp_plus_e8(e8);
+        self.reg.write_sp(sum_result);
     }
 
     fn and_a(&mut self, value: u8) {
         let and_result = self.reg.read_a() & value;
 
-        self.reg.set_zero_flag(and_result == 0);
-        self.reg.set_subtraction_flag(false);
-        self.reg.set_half_carry_flag(true);
-        self.reg.set_carry_flag(false);
+        self.set_flags(and_result == 0, false, true, false);
 
         self.reg.write_a(and_result);
     }
 
-    fn bit_u3_r8(&mut self, u3: i8, r8: u8) {
+    fn bit_u3_r8(&mut self, u3: u8, r8: u8) {
         let register_value = self.decode_r8(r8);
 
         self.reg.set_zero_flag(register_value & (1 << u3) == 0);
@@ -188,14 +264,9 @@ impl CPU {
         self.reg.set_half_carry_flag(true);
     }
 
-    fn call_cc_n16(&mut self, cc: u8, n16: u16) {
-        if self.resolve_condition(cc) {
-            self.call_n16(n16);
-        }
-    }
-
     fn call_n16(&mut self, n16: u16) {
-        todo!()
+        self.push(self.reg.read_pc());
+        self.reg.write_pc(n16);
     }
 
     fn ccf(&mut self) {
@@ -204,16 +275,9 @@ impl CPU {
         self.reg.set_carry_flag(!self.reg.read_carry_flag());
     }
 
-    fn cp_a_r8(&mut self, r8: u8) {
-        let register_value = self.decode_r8(r8);
-        self.cp_a(register_value);
-    }
-
     fn cp_a(&mut self, value: u8) {
-        self.reg.set_zero_flag(self.reg.read_a() == value);
-        self.reg.set_subtraction_flag(true);
-        self.reg.set_half_carry_flag(Self::detect_bit_3_borrow(self.reg.read_a(), value));
-        self.reg.set_carry_flag(value > self.reg.read_a());
+        let a = self.reg.read_a();
+        self.set_flags(a == value, true, Self::detect_bit_3_borrow(a, value), value > a);
     }
 
     fn cpl(&mut self) {
@@ -223,30 +287,36 @@ impl CPU {
         self.reg.set_half_carry_flag(true);
     }
 
+    /// Decimal adjusts A after a BCD addition or subtraction.
     fn daa(&mut self) {
+        let mut a = self.reg.read_a();
+        let mut carry = self.reg.read_carry_flag();
+
         if self.reg.read_subtraction_flag() {
             let mut adj = 0;
             if self.reg.read_half_carry_flag() {
                 adj += 0x6;
             }
-            if self.reg.read_carry_flag() {
+            if carry {
                 adj += 0x60;
             }
-            // TODO subtract adj from A
+            a = a.wrapping_sub(adj);
         } else {
             let mut adj = 0;
-            if self.reg.read_half_carry_flag() || (self.reg.read_a() & 0xF) > 0x9 {
+            if self.reg.read_half_carry_flag() || (a & 0xF) > 0x9 {
                 adj += 0x6;
             }
-            if self.reg.read_carry_flag() || self.reg.read_a() > 0x99 {
+            if carry || a > 0x99 {
                 adj += 0x60;
+                carry = true;
             }
-            // TODO add adj to A
+            a = a.wrapping_add(adj);
         }
 
-        self.reg.set_zero_flag(self.reg.read_a() == 0);
+        self.reg.set_zero_flag(a == 0);
         self.reg.set_half_carry_flag(false);
-        // TODO set carry flag depending on the operation
+        self.reg.set_carry_flag(carry);
+        self.reg.write_a(a);
     }
 
     fn detect_bit_3_borrow(a: u8, b: u8) -> bool {
@@ -274,6 +344,7 @@ impl CPU {
 
     fn di(&mut self) {
         self.ime = false;
+        self.set_ime_after_instruction = false;
     }
 
     fn ei(&mut self) {
@@ -281,11 +352,37 @@ impl CPU {
     }
 
     fn halt(&mut self) {
-        todo!()
+        // with IME clear and an interrupt already pending, HALT returns immediately
+        if !self.has_pending_interrupt() {
+            self.halted = true;
+        }
+    }
+
+    /// Switches the CPU speed if KEY1 asked for it, otherwise enters low power mode until a button is pressed.
+    /// Returns the machine cycles the CPU is stopped for the switch.
+    fn stop(&mut self) -> i32 {
+        // stop is followed by a padding byte
+        self.fetch_n8();
+        self.write(0xFF04, 0);
+
+        if self.memory.borrow_mut().take_speed_switch() {
+            return 2050;
+        }
+        self.stopped = true;
+        1
+    }
+
+    /// Hangs the CPU on the illegal opcode it just fetched.
+    fn lock(&mut self) {
+        self.locked = true;
+        self.reg.write_pc(self.reg.read_pc().wrapping_sub(1));
+        if let Some(tracer) = &mut self.tracer {
+            tracer.write_ring_buffer();
+        }
     }
 
     fn detect_bit_3_overflow(a: u8, b: u8) -> bool {
-        (a & 0x0F) + (b & 0x0F) & 0x10 != 0
+        ((a & 0x0F) + (b & 0x0F)) & 0x10 != 0
     }
 
     fn inc_r8(&mut self, r8: u8) {
@@ -311,16 +408,14 @@ impl CPU {
         self.reg.write_pc(n16);
     }
 
-    fn jp_cc_n16(&mut self, cc: u8, n16: u16) {
-        if self.resolve_condition(cc) {
-            self.reg.write_pc(n16);
-        }
-    }
-
     fn jp_hl(&mut self) {
         self.reg.write_pc(self.reg.read_hl())
     }
 
+    fn jr(&mut self, e8: i8) {
+        self.reg.write_pc(self.reg.read_pc().wrapping_add(e8 as u16));
+    }
+
     fn ld_r8_r8(&mut self, dest: u8, source: u8) {
         let right_value = self.decode_r8(source);
 
@@ -336,22 +431,149 @@ impl CPU {
     }
 
     fn ld_r16_a(&mut self, r16: u8) {
-        let a = self.reg.read_a();
-        self.encode_r16(r16, a as u16);
+        let address = self.decode_r16_mem(r16);
+        self.write(address, self.reg.read_a());
+    }
+
+    fn ld_a_r16(&mut self, r16: u8) {
+        let address = self.decode_r16_mem(r16);
+        let byte_at_address = self.read(address);
+        self.reg.write_a(byte_at_address);
     }
 
     fn ld_n16_a(&mut self, n16: u16) {
         let a = self.reg.read_a();
-        self.memory.borrow_mut().write(n16, a);
+        self.write(n16, a);
     }
 
     fn ld_a_n16(&mut self, n16: u16) {
-        let byte_at_address = self.memory.borrow().read(n16);
+        let byte_at_address = self.read(n16);
         self.reg.write_a(byte_at_address);
     }
 
+    fn ld_n16_sp(&mut self, n16: u16) {
+        let sp = self.reg.read_sp();
+        self.write(n16, sp as u8);
+        self.write(n16.wrapping_add(1), (sp >> 8) as u8);
+    }
+
+    fn or_a(&mut self, value: u8) {
+        let or_result = self.reg.read_a() | value;
+
+        self.set_flags(or_result == 0, false, false, false);
+
+        self.reg.write_a(or_result);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let sp = self.reg.read_sp();
+        let low = self.read(sp);
+        let high = self.read(sp.wrapping_add(1));
+        self.reg.write_sp(sp.wrapping_add(2));
+
+        (high as u16) << 8 | low as u16
+    }
+
+    fn push(&mut self, value: u16) {
+        let sp = self.reg.read_sp().wrapping_sub(2);
+        self.reg.write_sp(sp);
+        self.write(sp.wrapping_add(1), (value >> 8) as u8);
+        self.write(sp, value as u8);
+    }
+
+    /// pops the r16stk operand, where code 3 is AF instead of SP
+    fn pop_r16(&mut self, r16: u8) {
+        let value = self.pop();
+        if r16 == 3 {
+            self.reg.write_af(value);
+        } else {
+            self.encode_r16(r16, value);
+        }
+    }
+
+    fn push_r16(&mut self, r16: u8) {
+        let value = if r16 == 3 { self.reg.read_af() } else { self.decode_r16(r16) };
+        self.push(value);
+    }
+
+    fn ret(&mut self) {
+        let address = self.pop();
+        self.reg.write_pc(address);
+    }
+
+    /// Rotates and shifts shared by the CB-prefixed instructions and the short accumulator forms.
+    fn rotate_shift(&mut self, operation: u8, value: u8) -> u8 {
+        let carry = self.reg.read_carry_flag() as u8;
+        let (result, carry_out) = match operation {
+            0 => (value.rotate_left(1), value & 0x80 != 0),
+            1 => (value.rotate_right(1), value & 0x01 != 0),
+            2 => (value << 1 | carry, value & 0x80 != 0),
+            3 => (value >> 1 | carry << 7, value & 0x01 != 0),
+            4 => (value << 1, value & 0x80 != 0),
+            5 => ((value as i8 >> 1) as u8, value & 0x01 != 0),
+            6 => (value.rotate_left(4), false),
+            7 => (value >> 1, value & 0x01 != 0),
+            _ => panic!("Invalid rotate operation {operation}"),
+        };
+
+        self.set_flags(result == 0, false, false, carry_out);
+        result
+    }
+
+    fn sbc_a(&mut self, value: u8) {
+        let a = self.reg.read_a();
+        let carry = self.reg.read_carry_flag() as u8;
+        let result = a.wrapping_sub(value).wrapping_sub(carry);
+
+        self.set_flags(result == 0, true, (a & 0xF) < (value & 0xF) + carry, (a as u16) < value as u16 + carry as u16);
+
+        self.reg.write_a(result);
+    }
+
+    fn scf(&mut self) {
+        self.reg.set_subtraction_flag(false);
+        self.reg.set_half_carry_flag(false);
+        self.reg.set_carry_flag(true);
+    }
+
+    fn sub_a(&mut self, value: u8) {
+        self.cp_a(value);
+        self.reg.write_a(self.reg.read_a().wrapping_sub(value));
+    }
+
+    fn xor_a(&mut self, value: u8) {
+        let xor_result = self.reg.read_a() ^ value;
+
+        self.set_flags(xor_result == 0, false, false, false);
+
+        self.reg.write_a(xor_result);
+    }
+
+    /// Applies the 8-bit arithmetic or logic operation encoded in bits 3-5 to A.
+    fn alu_a(&mut self, operation: u8, value: u8) {
+        match operation {
+            0 => self.add_a(value),
+            1 => self.adc_a(value),
+            2 => self.sub_a(value),
+            3 => self.sbc_a(value),
+            4 => self.and_a(value),
+            5 => self.xor_a(value),
+            6 => self.or_a(value),
+            7 => self.cp_a(value),
+            _ => panic!("Invalid ALU operation {operation}"),
+        }
+    }
+
+    fn fetch_opcode(&mut self) -> u8 {
+        let opcode = self.memory.borrow().fetch(self.reg.read_pc(), coverage::OPCODE);
+
+        self.reg.inc_pc();
+
+        opcode
+    }
+
     fn fetch_instruction(&mut self) -> u8 {
-        let instruction = self.memory.borrow().read(self.reg.read_pc());
+        let instruction = self.memory.borrow().fetch(self.reg.read_pc(), coverage::OPERAND);
 
         self.reg.inc_pc();
 
@@ -369,95 +591,489 @@ impl CPU {
         self.fetch_instruction()
     }
 
+    fn fetch_e8(&mut self) -> i8 {
+        self.fetch_instruction() as i8
+    }
+
+    /// Replaces the tracer that logs each instruction before it runs, returning the old one.
+    pub(crate) fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    /// Replaces the profiler that counts the cycles of each instruction, returning the old one.
+    pub(crate) fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+        std::mem::replace(&mut self.profiler, profiler)
+    }
+
+    /// Interrupts that are both requested in IF and enabled in IE.
+    fn pending_interrupts(&self) -> u8 {
+        let memory = self.memory.borrow();
+        memory.peek(0xFF0F) & memory.peek(0xFFFF) & 0x1F
+    }
+
+    fn has_pending_interrupt(&self) -> bool {
+        self.pending_interrupts() != 0
+    }
+
+    /// Jumps to the handler of the highest priority pending interrupt, if IME allows it.
+    /// Returns the machine cycles taken, 0 when no interrupt was serviced.
+    fn service_interrupt(&mut self) -> i32 {
+        let pending = self.pending_interrupts();
+        if pending == 0 {
+            return 0;
+        }
+        // any pending interrupt ends HALT, even when IME keeps it from being serviced
+        self.halted = false;
+        if !self.ime {
+            return 0;
+        }
+
+        let bit = pending.trailing_zeros() as u16;
+        let interrupt_flags = self.read(0xFF0F);
+        self.write(0xFF0F, interrupt_flags & !(1 << bit));
+        self.ime = false;
+        self.call_n16(0x40 + 8 * bit);
+
+        5
+    }
+
+    /// Executes the next instruction, or services an interrupt, and returns the machine cycles it took.
     pub(crate) fn execute_next_instruction(&mut self) -> i32 {
-        let instruction = self.fetch_instruction();
-        if "01110110".is_match(instruction) {
-            // halt
-            print!("halt");
-            0
-        } else if "01......".is_match(instruction) {
-            let r8_dest = (instruction & 0b00111000) >> 3;
-            let r8_src = instruction & 0b00000111;
-            self.ld_r8_r8(r8_dest, r8_src);
-            println!("ld r8 r8");
-
-            2
-        } else if "00..0101".is_match(instruction) {
-            let operand_r8 = instruction & 0b00110000;
-            self.dec_r8(operand_r8);
-            println!("dec r8");
-
-            1
-        } else if "101100..".is_match(instruction) {
-            let operand_r8 = instruction & 0b00000011;
-
-            self.cp_a_r8(operand_r8);
-            println!("cp a r8");
-            1
-        } else if "00..0001".is_match(instruction) {
-            let n16 = self.fetch_n16();
-            let r16 = (instruction & 0b00110000) >> 4;
-            self.ld_r16_n16(r16, n16);
-            println!("ld r16 n16");
-
-            3
-        } else if "00..1010".is_match(instruction) {
-            let n16 = self.fetch_n16();
-            self.ld_a_n16(n16);
-            println!("ld a n16");
-
-            4
-        } else if "00..1011".is_match(instruction) {
-            let r16 = (instruction & 0b00110000) >> 4;
-            self.dec_r16(r16);
-            println!("dec r16");
-
-            2
-        } else if "11000011".is_match(instruction) {
-            let n16 = self.fetch_n16();
-            self.jp_n16(n16);
-            println!("jumping to {:#x}", n16);
-
-            4
-        } else if "00...110".is_match(instruction) {
-            let n8 = self.fetch_n8();
-            let r8 = (instruction & 0b00111000) >> 3;
-            self.ld_r8_n8(r8, n8);
-            println!("Copy the value n8 ({}) into register r8", n8);
-
-            2
-        } else if "11101010".is_match(instruction) {
-            let n16 = self.fetch_n16();
-            self.ld_n16_a(n16);
-            println!("Copy the value in register A {} into the byte at address n16.", self.reg.read_a());
-
-            4
-        } else if "11111010".is_match(instruction) {
-            let n16 = self.fetch_n16();
-            self.ld_a_n16(n16);
-
-            println!("Copy the byte at address n16 into register A.");
-
-            4
-        } else if "11111110".is_match(instruction) {
-            let n8 = self.fetch_n8();
-            self.cp_a(n8);
-            println!("ComPare the value in A with the value n8.");
-
-            2
-        } else if "110..010".is_match(instruction) {
-            if self.resolve_condition((instruction & 0b00011000) >> 3) {
+        if self.locked {
+            // not even interrupts wake it up
+            if let Some(profiler) = &mut self.profiler {
+                profiler.halted(1);
+            }
+            return 1;
+        }
+        let interrupt_cycles = self.service_interrupt();
+        if interrupt_cycles != 0 {
+            if let Some(profiler) = &mut self.profiler {
+                profiler.interrupt(&self.reg, &self.memory.borrow(), interrupt_cycles);
+            }
+            return interrupt_cycles;
+        }
+        if self.halted {
+            if let Some(profiler) = &mut self.profiler {
+                profiler.halted(1);
+            }
+            return 1;
+        }
+        if self.stopped {
+            // a pressed button in a selected group pulls its P1 line low
+            if self.read(0xFF00) & 0x0F == 0x0F {
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.halted(1);
+                }
+                return 1;
+            }
+            self.stopped = false;
+        }
+
+        // EI takes effect after the instruction that follows it
+        let enable_ime = std::mem::take(&mut self.set_ime_after_instruction);
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&self.reg, &self.memory.borrow());
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.before(&self.reg, &self.memory.borrow());
+        }
+        let instruction = self.fetch_opcode();
+        let cycles = self.execute(instruction);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.after(&self.reg, &self.memory.borrow(), cycles);
+        }
+
+        if enable_ime {
+            self.ime = true;
+        }
+        cycles
+    }
+
+    fn execute(&mut self, instruction: u8) -> i32 {
+        match opcode::decode(instruction) {
+            Operation::Nop => 1,
+            Operation::LdR16N16(r16) => {
+                let n16 = self.fetch_n16();
+                self.ld_r16_n16(r16, n16);
+
+                3
+            }
+            Operation::LdR16MemA(r16) => {
+                self.ld_r16_a(r16);
+
+                2
+            }
+            Operation::LdAR16Mem(r16) => {
+                self.ld_a_r16(r16);
+
+                2
+            }
+            Operation::LdN16Sp => {
+                let n16 = self.fetch_n16();
+                self.ld_n16_sp(n16);
+
+                5
+            }
+            Operation::IncR16(r16) => {
+                self.inc_r16(r16);
+
+                2
+            }
+            Operation::DecR16(r16) => {
+                self.dec_r16(r16);
+
+                2
+            }
+            Operation::AddHlR16(r16) => {
+                self.add_hl_r16(r16);
+
+                2
+            }
+            Operation::IncR8(r8) => {
+                self.inc_r8(r8);
+
+                if r8 == R8_HL { 3 } else { 1 }
+            }
+            Operation::DecR8(r8) => {
+                self.dec_r8(r8);
+
+                if r8 == R8_HL { 3 } else { 1 }
+            }
+            Operation::LdR8N8(r8) => {
+                let n8 = self.fetch_n8();
+                self.ld_r8_n8(r8, n8);
+
+                if r8 == R8_HL { 3 } else { 2 }
+            }
+            Operation::RotateA(operation) => {
+                // rlca, rrca, rla and rra always clear Z
+                let result = self.rotate_shift(operation, self.reg.read_a());
+                self.reg.write_a(result);
+                self.reg.set_zero_flag(false);
+
+                1
+            }
+            Operation::Daa => {
+                self.daa();
+
+                1
+            }
+            Operation::Cpl => {
+                self.cpl();
+
+                1
+            }
+            Operation::Scf => {
+                self.scf();
+
+                1
+            }
+            Operation::Ccf => {
+                self.ccf();
+
+                1
+            }
+            Operation::Jr => {
+                let e8 = self.fetch_e8();
+                self.jr(e8);
+
+                3
+            }
+            Operation::JrCondition(cond) => {
+                let e8 = self.fetch_e8();
+                if self.resolve_condition(cond) {
+                    self.jr(e8);
+                    return 3
+                }
+
+                2
+            }
+            Operation::Stop => self.stop(),
+            Operation::Halt => {
+                self.halt();
+
+                1
+            }
+            Operation::LdR8R8(r8_dest, r8_src) => {
+                self.ld_r8_r8(r8_dest, r8_src);
+
+                if r8_dest == R8_HL || r8_src == R8_HL { 2 } else { 1 }
+            }
+            Operation::AluR8(operation, r8) => {
+                let value = self.decode_r8(r8);
+                self.alu_a(operation, value);
+
+                if r8 == R8_HL { 2 } else { 1 }
+            }
+            Operation::AluN8(operation) => {
+                let n8 = self.fetch_n8();
+                self.alu_a(operation, n8);
+
+                2
+            }
+            Operation::RetCondition(cond) => {
+                if self.resolve_condition(cond) {
+                    self.ret();
+                    return 5
+                }
+
+                2
+            }
+            Operation::Ret => {
+                self.ret();
+
+                4
+            }
+            Operation::Reti => {
+                // reti enables interrupts without the delay of ei
+                self.ret();
+                self.ime = true;
+
+                4
+            }
+            Operation::JpCondition(cond) => {
+                let n16 = self.fetch_n16();
+                if self.resolve_condition(cond) {
+                    self.jp_n16(n16);
+                    return 4
+                }
+
+                3
+            }
+            Operation::Jp => {
                 let n16 = self.fetch_n16();
                 self.jp_n16(n16);
-                println!("jump to n16 {:#x}", n16);
 
-                return 4
+                4
             }
+            Operation::JpHl => {
+                self.jp_hl();
 
-            1
-        } else {
-            panic!("invalid instruction {:08b}", instruction);
+                1
+            }
+            Operation::CallCondition(cond) => {
+                let n16 = self.fetch_n16();
+                if self.resolve_condition(cond) {
+                    self.call_n16(n16);
+                    return 6
+                }
+
+                3
+            }
+            Operation::Call => {
+                let n16 = self.fetch_n16();
+                self.call_n16(n16);
+
+                6
+            }
+            Operation::Rst(target) => {
+                self.call_n16(target as u16);
+
+                4
+            }
+            Operation::Pop(r16) => {
+                self.pop_r16(r16);
+
+                3
+            }
+            Operation::Push(r16) => {
+                self.push_r16(r16);
+
+                4
+            }
+            Operation::Prefix => {
+                let prefixed = self.fetch_instruction();
+                self.execute_prefixed(prefixed)
+            }
+            Operation::LdhCA => {
+                self.ld_n16_a(0xFF00 + self.reg.read_c() as u16);
+
+                2
+            }
+            Operation::LdhN8A => {
+                let n8 = self.fetch_n8();
+                self.ld_n16_a(0xFF00 + n8 as u16);
+
+                3
+            }
+            Operation::LdN16A => {
+                let n16 = self.fetch_n16();
+                self.ld_n16_a(n16);
+
+                4
+            }
+            Operation::LdhAC => {
+                self.ld_a_n16(0xFF00 + self.reg.read_c() as u16);
+
+                2
+            }
+            Operation::LdhAN8 => {
+                let n8 = self.fetch_n8();
+                self.ld_a_n16(0xFF00 + n8 as u16);
+
+                3
+            }
+            Operation::LdAN16 => {
+                let n16 = self.fetch_n16();
+                self.ld_a_n16(n16);
+
+                4
+            }
+            Operation::AddSpE8 => {
+                let e8 = self.fetch_e8();
+                self.add_sp(e8);
+
+                4
+            }
+            Operation::LdHlSpE8 => {
+                let e8 = self.fetch_e8();
+                let address = self.sp_plus_e8(e8);
+                self.reg.write_hl(address);
+
+                3
+            }
+            Operation::LdSpHl => {
+                self.reg.write_sp(self.reg.read_hl());
+
+                2
+            }
+            Operation::Di => {
+                self.di();
+
+                1
+            }
+            Operation::Ei => {
+                self.ei();
+
+                1
+            }
+            Operation::Invalid => {
+                self.lock();
+
+                1
+            }
         }
+    }
+
+    fn execute_prefixed(&mut self, instruction: u8) -> i32 {
+        let (r8, result) = match opcode::decode_prefixed(instruction) {
+            PrefixedOperation::Bit(u3, r8) => {
+                self.bit_u3_r8(u3, r8);
 
+                return if r8 == R8_HL { 3 } else { 2 }
+            }
+            PrefixedOperation::RotateShift(operation, r8) => (r8, self.rotate_shift(operation, self.decode_r8(r8))),
+            PrefixedOperation::Res(u3, r8) => (r8, self.decode_r8(r8) & !(1 << u3)),
+            PrefixedOperation::Set(u3, r8) => (r8, self.decode_r8(r8) | (1 << u3)),
+        };
+        self.encode_r8(r8, result);
+
+        if r8 == R8_HL { 4 } else { 2 }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A CPU at 0xC000 in work RAM with `code` loaded there.
+    fn cpu_with_code(code: &[u8]) -> CPU {
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        for (idx, byte) in code.iter().enumerate() {
+            memory.borrow_mut().write(0xC000 + idx as u16, *byte);
+        }
+        let mut reg = Registers::new();
+        reg.write_pc(0xC000);
+        reg.write_sp(0xFFFE);
+        CPU::new(memory, reg)
+    }
+
+    #[test]
+    fn call_and_ret_use_the_stack() {
+        // call 0xC010; ... 0xC010: ret
+        let mut code = vec![0; 0x11];
+        code[0..3].copy_from_slice(&[0xCD, 0x10, 0xC0]);
+        code[0x10] = 0xC9;
+        let mut cpu = cpu_with_code(&code);
+
+        assert_eq!(cpu.execute_next_instruction(), 6);
+        assert_eq!(cpu.registers().read_pc(), 0xC010);
+        assert_eq!(cpu.registers().read_sp(), 0xFFFC);
+
+        assert_eq!(cpu.execute_next_instruction(), 4);
+        assert_eq!(cpu.registers().read_pc(), 0xC003);
+        assert_eq!(cpu.registers().read_sp(), 0xFFFE);
+    }
+
+    #[test]
+    fn daa_adjusts_bcd_addition() {
+        // ld a, 0x19; add a, 0x28; daa
+        let mut cpu = cpu_with_code(&[0x3E, 0x19, 0xC6, 0x28, 0x27]);
+        for _ in 0..3 {
+            cpu.execute_next_instruction();
+        }
+
+        assert_eq!(cpu.registers().read_a(), 0x47);
+        assert!(!cpu.registers().read_carry_flag());
+    }
+
+    #[test]
+    fn pop_af_clears_low_flag_bits() {
+        // ld bc, 0x12FF; push bc; pop af
+        let mut cpu = cpu_with_code(&[0x01, 0xFF, 0x12, 0xC5, 0xF1]);
+        for _ in 0..3 {
+            cpu.execute_next_instruction();
+        }
+
+        assert_eq!(cpu.registers().read_af(), 0x12F0);
+    }
+
+    #[test]
+    fn prefixed_instructions_operate_on_hl() {
+        // ld hl, 0xC100; ld [hl], 0x0F; swap [hl]; set 0, [hl]; bit 7, [hl]
+        let mut cpu = cpu_with_code(&[0x21, 0x00, 0xC1, 0x36, 0x0F, 0xCB, 0x36, 0xCB, 0xC6, 0xCB, 0x7E]);
+        cpu.execute_next_instruction();
+        cpu.execute_next_instruction();
+
+        assert_eq!(cpu.execute_next_instruction(), 4);
+        cpu.execute_next_instruction();
+        assert_eq!(cpu.execute_next_instruction(), 3);
+
+        assert_eq!(cpu.read(0xC100), 0xF1);
+        assert!(!cpu.registers().read_zero_flag());
+    }
+
+    #[test]
+    fn halt_wakes_up_for_enabled_interrupt() {
+        // ei; halt; nop
+        let mut cpu = cpu_with_code(&[0xFB, 0x76, 0x00]);
+        cpu.write(0xFFFF, 0x04);
+        cpu.execute_next_instruction();
+        cpu.execute_next_instruction();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.execute_next_instruction(), 1);
+
+        cpu.memory.borrow_mut().request_interrupt(0x04);
+        assert_eq!(cpu.execute_next_instruction(), 5);
+        assert!(!cpu.is_halted());
+        assert!(!cpu.ime());
+        assert_eq!(cpu.registers().read_pc(), 0x50);
+        assert_eq!(cpu.read(0xFF0F) & 0x04, 0);
+    }
+    #[test]
+    fn illegal_opcode_locks_up_for_good() {
+        // ei; db $DD
+        let mut cpu = cpu_with_code(&[0xFB, 0xDD]);
+        cpu.write(0xFFFF, 0x04);
+        cpu.execute_next_instruction();
+        assert_eq!(cpu.execute_next_instruction(), 1);
+        assert!(cpu.is_locked());
+        assert_eq!(cpu.registers().read_pc(), 0xC001);
+
+        cpu.memory.borrow_mut().request_interrupt(0x04);
+        assert_eq!(cpu.execute_next_instruction(), 1);
+        assert_eq!(cpu.registers().read_pc(), 0xC001);
+    }
+}
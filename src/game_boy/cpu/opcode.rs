@@ -0,0 +1,230 @@
+use std::sync::OnceLock;
+
+trait InstructionMatcher {
+    fn is_match(&self, opcode: u8) -> bool;
+}
+
+impl InstructionMatcher for str {
+    fn is_match(&self, instruction: u8) -> bool {
+        for (idx, bit) in self.chars().enumerate() {
+            if bit == '.' {
+                continue
+            }
+
+            let other_bit = if (instruction & (1 << (7 - idx))) != 0 { '1' } else { '0' };
+            if other_bit != bit {
+                return false
+            }
+        }
+
+        true
+    }
+}
+
+/// What an opcode does, with the register, condition and operation fields encoded in it.
+/// Shared by the CPU and the disassembler so both read the opcode the same way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Nop,
+    /// `ld r16, n16`
+    LdR16N16(u8),
+    /// `ld [r16], a`, with r16 3 and 2 being `[hl-]` and `[hl+]`
+    LdR16MemA(u8),
+    /// `ld a, [r16]`
+    LdAR16Mem(u8),
+    LdN16Sp,
+    IncR16(u8),
+    DecR16(u8),
+    AddHlR16(u8),
+    IncR8(u8),
+    DecR8(u8),
+    LdR8N8(u8),
+    /// rlca, rrca, rla and rra, numbered like the prefixed rotates
+    RotateA(u8),
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    Jr,
+    JrCondition(u8),
+    Stop,
+    Halt,
+    /// `ld dest, src`
+    LdR8R8(u8, u8),
+    /// add, adc, sub, sbc, and, xor, or or cp with an r8
+    AluR8(u8, u8),
+    AluN8(u8),
+    RetCondition(u8),
+    Ret,
+    Reti,
+    JpCondition(u8),
+    Jp,
+    JpHl,
+    CallCondition(u8),
+    Call,
+    /// The target address, 0x00 to 0x38.
+    Rst(u8),
+    Pop(u8),
+    Push(u8),
+    Prefix,
+    LdhCA,
+    LdhN8A,
+    LdN16A,
+    LdhAC,
+    LdhAN8,
+    LdAN16,
+    AddSpE8,
+    LdHlSpE8,
+    LdSpHl,
+    Di,
+    Ei,
+    /// One of the 11 opcodes that lock up real hardware.
+    Invalid,
+}
+
+impl Operation {
+    /// Bytes including the opcode and, for `Prefix`, the second opcode.
+    pub(crate) fn length(self) -> u8 {
+        match self {
+            Operation::LdR16N16(_) | Operation::LdN16Sp | Operation::JpCondition(_) | Operation::Jp
+            | Operation::CallCondition(_) | Operation::Call | Operation::LdN16A | Operation::LdAN16 => 3,
+            Operation::LdR8N8(_) | Operation::Jr | Operation::JrCondition(_) | Operation::Stop | Operation::AluN8(_)
+            | Operation::Prefix | Operation::LdhN8A | Operation::LdhAN8 | Operation::AddSpE8 | Operation::LdHlSpE8 => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// What a 0xCB prefixed opcode does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PrefixedOperation {
+    /// rlc, rrc, rl, rr, sla, sra, swap or srl of an r8
+    RotateShift(u8, u8),
+    /// `bit u3, r8`
+    Bit(u8, u8),
+    Res(u8, u8),
+    Set(u8, u8),
+}
+
+pub(crate) fn decode(opcode: u8) -> Operation {
+    static OPERATIONS: OnceLock<[Operation; 256]> = OnceLock::new();
+    OPERATIONS.get_or_init(|| std::array::from_fn(|opcode| decode_opcode(opcode as u8)))[opcode as usize]
+}
+
+pub(crate) fn decode_prefixed(instruction: u8) -> PrefixedOperation {
+    let r8 = instruction & 0b00000111;
+    let u3 = (instruction & 0b00111000) >> 3;
+
+    if "00......".is_match(instruction) {
+        PrefixedOperation::RotateShift(u3, r8)
+    } else if "01......".is_match(instruction) {
+        PrefixedOperation::Bit(u3, r8)
+    } else if "10......".is_match(instruction) {
+        PrefixedOperation::Res(u3, r8)
+    } else {
+        PrefixedOperation::Set(u3, r8)
+    }
+}
+
+fn decode_opcode(instruction: u8) -> Operation {
+    let r8_dest = (instruction & 0b00111000) >> 3;
+    let r8_src = instruction & 0b00000111;
+    let r16 = (instruction & 0b00110000) >> 4;
+    let cond = (instruction & 0b00011000) >> 3;
+
+    if "00000000".is_match(instruction) {
+        Operation::Nop
+    } else if "00..0001".is_match(instruction) {
+        Operation::LdR16N16(r16)
+    } else if "00..0010".is_match(instruction) {
+        Operation::LdR16MemA(r16)
+    } else if "00..1010".is_match(instruction) {
+        Operation::LdAR16Mem(r16)
+    } else if "00001000".is_match(instruction) {
+        Operation::LdN16Sp
+    } else if "00..0011".is_match(instruction) {
+        Operation::IncR16(r16)
+    } else if "00..1011".is_match(instruction) {
+        Operation::DecR16(r16)
+    } else if "00..1001".is_match(instruction) {
+        Operation::AddHlR16(r16)
+    } else if "00...100".is_match(instruction) {
+        Operation::IncR8(r8_dest)
+    } else if "00...101".is_match(instruction) {
+        Operation::DecR8(r8_dest)
+    } else if "00...110".is_match(instruction) {
+        Operation::LdR8N8(r8_dest)
+    } else if "000..111".is_match(instruction) {
+        Operation::RotateA(r8_dest)
+    } else if "00100111".is_match(instruction) {
+        Operation::Daa
+    } else if "00101111".is_match(instruction) {
+        Operation::Cpl
+    } else if "00110111".is_match(instruction) {
+        Operation::Scf
+    } else if "00111111".is_match(instruction) {
+        Operation::Ccf
+    } else if "00011000".is_match(instruction) {
+        Operation::Jr
+    } else if "001..000".is_match(instruction) {
+        Operation::JrCondition(cond)
+    } else if "00010000".is_match(instruction) {
+        Operation::Stop
+    } else if "01110110".is_match(instruction) {
+        Operation::Halt
+    } else if "01......".is_match(instruction) {
+        Operation::LdR8R8(r8_dest, r8_src)
+    } else if "10......".is_match(instruction) {
+        Operation::AluR8(r8_dest, r8_src)
+    } else if "11...110".is_match(instruction) {
+        Operation::AluN8(r8_dest)
+    } else if "110..000".is_match(instruction) {
+        Operation::RetCondition(cond)
+    } else if "11001001".is_match(instruction) {
+        Operation::Ret
+    } else if "11011001".is_match(instruction) {
+        Operation::Reti
+    } else if "110..010".is_match(instruction) {
+        Operation::JpCondition(cond)
+    } else if "11000011".is_match(instruction) {
+        Operation::Jp
+    } else if "11101001".is_match(instruction) {
+        Operation::JpHl
+    } else if "110..100".is_match(instruction) {
+        Operation::CallCondition(cond)
+    } else if "11001101".is_match(instruction) {
+        Operation::Call
+    } else if "11...111".is_match(instruction) {
+        Operation::Rst(instruction & 0b00111000)
+    } else if "11..0001".is_match(instruction) {
+        Operation::Pop(r16)
+    } else if "11..0101".is_match(instruction) {
+        Operation::Push(r16)
+    } else if "11001011".is_match(instruction) {
+        Operation::Prefix
+    } else if "11100010".is_match(instruction) {
+        Operation::LdhCA
+    } else if "11100000".is_match(instruction) {
+        Operation::LdhN8A
+    } else if "11101010".is_match(instruction) {
+        Operation::LdN16A
+    } else if "11110010".is_match(instruction) {
+        Operation::LdhAC
+    } else if "11110000".is_match(instruction) {
+        Operation::LdhAN8
+    } else if "11111010".is_match(instruction) {
+        Operation::LdAN16
+    } else if "11101000".is_match(instruction) {
+        Operation::AddSpE8
+    } else if "11111000".is_match(instruction) {
+        Operation::LdHlSpE8
+    } else if "11111001".is_match(instruction) {
+        Operation::LdSpHl
+    } else if "11110011".is_match(instruction) {
+        Operation::Di
+    } else if "11111011".is_match(instruction) {
+        Operation::Ei
+    } else {
+        Operation::Invalid
+    }
+}
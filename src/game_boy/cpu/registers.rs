@@ -1,4 +1,18 @@
-pub(crate) struct Registers {
+use crate::game_boy::model::Model;
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// The 16-bit registers, as debuggers see them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegisterPair {
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
+pub struct Registers {
     a: u8,
     f: u8,
 
@@ -22,10 +36,44 @@ impl Registers {
     const HALF_CARRY_FLAG_BITS: u8 = 0b0010_0000;
     const CARRY_FLAG_BITS: u8 = 0b0001_0000;
 
-    const LOWER_BITS: u16 = 0b00000000_11111111;
-
+    /// Registers at power-on, when the boot ROM starts executing.
     pub(crate) fn new() -> Registers {
-        Registers{ a: 0, f: 0, b: 0, c: 0, d: 0, e: 0, h: 0, l: 0, sp: 0, pc: 0x100, }
+        Registers{ a: 0, f: 0, b: 0, c: 0, d: 0, e: 0, h: 0, l: 0, sp: 0, pc: 0, }
+    }
+
+    /// Registers as the boot ROM of `model` leaves them when it jumps to the cartridge entry point.
+    /// Some values depend on the cartridge: the DMG flags on its header checksum
+    /// and the CGB registers on whether it is a CGB game.
+    pub(crate) fn post_boot(model: Model, header_checksum: u8, cgb_game: bool) -> Registers {
+        let checksum_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+        let [a, f, b, c, d, e, h, l] = match model {
+            Model::Dmg0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+            Model::Dmg => [0x01, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Mgb => [0xFF, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::Cgb if cgb_game => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0x00, 0x08, 0x00, 0x7C],
+            Model::Agb if cgb_game => [0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            Model::Agb => [0x11, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00, 0x7C],
+        };
+
+        Registers { a, f, b, c, d, e, h, l, sp: 0xFFFE, pc: 0x100 }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&[self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l]);
+        state.write_u16(self.sp);
+        state.write_u16(self.pc);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut bytes = [0; 8];
+        state.read_bytes(&mut bytes)?;
+        [self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l] = bytes;
+        self.f &= 0xF0;
+        self.sp = state.read_u16()?;
+        self.pc = state.read_u16()?;
+        Ok(())
     }
 
     fn merge_to_16_bit(first: u8, second: u8) -> u16 {
@@ -55,29 +103,59 @@ impl Registers {
         self.l = val as u8;
     }
 
+    pub(crate) fn write_af(&mut self, val: u16) {
+        self.a = (val >> 8) as u8;
+        // the lower nibble of F does not exist
+        self.f = val as u8 & 0xF0;
+    }
+
     pub(crate) fn write_sp(&mut self, value: u16) { self.sp = value; }
     pub(crate) fn write_pc(&mut self, value: u16) { self.pc = value; }
-    pub(crate) fn inc_pc(&mut self) { self.pc += 1; }
+    pub(crate) fn inc_pc(&mut self) { self.pc = self.pc.wrapping_add(1); }
     
-    pub(crate) fn read_a(&self) -> u8 { self.a }
-    pub(crate) fn read_b(&self) -> u8 { self.b }
-    pub(crate) fn read_c(&self) -> u8 { self.c }
-
-    pub(crate) fn read_d(&self) -> u8 { self.d }
-    pub(crate) fn read_e(&self) -> u8 { self.e }
-
-    pub(crate) fn read_h(&self) -> u8 { self.h }
-    pub(crate) fn read_l(&self) -> u8 { self.l }
-    pub(crate) fn read_hl(&self) -> u16 { Self::merge_to_16_bit(self.h, self.l) }
-    pub(crate) fn read_bc(&self) -> u16 { Self::merge_to_16_bit(self.b, self.c) }
-    pub(crate) fn read_de(&self) -> u16 { Self::merge_to_16_bit(self.d, self.e) }
-    pub(crate) fn read_sp(&self) -> u16 { self.sp }
-    pub(crate) fn read_pc(&self) -> u16 { self.pc }
-
-    pub(crate) fn read_zero_flag(&self) -> bool { (self.f & Self::ZERO_FLAG_BITS ) != 0 }
-    pub(crate) fn read_subtraction_flag(&self) -> bool { (self.f & Self::SUBTRACTION_FLAG_BITS) != 0 }
-    pub(crate) fn read_half_carry_flag(&self) -> bool { (self.f & Self::HALF_CARRY_FLAG_BITS) != 0 }
-    pub(crate) fn read_carry_flag(&self) -> bool { (self.f & Self::CARRY_FLAG_BITS) != 0 }
+    pub fn read_a(&self) -> u8 { self.a }
+    pub fn read_f(&self) -> u8 { self.f }
+    pub fn read_b(&self) -> u8 { self.b }
+    pub fn read_c(&self) -> u8 { self.c }
+
+    pub fn read_d(&self) -> u8 { self.d }
+    pub fn read_e(&self) -> u8 { self.e }
+
+    pub fn read_h(&self) -> u8 { self.h }
+    pub fn read_l(&self) -> u8 { self.l }
+    pub fn read_af(&self) -> u16 { Self::merge_to_16_bit(self.a, self.f) }
+    pub fn read_hl(&self) -> u16 { Self::merge_to_16_bit(self.h, self.l) }
+    pub fn read_bc(&self) -> u16 { Self::merge_to_16_bit(self.b, self.c) }
+    pub fn read_de(&self) -> u16 { Self::merge_to_16_bit(self.d, self.e) }
+    pub fn read_sp(&self) -> u16 { self.sp }
+    pub fn read_pc(&self) -> u16 { self.pc }
+
+    pub fn read_pair(&self, pair: RegisterPair) -> u16 {
+        match pair {
+            RegisterPair::AF => self.read_af(),
+            RegisterPair::BC => self.read_bc(),
+            RegisterPair::DE => self.read_de(),
+            RegisterPair::HL => self.read_hl(),
+            RegisterPair::SP => self.sp,
+            RegisterPair::PC => self.pc,
+        }
+    }
+
+    pub(crate) fn write_pair(&mut self, pair: RegisterPair, value: u16) {
+        match pair {
+            RegisterPair::AF => self.write_af(value),
+            RegisterPair::BC => self.write_bc(value),
+            RegisterPair::DE => self.write_de(value),
+            RegisterPair::HL => self.write_hl(value),
+            RegisterPair::SP => self.sp = value,
+            RegisterPair::PC => self.pc = value,
+        }
+    }
+
+    pub fn read_zero_flag(&self) -> bool { (self.f & Self::ZERO_FLAG_BITS ) != 0 }
+    pub fn read_subtraction_flag(&self) -> bool { (self.f & Self::SUBTRACTION_FLAG_BITS) != 0 }
+    pub fn read_half_carry_flag(&self) -> bool { (self.f & Self::HALF_CARRY_FLAG_BITS) != 0 }
+    pub fn read_carry_flag(&self) -> bool { (self.f & Self::CARRY_FLAG_BITS) != 0 }
 
     pub(crate) fn set_zero_flag(&mut self, value: bool) { if value { self.f |= Self::ZERO_FLAG_BITS } else { self.f &= !Self::ZERO_FLAG_BITS }; }
     pub(crate) fn set_subtraction_flag(&mut self, value: bool) { if value { self.f |= Self::SUBTRACTION_FLAG_BITS } else { self.f &= !Self::SUBTRACTION_FLAG_BITS }; }
@@ -101,6 +179,13 @@ mod tests {
         assert_eq!(register.read_c(), 0b11001100);
     }
 
+    #[test]
+    fn post_boot_flags_depend_on_header_checksum() {
+        assert_eq!(Registers::post_boot(Model::Dmg, 0x00, false).read_af(), 0x0180);
+        assert_eq!(Registers::post_boot(Model::Dmg, 0x4D, false).read_af(), 0x01B0);
+        assert_eq!(Registers::post_boot(Model::Dmg, 0x4D, false).read_hl(), 0x014D);
+    }
+
     #[test]
     fn read_hl() {
         let register = Registers{ a: 0, f: 0, b: 0, c: 0, d: 0, e: 0, h: 0b10101010, l: 0b01010101, sp: 0, pc: 0, };
@@ -0,0 +1,255 @@
+use std::collections::BTreeMap;
+use crate::game_boy::GameBoy;
+use crate::game_boy::cpu::opcode::{self, Operation};
+use crate::game_boy::disassembler::{self, Instruction};
+use crate::game_boy::memory::watchpoints::WatchpointHit;
+use crate::game_boy::symbols::Symbols;
+
+/// Machine cycles in a frame at normal speed.
+const CYCLES_PER_FRAME: u64 = 17_556;
+
+/// Why the debugger handed control back.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The requested number of instructions or frames ran.
+    Done,
+    /// PC reached a breakpoint, the instruction there has not run yet.
+    Breakpoint(u16),
+    /// The last instruction made watched accesses, it has already run.
+    Watchpoint(Vec<WatchpointHit>),
+    /// A step over or out of a call got back to the caller, at this address.
+    Returned(u16),
+    /// The CPU locked up on the illegal opcode at this address, see `CPU::is_locked`.
+    Locked(u16),
+}
+
+/// A return address on the stack, pushed by the call at `call_site`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct StackFrame {
+    pub stack_address: u16,
+    pub call_site: u16,
+    pub return_address: u16,
+}
+
+/// Breakpoints and stepping on top of `GameBoy`, for the frontends that debug a running game.
+#[derive(Default)]
+pub struct Debugger {
+    /// Addresses and the bank each has to be in, if only one.
+    breakpoints: BTreeMap<u16, Option<usize>>,
+    symbols: Symbols,
+    /// Where a step over or out of a call ends: the address returned to and the stack pointer at or above it.
+    return_to: Option<(u16, u16)>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Names addresses with `symbols`, usually from the `.sym` file of the game.
+    pub fn with_symbols(symbols: Symbols) -> Debugger {
+        Debugger { symbols, ..Debugger::default() }
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// Breaks at `address`, only while `bank` is mapped there if given. Returns false if there already was one.
+    pub fn add_breakpoint(&mut self, address: u16, bank: Option<usize>) -> bool {
+        self.breakpoints.insert(address, bank).is_none()
+    }
+
+    /// Returns false if there was none at `address`.
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (u16, Option<usize>)> + '_ {
+        self.breakpoints.iter().map(|(&address, &bank)| (address, bank))
+    }
+
+    /// The label `address` is at or after in whatever bank is mapped there now, like `Main+3`.
+    pub fn describe(&self, game_boy: &GameBoy, address: u16) -> Option<String> {
+        self.symbols.describe(game_boy.bank_at(address)?, address)
+    }
+
+    /// Executes up to `count` instructions, stopping early when the next one is at a breakpoint
+    /// or one hit a watchpoint.
+    pub fn step(&mut self, game_boy: &mut GameBoy, count: u64) -> Stop {
+        // hits from running without the debugger are old news
+        game_boy.take_watchpoint_hits();
+        for _ in 0..count {
+            game_boy.step();
+            if let Some(stop) = self.check_stop(game_boy) {
+                return stop;
+            }
+        }
+        Stop::Done
+    }
+
+    /// Executes the instruction at PC, running calls and rsts until they return.
+    /// Stopping elsewhere first, or after `frames`, leaves the step pending for `resume`.
+    pub fn step_over(&mut self, game_boy: &mut GameBoy, frames: Option<u64>) -> Stop {
+        let pc = game_boy.cpu().registers().read_pc();
+        let operation = opcode::decode(game_boy.memory().peek(pc));
+        if !matches!(operation, Operation::Call | Operation::CallCondition(_) | Operation::Rst(_)) {
+            return self.step(game_boy, 1);
+        }
+        self.return_to = Some((pc.wrapping_add(operation.length() as u16), game_boy.cpu().registers().read_sp()));
+        self.resume(game_boy, frames)
+    }
+
+    /// Runs until the current function returns to the call site `backtrace` finds for it.
+    /// Does nothing without a call on the stack.
+    pub fn step_out(&mut self, game_boy: &mut GameBoy, frames: Option<u64>) -> Stop {
+        let Some(frame) = backtrace(game_boy).into_iter().next() else { return Stop::Done };
+        self.return_to = Some((frame.return_address, frame.stack_address.wrapping_add(2)));
+        self.resume(game_boy, frames)
+    }
+
+    /// Whether a step over or out is still waiting for its call to return.
+    pub fn is_stepping(&self) -> bool {
+        self.return_to.is_some()
+    }
+
+    pub fn cancel_step(&mut self) {
+        self.return_to = None;
+    }
+
+    /// Runs until a breakpoint or watchpoint, or for about `frames` frames when given.
+    pub fn resume(&mut self, game_boy: &mut GameBoy, frames: Option<u64>) -> Stop {
+        let limit = frames.map_or(u64::MAX, |frames| frames * CYCLES_PER_FRAME);
+        let mut cycles = 0;
+        game_boy.take_watchpoint_hits();
+        while cycles < limit {
+            cycles += game_boy.step() as u64;
+            if let Some(stop) = self.check_stop(game_boy) {
+                return stop;
+            }
+        }
+        Stop::Done
+    }
+
+    /// Stopping for anything else cancels a pending step, like it does in GDB.
+    fn check_stop(&mut self, game_boy: &mut GameBoy) -> Option<Stop> {
+        let hits = game_boy.take_watchpoint_hits();
+        if !hits.is_empty() {
+            self.return_to = None;
+            return Some(Stop::Watchpoint(hits));
+        }
+        let pc = game_boy.cpu().registers().read_pc();
+        if game_boy.cpu().is_locked() {
+            self.return_to = None;
+            return Some(Stop::Locked(pc));
+        }
+        if let Some((address, stack)) = self.return_to
+            && pc == address
+            && game_boy.cpu().registers().read_sp() >= stack
+        {
+            self.return_to = None;
+            return Some(Stop::Returned(pc));
+        }
+        let bank = *self.breakpoints.get(&pc)?;
+        let hit = bank.is_none_or(|bank| game_boy.bank_at(pc) == Some(bank));
+        if hit {
+            self.return_to = None;
+        }
+        hit.then_some(Stop::Breakpoint(pc))
+    }
+}
+
+/// Decodes the instruction at `address` as the CPU would see it now.
+pub fn instruction_at(game_boy: &GameBoy, address: u16) -> Instruction {
+    let memory = game_boy.memory();
+    let bytes = [0, 1, 2].map(|offset| memory.peek(address.wrapping_add(offset)));
+    disassembler::decode(bytes, address)
+}
+
+/// About `before` instructions leading up to `address` and `after` from it on. Instructions have
+/// different lengths, so the ones before come from the earliest start that decodes right up to `address`.
+pub fn disassemble_around(game_boy: &GameBoy, address: u16, before: usize, after: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    for distance in (1..=3 * before as u16).rev() {
+        let mut current = address.wrapping_sub(distance);
+        let mut leading = Vec::new();
+        while current != address && address.wrapping_sub(current) <= distance {
+            let instruction = instruction_at(game_boy, current);
+            current = current.wrapping_add(instruction.length as u16);
+            leading.push(instruction);
+        }
+        if current == address && leading.len() >= before {
+            instructions = leading.split_off(leading.len() - before);
+            break;
+        }
+    }
+
+    let mut current = address;
+    for _ in 0..after {
+        let instruction = instruction_at(game_boy, current);
+        current = current.wrapping_add(instruction.length as u16);
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+/// Walks the stack from SP up for return addresses: words that follow a CALL or RST.
+/// Anything else pushed in between is skipped, so data that happens to look like one shows up too.
+pub fn backtrace(game_boy: &GameBoy) -> Vec<StackFrame> {
+    let memory = game_boy.memory();
+    let mut frames = Vec::new();
+    let mut stack_address = game_boy.cpu().registers().read_sp();
+    // the stack lives in WRAM or HRAM, stop at their end or after a generous depth
+    while stack_address < 0xFFFE && frames.len() < 64 && !(0xE000..0xFF80).contains(&stack_address) {
+        let return_address = u16::from_le_bytes([memory.peek(stack_address), memory.peek(stack_address + 1)]);
+        let is_call = matches!(memory.peek(return_address.wrapping_sub(3)), 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC);
+        let is_rst = memory.peek(return_address.wrapping_sub(1)) & 0xC7 == 0xC7;
+        if is_call || is_rst {
+            let call_site = return_address.wrapping_sub(if is_call { 3 } else { 1 });
+            frames.push(StackFrame { stack_address, call_site, return_address });
+        }
+        stack_address += 2;
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::tests::test_rom;
+
+    #[test]
+    fn stops_at_breakpoints_with_a_backtrace() {
+        // 0150: call 0156; jr -5; ... 0156: nop; nop; ret
+        let mut game_boy = GameBoy::new(test_rom(&[0xCD, 0x56, 0x01, 0x18, 0xFB, 0x00, 0x00, 0x00, 0xC9])).unwrap();
+        let mut debugger = Debugger::with_symbols(Symbols::parse("00:0156 Helper").unwrap());
+        debugger.add_breakpoint(0x0157, Some(0));
+
+        assert_eq!(debugger.resume(&mut game_boy, Some(1)), Stop::Breakpoint(0x0157));
+        let frames = backtrace(&game_boy);
+        assert_eq!(frames[0].call_site, 0x0150);
+        assert_eq!(frames[0].return_address, 0x0153);
+        assert_eq!(debugger.describe(&game_boy, 0x0157).as_deref(), Some("Helper+1"));
+
+        assert_eq!(debugger.step_out(&mut game_boy, None), Stop::Returned(0x0153));
+        assert_eq!(debugger.step(&mut game_boy, 1), Stop::Done);
+        assert_eq!(debugger.step_over(&mut game_boy, None), Stop::Breakpoint(0x0157));
+        assert!(!debugger.is_stepping());
+        debugger.remove_breakpoint(0x0157);
+        assert_eq!(debugger.step_out(&mut game_boy, None), Stop::Returned(0x0153));
+        debugger.step(&mut game_boy, 1);
+        assert_eq!(debugger.step_over(&mut game_boy, None), Stop::Returned(0x0153));
+        let texts: Vec<String> = disassemble_around(&game_boy, 0x0153, 1, 2).into_iter().map(|instruction| instruction.text).collect();
+        assert_eq!(texts, ["call $0156", "jr $0150", "nop"]);
+    }
+
+    #[test]
+    fn stops_when_the_cpu_locks_up() {
+        // 0150: nop; db $FC
+        let mut game_boy = GameBoy::new(test_rom(&[0x00, 0xFC])).unwrap();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.resume(&mut game_boy, Some(1)), Stop::Locked(0x0151));
+        assert_eq!(debugger.step(&mut game_boy, 1), Stop::Locked(0x0151));
+    }
+}
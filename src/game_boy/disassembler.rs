@@ -0,0 +1,441 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::ops::Range;
+use crate::game_boy::coverage::{self, Coverage};
+use crate::game_boy::cpu::opcode::{self, Operation, PrefixedOperation};
+use crate::game_boy::symbols::Symbols;
+
+const BANK_SIZE: usize = 0x4000;
+/// The cartridge header, data between the jump at the entry point and the code it jumps to.
+const HEADER: Range<u16> = 0x0104..0x0150;
+/// Runs of one byte at least this long become `ds`, which keeps the padding of large ROMs short.
+const MIN_FILL: usize = 16;
+/// Code the hardware jumps to by itself.
+const VECTORS: [(u16, &str); 6] = [
+    (0x0040, "VBlankInterrupt"), (0x0048, "StatInterrupt"), (0x0050, "TimerInterrupt"),
+    (0x0058, "SerialInterrupt"), (0x0060, "JoypadInterrupt"), (0x0100, "Entry"),
+];
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+/// Register pairs of PUSH and POP.
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+/// Memory operands of `ld [r16], a` and `ld a, [r16]`.
+const R16_MEMORY: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+/// rlca, rrca, rla and rra, the unprefixed rotates of A.
+const ROTATE_A: [&str; 4] = ["rlca", "rrca", "rla", "rra"];
+const ROTATE_SHIFT: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+/// One decoded SM83 instruction.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    /// 1 to 3 bytes, including the 0xCB prefix.
+    pub length: u8,
+    /// RGBDS syntax, e.g. `ld a, [hl+]` or `jr nz, $0150`. Bytes that are no instruction become `db $D3`.
+    pub text: String,
+    /// Where a jump, call or rst goes.
+    pub target: Option<u16>,
+}
+
+/// Decodes the instruction at `address` from `bytes`, which start with the opcode and are padded as needed.
+pub fn decode(bytes: [u8; 3], address: u16) -> Instruction {
+    render(bytes, address, &|_| None)
+}
+
+/// Decodes `bytes` as one instruction after the other, the first at `address`.
+/// An instruction cut off at the end becomes `db`.
+pub fn disassemble(bytes: &[u8], address: u16) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let address = address.wrapping_add(offset as u16);
+        let mut instruction = decode(padded(bytes, offset), address);
+        if offset + instruction.length as usize > bytes.len() {
+            instruction = Instruction { address, length: 1, text: format!("db ${:02X}", bytes[offset]), target: None };
+        }
+        offset += instruction.length as usize;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+/// The three bytes from `offset` on, zero past the end.
+fn padded(bytes: &[u8], offset: usize) -> [u8; 3] {
+    [0, 1, 2].map(|idx| bytes.get(offset + idx).copied().unwrap_or(0))
+}
+
+/// Like `decode`, writing jump and call targets as the label `label` gives them, if any.
+fn render(bytes: [u8; 3], address: u16, label: &dyn Fn(u16) -> Option<String>) -> Instruction {
+    let [opcode, low, high] = bytes;
+    let n8 = format!("${low:02X}");
+    let n16 = u16::from_le_bytes([low, high]);
+    let n16_text = format!("${n16:04X}");
+    let relative = address.wrapping_add(2).wrapping_add(low as i8 as u16);
+    let signed = |value: u8| if (value as i8) < 0 { format!("-{}", -(value as i8 as i16)) } else { format!("{value}") };
+    let condition = |cond: u8| CONDITIONS[cond as usize];
+    let operation = opcode::decode(opcode);
+
+    let target = match operation {
+        Operation::Jr | Operation::JrCondition(_) => Some(relative),
+        Operation::Jp | Operation::JpCondition(_) | Operation::Call | Operation::CallCondition(_) => Some(n16),
+        Operation::Rst(target) => Some(target as u16),
+        _ => None,
+    };
+    let target_text = target.map(|target| label(target).unwrap_or_else(|| format!("${target:04X}"))).unwrap_or_default();
+
+    let text = match operation {
+        Operation::Nop => String::from("nop"),
+        Operation::LdR16N16(r16) => format!("ld {}, {n16_text}", R16[r16 as usize]),
+        Operation::LdR16MemA(r16) => format!("ld {}, a", R16_MEMORY[r16 as usize]),
+        Operation::LdAR16Mem(r16) => format!("ld a, {}", R16_MEMORY[r16 as usize]),
+        Operation::LdN16Sp => format!("ld [{n16_text}], sp"),
+        Operation::IncR16(r16) => format!("inc {}", R16[r16 as usize]),
+        Operation::DecR16(r16) => format!("dec {}", R16[r16 as usize]),
+        Operation::AddHlR16(r16) => format!("add hl, {}", R16[r16 as usize]),
+        Operation::IncR8(r8) => format!("inc {}", R8[r8 as usize]),
+        Operation::DecR8(r8) => format!("dec {}", R8[r8 as usize]),
+        Operation::LdR8N8(r8) => format!("ld {}, {n8}", R8[r8 as usize]),
+        Operation::RotateA(operation) => String::from(ROTATE_A[operation as usize]),
+        Operation::Daa => String::from("daa"),
+        Operation::Cpl => String::from("cpl"),
+        Operation::Scf => String::from("scf"),
+        Operation::Ccf => String::from("ccf"),
+        Operation::Jr => format!("jr {target_text}"),
+        Operation::JrCondition(cond) => format!("jr {}, {target_text}", condition(cond)),
+        Operation::Stop => String::from("stop"),
+        Operation::Halt => String::from("halt"),
+        Operation::LdR8R8(dest, src) => format!("ld {}, {}", R8[dest as usize], R8[src as usize]),
+        Operation::AluR8(operation, r8) => format!("{} a, {}", ALU[operation as usize], R8[r8 as usize]),
+        Operation::AluN8(operation) => format!("{} a, {n8}", ALU[operation as usize]),
+        Operation::RetCondition(cond) => format!("ret {}", condition(cond)),
+        Operation::Ret => String::from("ret"),
+        Operation::Reti => String::from("reti"),
+        Operation::JpCondition(cond) => format!("jp {}, {target_text}", condition(cond)),
+        Operation::Jp => format!("jp {target_text}"),
+        Operation::JpHl => String::from("jp hl"),
+        Operation::CallCondition(cond) => format!("call {}, {target_text}", condition(cond)),
+        Operation::Call => format!("call {target_text}"),
+        Operation::Rst(target) => format!("rst ${target:02X}"),
+        Operation::Pop(r16) => format!("pop {}", R16_STACK[r16 as usize]),
+        Operation::Push(r16) => format!("push {}", R16_STACK[r16 as usize]),
+        Operation::Prefix => render_prefixed(low),
+        Operation::LdhCA => String::from("ldh [c], a"),
+        Operation::LdhN8A => format!("ldh [$FF{low:02X}], a"),
+        Operation::LdN16A => format!("ld [{n16_text}], a"),
+        Operation::LdhAC => String::from("ldh a, [c]"),
+        Operation::LdhAN8 => format!("ldh a, [$FF{low:02X}]"),
+        Operation::LdAN16 => format!("ld a, [{n16_text}]"),
+        Operation::AddSpE8 => format!("add sp, {}", signed(low)),
+        Operation::LdHlSpE8 => format!("ld hl, sp + {}", signed(low)).replace("+ -", "- "),
+        Operation::LdSpHl => String::from("ld sp, hl"),
+        Operation::Di => String::from("di"),
+        Operation::Ei => String::from("ei"),
+        Operation::Invalid => format!("db ${opcode:02X}"),
+    };
+    Instruction { address, length: operation.length(), text, target }
+}
+
+fn render_prefixed(opcode: u8) -> String {
+    match opcode::decode_prefixed(opcode) {
+        PrefixedOperation::RotateShift(operation, r8) => format!("{} {}", ROTATE_SHIFT[operation as usize], R8[r8 as usize]),
+        PrefixedOperation::Bit(bit, r8) => format!("bit {bit}, {}", R8[r8 as usize]),
+        PrefixedOperation::Res(bit, r8) => format!("res {bit}, {}", R8[r8 as usize]),
+        PrefixedOperation::Set(bit, r8) => format!("set {bit}, {}", R8[r8 as usize]),
+    }
+}
+
+/// A piece of a ROM listing.
+enum Line {
+    Code(Instruction),
+    Data(u16, Vec<u8>),
+    /// `ds` of a run of one byte value, as ROMs are padded.
+    Fill(u16, usize, u8),
+}
+
+impl Line {
+    fn address(&self) -> u16 {
+        match self {
+            Line::Code(instruction) => instruction.address,
+            Line::Data(address, _) | Line::Fill(address, _, _) => *address,
+        }
+    }
+}
+
+/// A label in the listing, global unless it only gets jumped to from code that precedes it in the same bank.
+struct Label {
+    name: String,
+    global: bool,
+}
+
+/// Disassembles a whole ROM into RGBDS source, one section per bank, with labels for jump and call targets.
+///
+/// This is a linear sweep that takes everything but the header for code, so data shows up as nonsense
+/// instructions. Code in bank 0 that jumps into 0x4000-0x7FFF gets the bank a preceding `ld a, n`
+/// and write to 0x2000-0x3FFF switched to, as MBCs take it.
+/// Labels from `symbols` replace the generated ones wherever they are in ROM. With a `coverage` map,
+/// bytes the CPU only read as data become `db` and no instruction runs over one it executed as an opcode.
+pub fn disassemble_rom(rom: &[u8], symbols: &Symbols, coverage: &Coverage) -> String {
+    let banks = rom.len().div_ceil(BANK_SIZE).max(1);
+
+    // first sweep without labels to find the targets, the second splits instructions that hide one
+    let mut target_banks = HashMap::new();
+    let mut calls = BTreeSet::new();
+    let mut jumps = BTreeMap::new();
+    for bank in 0..banks {
+        let mut last_a: Option<u8> = None;
+        let mut switched = (banks == 2).then_some(1);
+        for line in sweep(rom, bank, &BTreeMap::new(), coverage, &|_, _| None) {
+            let Line::Code(instruction) = line else { continue };
+            let bytes = padded(bank_data(rom, bank), (instruction.address - bank_base(bank)) as usize);
+            let operation = opcode::decode(bytes[0]);
+            if let Some(target) = instruction.target
+                && let Some(target_bank) = target_bank(bank, target, switched)
+                && target_bank < banks
+                && ((target - bank_base(target_bank)) as usize) < bank_data(rom, target_bank).len()
+            {
+                target_banks.insert((bank, instruction.address), target_bank);
+                match operation {
+                    Operation::Call | Operation::CallCondition(_) | Operation::Rst(_) => {
+                        calls.insert((target_bank, target));
+                    }
+                    _ => {
+                        jumps.entry((target_bank, target)).or_insert_with(BTreeSet::new).insert(bank);
+                    }
+                }
+            }
+
+            if operation == Operation::LdN16A && (0x2000..0x4000).contains(&u16::from_le_bytes([bytes[1], bytes[2]])) {
+                // MBC1 maps bank 0 writes to bank 1
+                switched = last_a.map(|bank| bank.max(1) as usize).or(switched);
+            }
+            last_a = match operation {
+                Operation::LdR8N8(7) => Some(bytes[1]),
+                // xor a
+                Operation::AluR8(5, 7) => Some(0),
+                _ => None,
+            };
+            if matches!(operation, Operation::Jp | Operation::Jr | Operation::JpHl | Operation::Ret | Operation::Reti) {
+                switched = (banks == 2).then_some(1);
+            }
+        }
+    }
+
+    let mut labels = BTreeMap::new();
+    for (address, name) in VECTORS {
+        labels.insert((0, address), Label { name: String::from(name), global: true });
+    }
+    for &(bank, address) in &calls {
+        labels.entry((bank, address)).or_insert_with(|| Label { name: format!("Call_{bank:03X}_{address:04X}"), global: true });
+    }
+    // jumps from other banks and before the first global label need a global label themselves
+    for (&(bank, address), from_banks) in &jumps {
+        let has_scope = labels.range((bank, 0)..=(bank, address)).any(|(_, label)| label.global);
+        if !labels.contains_key(&(bank, address)) && (!has_scope || from_banks.iter().any(|&from| from != bank)) {
+            labels.insert((bank, address), Label { name: format!("Jump_{bank:03X}_{address:04X}"), global: true });
+        }
+    }
+    for &(bank, address) in jumps.keys() {
+        labels.entry((bank, address)).or_insert_with(|| Label { name: format!(".jump_{address:04X}"), global: false });
+    }
+    add_symbols(&mut labels, symbols, banks);
+
+    let mut out = String::new();
+    for bank in 0..banks {
+        if bank == 0 {
+            writeln!(out, "SECTION \"ROM Bank $000\", ROM0[$0000]").unwrap();
+        } else {
+            writeln!(out, "\nSECTION \"ROM Bank ${bank:03X}\", ROMX[$4000], BANK[${bank:X}]").unwrap();
+        }
+        let bank_labels: BTreeMap<u16, &Label> = labels.range((bank, 0)..=(bank, 0xFFFF)).map(|(&(_, address), label)| (address, label)).collect();
+        for line in sweep(rom, bank, &bank_labels, coverage, &|address, target| {
+            let target_bank = target_banks.get(&(bank, address)).copied()?;
+            label_reference(&labels, bank, address, target_bank, target)
+        }) {
+            if let Some(label) = bank_labels.get(&line.address()) {
+                if label.global {
+                    writeln!(out, "\n{}:", label.name).unwrap();
+                } else {
+                    writeln!(out, "{}", label.name).unwrap();
+                }
+            }
+            match line {
+                Line::Code(instruction) => writeln!(out, "    {}", instruction.text).unwrap(),
+                Line::Data(_, bytes) => {
+                    for chunk in bytes.chunks(16) {
+                        let bytes: Vec<String> = chunk.iter().map(|byte| format!("${byte:02X}")).collect();
+                        writeln!(out, "    db {}", bytes.join(", ")).unwrap();
+                    }
+                }
+                Line::Fill(_, length, value) => writeln!(out, "    ds {length}, ${value:02X}").unwrap(),
+            }
+        }
+    }
+    out
+}
+
+fn bank_base(bank: usize) -> u16 {
+    if bank == 0 { 0x0000 } else { 0x4000 }
+}
+
+fn bank_data(rom: &[u8], bank: usize) -> &[u8] {
+    &rom[(bank * BANK_SIZE).min(rom.len())..((bank + 1) * BANK_SIZE).min(rom.len())]
+}
+
+/// The bank a jump or call from `bank` to `target` ends up in, `switched` being the bank mapped at 0x4000
+/// as far as bank 0 code can tell.
+fn target_bank(bank: usize, target: u16, switched: Option<usize>) -> Option<usize> {
+    match target {
+        0x0000..=0x3FFF => Some(0),
+        0x4000..=0x7FFF if bank != 0 => Some(bank),
+        0x4000..=0x7FFF => switched,
+        // RAM has no labels
+        _ => None,
+    }
+}
+
+/// Puts the labels of `symbols` in ROM over the generated ones, globals first so local labels like
+/// `Main.loop` can stay local when they follow their global label.
+fn add_symbols(labels: &mut BTreeMap<(usize, u16), Label>, symbols: &Symbols, banks: usize) {
+    let in_rom = |&(bank, address, _): &(usize, u16, &str)| bank < banks && address < 0x8000 && (bank == 0) == (address < 0x4000);
+    for (bank, address, name) in symbols.iter().filter(in_rom) {
+        if !name.contains('.') {
+            labels.insert((bank, address), Label { name: String::from(name), global: true });
+        }
+    }
+    for (bank, address, name) in symbols.iter().filter(in_rom) {
+        if let Some((parent, child)) = name.split_once('.') {
+            // the generated label this one replaces does not count as its scope
+            let scope = labels.range((bank, 0)..(bank, address)).rev().find(|(_, label)| label.global);
+            let label = if scope.is_some_and(|(_, label)| label.name == parent) {
+                Label { name: format!(".{child}"), global: false }
+            } else {
+                Label { name: name.replace('.', "_"), global: true }
+            };
+            labels.insert((bank, address), label);
+        }
+    }
+}
+
+/// The global label that local labels at `address` belong to.
+fn scope(labels: &BTreeMap<(usize, u16), Label>, bank: usize, address: u16) -> Option<&str> {
+    labels.range((bank, 0)..=(bank, address)).rev().find(|(_, label)| label.global).map(|(_, label)| label.name.as_str())
+}
+
+/// How code at `address` in `bank` refers to the label at `target`: local labels need the global one
+/// they follow when that is not the one in scope.
+fn label_reference(labels: &BTreeMap<(usize, u16), Label>, bank: usize, address: u16, target_bank: usize, target: u16) -> Option<String> {
+    let label = labels.get(&(target_bank, target))?;
+    if label.global {
+        return Some(label.name.clone());
+    }
+    let target_scope = scope(labels, target_bank, target)?;
+    if bank == target_bank && scope(labels, bank, address) == Some(target_scope) {
+        Some(label.name.clone())
+    } else {
+        Some(format!("{target_scope}{}", label.name))
+    }
+}
+
+/// Splits a bank into lines, never letting one run past the address of a label.
+fn sweep(rom: &[u8], bank: usize, labels: &BTreeMap<u16, &Label>, coverage: &Coverage, label: &dyn Fn(u16, u16) -> Option<String>) -> Vec<Line> {
+    let data = bank_data(rom, bank);
+    let base = bank_base(bank);
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let address = base + offset as u16;
+        let in_header = bank == 0 && HEADER.contains(&address);
+        let region_end = if in_header { HEADER.end } else if bank == 0 && address < HEADER.start { HEADER.start } else { base + data.len() as u16 };
+        let next_label = labels.range(address + 1..).next().map_or(region_end, |(&label, _)| label.min(region_end));
+        let limit = (next_label - base) as usize;
+
+        let run = data[offset..limit].iter().take_while(|&&byte| byte == data[offset]).count();
+        let usage = |offset: usize| coverage.at(bank * BANK_SIZE + offset);
+        let only_data = |offset: usize| usage(offset) == coverage::DATA;
+        if in_header {
+            lines.push(Line::Data(address, data[offset..limit].to_vec()));
+            offset = limit;
+        } else if run >= MIN_FILL {
+            lines.push(Line::Fill(address, run, data[offset]));
+            offset += run;
+        } else if only_data(offset) {
+            let end = (offset..limit).find(|&offset| !only_data(offset)).unwrap_or(limit);
+            lines.push(Line::Data(address, data[offset..end].to_vec()));
+            offset = end;
+        } else {
+            let instruction = render(padded(data, offset), address, &|target| label(address, target));
+            // an opcode that ran or data that was read cuts this instruction short
+            let end = (offset + 1..offset + instruction.length as usize).find(|&offset| usage(offset) & coverage::OPCODE != 0 || only_data(offset));
+            if let Some(end) = end.filter(|&end| end < limit) {
+                lines.push(Line::Data(address, data[offset..end].to_vec()));
+                offset = end;
+            } else if offset + instruction.length as usize > limit {
+                lines.push(Line::Data(address, data[offset..limit].to_vec()));
+                offset = limit;
+            } else {
+                offset += instruction.length as usize;
+                lines.push(Line::Code(instruction));
+            }
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::tests::test_rom;
+
+    #[test]
+    fn decodes_rgbds_syntax() {
+        let text = |bytes: [u8; 3]| decode(bytes, 0x0150).text;
+        assert_eq!(text([0x2A, 0, 0]), "ld a, [hl+]");
+        assert_eq!(text([0x20, 0xFE, 0]), "jr nz, $0150");
+        assert_eq!(text([0xCB, 0x7C, 0]), "bit 7, h");
+        assert_eq!(text([0xCD, 0x34, 0x12]), "call $1234");
+        assert_eq!(text([0xE0, 0x40, 0]), "ldh [$FF40], a");
+        assert_eq!(text([0xF8, 0xFE, 0]), "ld hl, sp - 2");
+        assert_eq!(text([0x96, 0, 0]), "sub a, [hl]");
+        assert_eq!(text([0xFF, 0, 0]), "rst $38");
+        assert_eq!(text([0xD3, 0, 0]), "db $D3");
+        assert_eq!(decode([0x01, 0x34, 0x12], 0).length, 3);
+    }
+
+    #[test]
+    fn labels_targets_in_the_bank_switched_to() {
+        let texts: Vec<String> = disassemble(&[0xAF, 0xCB, 0x7C, 0x20], 0xC000).into_iter().map(|instruction| instruction.text).collect();
+        assert_eq!(texts, ["xor a, a", "bit 7, h", "db $20"]);
+
+        // 0150: ld a, 3; ld [$2000], a; call $4000; jr -2, and a ret at the start of bank 3
+        let mut rom = test_rom(&[0x3E, 0x03, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40, 0x18, 0xFE]);
+        rom.resize(4 * BANK_SIZE, 0);
+        rom[3 * BANK_SIZE] = 0xC9;
+        let listing = disassemble_rom(&rom, &Symbols::default(), &Coverage::new(rom.len()));
+        assert!(listing.contains("Entry:\n    jp .jump_0150\n"));
+        assert!(listing.contains("    call Call_003_4000\n.jump_0158\n    jr .jump_0158\n"));
+        assert!(listing.contains("SECTION \"ROM Bank $003\", ROMX[$4000], BANK[$3]\n\nCall_003_4000:\n    ret\n"));
+
+        // labels from a .sym file win, local ones stay local under their own global label
+        let symbols = Symbols::parse("00:0150 Main\n00:0158 Main.loop\n03:4000 Helper\n").unwrap();
+        let listing = disassemble_rom(&rom, &symbols, &Coverage::new(rom.len()));
+        assert!(listing.contains("Entry:\n    jp Main\n"));
+        assert!(listing.contains("    call Helper\n.loop\n    jr .loop\n"));
+    }
+
+    #[test]
+    fn separates_code_from_data_by_coverage() {
+        // 0150: jr +2, two bytes of data, then ld a, $00 that a linear sweep would miss
+        let rom = test_rom(&[0x18, 0x02, 0x3E, 0x01, 0x3E, 0x00]);
+        let coverage = Coverage::parse("00:0150-0150 x\n00:0151-0151 o\n00:0152-0153 d\n00:0154-0154 x\n00:0155-0155 o\n", rom.len()).unwrap();
+        let listing = disassemble_rom(&rom, &Symbols::default(), &coverage);
+        assert!(listing.contains("    jr .jump_0154\n    db $3E, $01\n.jump_0154\n    ld a, $00\n"));
+
+        // an executed opcode also splits an instruction that covers it
+        let coverage = Coverage::parse("00:0151-0151 x", rom.len()).unwrap();
+        let listing = disassemble_rom(&rom, &Symbols::default(), &coverage);
+        assert!(listing.contains("    db $18\n    ld [bc], a\n    ld a, $01\n"));
+    }
+}
@@ -1,130 +1,402 @@
+use std::cell::RefCell;
+use crate::game_boy::LoadError;
+use crate::game_boy::bess::Bess;
+use crate::game_boy::coverage::{self, Coverage};
+use crate::game_boy::memory::audio_registers::AudioRegisters;
+use crate::game_boy::memory::cartridge::Cartridge;
+use crate::game_boy::memory::color_palette_ram::ColorPaletteRam;
+use crate::game_boy::memory::joypad::{Button, Joypad};
 use crate::game_boy::memory::lcdc::Lcdc;
-use crate::game_boy::memory::vram_tile_data::VramTileData;
+use crate::game_boy::memory::object_attribute_memory::ObjectAttributeMemory;
+use crate::game_boy::memory::timer::Timer;
+use crate::game_boy::memory::video_ram_bank::VideoRamBank;
+use crate::game_boy::memory::vram_dma::{VramDma, VramDmaMode};
+use crate::game_boy::memory::watchpoints::{Access, Watchpoints};
+use crate::game_boy::model::Model;
+use crate::game_boy::save_state::{Sections, StateError, StateWriter};
+use crate::game_boy::sgb::Sgb;
 
 pub mod object_attribute_memory;
 mod vram_tile_data;
+mod video_ram_bank;
 mod lcdc;
+pub(crate) mod audio_registers;
+pub(crate) mod cartridge;
+mod color_palette_ram;
+pub(crate) mod joypad;
+mod timer;
+mod vram_dma;
+pub mod watchpoints;
 
-pub(crate) struct Memory {
-    rom_bank_00: [u8; 16 * 1024],
-    rom_bank_01: [u8; 16 * 1024],
+pub(crate) const INTERRUPT_VBLANK: u8 = 1 << 0;
+pub(crate) const INTERRUPT_STAT: u8 = 1 << 1;
+pub(crate) const INTERRUPT_TIMER: u8 = 1 << 2;
+pub(crate) const INTERRUPT_JOYPAD: u8 = 1 << 4;
 
-    vram_tile_data_block0: VramTileData,
-    vram_tile_data_block1: VramTileData,
-    vram_tile_data_block2: VramTileData,
+pub struct Memory {
+    cartridge: Cartridge,
+    /// Mapped over the cartridge until a write to 0xFF50.
+    boot_rom: Option<Vec<u8>>,
 
-    video_ram: [u8; 8 * 1024],
+    /// Whether CGB features are enabled: a CGB running a CGB game, rather than a DMG game in compatibility mode.
+    cgb_mode: bool,
 
-    external_ram: [u8; 8 * 1024],
-    work_ram_00: [u8; 4 * 1024],
-    work_ram_01: [u8; 4 * 1024],
-    object_attribute_memory: [u8; 160],
+    video_ram: [VideoRamBank; 2],
+    /// VRAM bank the CPU sees at 0x8000, selected through VBK.
+    video_ram_bank: usize,
 
+    /// Bank 0 is fixed at 0xC000, banks 1-7 are switched in at 0xD000 through SVBK.
+    work_ram: [[u8; 4 * 1024]; 8],
+    work_ram_bank: usize,
+    object_attribute_memory: ObjectAttributeMemory,
+    vram_dma: VramDma,
+    /// Set by the PPU when HBlank begins, the HBlank DMA copies its next block then.
+    hblank_dma_pending: bool,
+    /// Machine cycles the CPU has to wait for VRAM DMA copies.
+    dma_stall_cycles: i32,
+
+    joypad: Joypad,
+    /// Listens to P1 for command packets when running on an SGB.
+    sgb: Option<Sgb>,
+    timer: Timer,
     lcdc: Lcdc,
+    bg_palettes: ColorPaletteRam,
+    obj_palettes: ColorPaletteRam,
+    audio_registers: AudioRegisters,
     input_output_registers: [u8; 128],
 
 
     high_ram: [u8; 128],
     interrupt_enable_register: u8,
+
+    /// KEY1 bit 0, requests a speed switch on the next STOP.
+    speed_switch_armed: bool,
+    double_speed: bool,
+
+    /// Checked on every CPU access, so keep the empty case cheap.
+    watchpoints: Watchpoints,
+    /// How the CPU used each ROM byte, while recording.
+    coverage: Option<RefCell<Coverage>>,
 }
 impl Memory {
     pub(crate) fn new() -> Memory {
         Memory {
-            rom_bank_00: [0; 16 * 1024],
-            rom_bank_01: [0; 16 * 1024],
-            vram_tile_data_block0: VramTileData::new(),
-            vram_tile_data_block1: VramTileData::new(),
-            vram_tile_data_block2: VramTileData::new(),
-            video_ram: [0; 8 * 1024],
-            external_ram: [0; 8 * 1024],
-            work_ram_00: [0; 4 * 1024],
-            work_ram_01: [0; 4 * 1024],
-            object_attribute_memory: [0; 160],
+            cartridge: Cartridge::new(),
+            boot_rom: None,
+            cgb_mode: false,
+            video_ram: [VideoRamBank::new(), VideoRamBank::new()],
+            video_ram_bank: 0,
+            work_ram: [[0; 4 * 1024]; 8],
+            work_ram_bank: 1,
+            object_attribute_memory: ObjectAttributeMemory::new(),
+            vram_dma: VramDma::new(),
+            hblank_dma_pending: false,
+            dma_stall_cycles: 0,
+            joypad: Joypad::new(),
+            sgb: None,
+            timer: Timer::new(),
             lcdc: Lcdc::default(),
+            bg_palettes: ColorPaletteRam::new(),
+            obj_palettes: ColorPaletteRam::new(),
+            audio_registers: AudioRegisters::new(),
             input_output_registers: [0; 128],
             high_ram: [0; 128],
             interrupt_enable_register: 0,
+            speed_switch_armed: false,
+            double_speed: false,
+            watchpoints: Watchpoints::default(),
+            coverage: None,
         }
     }
 
-    pub (crate) fn load_cartridge(&mut self, cartridge_rom: Vec<u8>) {
-        assert_eq!(cartridge_rom.len(), 32 * 1024);
+    pub (crate) fn load_cartridge(&mut self, cartridge_rom: Vec<u8>) -> Result<(), LoadError> {
+        self.cartridge = Cartridge::from_rom(cartridge_rom)?;
+        Ok(())
+    }
 
-        self.rom_bank_00.copy_from_slice(&cartridge_rom[0..16 * 1024]);
-        self.rom_bank_01.copy_from_slice(&cartridge_rom[16 * 1024..32 * 1024]);
+    pub(crate) fn load_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
     }
 
-    pub(crate) fn read_video_ram(&self, address: u16) -> u8 {
+    /// Sets the I/O registers to the values the boot ROM of `model` leaves behind.
+    pub(crate) fn apply_post_boot_state(&mut self, model: Model) {
+        // the boot ROM leaves both button groups selected, P1 reads 0xCF
+        self.joypad.write(0x00);
+        self.timer.set_counter(match model {
+            Model::Dmg0 => 0x1800,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb => 0xD800,
+            Model::Cgb | Model::Agb => 0x2678,
+        });
+        self.timer.write(Timer::TAC, 0xF8);
+        self.input_output_registers[0x02] = 0x7E;
+        self.input_output_registers[0x0F] = 0xE1;
+
+        let audio = &mut self.audio_registers;
+        audio.set(AudioRegisters::NR52, 0x80);
+        for (address, value) in [
+            (AudioRegisters::NR10, 0x80), (AudioRegisters::NR11, 0xBF), (AudioRegisters::NR12, 0xF3), (AudioRegisters::NR14, 0xBF),
+            (AudioRegisters::NR21, 0x3F), (AudioRegisters::NR24, 0xBF),
+            (AudioRegisters::NR30, 0x7F), (AudioRegisters::NR31, 0xFF), (AudioRegisters::NR32, 0x9F), (AudioRegisters::NR34, 0xBF),
+            (AudioRegisters::NR41, 0xFF), (AudioRegisters::NR44, 0xBF),
+            (AudioRegisters::NR50, 0x77), (AudioRegisters::NR51, 0xF3),
+        ] {
+            audio.set(address, value);
+        }
 
+        self.lcdc.set_flags(0x91);
+        if self.cgb_mode {
+            // the boot ROM leaves every BG color white, OBJ palettes keep their power-on garbage
+            self.bg_palettes.fill(0x7FFF);
+        }
+        self.input_output_registers[0x46] = if model.is_cgb() { 0x00 } else { 0xFF };
+        self.input_output_registers[0x47] = 0xFC;
+    }
+
+    fn read_rom(&self, address: u16) -> u8 {
+        match &self.boot_rom {
+            Some(boot_rom) if self.is_boot_rom_at(address) => boot_rom[address as usize],
+            _ => self.cartridge.read_rom(address),
+        }
+    }
+
+    fn is_boot_rom_at(&self, address: u16) -> bool {
+        // the CGB boot ROM leaves a hole for the cartridge header
+        self.boot_rom.as_ref().is_some_and(|boot_rom| address < 0x100 || (0x200..boot_rom.len()).contains(&(address as usize)))
+    }
+
+    /// The bank of whatever memory is mapped at `address`, numbered like RGBDS does. 0 for memory
+    /// without banks and None in the boot ROM, which is no bank of the cartridge.
+    pub(crate) fn bank_at(&self, address: u16) -> Option<usize> {
         match address {
-            0x8000 ..= 0x87FF => self.vram_tile_data_block0.read(address - 0x8000),
-            0x8800 ..= 0x8FFF => self.vram_tile_data_block1.read(address - 0x8800),
-            0x9000 ..= 0x97FF => self.vram_tile_data_block2.read(address - 0x9000),
+            0x0000 ..= 0x7FFF if self.is_boot_rom_at(address) => None,
+            0x0000 ..= 0x7FFF => Some(self.cartridge.rom_bank(address)),
+            0x8000 ..= 0x9FFF => Some(self.video_ram_bank),
+            0xA000 ..= 0xBFFF => Some(self.cartridge.current_ram_bank()),
+            0xD000 ..= 0xDFFF => Some(self.work_ram_bank),
+            _ => Some(0),
+        }
+    }
+
+    /// VRAM `bank` as the PPU sees it, regardless of the bank the CPU selected.
+    pub(crate) fn video_ram(&self, bank: usize) -> &VideoRamBank {
+        &self.video_ram[bank]
+    }
+
+    pub(crate) fn is_cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    /// Enables the CGB registers and banking, set when a CGB runs a CGB game.
+    pub(crate) fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+        if !cgb_mode {
+            self.video_ram_bank = 0;
+            self.work_ram_bank = 1;
+        }
+    }
+
+    pub(crate) fn enable_sgb(&mut self) {
+        self.sgb = Some(Sgb::new());
+    }
+
+    pub(crate) fn sgb(&self) -> Option<&Sgb> {
+        self.sgb.as_ref()
+    }
+
+    pub(crate) fn sgb_mut(&mut self) -> Option<&mut Sgb> {
+        self.sgb.as_mut()
+    }
 
-            _ => unimplemented!()
+    /// P1, where with MLT_REQ the SGB reports which joypad is selected while no button group is,
+    /// and the joypads besides the first have nothing pressed.
+    fn read_joypad(&self) -> u8 {
+        let value = self.joypad.read();
+        let player = self.sgb.as_ref().map_or(0, Sgb::player);
+        if value & 0x30 == 0x30 {
+            value & 0xF0 | (0x0F - player)
+        } else if player != 0 {
+            value | 0x0F
+        } else {
+            value
         }
     }
 
+    pub(crate) fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /// Called on STOP. Switches the CPU speed if KEY1 armed a switch and returns whether it did.
+    pub(crate) fn take_speed_switch(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    /// Reads like the CPU, triggering watchpoints.
     pub(crate) fn read(&self, address: u16) -> u8 {
+        self.fetch(address, coverage::DATA)
+    }
+
+    /// Reads like `read`, a byte of an instruction counts as `usage` rather than data for the coverage map.
+    pub(crate) fn fetch(&self, address: u16, usage: u8) -> u8 {
+        let value = self.peek(address);
+        if !self.watchpoints.is_empty() {
+            self.watchpoints.check(Access::Read, address, value, self);
+        }
+        if let Some(coverage) = &self.coverage
+            && address < 0x8000
+            && !self.is_boot_rom_at(address)
+        {
+            coverage.borrow_mut().mark(self.cartridge.rom_offset(address), usage);
+        }
+        value
+    }
+
+    /// Replaces the coverage map the CPU accesses go into, returning the old one.
+    pub(crate) fn set_coverage(&mut self, coverage: Option<Coverage>) -> Option<Coverage> {
+        std::mem::replace(&mut self.coverage, coverage.map(RefCell::new)).map(RefCell::into_inner)
+    }
+
+    /// Reads without triggering watchpoints, for the PPU and debuggers.
+    pub fn peek(&self, address: u16) -> u8 {
         match address {
-            0x0000 ..= 0x3FFF => self.rom_bank_00[address as usize],
-            0x4000 ..= 0x7FFF => self.rom_bank_01[(address - 0x4000) as usize],
-            0x8000 ..= 0x9FFF => self.read_video_ram(address),  //self.video_ram[(address - 0x8000) as usize],
-            0xA000 ..= 0xBFFF => self.external_ram[(address - 0xA000) as usize],
-            0xC000 ..= 0xCFFF => self.work_ram_00[(address - 0xC000) as usize],
-            0xD000 ..= 0xDFFF => self.work_ram_01[(address - 0xD000) as usize],
-            0xE000 ..= 0xFDFF => panic!("Echo RAM, prohibited"),
-            0xFE00 ..= 0xFE9F => self.object_attribute_memory[(address - 0xFE00) as usize],
-            0xFEA0 ..= 0xFEFF => panic!("Not Usable, prohibited"),
-            0xFF00 ..= 0xFF7F => {
-                let local_address = (address - 0xFF00) as usize;
-                self.input_output_registers[local_address]
-            },
+            0x0000 ..= 0x7FFF => self.read_rom(address),
+            0x8000 ..= 0x9FFF => self.video_ram[self.video_ram_bank].read(address),
+            0xA000 ..= 0xBFFF => self.cartridge.read_ram(address),
+            0xC000 ..= 0xCFFF => self.work_ram[0][(address - 0xC000) as usize],
+            0xD000 ..= 0xDFFF => self.work_ram[self.work_ram_bank][(address - 0xD000) as usize],
+            // echo RAM mirrors the work RAM, the unusable range reads as an open bus
+            0xE000 ..= 0xFDFF => self.peek(address - 0x2000),
+            0xFE00 ..= 0xFE9F => self.object_attribute_memory.read(address - 0xFE00),
+            0xFEA0 ..= 0xFEFF => 0xFF,
+            0xFF00 ..= 0xFF7F => self.read_register(address),
             0xFF80 ..= 0xFFFE => {
                 let local_address = (address - 0xFF80) as usize;
                 self.high_ram[local_address]
             },
             0xFFFF => self.interrupt_enable_register,
-            _ => unreachable!(),
         }
     }
 
+    /// Writes like the CPU, triggering watchpoints. They see the state from before the write.
     pub(crate) fn write(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            self.watchpoints.check(Access::Write, address, value, self);
+        }
+        self.poke(address, value);
+    }
+
+    /// Writes without triggering watchpoints, for debuggers.
+    pub(crate) fn poke(&mut self, address: u16, value: u8) {
         match address {
-            0x0000 ..= 0x3FFF => panic!("Cartridge ROM is readonly!"),
-            0x4000 ..= 0x7FFF => panic!("Cartridge ROM is readonly!"),
-            0x8000 ..= 0x9FFF => self.video_ram[(address - 0x8000) as usize] = value,
-            0xA000 ..= 0xBFFF => self.external_ram[(address - 0xA000) as usize] = value,
-            0xC000 ..= 0xCFFF => self.work_ram_00[(address - 0xC000) as usize] = value,
-            0xD000 ..= 0xDFFF => self.work_ram_01[(address - 0xD000) as usize] = value,
-            0xE000 ..= 0xFDFF => panic!("Echo RAM, prohibited"),
-            0xFE00 ..= 0xFE9F => self.object_attribute_memory[(address - 0xFE00) as usize] = value,
-            0xFEA0 ..= 0xFEFF => panic!("Not Usable, prohibited"),
+            0x0000 ..= 0x7FFF => self.cartridge.write_rom(address, value),
+            0x8000 ..= 0x9FFF => self.video_ram[self.video_ram_bank].write(address, value),
+            0xA000 ..= 0xBFFF => self.cartridge.write_ram(address, value),
+            0xC000 ..= 0xCFFF => self.work_ram[0][(address - 0xC000) as usize] = value,
+            0xD000 ..= 0xDFFF => self.work_ram[self.work_ram_bank][(address - 0xD000) as usize] = value,
+            0xE000 ..= 0xFDFF => self.poke(address - 0x2000, value),
+            0xFE00 ..= 0xFE9F => self.object_attribute_memory.write(address - 0xFE00, value),
+            0xFEA0 ..= 0xFEFF => {}
             0xFF00 ..= 0xFF7F => self.write_to_register(address, value),
             0xFF80 ..= 0xFFFE => self.high_ram[(address - 0xFF80) as usize] = value,
             0xFFFF => self.interrupt_enable_register = value,
-            _ => {}
         };
     }
 
+    fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF00 => self.read_joypad(),
+            Timer::DIV ..= Timer::TAC => self.timer.read(address),
+            0xFF0F => self.input_output_registers[0x0F] | 0xE0,
+            0xFF10 ..= 0xFF3F => self.audio_registers.read(address),
+            0xFF40 => self.lcdc.flags(),
+            0xFF41 => self.input_output_registers[0x41] | 0x80,
+            0xFF4D if self.cgb_mode => 0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8,
+            0xFF4F if self.cgb_mode => 0xFE | self.video_ram_bank as u8,
+            VramDma::HDMA5 if self.cgb_mode => self.vram_dma.read_status(),
+            0xFF68 if self.cgb_mode => self.bg_palettes.read_specification(),
+            0xFF69 if self.cgb_mode => self.bg_palettes.read_data(),
+            0xFF6A if self.cgb_mode => self.obj_palettes.read_specification(),
+            0xFF6B if self.cgb_mode => self.obj_palettes.read_data(),
+            0xFF70 if self.cgb_mode => 0xF8 | self.work_ram_bank as u8,
+            0xFF4C ..= 0xFF7F => 0xFF,
+            _ => self.input_output_registers[(address - 0xFF00) as usize]
+        }
+    }
+
     fn write_to_register(&mut self, address: u16, value: u8) {
         match address {
+            0xFF00 => {
+                self.joypad.write(value);
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_joypad(value);
+                }
+            }
+            Timer::DIV ..= Timer::TAC => {
+                if self.timer.write(address, value) {
+                    self.request_interrupt(INTERRUPT_TIMER);
+                }
+            }
+            0xFF10 ..= 0xFF3F => {
+                self.audio_registers.write(address, value);
+            }
             0xFF40 => {
                 self.lcdc.set_flags(value);
             }
             0xFF41 => {
-                // STAT
+                // only the interrupt selection bits are writable, the mode and LYC flag belong to the PPU
+                let stat = &mut self.input_output_registers[0x41];
+                *stat = (*stat & 0x07) | (value & 0x78);
             }
             0xFF44 => {
-                panic!("LY is readonly!")
+                // LY belongs to the PPU
+            }
+            0xFF4C => {
+                // the CGB boot ROM writes 0x04 here to fall back to DMG compatibility mode
+                if self.boot_rom.is_some() {
+                    self.set_cgb_mode(value & 0x04 == 0);
+                }
             }
-            0xFF45 => {
-                // LY compare
+            0xFF4D => {
+                self.speed_switch_armed = value & 0x01 != 0;
+            }
+            0xFF4F => {
+                if self.cgb_mode {
+                    self.video_ram_bank = (value & 0x01) as usize;
+                }
+            }
+            VramDma::HDMA1 ..= VramDma::HDMA5 if !self.cgb_mode => {}
+            VramDma::HDMA1 ..= VramDma::HDMA5 => {
+                self.vram_dma.write(address, value);
+                while self.vram_dma.mode() == VramDmaMode::General {
+                    self.copy_vram_dma_block();
+                }
+            }
+            0xFF68 ..= 0xFF6B if !self.cgb_mode => {}
+            0xFF68 => self.bg_palettes.write_specification(value),
+            0xFF69 => self.bg_palettes.write_data(value),
+            0xFF6A => self.obj_palettes.write_specification(value),
+            0xFF6B => self.obj_palettes.write_data(value),
+            0xFF70 => {
+                if self.cgb_mode {
+                    // bank 0 selects bank 1 as well
+                    self.work_ram_bank = ((value & 0x07) as usize).max(1);
+                }
+            }
+            0xFF50 => {
+                if value != 0 {
+                    self.boot_rom = None;
+                }
             }
             0xFF46 => {
+                self.input_output_registers[0x46] = value;
                 if value <= 0xDF {
-                    for idx in 0x00..0x9F {
-                        self.write(0xFE00 + idx, self.read(0x100 * (value as u16) + idx));
+                    for idx in 0x00..0xA0 {
+                        self.object_attribute_memory.write(idx, self.peek(0x100 * (value as u16) + idx));
                     }
                 }
             }
@@ -132,7 +404,297 @@ impl Memory {
         }
     }
 
-    pub fn set_ly(&mut self, ly: u8) {
+    /// Copies the next 16 bytes of a VRAM DMA into the VRAM bank selected by the CPU.
+    fn copy_vram_dma_block(&mut self) {
+        let (source, destination) = self.vram_dma.next_block();
+        for offset in 0..VramDma::BLOCK_SIZE {
+            let source = source.wrapping_add(offset);
+            // VRAM and everything above the work RAM cannot be a source
+            let value = match source {
+                0x0000 ..= 0x7FFF | 0xA000 ..= 0xDFFF => self.peek(source),
+                _ => 0xFF,
+            };
+            self.video_ram[self.video_ram_bank].write(0x8000 | (destination + offset), value);
+        }
+        // a block takes as long in double speed, which is twice as many machine cycles
+        self.dma_stall_cycles += if self.double_speed { 16 } else { 8 };
+    }
+
+    /// Called by the PPU when a visible line enters HBlank.
+    pub(crate) fn request_hblank_dma(&mut self) {
+        self.hblank_dma_pending = self.vram_dma.mode() == VramDmaMode::HBlank;
+    }
+
+    /// Copies the block of a pending HBlank DMA. A halted CPU skips it, the transfer resumes on the next HBlank after waking up.
+    pub(crate) fn run_hblank_dma(&mut self, cpu_halted: bool) {
+        if std::mem::take(&mut self.hblank_dma_pending) && !cpu_halted {
+            self.copy_vram_dma_block();
+        }
+    }
+
+    /// Machine cycles the CPU stalls for the VRAM DMA blocks copied since the last call.
+    pub(crate) fn take_dma_stall_cycles(&mut self) -> i32 {
+        std::mem::take(&mut self.dma_stall_cycles)
+    }
+
+    pub(crate) fn set_ly(&mut self, ly: u8) {
         self.input_output_registers[0xFF44 - 0xFF00] = ly;
     }
-}
\ No newline at end of file
+
+    /// Updates the read-only part of STAT: the PPU mode and the LY=LYC flag.
+    pub(crate) fn set_stat(&mut self, mode: u8, ly_equals_lyc: bool) {
+        let stat = &mut self.input_output_registers[0x41];
+        *stat = (*stat & 0x78) | ((ly_equals_lyc as u8) << 2) | mode;
+    }
+
+    pub(crate) fn step_timer(&mut self, cycles: i32) {
+        if self.timer.step(cycles) {
+            self.request_interrupt(INTERRUPT_TIMER);
+        }
+    }
+
+    pub(crate) fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    pub(crate) fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    pub(crate) fn step_rtc(&mut self, dots: i32) {
+        self.cartridge.step_rtc(dots);
+    }
+
+    pub(crate) fn set_rtc_emulated_time(&mut self, seconds: u64) {
+        self.cartridge.set_rtc_emulated_time(seconds);
+    }
+
+    pub(crate) fn request_interrupt(&mut self, interrupt: u8) {
+        self.input_output_registers[0x0F] |= interrupt;
+    }
+
+    pub(crate) fn lcdc(&self) -> &Lcdc {
+        &self.lcdc
+    }
+
+    pub(crate) fn bg_palettes(&self) -> &ColorPaletteRam {
+        &self.bg_palettes
+    }
+
+    pub(crate) fn obj_palettes(&self) -> &ColorPaletteRam {
+        &self.obj_palettes
+    }
+
+    pub(crate) fn object_attribute_memory(&self) -> &ObjectAttributeMemory {
+        &self.object_attribute_memory
+    }
+
+    pub(crate) fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.joypad.set_pressed(button, pressed) {
+            self.request_interrupt(INTERRUPT_JOYPAD);
+        }
+    }
+
+    pub(crate) fn pressed_buttons(&self) -> u8 {
+        self.joypad.pressed()
+    }
+
+    pub(crate) fn has_battery(&self) -> bool {
+        self.cartridge.has_battery()
+    }
+
+    pub(crate) fn save_data(&mut self) -> Vec<u8> {
+        self.cartridge.save_data()
+    }
+
+    pub(crate) fn load_save_data(&mut self, data: &[u8]) {
+        self.cartridge.load_save_data(data);
+    }
+
+    pub(crate) fn audio_registers_mut(&mut self) -> &mut AudioRegisters {
+        &mut self.audio_registers
+    }
+
+    /// Fills in the memory buffers, the I/O page, INFO and the cartridge blocks.
+    pub(crate) fn export_bess(&mut self, bess: &mut Bess) {
+        let mut title = [0; 16];
+        for (idx, byte) in title.iter_mut().enumerate() {
+            *byte = self.cartridge.read_rom(0x134 + idx as u16);
+        }
+        let checksum = u16::from_be_bytes([self.cartridge.read_rom(0x14E), self.cartridge.read_rom(0x14F)]);
+        bess.info = Some((title, checksum));
+
+        let (work_ram_banks, video_ram_banks) = if self.cgb_mode { (8, 2) } else { (2, 1) };
+        let core = &mut bess.core;
+        core.work_ram = self.work_ram[..work_ram_banks].concat();
+        core.video_ram = self.video_ram[..video_ram_banks].iter()
+            .flat_map(|bank| (0x8000..=0x9FFF).map(|address| bank.read(address)))
+            .collect();
+        core.object_attribute_memory = (0..0xA0).map(|address| self.object_attribute_memory.read(address)).collect();
+        core.high_ram = self.high_ram[..0x7F].to_vec();
+        if self.cgb_mode {
+            core.bg_palettes = self.bg_palettes.bytes().to_vec();
+            core.obj_palettes = self.obj_palettes.bytes().to_vec();
+        }
+        core.interrupt_enable = self.interrupt_enable_register;
+        for (idx, value) in core.io_registers.iter_mut().enumerate() {
+            let address = 0xFF00 + idx as u16;
+            *value = match address {
+                0xFF46 => self.input_output_registers[0x46],
+                0xFF50 => if self.boot_rom.is_some() { 0x00 } else { 0xFF },
+                // sound registers as last written, most of their bits cannot be read back
+                0xFF10 ..= 0xFF25 | 0xFF27 ..= 0xFF3F => self.audio_registers.get(address),
+                VramDma::HDMA1 ..= VramDma::HDMA4 => self.vram_dma.address_registers()[(address - VramDma::HDMA1) as usize],
+                _ => self.read_register(address),
+            };
+        }
+        self.cartridge.export_bess(bess);
+    }
+
+    /// Takes over the memory buffers and I/O page of `bess`. Registers are set without their side effects,
+    /// an OAM DMA or VRAM DMA in progress is not resumed.
+    pub(crate) fn import_bess(&mut self, bess: &Bess) {
+        let core = &bess.core;
+        for (bank, data) in self.work_ram.iter_mut().zip(core.work_ram.chunks(4 * 1024)) {
+            bank[..data.len()].copy_from_slice(data);
+        }
+        for (bank, data) in self.video_ram.iter_mut().zip(core.video_ram.chunks(0x2000)) {
+            for (address, value) in (0x8000..).zip(data) {
+                bank.write(address, *value);
+            }
+        }
+        for (address, value) in (0..0xA0).zip(&core.object_attribute_memory) {
+            self.object_attribute_memory.write(address, *value);
+        }
+        let high_ram = core.high_ram.len().min(0x7F);
+        self.high_ram[..high_ram].copy_from_slice(&core.high_ram[..high_ram]);
+        self.bg_palettes.set_bytes(&core.bg_palettes);
+        self.obj_palettes.set_bytes(&core.obj_palettes);
+        self.interrupt_enable_register = core.interrupt_enable;
+
+        self.vram_dma = VramDma::new();
+        for (idx, &value) in core.io_registers.iter().enumerate() {
+            let address = 0xFF00 + idx as u16;
+            match address {
+                0xFF00 => self.joypad.write(value),
+                Timer::DIV => self.timer.set_counter((value as u16) << 8),
+                Timer::TIMA ..= Timer::TAC => {
+                    self.timer.write(address, value);
+                }
+                AudioRegisters::NR52 => {
+                    let registers = &core.io_registers[0x10..0x40];
+                    self.audio_registers.restore(registers, value);
+                }
+                0xFF10 ..= 0xFF3F => {}
+                0xFF40 => self.lcdc.set_flags(value),
+                0xFF41 => self.input_output_registers[0x41] = value & 0x7F,
+                0xFF4D => {
+                    self.double_speed = self.cgb_mode && value & 0x80 != 0;
+                    self.speed_switch_armed = value & 0x01 != 0;
+                }
+                0xFF4F if self.cgb_mode => self.video_ram_bank = (value & 0x01) as usize,
+                0xFF50 => {
+                    if value != 0 {
+                        self.boot_rom = None;
+                    }
+                }
+                VramDma::HDMA1 ..= VramDma::HDMA4 => self.vram_dma.write(address, value),
+                0xFF68 => self.bg_palettes.write_specification(value),
+                0xFF6A => self.obj_palettes.write_specification(value),
+                0xFF70 if self.cgb_mode => self.work_ram_bank = ((value & 0x07) as usize).max(1),
+                VramDma::HDMA5 | 0xFF69 | 0xFF6B | 0xFF4F | 0xFF70 => {}
+                _ => self.input_output_registers[idx] = value,
+            }
+        }
+        self.hblank_dma_pending = false;
+        self.dma_stall_cycles = 0;
+        self.cartridge.import_bess(bess);
+    }
+
+    pub(crate) fn rom_checksum(&self) -> u16 {
+        self.cartridge.rom_checksum()
+    }
+
+    /// Writes the MEM and CART sections, and the SGB one on a Super Game Boy.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"MEM ", |state| {
+            state.write_bool(self.boot_rom.is_some());
+            state.write_bool(self.cgb_mode);
+            for bank in &self.video_ram {
+                for address in 0x8000..=0x9FFF {
+                    state.write_u8(bank.read(address));
+                }
+            }
+            state.write_u8(self.video_ram_bank as u8);
+            for bank in &self.work_ram {
+                state.write_bytes(bank);
+            }
+            state.write_u8(self.work_ram_bank as u8);
+            for address in 0..0xA0 {
+                state.write_u8(self.object_attribute_memory.read(address));
+            }
+            self.vram_dma.save_state(state);
+            state.write_bool(self.hblank_dma_pending);
+            state.write_i32(self.dma_stall_cycles);
+            self.joypad.save_state(state);
+            self.timer.save_state(state);
+            state.write_u8(self.lcdc.flags());
+            self.bg_palettes.save_state(state);
+            self.obj_palettes.save_state(state);
+            self.audio_registers.save_state(state);
+            state.write_bytes(&self.input_output_registers);
+            state.write_bytes(&self.high_ram);
+            state.write_u8(self.interrupt_enable_register);
+            state.write_bool(self.speed_switch_armed);
+            state.write_bool(self.double_speed);
+        });
+        state.section(b"CART", |state| self.cartridge.save_state(state));
+        if let Some(sgb) = &self.sgb {
+            state.section(b"SGB ", |state| sgb.save_state(state));
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, sections: &mut Sections) -> Result<(), StateError> {
+        let state = sections.get(b"MEM ")?;
+        // the boot ROM image is not part of the state, it can only stay mapped if it was loaded
+        match (state.read_bool()?, self.boot_rom.is_some()) {
+            (false, _) => self.boot_rom = None,
+            (true, true) => {}
+            (true, false) => return Err(StateError::Invalid("boot ROM mapping, the state was made while booting")),
+        }
+        self.cgb_mode = state.read_bool()?;
+        for bank in &mut self.video_ram {
+            for address in 0x8000..=0x9FFF {
+                bank.write(address, state.read_u8()?);
+            }
+        }
+        self.video_ram_bank = (state.read_u8()? & 0x01) as usize;
+        for bank in &mut self.work_ram {
+            state.read_bytes(bank)?;
+        }
+        self.work_ram_bank = (state.read_u8()? & 0x07).max(1) as usize;
+        for address in 0..0xA0 {
+            self.object_attribute_memory.write(address, state.read_u8()?);
+        }
+        self.vram_dma.load_state(state)?;
+        self.hblank_dma_pending = state.read_bool()?;
+        self.dma_stall_cycles = state.read_i32()?;
+        self.joypad.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.lcdc.set_flags(state.read_u8()?);
+        self.bg_palettes.load_state(state)?;
+        self.obj_palettes.load_state(state)?;
+        self.audio_registers.load_state(state)?;
+        state.read_bytes(&mut self.input_output_registers)?;
+        state.read_bytes(&mut self.high_ram)?;
+        self.interrupt_enable_register = state.read_u8()?;
+        self.speed_switch_armed = state.read_bool()?;
+        self.double_speed = state.read_bool()?;
+
+        self.cartridge.load_state(sections.get(b"CART")?)?;
+        if let Some(sgb) = &mut self.sgb {
+            sgb.load_state(sections.get(b"SGB ")?)?;
+        }
+        Ok(())
+    }
+}
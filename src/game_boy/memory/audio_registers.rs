@@ -0,0 +1,149 @@
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// Sound registers NR10-NR52 (0xFF10-0xFF26) and wave RAM (0xFF30-0xFF3F).
+/// Writes that have side effects on the channels (trigger, length reload)
+/// are latched here and picked up by the APU on its next step.
+pub struct AudioRegisters {
+    registers: [u8; 0x30],
+    channel_status: u8,
+    triggered: u8,
+    length_written: u8,
+}
+
+impl AudioRegisters {
+    pub const NR10: u16 = 0xFF10;
+    pub const NR11: u16 = 0xFF11;
+    pub const NR12: u16 = 0xFF12;
+    pub const NR13: u16 = 0xFF13;
+    pub const NR14: u16 = 0xFF14;
+    pub const NR21: u16 = 0xFF16;
+    pub const NR22: u16 = 0xFF17;
+    pub const NR23: u16 = 0xFF18;
+    pub const NR24: u16 = 0xFF19;
+    pub const NR30: u16 = 0xFF1A;
+    pub const NR31: u16 = 0xFF1B;
+    pub const NR32: u16 = 0xFF1C;
+    pub const NR33: u16 = 0xFF1D;
+    pub const NR34: u16 = 0xFF1E;
+    pub const NR41: u16 = 0xFF20;
+    pub const NR42: u16 = 0xFF21;
+    pub const NR43: u16 = 0xFF22;
+    pub const NR44: u16 = 0xFF23;
+    pub const NR50: u16 = 0xFF24;
+    pub const NR51: u16 = 0xFF25;
+    pub const NR52: u16 = 0xFF26;
+
+    /// Bits that always read back as 1, indexed from 0xFF10.
+    const READ_MASKS: [u8; 0x20] = [
+        0x80, 0x3F, 0x00, 0xFF, 0xBF,
+        0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+        0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+        0xFF, 0xFF, 0x00, 0x00, 0xBF,
+        0x00, 0x00, 0x70,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    ];
+
+    pub fn new() -> AudioRegisters {
+        AudioRegisters { registers: [0; 0x30], channel_status: 0, triggered: 0, length_written: 0 }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_bytes(&[self.channel_status, self.triggered, self.length_written]);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.registers)?;
+        let mut bytes = [0; 3];
+        state.read_bytes(&mut bytes)?;
+        [self.channel_status, self.triggered, self.length_written] = bytes;
+        Ok(())
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        let local_address = (address - Self::NR10) as usize;
+        match address {
+            Self::NR52 => (self.registers[local_address] & 0x80) | Self::READ_MASKS[local_address] | self.channel_status,
+            0xFF10 ..= 0xFF2F => self.registers[local_address] | Self::READ_MASKS[local_address],
+            0xFF30 ..= 0xFF3F => self.registers[local_address],
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        let local_address = (address - Self::NR10) as usize;
+        match address {
+            Self::NR52 => {
+                if value & 0x80 == 0 {
+                    // turning the APU off clears every sound register except wave RAM
+                    self.registers[..local_address].fill(0);
+                    self.channel_status = 0;
+                }
+                self.registers[local_address] = value & 0x80;
+            }
+            0xFF30 ..= 0xFF3F => self.registers[local_address] = value,
+            0xFF10 ..= 0xFF2F => {
+                if !self.is_powered_on() {
+                    return;
+                }
+                self.registers[local_address] = value;
+
+                match address {
+                    Self::NR11 => self.length_written |= 1 << 0,
+                    Self::NR21 => self.length_written |= 1 << 1,
+                    Self::NR31 => self.length_written |= 1 << 2,
+                    Self::NR41 => self.length_written |= 1 << 3,
+                    Self::NR14 if value & 0x80 != 0 => self.triggered |= 1 << 0,
+                    Self::NR24 if value & 0x80 != 0 => self.triggered |= 1 << 1,
+                    Self::NR34 if value & 0x80 != 0 => self.triggered |= 1 << 2,
+                    Self::NR44 if value & 0x80 != 0 => self.triggered |= 1 << 3,
+                    _ => {}
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Raw register value, without the read masks applied.
+    pub fn get(&self, address: u16) -> u8 {
+        self.registers[(address - Self::NR10) as usize]
+    }
+
+    /// Writes a register without any of the side effects of a CPU write.
+    pub fn set(&mut self, address: u16, value: u8) {
+        self.registers[(address - Self::NR10) as usize] = value;
+    }
+
+    pub fn wave_ram(&self) -> &[u8] {
+        &self.registers[0x20..0x30]
+    }
+
+    pub fn is_powered_on(&self) -> bool {
+        self.registers[(Self::NR52 - Self::NR10) as usize] & 0x80 != 0
+    }
+
+    /// Channels triggered since the last call, as a bitmask with channel 1 in bit 0.
+    pub fn take_triggered(&mut self) -> u8 {
+        std::mem::take(&mut self.triggered)
+    }
+
+    /// Channels whose length timer was reloaded since the last call.
+    pub fn take_length_written(&mut self) -> u8 {
+        std::mem::take(&mut self.length_written)
+    }
+
+    /// Takes over the register values of another emulator's state. The channels that were playing
+    /// are restarted, their lengths and envelopes start over.
+    pub(crate) fn restore(&mut self, registers: &[u8], channel_status: u8) {
+        self.registers.copy_from_slice(registers);
+        self.registers[(Self::NR52 - Self::NR10) as usize] &= 0x80;
+        self.channel_status = channel_status & 0x0F;
+        self.triggered = self.channel_status;
+        self.length_written = 0x0F;
+    }
+
+    /// Updates the read-only channel status bits of NR52.
+    pub fn set_channel_status(&mut self, status: u8) {
+        self.channel_status = status & 0x0F;
+    }
+}
@@ -0,0 +1,400 @@
+use crate::game_boy::LoadError;
+use crate::game_boy::bess::Bess;
+use crate::game_boy::cartridge_header::CartridgeHeader;
+use crate::game_boy::memory::cartridge::real_time_clock::RealTimeClock;
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+pub(crate) mod real_time_clock;
+
+const ROM_BANK_SIZE: usize = 16 * 1024;
+const RAM_BANK_SIZE: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MbcKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+/// The hardware a cartridge header asks for.
+pub(crate) struct Layout {
+    kind: MbcKind,
+    has_battery: bool,
+    has_rtc: bool,
+    ram_size: usize,
+}
+
+/// Cartridge ROM and RAM behind the memory bank controller selected by the header.
+pub(crate) struct Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    kind: MbcKind,
+    has_battery: bool,
+
+    ram_enabled: bool,
+    rom_bank: u16,
+    /// RAM bank, the upper ROM bank bits on MBC1, or an RTC register on MBC3.
+    ram_bank: u8,
+    /// MBC1 only: applies `ram_bank` to RAM and to the 0x0000-0x3FFF ROM area.
+    advanced_banking: bool,
+    rtc: Option<RealTimeClock>,
+}
+
+impl Cartridge {
+    pub(crate) fn new() -> Cartridge {
+        Cartridge::with_layout(vec![0; 2 * ROM_BANK_SIZE], Layout { kind: MbcKind::None, has_battery: false, has_rtc: false, ram_size: 0 })
+    }
+
+    pub(crate) fn from_rom(rom: Vec<u8>) -> Result<Cartridge, LoadError> {
+        let layout = Cartridge::layout(&rom)?;
+        Ok(Cartridge::with_layout(rom, layout))
+    }
+
+    /// What the header asks for, or why this emulator cannot run `rom`.
+    pub(crate) fn layout(rom: &[u8]) -> Result<Layout, LoadError> {
+        if rom.len() < 2 * ROM_BANK_SIZE {
+            return Err(LoadError::RomTooSmall(rom.len()));
+        }
+        let cartridge_type = rom[0x147];
+        let (kind, has_battery, has_rtc) = match cartridge_type {
+            0x00 | 0x08 => (MbcKind::None, false, false),
+            0x09 => (MbcKind::None, true, false),
+            0x01 | 0x02 => (MbcKind::Mbc1, false, false),
+            0x03 => (MbcKind::Mbc1, true, false),
+            0x05 => (MbcKind::Mbc2, false, false),
+            0x06 => (MbcKind::Mbc2, true, false),
+            0x0F | 0x10 => (MbcKind::Mbc3, true, true),
+            0x11 | 0x12 => (MbcKind::Mbc3, false, false),
+            0x13 => (MbcKind::Mbc3, true, false),
+            0x19 | 0x1A | 0x1C | 0x1D => (MbcKind::Mbc5, false, false),
+            0x1B | 0x1E => (MbcKind::Mbc5, true, false),
+            _ => return Err(LoadError::UnsupportedCartridgeType(cartridge_type)),
+        };
+
+        let ram_size = match kind {
+            // MBC2 has 512 half-bytes of RAM built in
+            MbcKind::Mbc2 => 512,
+            _ => match rom[0x149] {
+                0 | 1 => 0,
+                2 => RAM_BANK_SIZE,
+                3 => 4 * RAM_BANK_SIZE,
+                4 => 16 * RAM_BANK_SIZE,
+                5 => 8 * RAM_BANK_SIZE,
+                code => return Err(LoadError::InvalidRamSize(code)),
+            },
+        };
+
+        Ok(Layout { kind, has_battery, has_rtc, ram_size })
+    }
+
+    fn with_layout(rom: Vec<u8>, layout: Layout) -> Cartridge {
+        Cartridge {
+            rom,
+            ram: vec![0; layout.ram_size],
+            kind: layout.kind,
+            has_battery: layout.has_battery,
+            ram_enabled: layout.kind == MbcKind::None,
+            rom_bank: 1,
+            ram_bank: 0,
+            advanced_banking: false,
+            rtc: if layout.has_rtc { Some(RealTimeClock::new()) } else { None },
+        }
+    }
+
+    pub(crate) fn has_battery(&self) -> bool {
+        self.has_battery
+    }
+
+    /// Global checksum of the ROM, which save states use to tell ROMs apart.
+    pub(crate) fn rom_checksum(&self) -> u16 {
+        CartridgeHeader::calculate_global_checksum(&self.rom)
+    }
+
+    /// See `RealTimeClock::set_emulated_time`, does nothing without a clock.
+    pub(crate) fn set_rtc_emulated_time(&mut self, seconds: u64) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.set_emulated_time(seconds);
+        }
+    }
+
+    pub(crate) fn step_rtc(&mut self, dots: i32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.step(dots);
+        }
+    }
+
+    /// The banking state and RAM. The ROM is not part of it, states are loaded on top of the same cartridge.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_blob(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u16(self.rom_bank);
+        state.write_u8(self.ram_bank);
+        state.write_bool(self.advanced_banking);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(state);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let ram = state.read_blob()?;
+        if ram.len() != self.ram.len() {
+            return Err(StateError::Invalid("cartridge RAM size"));
+        }
+        self.ram.copy_from_slice(ram);
+        self.ram_enabled = state.read_bool()?;
+        self.rom_bank = state.read_u16()?;
+        self.ram_bank = state.read_u8()?;
+        self.advanced_banking = state.read_bool()?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(state)?;
+        }
+        Ok(())
+    }
+
+    /// Describes the bank controller as the writes that put it into its current state.
+    pub(crate) fn export_bess(&mut self, bess: &mut Bess) {
+        let ram_enable = if self.ram_enabled { 0x0A } else { 0x00 };
+        bess.mbc_writes = match self.kind {
+            MbcKind::None => Vec::new(),
+            MbcKind::Mbc1 => vec![
+                (0x0000, ram_enable), (0x2000, self.rom_bank as u8), (0x4000, self.ram_bank), (0x6000, self.advanced_banking as u8),
+            ],
+            MbcKind::Mbc2 => vec![(0x0000, ram_enable), (0x0100, self.rom_bank as u8)],
+            MbcKind::Mbc3 => vec![(0x0000, ram_enable), (0x2000, self.rom_bank as u8), (0x4000, self.ram_bank)],
+            MbcKind::Mbc5 => vec![
+                (0x0000, ram_enable), (0x2000, self.rom_bank as u8), (0x3000, (self.rom_bank >> 8) as u8), (0x4000, self.ram_bank),
+            ],
+        };
+        bess.core.cartridge_ram = self.ram.clone();
+        bess.rtc = self.rtc.as_mut().map(|rtc| rtc.save_bytes());
+    }
+
+    /// Takes as much RAM as both sides have and replays the bank controller writes.
+    pub(crate) fn import_bess(&mut self, bess: &Bess) {
+        let ram = &bess.core.cartridge_ram;
+        let length = self.ram.len().min(ram.len());
+        self.ram[..length].copy_from_slice(&ram[..length]);
+        for &(address, value) in &bess.mbc_writes {
+            if address < 0x8000 {
+                self.write_rom(address, value);
+            }
+        }
+        if let Some(rtc) = &mut self.rtc
+            && let Some(bytes) = &bess.rtc {
+            rtc.load_save_bytes(bytes);
+        }
+    }
+
+    fn read_rom_bank(&self, bank: usize, offset: u16) -> u8 {
+        self.rom[(bank * ROM_BANK_SIZE + offset as usize) % self.rom.len()]
+    }
+
+    /// Where in the ROM a read of `address` in 0x0000-0x7FFF ends up.
+    pub(crate) fn rom_offset(&self, address: u16) -> usize {
+        (self.rom_bank(address) * ROM_BANK_SIZE + (address & 0x3FFF) as usize) % self.rom.len()
+    }
+
+    /// Bank mapped to 0x4000-0x7FFF.
+    pub(crate) fn current_rom_bank(&self) -> usize {
+        match self.kind {
+            MbcKind::None => 1,
+            MbcKind::Mbc1 => {
+                let low = (self.rom_bank & 0x1F).max(1) as usize;
+                low | ((self.ram_bank as usize & 0x03) << 5)
+            }
+            MbcKind::Mbc2 => (self.rom_bank & 0x0F).max(1) as usize,
+            MbcKind::Mbc3 => (self.rom_bank & 0x7F).max(1) as usize,
+            MbcKind::Mbc5 => (self.rom_bank & 0x1FF) as usize,
+        }
+    }
+
+    /// Bank mapped at `address`, which has to be in 0x0000-0x7FFF.
+    pub(crate) fn rom_bank(&self, address: u16) -> usize {
+        match address {
+            0x0000 ..= 0x3FFF if self.kind == MbcKind::Mbc1 && self.advanced_banking => (self.ram_bank as usize & 0x03) << 5,
+            0x0000 ..= 0x3FFF => 0,
+            0x4000 ..= 0x7FFF => self.current_rom_bank(),
+            _ => unreachable!(),
+        }
+    }
+
+    pub(crate) fn read_rom(&self, address: u16) -> u8 {
+        self.read_rom_bank(self.rom_bank(address), address & 0x3FFF)
+    }
+
+    /// Writes to the ROM area control the memory bank controller.
+    pub(crate) fn write_rom(&mut self, address: u16, value: u8) {
+        match (self.kind, address) {
+            (MbcKind::None, _) => {}
+            (MbcKind::Mbc2, 0x0000 ..= 0x3FFF) => {
+                // address bit 8 selects between RAM enable and ROM bank
+                if address & 0x0100 == 0 {
+                    self.ram_enabled = value & 0x0F == 0x0A;
+                } else {
+                    self.rom_bank = (value & 0x0F) as u16;
+                }
+            }
+            (MbcKind::Mbc2, _) => {}
+            (_, 0x0000 ..= 0x1FFF) => self.ram_enabled = value & 0x0F == 0x0A,
+            (MbcKind::Mbc1, 0x2000 ..= 0x3FFF) => self.rom_bank = (value & 0x1F) as u16,
+            (MbcKind::Mbc3, 0x2000 ..= 0x3FFF) => self.rom_bank = (value & 0x7F) as u16,
+            (MbcKind::Mbc5, 0x2000 ..= 0x2FFF) => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            (MbcKind::Mbc5, 0x3000 ..= 0x3FFF) => self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 1) << 8),
+            (MbcKind::Mbc1, 0x4000 ..= 0x5FFF) => self.ram_bank = value & 0x03,
+            (MbcKind::Mbc3, 0x4000 ..= 0x5FFF) => self.ram_bank = value & 0x0F,
+            (MbcKind::Mbc5, 0x4000 ..= 0x5FFF) => self.ram_bank = value & 0x0F,
+            (MbcKind::Mbc1, 0x6000 ..= 0x7FFF) => self.advanced_banking = value & 0x01 != 0,
+            (MbcKind::Mbc3, 0x6000 ..= 0x7FFF) => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ram_address(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let offset = (address - 0xA000) as usize;
+        Some((self.current_ram_bank() * RAM_BANK_SIZE + offset) % self.ram.len())
+    }
+
+    /// RAM bank mapped to 0xA000-0xBFFF.
+    pub(crate) fn current_ram_bank(&self) -> usize {
+        match self.kind {
+            MbcKind::None | MbcKind::Mbc2 => 0,
+            MbcKind::Mbc1 => if self.advanced_banking { self.ram_bank as usize } else { 0 },
+            MbcKind::Mbc3 => (self.ram_bank & 0x03) as usize,
+            MbcKind::Mbc5 => self.ram_bank as usize,
+        }
+    }
+
+    fn is_rtc_selected(&self) -> bool {
+        self.rtc.is_some() && (0x08..=0x0C).contains(&self.ram_bank)
+    }
+
+    pub(crate) fn read_ram(&self, address: u16) -> u8 {
+        if self.ram_enabled && self.is_rtc_selected() {
+            return self.rtc.as_ref().unwrap().read(self.ram_bank);
+        }
+        match self.ram_address(address) {
+            // only the lower nibble of MBC2 RAM exists
+            Some(idx) if self.kind == MbcKind::Mbc2 => self.ram[idx] | 0xF0,
+            Some(idx) => self.ram[idx],
+            None => 0xFF,
+        }
+    }
+
+    pub(crate) fn write_ram(&mut self, address: u16, value: u8) {
+        if self.ram_enabled && self.is_rtc_selected() {
+            let register = self.ram_bank;
+            self.rtc.as_mut().unwrap().write(register, value);
+            return;
+        }
+        if let Some(idx) = self.ram_address(address) {
+            self.ram[idx] = value;
+        }
+    }
+
+    /// Battery-backed RAM followed by the RTC state, in the .sav layout other emulators use.
+    pub(crate) fn save_data(&mut self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = &mut self.rtc {
+            data.extend(rtc.save_bytes());
+        }
+        data
+    }
+
+    pub(crate) fn load_save_data(&mut self, data: &[u8]) {
+        let ram_length = self.ram.len().min(data.len());
+        self.ram[..ram_length].copy_from_slice(&data[..ram_length]);
+
+        if let Some(rtc) = &mut self.rtc
+            && data.len() >= self.ram.len() + real_time_clock::SAVE_LENGTH {
+            rtc.load_save_bytes(&data[self.ram.len()..]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(cartridge_type: u8, banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom[0x147] = cartridge_type;
+        rom[0x149] = 3;
+        rom
+    }
+
+    #[test]
+    fn mbc1_switches_rom_banks() {
+        let mut cartridge = Cartridge::from_rom(rom(0x01, 64)).unwrap();
+        assert_eq!(cartridge.read_rom(0x4000), 1);
+
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(cartridge.read_rom(0x4000), 1);
+
+        cartridge.write_rom(0x2000, 0x05);
+        cartridge.write_rom(0x4000, 0x01);
+        assert_eq!(cartridge.read_rom(0x4000), 0x25);
+        assert_eq!(cartridge.read_rom(0x0000), 0);
+
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(cartridge.read_rom(0x0000), 0x20);
+    }
+
+    #[test]
+    fn ram_is_disabled_until_enabled() {
+        let mut cartridge = Cartridge::from_rom(rom(0x1B, 4)).unwrap();
+        cartridge.write_ram(0xA000, 0x42);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x02);
+        cartridge.write_ram(0xA000, 0x42);
+        assert_eq!(cartridge.read_ram(0xA000), 0x42);
+        assert_eq!(cartridge.save_data()[2 * RAM_BANK_SIZE], 0x42);
+    }
+
+    #[test]
+    fn mbc3_rtc_is_saved_after_ram() {
+        let mut cartridge = Cartridge::from_rom(rom(0x10, 4)).unwrap();
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x09);
+        cartridge.write_ram(0xA000, 42);
+        cartridge.write_rom(0x6000, 0x00);
+        cartridge.write_rom(0x6000, 0x01);
+        assert!(cartridge.read_ram(0xA000) >= 42);
+
+        let data = cartridge.save_data();
+        assert_eq!(data.len(), 4 * RAM_BANK_SIZE + 48);
+
+        let mut restored = Cartridge::from_rom(rom(0x10, 4)).unwrap();
+        restored.load_save_data(&data);
+        restored.write_rom(0x0000, 0x0A);
+        restored.write_rom(0x4000, 0x09);
+        assert!(restored.read_ram(0xA000) >= 42);
+    }
+
+    #[test]
+    fn mbc3_rtc_follows_emulated_time() {
+        let mut cartridge = Cartridge::from_rom(rom(0x10, 4)).unwrap();
+        cartridge.set_rtc_emulated_time(1_000_000);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x08);
+        for _ in 0..90 {
+            cartridge.step_rtc(4_194_304 / 2);
+        }
+        cartridge.write_rom(0x6000, 0x00);
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(cartridge.read_ram(0xA000), 45);
+    }
+}
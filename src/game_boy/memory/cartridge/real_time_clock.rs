@@ -0,0 +1,139 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// Length of the RTC footer appended to MBC3 save files, in the layout used by VBA and BGB.
+pub(crate) const SAVE_LENGTH: usize = 48;
+/// The PPU clock, which keeps its pace in double speed like the RTC crystal.
+const DOTS_PER_SECOND: i32 = 4_194_304;
+
+/// The MBC3 real time clock. Registers 0x08-0x0C are seconds, minutes, hours,
+/// the lower 8 bits of the day counter and the upper day bit plus halt and carry flags.
+pub(crate) struct RealTimeClock {
+    registers: [u8; 5],
+    latched: [u8; 5],
+    latch_armed: bool,
+    /// Host time in seconds since the epoch at which `registers` were last brought up to date.
+    last_update: u64,
+    /// Replaces the host clock with emulated time for deterministic runs, in seconds and the dots into the next one.
+    /// Not part of save states, it belongs to whoever drives the machine.
+    emulated_time: Option<(u64, i32)>,
+}
+
+impl RealTimeClock {
+    const HALT_BIT: u8 = 1 << 6;
+    const CARRY_BIT: u8 = 1 << 7;
+
+    pub(crate) fn new() -> RealTimeClock {
+        RealTimeClock { registers: [0; 5], latched: [0; 5], latch_armed: false, last_update: Self::host_time(), emulated_time: None }
+    }
+
+    fn host_time() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+    }
+
+    fn now(&self) -> u64 {
+        self.emulated_time.map_or_else(Self::host_time, |(seconds, _)| seconds)
+    }
+
+    /// Stops following the host clock and counts emulated time from `seconds` since the epoch on.
+    /// Time that passed before does not count, as if the clock had just been brought up to date.
+    pub(crate) fn set_emulated_time(&mut self, seconds: u64) {
+        self.emulated_time = Some((seconds, 0));
+        self.last_update = seconds;
+    }
+
+    pub(crate) fn step(&mut self, dots: i32) {
+        if let Some((seconds, elapsed_dots)) = &mut self.emulated_time {
+            *elapsed_dots += dots;
+            if *elapsed_dots >= DOTS_PER_SECOND {
+                *elapsed_dots -= DOTS_PER_SECOND;
+                *seconds += 1;
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        let now = self.now();
+        let elapsed = now.saturating_sub(self.last_update);
+        self.last_update = now;
+
+        if self.registers[4] & Self::HALT_BIT == 0 {
+            self.advance(elapsed);
+        }
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        let days = ((self.registers[4] as u64 & 1) << 8) | self.registers[3] as u64;
+        let total = self.registers[0] as u64
+            + 60 * self.registers[1] as u64
+            + 3600 * self.registers[2] as u64
+            + 86400 * days
+            + seconds;
+
+        let days = total / 86400;
+        self.registers[0] = (total % 60) as u8;
+        self.registers[1] = (total / 60 % 60) as u8;
+        self.registers[2] = (total / 3600 % 24) as u8;
+        self.registers[3] = days as u8;
+        let mut flags = (self.registers[4] & (Self::HALT_BIT | Self::CARRY_BIT)) | ((days >> 8) & 1) as u8;
+        if days > 0x1FF {
+            flags |= Self::CARRY_BIT;
+        }
+        self.registers[4] = flags;
+    }
+
+    pub(crate) fn read(&self, register: u8) -> u8 {
+        self.latched[(register - 0x08) as usize]
+    }
+
+    pub(crate) fn write(&mut self, register: u8, value: u8) {
+        self.update();
+        self.registers[(register - 0x08) as usize] = value;
+    }
+
+    /// Writing 0x00 and then 0x01 to 0x6000-0x7FFF copies the clock into the readable registers.
+    pub(crate) fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.update();
+            self.latched = self.registers;
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    /// Unlike the save file footer this keeps the clock as it is, the time passed since is added on the next access.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_bytes(&self.latched);
+        state.write_bool(self.latch_armed);
+        state.write_u64(self.last_update);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.registers)?;
+        state.read_bytes(&mut self.latched)?;
+        self.latch_armed = state.read_bool()?;
+        self.last_update = state.read_u64()?;
+        Ok(())
+    }
+
+    pub(crate) fn save_bytes(&mut self) -> [u8; SAVE_LENGTH] {
+        self.update();
+
+        let mut bytes = [0; SAVE_LENGTH];
+        for (idx, value) in self.registers.iter().chain(&self.latched).enumerate() {
+            bytes[4 * idx..4 * idx + 4].copy_from_slice(&(*value as u32).to_le_bytes());
+        }
+        bytes[40..48].copy_from_slice(&self.last_update.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn load_save_bytes(&mut self, bytes: &[u8]) {
+        let word = |idx: usize| bytes[4 * idx];
+        for idx in 0..5 {
+            self.registers[idx] = word(idx);
+            self.latched[idx] = word(idx + 5);
+        }
+        self.last_update = u64::from_le_bytes(bytes[40..48].try_into().unwrap());
+        self.update();
+    }
+}
@@ -0,0 +1,87 @@
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// CGB palette memory behind BCPS/BCPD or OCPS/OCPD: 8 palettes of 4 little-endian RGB555 colors.
+pub struct ColorPaletteRam {
+    data: [u8; 64],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl ColorPaletteRam {
+    pub fn new() -> ColorPaletteRam {
+        ColorPaletteRam { data: [0; 64], index: 0, auto_increment: false }
+    }
+
+    /// BCPS/OCPS: the byte to access, with bit 7 set to advance after each data write.
+    pub fn read_specification(&self) -> u8 {
+        (self.auto_increment as u8) << 7 | 0x40 | self.index
+    }
+
+    pub fn write_specification(&mut self, value: u8) {
+        self.index = value & 0x3F;
+        self.auto_increment = value & 0x80 != 0;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    /// Reading does not advance the index, only writing does.
+    pub fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    /// RGB555 value of `color` in `palette`, red in the lowest bits.
+    pub fn color(&self, palette: u8, color: u8) -> u16 {
+        let offset = (palette as usize * 4 + color as usize) * 2;
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u8(self.read_specification());
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.data)?;
+        self.write_specification(state.read_u8()?);
+        Ok(())
+    }
+
+    /// All 64 bytes, two per RGB555 color.
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub(crate) fn set_bytes(&mut self, bytes: &[u8]) {
+        let length = self.data.len().min(bytes.len());
+        self.data[..length].copy_from_slice(&bytes[..length]);
+    }
+
+    pub fn fill(&mut self, color: u16) {
+        for pair in self.data.chunks_exact_mut(2) {
+            pair.copy_from_slice(&color.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_writes_auto_increment() {
+        let mut palettes = ColorPaletteRam::new();
+        palettes.write_specification(0x80 | 0x3E);
+        palettes.write_data(0x1F);
+        palettes.write_data(0x7C);
+        palettes.write_data(0xFF);
+
+        assert_eq!(palettes.color(7, 3), 0x7C1F);
+        assert_eq!(palettes.read_specification(), 0xC1);
+        assert_eq!(palettes.color(0, 0), 0x00FF);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    /// In the order of their bits in a button mask, see `mask`.
+    pub const ALL: [Button; 8] =
+        [Button::Right, Button::Left, Button::Up, Button::Down, Button::A, Button::B, Button::Select, Button::Start];
+
+    /// The button's bit in masks of pressed buttons, the d-pad in the lower nibble and the action buttons above.
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// The P1 register at 0xFF00. Buttons are active low, and the game selects
+/// whether the d-pad or the action buttons show up in the lower nibble.
+pub(crate) struct Joypad {
+    select: u8,
+    /// Pressed buttons, d-pad in the lower nibble and action buttons in the upper one.
+    pressed: u8,
+}
+
+impl Joypad {
+    pub(crate) fn new() -> Joypad {
+        Joypad { select: 0x30, pressed: 0 }
+    }
+
+    pub(crate) fn read(&self) -> u8 {
+        let mut lines = 0;
+        if self.select & 0x10 == 0 {
+            lines |= self.pressed & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            lines |= self.pressed >> 4;
+        }
+        0xC0 | self.select | (!lines & 0x0F)
+    }
+
+    pub(crate) fn write(&mut self, value: u8) {
+        self.select = value & 0x30;
+    }
+
+    pub(crate) fn pressed(&self) -> u8 {
+        self.pressed
+    }
+
+    /// Only the selected button group is machine state, pressed buttons belong to the host.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.select);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.select = state.read_u8()? & 0x30;
+        Ok(())
+    }
+
+    /// Returns true when a button goes from released to pressed, which requests the joypad interrupt.
+    pub(crate) fn set_pressed(&mut self, button: Button, pressed: bool) -> bool {
+        let mask = button.mask();
+        let was_pressed = self.pressed & mask != 0;
+        if pressed {
+            self.pressed |= mask;
+        } else {
+            self.pressed &= !mask;
+        }
+        pressed && !was_pressed
+    }
+}
@@ -33,4 +33,50 @@ impl Lcdc {
         self.obj_enable = flags & (1 << 1) != 0;
         self.bg_window_enable_priority = flags & (1 << 0) != 0;
     }
+
+    pub fn flags(&self) -> u8 {
+        (self.lcd_ppu_enabled as u8) << 7
+            | (self.window_tile_map_are as u8) << 6
+            | (self.window_enable as u8) << 5
+            | (self.bg_window_tile_data_area as u8) << 4
+            | (self.bg_tile_map_are as u8) << 3
+            | (self.obj_size as u8) << 2
+            | (self.obj_enable as u8) << 1
+            | self.bg_window_enable_priority as u8
+    }
+
+    pub fn is_lcd_ppu_enabled(&self) -> bool {
+        self.lcd_ppu_enabled
+    }
+
+    /// Base address of the 32x32 tile map used by the window.
+    pub fn window_tile_map_address(&self) -> u16 {
+        if self.window_tile_map_are { 0x9C00 } else { 0x9800 }
+    }
+
+    pub fn is_window_enabled(&self) -> bool {
+        self.window_enable
+    }
+
+    /// Whether BG and window tiles are indexed unsigned from 0x8000 instead of signed from 0x9000.
+    pub fn is_unsigned_tile_data_area(&self) -> bool {
+        self.bg_window_tile_data_area
+    }
+
+    /// Base address of the 32x32 tile map used by the background.
+    pub fn bg_tile_map_address(&self) -> u16 {
+        if self.bg_tile_map_are { 0x9C00 } else { 0x9800 }
+    }
+
+    pub fn obj_height(&self) -> u8 {
+        if self.obj_size { 16 } else { 8 }
+    }
+
+    pub fn is_obj_enabled(&self) -> bool {
+        self.obj_enable
+    }
+
+    pub fn is_bg_window_enabled(&self) -> bool {
+        self.bg_window_enable_priority
+    }
 }
@@ -1,17 +1,29 @@
 use crate::game_boy::memory::object_attribute_memory::object_attributes::ObjectAttributes;
 
-mod object_attributes;
+pub(crate) mod object_attributes;
 
-struct ObjectAttributeMemory {
+pub(crate) struct ObjectAttributeMemory {
     objects: Vec<ObjectAttributes>,
 }
 
 impl ObjectAttributeMemory {
-    fn new() -> ObjectAttributeMemory {
+    pub(crate) fn new() -> ObjectAttributeMemory {
         let mut objects: Vec<ObjectAttributes> = Vec::new();
         for _ in 0..40 {
             objects.push(ObjectAttributes::new());
         }
         ObjectAttributeMemory { objects }
     }
-}
\ No newline at end of file
+
+    pub(crate) fn read(&self, address: u16) -> u8 {
+        self.objects[(address / 4) as usize].read(address % 4)
+    }
+
+    pub(crate) fn write(&mut self, address: u16, value: u8) {
+        self.objects[(address / 4) as usize].write(address % 4, value);
+    }
+
+    pub(crate) fn objects(&self) -> &[ObjectAttributes] {
+        &self.objects
+    }
+}
@@ -1,14 +1,17 @@
-enum Priority {
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
     No, 
     DrawOver,
 }
 
-enum Flip {
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Flip {
     Normal,
     Mirror,
 }
 
-enum DmgPalette {
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DmgPalette {
     OBP0,
     OBP1,
 }
@@ -22,6 +25,10 @@ pub struct ObjectAttributes {
     y_flip: Flip,
     x_flip: Flip,
     dmg_palette: DmgPalette,
+    /// CGB only: VRAM bank holding the tile.
+    vram_bank: u8,
+    /// CGB only: object palette 0-7.
+    cgb_palette: u8,
 }
 
 impl ObjectAttributes {
@@ -34,6 +41,81 @@ impl ObjectAttributes {
             y_flip: Flip::Normal,
             x_flip: Flip::Normal,
             dmg_palette: DmgPalette::OBP0,
+            vram_bank: 0,
+            cgb_palette: 0,
         }
     }
-}
\ No newline at end of file
+
+    pub fn read(&self, offset: u16) -> u8 {
+        match offset {
+            0 => self.y_position,
+            1 => self.x_position,
+            2 => self.tile_index,
+            3 => {
+                let mut flags = self.vram_bank << 3 | self.cgb_palette;
+                if self.priority == Priority::DrawOver { flags |= 1 << 7; }
+                if self.y_flip == Flip::Mirror { flags |= 1 << 6; }
+                if self.x_flip == Flip::Mirror { flags |= 1 << 5; }
+                if self.dmg_palette == DmgPalette::OBP1 { flags |= 1 << 4; }
+                flags
+            }
+            _ => panic!("invalid object attribute offset {offset}"),
+        }
+    }
+
+    pub fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            0 => self.y_position = value,
+            1 => self.x_position = value,
+            2 => self.tile_index = value,
+            3 => {
+                self.priority = if value & (1 << 7) != 0 { Priority::DrawOver } else { Priority::No };
+                self.y_flip = if value & (1 << 6) != 0 { Flip::Mirror } else { Flip::Normal };
+                self.x_flip = if value & (1 << 5) != 0 { Flip::Mirror } else { Flip::Normal };
+                self.dmg_palette = if value & (1 << 4) != 0 { DmgPalette::OBP1 } else { DmgPalette::OBP0 };
+                self.vram_bank = (value >> 3) & 0x01;
+                self.cgb_palette = value & 0x07;
+            }
+            _ => panic!("invalid object attribute offset {offset}"),
+        }
+    }
+
+    /// Screen position of the top edge, which is stored offset by 16.
+    pub fn top(&self) -> i16 {
+        self.y_position as i16 - 16
+    }
+
+    /// Screen position of the left edge, which is stored offset by 8.
+    pub fn left(&self) -> i16 {
+        self.x_position as i16 - 8
+    }
+
+    pub fn tile_index(&self) -> u8 {
+        self.tile_index
+    }
+
+    /// Whether BG and window colors 1-3 are drawn over this object.
+    pub fn is_behind_background(&self) -> bool {
+        self.priority == Priority::DrawOver
+    }
+
+    pub fn y_flip(&self) -> bool {
+        self.y_flip == Flip::Mirror
+    }
+
+    pub fn x_flip(&self) -> bool {
+        self.x_flip == Flip::Mirror
+    }
+
+    pub fn dmg_palette(&self) -> DmgPalette {
+        self.dmg_palette
+    }
+
+    pub fn vram_bank(&self) -> usize {
+        self.vram_bank as usize
+    }
+
+    pub fn cgb_palette(&self) -> u8 {
+        self.cgb_palette
+    }
+}
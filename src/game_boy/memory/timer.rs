@@ -0,0 +1,121 @@
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// DIV, TIMA, TMA and TAC. DIV is the upper byte of a 16-bit counter that runs at 4 MiHz,
+/// and TIMA counts falling edges of the counter bit selected by TAC.
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+}
+
+impl Timer {
+    pub const DIV: u16 = 0xFF04;
+    pub const TIMA: u16 = 0xFF05;
+    pub const TMA: u16 = 0xFF06;
+    pub const TAC: u16 = 0xFF07;
+
+    pub fn new() -> Timer {
+        Timer { counter: 0, tima: 0, tma: 0, tac: 0 }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_bytes(&[self.tima, self.tma, self.tac]);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        let mut bytes = [0; 3];
+        state.read_bytes(&mut bytes)?;
+        [self.tima, self.tma, self.tac] = bytes;
+        Ok(())
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            Self::DIV => (self.counter >> 8) as u8,
+            Self::TIMA => self.tima,
+            Self::TMA => self.tma,
+            Self::TAC => self.tac | 0xF8,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns whether the write overflowed TIMA, which happens when the selected
+    /// counter bit falls because DIV was reset or the timer was disabled.
+    pub fn write(&mut self, address: u16, value: u8) -> bool {
+        let old_signal = self.signal();
+        match address {
+            Self::DIV => self.counter = 0,
+            Self::TIMA => self.tima = value,
+            Self::TMA => self.tma = value,
+            Self::TAC => self.tac = value & 0x07,
+            _ => unreachable!(),
+        }
+
+        old_signal && !self.signal() && self.increment_tima()
+    }
+
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    /// Advances by `cycles` machine cycles and returns whether TIMA overflowed.
+    pub fn step(&mut self, cycles: i32) -> bool {
+        let mut overflowed = false;
+        for _ in 0..cycles {
+            let old_signal = self.signal();
+            self.counter = self.counter.wrapping_add(4);
+            if old_signal && !self.signal() {
+                overflowed |= self.increment_tima();
+            }
+        }
+        overflowed
+    }
+
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.tac & 0x04 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn increment_tima(&mut self) -> bool {
+        let (tima, overflowed) = self.tima.overflowing_add(1);
+        self.tima = if overflowed { self.tma } else { tima };
+        overflowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tima_counts_at_selected_rate_and_reloads() {
+        let mut timer = Timer::new();
+        timer.write(Timer::TMA, 0xF0);
+        timer.write(Timer::TIMA, 0xFE);
+        // 262144 Hz, every 4 machine cycles
+        timer.write(Timer::TAC, 0x05);
+
+        assert!(!timer.step(4));
+        assert_eq!(timer.read(Timer::TIMA), 0xFF);
+        assert!(timer.step(4));
+        assert_eq!(timer.read(Timer::TIMA), 0xF0);
+    }
+
+    #[test]
+    fn div_write_resets_counter() {
+        let mut timer = Timer::new();
+        timer.step(1000);
+        assert_eq!(timer.read(Timer::DIV), (4000 >> 8) as u8);
+
+        timer.write(Timer::DIV, 0x12);
+        assert_eq!(timer.read(Timer::DIV), 0);
+    }
+}
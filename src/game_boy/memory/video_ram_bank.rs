@@ -0,0 +1,63 @@
+use crate::game_boy::memory::vram_tile_data::tile::Tile;
+use crate::game_boy::memory::vram_tile_data::VramTileData;
+
+/// 8 KiB of VRAM at 0x8000-0x9FFF. The DMG has one bank, the CGB a second one
+/// that holds more tiles and, in place of the tile maps, the BG map attributes.
+pub struct VideoRamBank {
+    tile_data_block0: VramTileData,
+    tile_data_block1: VramTileData,
+    tile_data_block2: VramTileData,
+
+    tile_maps: [u8; 2 * 1024],
+}
+
+impl VideoRamBank {
+    pub fn new() -> VideoRamBank {
+        VideoRamBank {
+            tile_data_block0: VramTileData::new(),
+            tile_data_block1: VramTileData::new(),
+            tile_data_block2: VramTileData::new(),
+            tile_maps: [0; 2 * 1024],
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0x8000 ..= 0x87FF => self.tile_data_block0.read(address - 0x8000),
+            0x8800 ..= 0x8FFF => self.tile_data_block1.read(address - 0x8800),
+            0x9000 ..= 0x97FF => self.tile_data_block2.read(address - 0x9000),
+            0x9800 ..= 0x9FFF => self.tile_maps[(address - 0x9800) as usize],
+
+            _ => unreachable!()
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000 ..= 0x87FF => self.tile_data_block0.write(address - 0x8000, value),
+            0x8800 ..= 0x8FFF => self.tile_data_block1.write(address - 0x8800, value),
+            0x9000 ..= 0x97FF => self.tile_data_block2.write(address - 0x9000, value),
+            0x9800 ..= 0x9FFF => self.tile_maps[(address - 0x9800) as usize] = value,
+
+            _ => unreachable!()
+        }
+    }
+
+    /// Tile `idx` as addressed by objects, or by the BG and window when LCDC.4 is set.
+    pub fn unsigned_tile(&self, idx: u8) -> &Tile {
+        if idx < 128 {
+            self.tile_data_block0.tile(idx)
+        } else {
+            self.tile_data_block1.tile(idx)
+        }
+    }
+
+    /// Tile `idx` as addressed by the BG and window when LCDC.4 is clear.
+    pub fn signed_tile(&self, idx: u8) -> &Tile {
+        if idx < 128 {
+            self.tile_data_block2.tile(idx)
+        } else {
+            self.tile_data_block1.tile(idx)
+        }
+    }
+}
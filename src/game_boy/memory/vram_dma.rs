@@ -0,0 +1,150 @@
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VramDmaMode {
+    Idle,
+    /// Copies everything at once while the CPU waits.
+    General,
+    /// Copies one block at the start of every HBlank.
+    HBlank,
+}
+
+/// HDMA1-HDMA5, the CGB DMA that copies 16-byte blocks from ROM or RAM into VRAM.
+/// The copying itself is up to `Memory`, this keeps track of addresses and remaining blocks.
+pub struct VramDma {
+    source: u16,
+    /// Offset into VRAM, the transfer wraps around at its end.
+    destination: u16,
+    remaining_blocks: u8,
+    mode: VramDmaMode,
+}
+
+impl VramDma {
+    pub const HDMA1: u16 = 0xFF51;
+    pub const HDMA2: u16 = 0xFF52;
+    pub const HDMA3: u16 = 0xFF53;
+    pub const HDMA4: u16 = 0xFF54;
+    pub const HDMA5: u16 = 0xFF55;
+
+    pub const BLOCK_SIZE: u16 = 16;
+
+    pub fn new() -> VramDma {
+        VramDma { source: 0, destination: 0, remaining_blocks: 0, mode: VramDmaMode::Idle }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.source);
+        state.write_u16(self.destination);
+        state.write_u8(self.remaining_blocks);
+        state.write_u8(self.mode as u8);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.read_u16()?;
+        self.destination = state.read_u16()? & 0x1FF0;
+        self.remaining_blocks = state.read_u8()?;
+        self.mode = match state.read_u8()? {
+            0 => VramDmaMode::Idle,
+            1 => VramDmaMode::General,
+            2 => VramDmaMode::HBlank,
+            _ => return Err(StateError::Invalid("VRAM DMA mode")),
+        };
+        // HDMA5 starts at most 128 blocks, and a running transfer has at least one left
+        if self.remaining_blocks > 0x80 || (self.remaining_blocks == 0 && self.mode != VramDmaMode::Idle) {
+            return Err(StateError::Invalid("number of VRAM DMA blocks"));
+        }
+        Ok(())
+    }
+
+    /// HDMA1-HDMA4 as last written, for formats that store them.
+    pub(crate) fn address_registers(&self) -> [u8; 4] {
+        let destination = self.destination | 0x8000;
+        [(self.source >> 8) as u8, self.source as u8, (destination >> 8) as u8, destination as u8]
+    }
+
+    pub fn mode(&self) -> VramDmaMode {
+        self.mode
+    }
+
+    /// HDMA5: bit 7 clear while a transfer is running, and the number of blocks left minus one.
+    /// Reads 0xFF once a transfer is done.
+    pub fn read_status(&self) -> u8 {
+        let active = self.mode != VramDmaMode::Idle;
+        (!active as u8) << 7 | (self.remaining_blocks.wrapping_sub(1) & 0x7F)
+    }
+
+    /// HDMA1-HDMA4 are write-only and HDMA5 starts a transfer, or cancels a running HBlank one
+    /// when bit 7 is clear.
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            Self::HDMA1 => self.source = (self.source & 0x00FF) | (value as u16) << 8,
+            Self::HDMA2 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            Self::HDMA3 => self.destination = (self.destination & 0x00FF) | ((value & 0x1F) as u16) << 8,
+            Self::HDMA4 => self.destination = (self.destination & 0x1F00) | (value & 0xF0) as u16,
+            Self::HDMA5 => {
+                if self.mode == VramDmaMode::HBlank && value & 0x80 == 0 {
+                    self.mode = VramDmaMode::Idle;
+                    return;
+                }
+                self.remaining_blocks = (value & 0x7F) + 1;
+                self.mode = if value & 0x80 != 0 { VramDmaMode::HBlank } else { VramDmaMode::General };
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Source address and VRAM offset of the next block. Advances past it and ends the transfer after the last one.
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, self.destination);
+        self.source = self.source.wrapping_add(Self::BLOCK_SIZE);
+        self.destination = (self.destination + Self::BLOCK_SIZE) & 0x1FF0;
+        self.remaining_blocks -= 1;
+        if self.remaining_blocks == 0 {
+            self.mode = VramDmaMode::Idle;
+        }
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hblank_transfer_reports_progress_and_can_be_cancelled() {
+        let mut dma = VramDma::new();
+        dma.write(VramDma::HDMA1, 0x12);
+        dma.write(VramDma::HDMA2, 0x3F);
+        dma.write(VramDma::HDMA3, 0xFF);
+        dma.write(VramDma::HDMA4, 0xF0);
+        dma.write(VramDma::HDMA5, 0x82);
+        assert_eq!(dma.read_status(), 0x02);
+
+        assert_eq!(dma.next_block(), (0x1230, 0x1FF0));
+        assert_eq!(dma.next_block(), (0x1240, 0x0000));
+        assert_eq!(dma.read_status(), 0x00);
+
+        dma.write(VramDma::HDMA5, 0x00);
+        assert_eq!(dma.mode(), VramDmaMode::Idle);
+        assert_eq!(dma.read_status(), 0x80);
+
+        dma.write(VramDma::HDMA5, 0x80);
+        dma.next_block();
+        assert_eq!(dma.read_status(), 0xFF);
+    }
+
+    #[test]
+    fn rejects_states_of_running_transfers_without_blocks() {
+        let mut dma = VramDma::new();
+        dma.write(VramDma::HDMA5, 0x81);
+        let mut state = StateWriter::without_header();
+        dma.save_state(&mut state);
+        let mut data = state.into_bytes();
+        assert_eq!(VramDma::new().load_state(&mut StateReader::new(&data)), Ok(()));
+
+        data[4] = 0;
+        assert_eq!(VramDma::new().load_state(&mut StateReader::new(&data)), Err(StateError::Invalid("number of VRAM DMA blocks")));
+        data[5] = 0;
+        assert_eq!(VramDma::new().load_state(&mut StateReader::new(&data)), Ok(()));
+    }
+}
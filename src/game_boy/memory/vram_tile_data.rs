@@ -1,7 +1,8 @@
 use crate::game_boy::memory::vram_tile_data::tile::Tile;
 
-mod tile;
+pub(crate) mod tile;
 
+/// One of the three 2 KiB blocks of tile data at 0x8000, 0x8800 and 0x9000.
 pub struct VramTileData {
     tiles: Vec<Tile>
 }
@@ -16,7 +17,14 @@ impl VramTileData {
     }
 
     pub(crate) fn read(&self, address: u16) -> u8 {
-        todo!()
+        self.tiles[(address / 16) as usize].read(address % 16)
+    }
+
+    pub(crate) fn write(&mut self, address: u16, value: u8) {
+        self.tiles[(address / 16) as usize].write(address % 16, value);
     }
-}
 
+    pub(crate) fn tile(&self, idx: u8) -> &Tile {
+        &self.tiles[(idx & 0x7F) as usize]
+    }
+}
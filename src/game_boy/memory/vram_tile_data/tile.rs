@@ -7,6 +7,7 @@ impl Tile {
         Tile {data: [[0; 2]; 8] }
     }
 
+    #[cfg(test)]
     pub fn from_vec(external_data: Vec<u8>) -> Tile {
         let mut tile = Tile::new();
         let mut idx = 0;
@@ -20,26 +21,35 @@ impl Tile {
         tile
     }
 
+    pub(crate) fn read(&self, offset: u16) -> u8 {
+        self.data[(offset / 2) as usize][(offset % 2) as usize]
+    }
+
+    pub(crate) fn write(&mut self, offset: u16, value: u8) {
+        self.data[(offset / 2) as usize][(offset % 2) as usize] = value;
+    }
+
+    /// Color indices of one row of pixels, from left to right.
+    pub fn row(&self, row_idx: usize) -> [u8; 8] {
+        let [first_byte, second_byte] = self.data[row_idx];
+        let mut row = [0; 8];
+
+        for (bit_pos, pixel) in row.iter_mut().enumerate() {
+            let flag = 1 << (7 - bit_pos);
+            let low = if first_byte & flag != 0 { 0b01 } else { 0b00 };
+            let high = if second_byte & flag != 0 { 0b10 } else { 0b00 };
+            *pixel = high | low;
+        }
+
+        row
+    }
+
+    #[cfg(test)]
     pub fn to_array(&self) -> [[u8; 8]; 8] {
         let mut array = [[0; 8]; 8];
 
-        for row_idx in 0..8 {
-            let row = &self.data[row_idx];
-            let first_byte = row[0];
-            let second_byte = row[1];
-
-            for bit_pos in 0..8 {
-                let flag = 1 << (7 - bit_pos);
-                if first_byte & flag != 0 && second_byte & flag != 0 {
-                    array[row_idx][bit_pos] = 0b11;
-                } else if first_byte & flag == 0 && second_byte & flag == 0 {
-                    array[row_idx][bit_pos] = 0b00;
-                } else if first_byte & flag == 0 && second_byte & flag != 0 {
-                    array[row_idx][bit_pos] = 0b10;
-                } else {
-                    array[row_idx][bit_pos] = 0b01;
-                }
-            }
+        for (row_idx, row) in array.iter_mut().enumerate() {
+            *row = self.row(row_idx);
         }
 
         array
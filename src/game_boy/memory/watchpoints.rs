@@ -0,0 +1,188 @@
+use std::cell::{Cell, RefCell};
+use std::ops::RangeInclusive;
+use crate::game_boy::memory::Memory;
+
+/// Hits stay pending until taken, a watchpoint in a busy loop stops adding more after this many.
+const MAX_PENDING_HITS: usize = 1024;
+
+/// I/O registers by the names Pan Docs and hardware.inc give them.
+const IO_REGISTERS: [(&str, u16); 54] = [
+    ("P1", 0xFF00), ("JOYP", 0xFF00), ("SB", 0xFF01), ("SC", 0xFF02),
+    ("DIV", 0xFF04), ("TIMA", 0xFF05), ("TMA", 0xFF06), ("TAC", 0xFF07), ("IF", 0xFF0F),
+    ("NR10", 0xFF10), ("NR11", 0xFF11), ("NR12", 0xFF12), ("NR13", 0xFF13), ("NR14", 0xFF14),
+    ("NR21", 0xFF16), ("NR22", 0xFF17), ("NR23", 0xFF18), ("NR24", 0xFF19),
+    ("NR30", 0xFF1A), ("NR31", 0xFF1B), ("NR32", 0xFF1C), ("NR33", 0xFF1D), ("NR34", 0xFF1E),
+    ("NR41", 0xFF20), ("NR42", 0xFF21), ("NR43", 0xFF22), ("NR44", 0xFF23),
+    ("NR50", 0xFF24), ("NR51", 0xFF25), ("NR52", 0xFF26),
+    ("LCDC", 0xFF40), ("STAT", 0xFF41), ("SCY", 0xFF42), ("SCX", 0xFF43), ("LY", 0xFF44), ("LYC", 0xFF45),
+    ("DMA", 0xFF46), ("BGP", 0xFF47), ("OBP0", 0xFF48), ("OBP1", 0xFF49), ("WY", 0xFF4A), ("WX", 0xFF4B),
+    ("KEY1", 0xFF4D), ("VBK", 0xFF4F),
+    ("HDMA1", 0xFF51), ("HDMA2", 0xFF52), ("HDMA3", 0xFF53), ("HDMA4", 0xFF54), ("HDMA5", 0xFF55),
+    ("BCPS", 0xFF68), ("BCPD", 0xFF69), ("OCPS", 0xFF6A), ("OCPD", 0xFF6B), ("SVBK", 0xFF70),
+];
+
+/// The address of the I/O register called `name`, ignoring case.
+pub fn io_register(name: &str) -> Option<u16> {
+    IO_REGISTERS.iter().find(|(register, _)| register.eq_ignore_ascii_case(name)).map(|&(_, address)| address)
+}
+
+/// The name of the I/O register at `address`, if it has one.
+pub fn io_register_name(address: u16) -> Option<&'static str> {
+    // P1 comes first, JOYP is only accepted as an alias
+    IO_REGISTERS.iter().find(|&&(_, register)| register == address).map(|&(name, _)| name)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// What an access has to look like beyond hitting the range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    Always,
+    /// The value read or written is this one.
+    Equals(u8),
+    /// A write to LCDC turns the LCD off while the PPU is not in VBlank, which real hardware does not survive for long.
+    LcdOffOutsideVBlank,
+}
+
+/// Watches CPU accesses to a range of addresses. The PPU and debugger reads through `Memory::peek` go unnoticed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub reads: bool,
+    pub writes: bool,
+    pub condition: Condition,
+}
+
+impl Watchpoint {
+    pub fn read(range: RangeInclusive<u16>) -> Watchpoint {
+        Watchpoint { range, reads: true, writes: false, condition: Condition::Always }
+    }
+
+    pub fn write(range: RangeInclusive<u16>) -> Watchpoint {
+        Watchpoint { range, reads: false, writes: true, condition: Condition::Always }
+    }
+
+    pub fn access(range: RangeInclusive<u16>) -> Watchpoint {
+        Watchpoint { range, reads: true, writes: true, condition: Condition::Always }
+    }
+
+    /// Breaks when LCDC is written with the LCD turned off during rendering.
+    pub fn lcd_off_outside_vblank() -> Watchpoint {
+        Watchpoint { range: 0xFF40..=0xFF40, reads: false, writes: true, condition: Condition::LcdOffOutsideVBlank }
+    }
+
+    /// Only hits for accesses of `value`.
+    pub fn with_value(self, value: u8) -> Watchpoint {
+        Watchpoint { condition: Condition::Equals(value), ..self }
+    }
+
+    fn matches(&self, access: Access, address: u16, value: u8, memory: &Memory) -> bool {
+        let kind = match access {
+            Access::Read => self.reads,
+            Access::Write => self.writes,
+        };
+        kind && self.range.contains(&address) && match self.condition {
+            Condition::Always => true,
+            Condition::Equals(expected) => value == expected,
+            Condition::LcdOffOutsideVBlank => {
+                let vblank = memory.peek(0xFF41) & 0b11 == 1;
+                value & 0x80 == 0 && memory.lcdc().is_lcd_ppu_enabled() && !vblank
+            }
+        }
+    }
+}
+
+/// A watched access, and the instruction that made it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchpointHit {
+    /// As returned by `GameBoy::add_watchpoint`.
+    pub id: usize,
+    pub access: Access,
+    pub address: u16,
+    pub value: u8,
+    /// Address of the instruction that ran. Pushes of an interrupt dispatch count for the instruction it interrupted.
+    pub instruction: u16,
+}
+
+/// The watchpoints of a `Memory` and the hits they collected, behind cells since reads only borrow it.
+#[derive(Default)]
+pub(crate) struct Watchpoints {
+    watchpoints: Vec<(usize, Watchpoint)>,
+    next_id: usize,
+    instruction: Cell<u16>,
+    hits: RefCell<Vec<WatchpointHit>>,
+}
+
+impl Watchpoints {
+    pub(crate) fn add(&mut self, watchpoint: Watchpoint) -> usize {
+        self.next_id += 1;
+        self.watchpoints.push((self.next_id, watchpoint));
+        self.next_id
+    }
+
+    pub(crate) fn remove(&mut self, id: usize) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|&(watchpoint_id, _)| watchpoint_id != id);
+        self.watchpoints.len() != count
+    }
+
+    pub(crate) fn list(&self) -> &[(usize, Watchpoint)] {
+        &self.watchpoints
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty()
+    }
+
+    /// Called before each instruction, so hits know where they came from.
+    pub(crate) fn set_instruction(&self, address: u16) {
+        self.instruction.set(address);
+    }
+
+    pub(crate) fn check(&self, access: Access, address: u16, value: u8, memory: &Memory) {
+        for (id, watchpoint) in &self.watchpoints {
+            if watchpoint.matches(access, address, value, memory) {
+                let mut hits = self.hits.borrow_mut();
+                if hits.len() < MAX_PENDING_HITS {
+                    hits.push(WatchpointHit { id: *id, access, address, value, instruction: self.instruction.get() });
+                }
+            }
+        }
+    }
+
+    pub(crate) fn take_hits(&self) -> Vec<WatchpointHit> {
+        self.hits.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::GameBoy;
+    use crate::game_boy::tests::test_rom;
+
+    #[test]
+    fn hits_name_the_instruction_that_caused_them() {
+        // 0150: ld a, $42; ld [$C000], a; wait for LY 16; xor a; ldh [$FF40], a; jr -2
+        let mut game_boy = GameBoy::new(test_rom(&[
+            0x3E, 0x42, 0xEA, 0x00, 0xC0, 0xF0, 0x44, 0xFE, 0x10, 0x20, 0xFA, 0xAF, 0xE0, 0x40, 0x18, 0xFE,
+        ])).unwrap();
+        let store = game_boy.add_watchpoint(Watchpoint::write(0xC000..=0xC0FF).with_value(0x42));
+        let lcd_off = game_boy.add_watchpoint(Watchpoint::lcd_off_outside_vblank());
+        // the PPU reads STAT all the time, only the CPU counts
+        game_boy.add_watchpoint(Watchpoint::read(0xFF41..=0xFF41));
+
+        game_boy.run_cycles(2 * 17_556);
+        assert_eq!(game_boy.take_watchpoint_hits(), [
+            WatchpointHit { id: store, access: Access::Write, address: 0xC000, value: 0x42, instruction: 0x0152 },
+            WatchpointHit { id: lcd_off, access: Access::Write, address: 0xFF40, value: 0x00, instruction: 0x015C },
+        ]);
+        assert!(game_boy.remove_watchpoint(store));
+        assert_eq!(game_boy.watchpoints().len(), 2);
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::game_boy::cartridge_header::CartridgeHeader;
+
+/// Hardware revision to emulate. Besides the obvious CGB differences,
+/// the models leave different values in the registers when their boot ROM hands over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    /// The earliest Japanese DMG, with a slightly different boot ROM.
+    Dmg0,
+    Dmg,
+    /// Game Boy Pocket and Light.
+    Mgb,
+    Sgb,
+    Cgb,
+    /// Game Boy Advance in Game Boy Color mode.
+    Agb,
+}
+
+impl Model {
+    /// The model a cartridge is meant for: a CGB when the header flags CGB support,
+    /// an SGB when it flags SGB support, a DMG otherwise.
+    pub fn for_cartridge(header: &CartridgeHeader) -> Model {
+        if header.cgb_flag & 0x80 != 0 {
+            Model::Cgb
+        } else if header.supports_sgb() {
+            Model::Sgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    /// Size of the boot ROM, whose CGB variant continues at 0x0200 after the cartridge header.
+    pub fn boot_rom_size(&self) -> usize {
+        if self.is_cgb() { 0x900 } else { 0x100 }
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Model, String> {
+        match name {
+            "dmg0" => Ok(Model::Dmg0),
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "cgb" => Ok(Model::Cgb),
+            "agb" => Ok(Model::Agb),
+            _ => Err(format!("unknown model '{name}', expected dmg0, dmg, mgb, sgb, cgb or agb")),
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Model::Dmg0 => "dmg0",
+            Model::Dmg => "dmg",
+            Model::Mgb => "mgb",
+            Model::Sgb => "sgb",
+            Model::Cgb => "cgb",
+            Model::Agb => "agb",
+        };
+        f.write_str(name)
+    }
+}
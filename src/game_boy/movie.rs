@@ -0,0 +1,355 @@
+use std::fmt;
+use crate::game_boy::GameBoy;
+use crate::game_boy::cartridge_header::CartridgeHeader;
+use crate::game_boy::model::Model;
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+mod bk2;
+mod vbm;
+mod zip;
+
+const MAGIC: &[u8; 4] = b"GBMV";
+/// Bumped whenever the layout changes, movies of other versions are not read.
+pub const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MovieError {
+    /// The data does not start with a movie header.
+    NotAMovie,
+    Truncated,
+    UnsupportedVersion(u16),
+    UnknownModel(String),
+    RomMismatch { movie_checksum: u16, rom_checksum: u16 },
+    ModelMismatch { movie_model: Model, model: Model },
+    /// The save state the movie starts from does not load.
+    Start(StateError),
+    Invalid(&'static str),
+    /// An imported movie relies on something this emulator cannot replay.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "not a movie"),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "movie has format version {version}, this build reads version {FORMAT_VERSION}")
+            }
+            MovieError::UnknownModel(model) => write!(f, "movie was made on an unknown model '{model}'"),
+            MovieError::RomMismatch { movie_checksum, rom_checksum } => write!(
+                f, "movie belongs to another ROM (checksum {movie_checksum:04X}, this ROM has {rom_checksum:04X})"
+            ),
+            MovieError::ModelMismatch { movie_model, model } => {
+                write!(f, "movie was made on a {movie_model}, this machine is a {model}")
+            }
+            MovieError::Start(error) => write!(f, "movie start: {error}"),
+            MovieError::Invalid(what) => write!(f, "movie has an invalid {what}"),
+            MovieError::Unsupported(what) => write!(f, "movie uses {what}, which cannot be replayed"),
+        }
+    }
+}
+
+/// What a movie starts from: power-on without either, else the battery save and a save state loaded on top of it.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MovieStart {
+    pub save_data: Option<Vec<u8>>,
+    pub state: Option<Vec<u8>>,
+}
+
+/// The joypad input of a run, one button mask per frame, along with everything needed to replay it
+/// bit for bit: the machine, the start condition and the time the cartridge clock starts at.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Movie {
+    model: Model,
+    rom_checksum: u16,
+    boot_rom: bool,
+    rtc_time: u64,
+    start: MovieStart,
+    inputs: Vec<u8>,
+}
+
+impl Movie {
+    /// An empty movie for the machine and ROM of `game_boy`, which was or will be started with a boot ROM if `boot_rom`.
+    pub fn new(game_boy: &GameBoy, boot_rom: bool, rtc_time: u64, start: MovieStart) -> Movie {
+        let rom_checksum = game_boy.memory.borrow().rom_checksum();
+        Movie { model: game_boy.model, rom_checksum, boot_rom, rtc_time, start, inputs: Vec::new() }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Whether the run began in the boot ROM, playback needs the same boot ROM to stay in sync.
+    pub fn boot_rom(&self) -> bool {
+        self.boot_rom
+    }
+
+    /// Seconds since the epoch the cartridge clock starts at, see `GameBoy::set_rtc_time`.
+    pub fn rtc_time(&self) -> u64 {
+        self.rtc_time
+    }
+
+    pub fn start_condition(&self) -> &MovieStart {
+        &self.start
+    }
+
+    /// Number of frames recorded.
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// The buttons held during `frame`, as a `Button::mask` mask.
+    pub fn input(&self, frame: usize) -> Option<u8> {
+        self.inputs.get(frame).copied()
+    }
+
+    pub fn push_input(&mut self, buttons: u8) {
+        self.inputs.push(buttons);
+    }
+
+    /// Puts a freshly powered on `game_boy` into the movie's start condition, on emulated RTC time.
+    pub fn start(&self, game_boy: &mut GameBoy) -> Result<(), MovieError> {
+        if game_boy.model != self.model {
+            return Err(MovieError::ModelMismatch { movie_model: self.model, model: game_boy.model });
+        }
+        let rom_checksum = game_boy.memory.borrow().rom_checksum();
+        if rom_checksum != self.rom_checksum {
+            return Err(MovieError::RomMismatch { movie_checksum: self.rom_checksum, rom_checksum });
+        }
+
+        game_boy.set_rtc_time(self.rtc_time);
+        if let Some(data) = &self.start.save_data {
+            game_boy.load_save_data(data);
+        }
+        if let Some(state) = &self.start.state {
+            game_boy.load_state(state).map_err(MovieError::Start)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut movie = StateWriter::without_header();
+        movie.write_bytes(MAGIC);
+        movie.write_u16(FORMAT_VERSION);
+        movie.write_blob(env!("CARGO_PKG_VERSION").as_bytes());
+        movie.write_blob(self.model.to_string().as_bytes());
+        movie.write_u16(self.rom_checksum);
+        movie.write_bool(self.boot_rom);
+        movie.write_u64(self.rtc_time);
+        for data in [&self.start.save_data, &self.start.state] {
+            movie.write_bool(data.is_some());
+            movie.write_blob(data.as_deref().unwrap_or_default());
+        }
+        movie.write_blob(&self.inputs);
+        movie.into_bytes()
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut movie = StateReader::new(data);
+        let mut magic = [0; 4];
+        movie.read_bytes(&mut magic).map_err(|_| MovieError::NotAMovie)?;
+        if &magic != MAGIC {
+            return Err(MovieError::NotAMovie);
+        }
+        let version = movie.read_u16().map_err(|_| MovieError::Truncated)?;
+        if version != FORMAT_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let _emulator_version = movie.read_string().map_err(|_| MovieError::Truncated)?;
+        let model = movie.read_string().map_err(|_| MovieError::Truncated)?;
+        let model = model.parse().map_err(|_| MovieError::UnknownModel(model))?;
+        Self::read(&mut movie, model).map_err(|_| MovieError::Truncated)
+    }
+
+    /// Imports a BizHawk BK2 or VisualBoyAdvance VBM movie made with `cartridge_rom`. Runs made on other emulators
+    /// only stay in sync as long as both read the joypad in the same frames, resets and save state starts are not supported.
+    pub fn import(data: &[u8], cartridge_rom: &[u8]) -> Result<Movie, MovieError> {
+        if data.starts_with(b"VBM\x1A") {
+            vbm::import(data, cartridge_rom)
+        } else if data.starts_with(b"PK\x03\x04") {
+            bk2::import(data, cartridge_rom)
+        } else {
+            Err(MovieError::NotAMovie)
+        }
+    }
+
+    fn read(movie: &mut StateReader, model: Model) -> Result<Movie, StateError> {
+        let rom_checksum = movie.read_u16()?;
+        let boot_rom = movie.read_bool()?;
+        let rtc_time = movie.read_u64()?;
+        let mut optional_blob = || -> Result<Option<Vec<u8>>, StateError> {
+            let present = movie.read_bool()?;
+            let data = movie.read_blob()?;
+            Ok(present.then(|| data.to_vec()))
+        };
+        let start = MovieStart { save_data: optional_blob()?, state: optional_blob()? };
+        let inputs = movie.read_blob()?.to_vec();
+        Ok(Movie { model, rom_checksum, boot_rom, rtc_time, start, inputs })
+    }
+}
+
+/// The model `GameBoy::new` picks for `cartridge_rom`, for imported movies that do not say.
+fn cartridge_model(cartridge_rom: &[u8]) -> Model {
+    CartridgeHeader::parse(cartridge_rom).map_or(Model::Dmg, |header| Model::for_cartridge(&header))
+}
+
+fn cartridge_checksum(cartridge_rom: &[u8]) -> u16 {
+    CartridgeHeader::calculate_global_checksum(cartridge_rom)
+}
+
+/// Plays a movie back into a Game Boy or records one from it, one frame at a time.
+pub struct MovieSession {
+    movie: Movie,
+    recording: bool,
+    frame: usize,
+}
+
+impl MovieSession {
+    /// Appends the buttons held at the start of every frame to `movie`.
+    pub fn record(movie: Movie) -> MovieSession {
+        MovieSession { movie, recording: true, frame: 0 }
+    }
+
+    /// Presses the buttons of `movie` until it runs out.
+    pub fn play(movie: Movie) -> MovieSession {
+        MovieSession { movie, recording: false, frame: 0 }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Whether the movie still has input to play, while it does the buttons belong to it.
+    pub fn is_playing(&self) -> bool {
+        !self.recording && self.frame < self.movie.len()
+    }
+
+    /// Records or plays the input of the frame `game_boy` is about to run. Must be called before every frame.
+    pub fn start_frame(&mut self, game_boy: &mut GameBoy) {
+        if self.recording {
+            self.movie.push_input(game_boy.pressed_buttons());
+        } else if let Some(buttons) = self.movie.input(self.frame) {
+            game_boy.set_buttons(buttons);
+        }
+        self.frame += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::Button;
+    use crate::game_boy::tests::test_rom;
+
+    /// Adds up the d-pad lines in WRAM at 0xC000 forever, so different input leaves a different state.
+    fn joypad_rom() -> Vec<u8> {
+        // ld a, 0x20; ldh [0x00], a; ldh a, [0x00]; ld hl, 0xC000; add a, [hl]; ld [hl], a; jr back to the start
+        test_rom(&[0x3E, 0x20, 0xE0, 0x00, 0xF0, 0x00, 0x21, 0x00, 0xC0, 0x86, 0x77, 0x18, 0xF3])
+    }
+
+    #[test]
+    fn playback_reproduces_the_recorded_run() {
+        let mut game_boy = GameBoy::new(joypad_rom()).unwrap();
+        game_boy.run_frame();
+        let start = MovieStart { save_data: None, state: Some(game_boy.save_state()) };
+        let movie = Movie::new(&game_boy, false, 1_700_000_000, start);
+        movie.start(&mut game_boy).unwrap();
+
+        let mut session = MovieSession::record(movie);
+        for frame in 0..30 {
+            game_boy.set_button(Button::Right, frame % 3 == 0);
+            game_boy.set_button(Button::Down, frame % 7 < 2);
+            session.start_frame(&mut game_boy);
+            game_boy.run_frame();
+        }
+        let recorded_state = game_boy.save_state();
+
+        let movie = Movie::parse(&session.movie().to_bytes()).unwrap();
+        assert_eq!(&movie, session.movie());
+        let mut game_boy = GameBoy::new(joypad_rom()).unwrap();
+        movie.start(&mut game_boy).unwrap();
+        let mut session = MovieSession::play(movie);
+        while session.is_playing() {
+            session.start_frame(&mut game_boy);
+            game_boy.run_frame();
+        }
+        assert_eq!(game_boy.save_state(), recorded_state);
+
+        let mut other_model = GameBoy::with_model(joypad_rom(), Model::Cgb, None).unwrap();
+        assert!(matches!(session.movie().start(&mut other_model), Err(MovieError::ModelMismatch { .. })));
+        assert_eq!(Movie::parse(b"GBST"), Err(MovieError::NotAMovie));
+    }
+
+    /// A zip archive of uncompressed files, without the CRCs the reader does not check.
+    pub(super) fn stored_zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+        for (name, data) in files {
+            let sizes = [(data.len() as u32).to_le_bytes(), (data.len() as u32).to_le_bytes()].concat();
+            directory.extend(0x0201_4B50_u32.to_le_bytes());
+            directory.extend([0; 16]);
+            directory.extend(&sizes);
+            directory.extend((name.len() as u16).to_le_bytes());
+            directory.extend([0; 12]);
+            directory.extend((archive.len() as u32).to_le_bytes());
+            directory.extend(name.as_bytes());
+
+            archive.extend(0x0403_4B50_u32.to_le_bytes());
+            archive.extend([0; 14]);
+            archive.extend(&sizes);
+            archive.extend((name.len() as u16).to_le_bytes());
+            archive.extend([0; 2]);
+            archive.extend(name.as_bytes());
+            archive.extend(data.as_bytes());
+        }
+        let directory_offset = archive.len() as u32;
+        let directory_length = directory.len() as u32;
+        archive.extend(directory);
+        archive.extend(0x0605_4B50_u32.to_le_bytes());
+        archive.extend([0; 4]);
+        archive.extend([(files.len() as u16).to_le_bytes(), (files.len() as u16).to_le_bytes()].concat());
+        archive.extend([directory_length.to_le_bytes(), directory_offset.to_le_bytes()].concat());
+        archive.extend([0; 2]);
+        archive
+    }
+
+    #[test]
+    fn imports_bk2_and_vbm_input() {
+        let rom = joypad_rom();
+        let bk2 = stored_zip(&[
+            ("Header.txt", "MovieVersion BizHawk v2.0\nPlatform GB\nCore Gambatte\n"),
+            ("SyncSettings.json", "{\"o\":{\"EnableBIOS\":false,\"RTCInitialTime\": 12345}}"),
+            ("Input Log.txt", "[Input]\nLogKey:#Up|Down|Left|Right|Start|Select|B|A|Power|\n|.........|\n|...R...A.|\n[/Input]\n"),
+        ]);
+        let movie = Movie::import(&bk2, &rom).unwrap();
+        assert_eq!(movie.model(), Model::Dmg);
+        assert_eq!(movie.rtc_time(), 12345);
+        assert!(!movie.boot_rom());
+        assert_eq!(movie.inputs, [0, Button::Right.mask() | Button::A.mask()]);
+
+        let mut vbm = vec![0; 0x40];
+        vbm[0..4].copy_from_slice(b"VBM\x1A");
+        vbm[0x0C] = 2;
+        vbm[0x15] = 1;
+        vbm[0x16] = 0x02;
+        vbm[0x31] = rom[0x14D];
+        vbm[0x38] = 0x40;
+        vbm[0x3C] = 0x40;
+        // Start and Down, then a reset
+        vbm.extend([0x88, 0x00, 0x00, 0x08]);
+        assert_eq!(Movie::import(&vbm, &rom), Err(MovieError::Unsupported("resets")));
+        vbm[0x0C] = 1;
+        let movie = Movie::import(&vbm, &rom).unwrap();
+        assert_eq!(movie.model(), Model::Cgb);
+        assert_eq!(movie.inputs, [Button::Start.mask() | Button::Down.mask()]);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::game_boy::Button;
+use crate::game_boy::model::Model;
+use crate::game_boy::movie::{zip, Movie, MovieError, MovieStart};
+
+/// Reads a BizHawk BK2 movie: a zip archive with a text header, the core's sync settings
+/// and an input log of one line per frame.
+pub(super) fn import(data: &[u8], cartridge_rom: &[u8]) -> Result<Movie, MovieError> {
+    let files = zip::read_files(data)?;
+    let file = |name: &str| {
+        files.iter().find(|(file_name, _)| file_name.starts_with(name)).map(|(_, data)| String::from_utf8_lossy(data))
+    };
+    let header = file("Header").ok_or(MovieError::Invalid("BK2 header"))?;
+    let header_value = |key: &str| header.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix(' ')).map(str::trim);
+    let sync_settings = file("SyncSettings").unwrap_or_default();
+
+    if header_value("StartsFromSavestate") == Some("True") {
+        return Err(MovieError::Unsupported("BizHawk save state starts"));
+    }
+    let save_data = match header_value("StartsFromSaveRam") {
+        Some("True") => {
+            let save_data = files.iter().find(|(name, _)| name.starts_with("MovieSaveRam") || name.starts_with("SaveRam"));
+            Some(save_data.ok_or(MovieError::Invalid("BK2 save RAM"))?.1.clone())
+        }
+        _ => None,
+    };
+    let model = match header_value("Platform") {
+        _ if matches!(header_value("IsCGBMode"), Some("1" | "True")) => Model::Cgb,
+        Some("GBC") => Model::Cgb,
+        Some("SGB") => Model::Sgb,
+        Some("GB") => Model::Dmg,
+        _ => super::cartridge_model(cartridge_rom),
+    };
+
+    let log = file("Input Log").ok_or(MovieError::Invalid("BK2 input log"))?;
+    let keys: Vec<Option<Button>> = log.lines()
+        .find_map(|line| line.strip_prefix("LogKey:"))
+        .ok_or(MovieError::Invalid("BK2 input log"))?
+        .split(['#', '|'])
+        .filter(|key| !key.is_empty())
+        .map(|key| key.strip_prefix("P1 ").unwrap_or(key))
+        .map(|key| match key {
+            "Power" | "Reset" => Ok(None),
+            _ => button(key).map(Some).ok_or(MovieError::Unsupported("BK2 inputs other than the Game Boy buttons")),
+        })
+        .collect::<Result<_, _>>()?;
+
+    let mut inputs = Vec::new();
+    for line in log.lines().filter(|line| line.starts_with('|')) {
+        let states: Vec<char> = line.chars().filter(|&state| state != '|').collect();
+        if states.len() != keys.len() {
+            return Err(MovieError::Invalid("BK2 input log line"));
+        }
+        let mut buttons = 0;
+        for (key, state) in keys.iter().zip(states) {
+            match key {
+                _ if state == '.' => {}
+                Some(button) => buttons |= button.mask(),
+                None => return Err(MovieError::Unsupported("resets")),
+            }
+        }
+        inputs.push(buttons);
+    }
+
+    Ok(Movie {
+        model,
+        rom_checksum: super::cartridge_checksum(cartridge_rom),
+        boot_rom: json_value(&sync_settings, "EnableBIOS") == Some("true"),
+        rtc_time: json_value(&sync_settings, "RTCInitialTime").and_then(|time| time.parse().ok()).unwrap_or(0),
+        start: MovieStart { save_data, state: None },
+        inputs,
+    })
+}
+
+fn button(name: &str) -> Option<Button> {
+    match name {
+        "Up" => Some(Button::Up),
+        "Down" => Some(Button::Down),
+        "Left" => Some(Button::Left),
+        "Right" => Some(Button::Right),
+        "Start" => Some(Button::Start),
+        "Select" => Some(Button::Select),
+        "B" => Some(Button::B),
+        "A" => Some(Button::A),
+        _ => None,
+    }
+}
+
+/// The raw value of the first `key` in `json`, which is enough for the flat numbers and flags of sync settings.
+fn json_value<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let rest = &json[json.find(&format!("\"{key}\""))? + key.len() + 2..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    Some(rest[..rest.find([',', '}', '\n']).unwrap_or(rest.len())].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::movie::tests::stored_zip;
+    use crate::game_boy::tests::test_rom;
+
+    const LOG_KEY: &str = "LogKey:#Up|Down|Left|Right|Start|Select|B|A|Power|Reset|";
+
+    fn bk2(header: &str, log: &[&str]) -> Vec<u8> {
+        let log = format!("[Input]\n{LOG_KEY}\n{}\n[/Input]\n", log.join("\n"));
+        stored_zip(&[("Header.txt", header), ("Input Log.txt", &log)])
+    }
+
+    #[test]
+    fn reads_the_buttons_of_every_frame() {
+        let rom = test_rom(&[]);
+        let movie = import(&bk2("Platform GB\n", &["|..........|", "|U..R...A..|"]), &rom).unwrap();
+        assert_eq!(movie.inputs, [0, Button::Up.mask() | Button::Right.mask() | Button::A.mask()]);
+    }
+
+    #[test]
+    fn rejects_resets() {
+        let rom = test_rom(&[]);
+        for line in ["|........P.|", "|.........r|"] {
+            assert_eq!(import(&bk2("Platform GB\n", &[line]), &rom), Err(MovieError::Unsupported("resets")));
+        }
+    }
+
+    #[test]
+    fn rejects_movies_of_other_roms() {
+        let rom = test_rom(&[]);
+        let movie = import(&bk2("Platform GB\n", &["|..........|"]), &rom).unwrap();
+        let mut other_rom = rom.clone();
+        other_rom[0x0200] = 0x01;
+        let mut game_boy = crate::game_boy::GameBoy::new(other_rom).unwrap();
+        assert!(matches!(movie.start(&mut game_boy), Err(MovieError::RomMismatch { .. })));
+    }
+
+    #[test]
+    fn rejects_save_state_starts() {
+        let rom = test_rom(&[]);
+        let data = bk2("Platform GB\nStartsFromSavestate True\n", &["|..........|"]);
+        assert_eq!(import(&data, &rom), Err(MovieError::Unsupported("BizHawk save state starts")));
+    }
+
+    #[test]
+    fn rejects_lines_with_the_wrong_number_of_keys() {
+        let rom = test_rom(&[]);
+        for line in ["|.........|", "|...........|"] {
+            assert_eq!(import(&bk2("Platform GB\n", &[line]), &rom), Err(MovieError::Invalid("BK2 input log line")));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_input_logs() {
+        let rom = test_rom(&[]);
+        let data = bk2("Platform GB\n", &["|..........|", "|...R"]);
+        assert_eq!(import(&data, &rom), Err(MovieError::Invalid("BK2 input log line")));
+    }
+
+    #[test]
+    fn rejects_movies_without_an_input_log() {
+        let rom = test_rom(&[]);
+        let data = stored_zip(&[("Header.txt", "Platform GB\n")]);
+        assert_eq!(import(&data, &rom), Err(MovieError::Invalid("BK2 input log")));
+    }
+}
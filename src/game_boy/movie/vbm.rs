@@ -0,0 +1,128 @@
+use crate::game_boy::model::Model;
+use crate::game_boy::movie::{Movie, MovieError, MovieStart};
+
+const START_FROM_STATE: u8 = 1 << 0;
+const START_FROM_SAVE_DATA: u8 = 1 << 1;
+// VBA-rr's button mask: A, B, Select, Start, the d-pad, then the GBA's R and L, the reset
+// of old and of new movies and the motion sensor, which only tilt cartridges read
+const OLD_RESET: u16 = 1 << 10;
+const NEW_RESET: u16 = 1 << 11;
+
+/// Reads a VisualBoyAdvance VBM movie: a 64-byte header, the save data it may start from
+/// and a little-endian button mask per frame for each controller.
+pub(super) fn import(data: &[u8], cartridge_rom: &[u8]) -> Result<Movie, MovieError> {
+    if data.len() < 0x40 {
+        return Err(MovieError::Truncated);
+    }
+    let u32_at = |offset: usize| -> Result<usize, MovieError> {
+        let bytes = data.get(offset..offset + 4).ok_or(MovieError::Truncated)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    };
+    let frame_count = u32_at(0x0C)?;
+    let start_flags = data[0x14];
+    let controllers = (data[0x15] & 0x0F).count_ones() as usize;
+    let system_flags = data[0x16];
+    let options = data[0x17];
+    let save_data_offset = u32_at(0x38)?;
+    let inputs_offset = u32_at(0x3C)?;
+
+    if start_flags & START_FROM_STATE != 0 {
+        return Err(MovieError::Unsupported("VisualBoyAdvance save state starts"));
+    }
+    // the header checksum byte, which is all VBM keeps to tell ROMs apart
+    if data[0x31] != cartridge_rom[0x14D] {
+        return Err(MovieError::RomMismatch { movie_checksum: data[0x31] as u16, rom_checksum: cartridge_rom[0x14D] as u16 });
+    }
+    let model = match system_flags {
+        _ if system_flags & 0x01 != 0 => Model::Agb,
+        _ if system_flags & 0x02 != 0 => Model::Cgb,
+        _ if system_flags & 0x04 != 0 => Model::Sgb,
+        _ => Model::Dmg,
+    };
+    let save_data = match start_flags & START_FROM_SAVE_DATA {
+        0 => None,
+        _ => Some(data.get(save_data_offset..inputs_offset).ok_or(MovieError::Truncated)?.to_vec()),
+    };
+
+    let frame_size = 2 * controllers.max(1);
+    let log = data.get(inputs_offset..inputs_offset + frame_count * frame_size).ok_or(MovieError::Truncated)?;
+    let inputs = log.chunks_exact(frame_size)
+        .map(|frame| {
+            // the first controller plays, VBA keeps A, B, Select and Start below the d-pad
+            let buttons = u16::from_le_bytes([frame[0], frame[1]]);
+            if buttons & (OLD_RESET | NEW_RESET) != 0 {
+                return Err(MovieError::Unsupported("resets"));
+            }
+            Ok((buttons as u8).rotate_left(4))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Movie {
+        model,
+        rom_checksum: super::cartridge_checksum(cartridge_rom),
+        // the BIOS runs when it is used and not skipped
+        boot_rom: options & 0x03 == 0x01,
+        // the movie's ID is the time recording started, which is also where VBA's clock starts
+        rtc_time: u32_at(0x08)? as u64,
+        start: MovieStart { save_data, state: None },
+        inputs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::tests::test_rom;
+
+    /// A DMG movie of one controller that starts from power on and plays `inputs`.
+    fn vbm(rom: &[u8], inputs: &[u16]) -> Vec<u8> {
+        let mut vbm = vec![0; 0x40];
+        vbm[0..4].copy_from_slice(b"VBM\x1A");
+        vbm[0x0C..0x10].copy_from_slice(&(inputs.len() as u32).to_le_bytes());
+        vbm[0x15] = 1;
+        vbm[0x31] = rom[0x14D];
+        vbm[0x38] = 0x40;
+        vbm[0x3C] = 0x40;
+        vbm.extend(inputs.iter().flat_map(|buttons| buttons.to_le_bytes()));
+        vbm
+    }
+
+    #[test]
+    fn ignores_the_motion_sensor() {
+        let rom = test_rom(&[]);
+        let movie = import(&vbm(&rom, &[0x1001, 0xF080]), &rom).unwrap();
+        assert_eq!(movie.inputs, [0x10, 0x08]);
+    }
+
+    #[test]
+    fn rejects_resets() {
+        let rom = test_rom(&[]);
+        for reset in [OLD_RESET, NEW_RESET] {
+            assert_eq!(import(&vbm(&rom, &[0, reset]), &rom), Err(MovieError::Unsupported("resets")));
+        }
+    }
+
+    #[test]
+    fn rejects_save_state_starts() {
+        let rom = test_rom(&[]);
+        let mut data = vbm(&rom, &[0]);
+        data[0x14] = START_FROM_STATE;
+        assert_eq!(import(&data, &rom), Err(MovieError::Unsupported("VisualBoyAdvance save state starts")));
+    }
+
+    #[test]
+    fn rejects_movies_of_other_roms() {
+        let rom = test_rom(&[]);
+        let mut data = vbm(&rom, &[0]);
+        data[0x31] = rom[0x14D].wrapping_add(1);
+        assert!(matches!(import(&data, &rom), Err(MovieError::RomMismatch { .. })));
+    }
+
+    #[test]
+    fn rejects_truncated_movies() {
+        let rom = test_rom(&[]);
+        let data = vbm(&rom, &[0, 0]);
+        assert_eq!(import(&data[..data.len() - 1], &rom), Err(MovieError::Truncated));
+        assert_eq!(import(&data[..0x3F], &rom), Err(MovieError::Truncated));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::game_boy::movie::MovieError;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// The order code length code lengths are stored in by dynamic blocks.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const INVALID_DEFLATE: MovieError = MovieError::Invalid("deflate stream");
+const INVALID_ZIP: MovieError = MovieError::Invalid("zip archive");
+
+/// The files of a zip archive, stored or deflated, by name.
+pub(super) fn read_files(archive: &[u8]) -> Result<Vec<(String, Vec<u8>)>, MovieError> {
+    let u16_at = |offset: usize| {
+        archive.get(offset..offset + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize).ok_or(INVALID_ZIP)
+    };
+    let u32_at = |offset: usize| {
+        archive.get(offset..offset + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize).ok_or(INVALID_ZIP)
+    };
+
+    // the end of central directory record sits at the end, behind a comment of up to 64 KiB
+    let end = (0..archive.len().saturating_sub(21)).rev()
+        .take(0x10000 + 22)
+        .find(|&offset| u32_at(offset) == Ok(0x0605_4B50))
+        .ok_or(INVALID_ZIP)?;
+    let count = u16_at(end + 10)?;
+    let mut entry = u32_at(end + 16)?;
+
+    let mut files = Vec::with_capacity(count);
+    for _ in 0..count {
+        if u32_at(entry)? != 0x0201_4B50 {
+            return Err(INVALID_ZIP);
+        }
+        let method = u16_at(entry + 10)?;
+        let compressed_size = u32_at(entry + 20)?;
+        let name_length = u16_at(entry + 28)?;
+        let extra_length = u16_at(entry + 30)?;
+        let comment_length = u16_at(entry + 32)?;
+        let local_header = u32_at(entry + 42)?;
+        let name = archive.get(entry + 46..entry + 46 + name_length).ok_or(INVALID_ZIP)?;
+        entry += 46 + name_length + extra_length + comment_length;
+
+        // the local header repeats the name and may have different extra data
+        let data_start = local_header + 30 + u16_at(local_header + 26)? + u16_at(local_header + 28)?;
+        let data = archive.get(data_start..data_start + compressed_size).ok_or(INVALID_ZIP)?;
+        let data = match method {
+            0 => data.to_vec(),
+            8 => inflate(data)?,
+            _ => return Err(MovieError::Unsupported("zip compression method")),
+        };
+        files.push((String::from_utf8_lossy(name).into_owned(), data));
+    }
+    Ok(files)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Result<u16, MovieError> {
+        let byte = self.data.get(self.position / 8).ok_or(INVALID_DEFLATE)?;
+        let bit = (byte >> (self.position % 8)) & 1;
+        self.position += 1;
+        Ok(bit as u16)
+    }
+
+    /// `count` bits, least significant first.
+    fn bits(&mut self, count: u8) -> Result<u16, MovieError> {
+        let mut value = 0;
+        for idx in 0..count {
+            value |= self.bit()? << idx;
+        }
+        Ok(value)
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|&symbol| lengths[symbol as usize] != 0).collect();
+        symbols.sort_by_key(|&symbol| lengths[symbol as usize]);
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u16, MovieError> {
+        // codes of one length are consecutive, and longer codes continue after the shorter ones shifted left
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+        for &count in &self.counts[1..] {
+            code |= bits.bit()?;
+            if code < first + count {
+                return self.symbols.get((index + code - first) as usize).copied().ok_or(INVALID_DEFLATE);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(INVALID_DEFLATE)
+    }
+}
+
+/// Decompresses a raw deflate stream, without the zlib wrapper.
+fn inflate(data: &[u8]) -> Result<Vec<u8>, MovieError> {
+    let mut bits = BitReader { data, position: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.bit()? == 1;
+        match bits.bits(2)? {
+            0 => {
+                let start = bits.position.div_ceil(8);
+                let header = data.get(start..start + 4).ok_or(INVALID_DEFLATE)?;
+                let length = u16::from_le_bytes([header[0], header[1]]) as usize;
+                out.extend_from_slice(data.get(start + 4..start + 4 + length).ok_or(INVALID_DEFLATE)?);
+                bits.position = 8 * (start + 4 + length);
+            }
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                inflate_block(&mut bits, &mut out, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, &literals, &distances)?;
+            }
+            _ => return Err(INVALID_DEFLATE),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn read_dynamic_codes(bits: &mut BitReader) -> Result<(Huffman, Huffman), MovieError> {
+    let literal_count = bits.bits(5)? as usize + 257;
+    let distance_count = bits.bits(5)? as usize + 1;
+    let code_length_count = bits.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_lengths.decode(bits)? {
+            length @ 0..=15 => (length as u8, 1),
+            16 => (*lengths.last().ok_or(INVALID_DEFLATE)?, 3 + bits.bits(2)?),
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        lengths.extend((0..repeat).map(|_| value));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err(INVALID_DEFLATE);
+    }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+fn inflate_block(bits: &mut BitReader, out: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Result<(), MovieError> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let idx = symbol - 257;
+                let length = *LENGTH_BASE.get(idx).ok_or(INVALID_DEFLATE)? as usize + bits.bits(LENGTH_EXTRA[idx])? as usize;
+                let idx = distances.decode(bits)? as usize;
+                let distance = *DISTANCE_BASE.get(idx).ok_or(INVALID_DEFLATE)? as usize + bits.bits(DISTANCE_EXTRA[idx])? as usize;
+                let start = out.len().checked_sub(distance).ok_or(INVALID_DEFLATE)?;
+                // the copy may overlap what it writes, repeating the last `distance` bytes
+                for offset in 0..length {
+                    out.push(out[start + offset]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inflates_fixed_and_dynamic_blocks() {
+        // zlib.compressobj(wbits=-15) output for a short string, which uses the fixed code
+        let fixed = [0x4B, 0x4C, 0x4A, 0x4E, 0x84, 0x21, 0x00];
+        assert_eq!(inflate(&fixed).unwrap(), b"abcabcabcabc");
+
+        let text: Vec<u8> = (0..400).flat_map(|idx: u32| format!("|..{}..A.|\n", idx % 7).into_bytes()).collect();
+        // the same for a longer input log, which gets its own dynamic code
+        let dynamic = [
+            0xED, 0xCC, 0xA9, 0x11, 0x00, 0x31, 0x10, 0x04, 0x31, 0x7E, 0xC1, 0x74, 0x9D, 0x5F,
+            0xEE, 0xBC, 0x36, 0x78, 0xB3, 0x09, 0xC0, 0xB8, 0x99, 0x90, 0x0A, 0x7E, 0x38, 0xD4,
+            0x57, 0xD0, 0xA2, 0x1E, 0x8D, 0x68, 0x46, 0x2B, 0xDA, 0x91, 0x8B, 0x8B, 0x8B, 0x8B,
+            0x8B, 0x8B, 0x8B, 0x8B, 0x8B, 0x8B, 0x8B, 0x8B, 0x8B, 0x8B, 0xCB, 0xF3, 0x72, 0x01,
+        ];
+        assert_eq!(inflate(&dynamic).unwrap(), text);
+    }
+}
@@ -0,0 +1,186 @@
+use std::fmt;
+use crate::game_boy::cartridge_header::CartridgeHeader;
+use crate::game_boy::ppu::Layer;
+
+pub type Rgb = [u8; 3];
+
+/// Colors for the four DMG shades, lightest first. The background and the two object
+/// palettes can differ, which is how a GBC colorizes DMG games.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    pub name: String,
+    pub background: [Rgb; 4],
+    pub object0: [Rgb; 4],
+    pub object1: [Rgb; 4],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParsePaletteError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParsePaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+const WHITE: Rgb = [0xFF, 0xFF, 0xFF];
+const BLACK: Rgb = [0x00, 0x00, 0x00];
+
+/// What the CGB boot ROM picks for DMG games it has no entry for.
+const GBC_DEFAULT_BACKGROUND: [Rgb; 4] = [WHITE, [0x7B, 0xFF, 0x31], [0x00, 0x63, 0xC5], BLACK];
+const GBC_RED: [Rgb; 4] = [WHITE, [0xFF, 0x84, 0x84], [0x94, 0x3A, 0x3A], BLACK];
+const GBC_BLUE: [Rgb; 4] = [WHITE, [0x63, 0xA5, 0xFF], [0x00, 0x00, 0xFF], BLACK];
+const GBC_GREEN: [Rgb; 4] = [WHITE, [0x7B, 0xFF, 0x31], [0x00, 0x84, 0x00], BLACK];
+
+/// An entry of the CGB boot ROM's table of Nintendo titles it colorizes.
+struct TitlePalette {
+    hash: u8,
+    /// Tells titles with colliding hashes apart.
+    fourth_letter: Option<u8>,
+    /// Background, OBP0 and OBP1 colors.
+    palettes: [[Rgb; 4]; 3],
+}
+
+/// Only a few well-known entries of the boot ROM's table are included, the rest get the default palette.
+const GBC_TITLE_PALETTES: &[TitlePalette] = &[
+    TitlePalette { hash: title_hash(b"POKEMON RED"), fourth_letter: None, palettes: [GBC_RED, GBC_GREEN, GBC_BLUE] },
+    TitlePalette { hash: title_hash(b"POKEMON BLUE"), fourth_letter: None, palettes: [GBC_BLUE, GBC_RED, GBC_GREEN] },
+    TitlePalette { hash: title_hash(b"POKEMON GREEN"), fourth_letter: None, palettes: [GBC_GREEN, GBC_RED, GBC_BLUE] },
+];
+
+/// Sum of the title bytes, which the CGB boot ROM uses to look up a palette.
+const fn title_hash(title: &[u8]) -> u8 {
+    let mut hash = 0u8;
+    let mut idx = 0;
+    while idx < title.len() {
+        hash = hash.wrapping_add(title[idx]);
+        idx += 1;
+    }
+    hash
+}
+
+impl Palette {
+    fn uniform(name: &str, colors: [Rgb; 4]) -> Palette {
+        Palette { name: String::from(name), background: colors, object0: colors, object1: colors }
+    }
+
+    /// The yellow-green screen of the original Game Boy.
+    pub fn dmg() -> Palette {
+        Palette::uniform("dmg", [[0x9B, 0xBC, 0x0F], [0x8B, 0xAC, 0x0F], [0x30, 0x62, 0x30], [0x0F, 0x38, 0x0F]])
+    }
+
+    /// The grey screen of the Game Boy Pocket.
+    pub fn pocket() -> Palette {
+        Palette::uniform("pocket", [WHITE, [0xAA, 0xAA, 0xAA], [0x55, 0x55, 0x55], BLACK])
+    }
+
+    /// The blue-green backlight of the Game Boy Light.
+    pub fn light() -> Palette {
+        Palette::uniform("light", [[0x00, 0xB5, 0x81], [0x00, 0x9A, 0x71], [0x00, 0x69, 0x4A], [0x00, 0x4F, 0x3B]])
+    }
+
+    /// Looks up "dmg", "pocket" or "light". The "gbc" palette depends on the cartridge, see `gbc_boot`.
+    pub fn preset(name: &str) -> Option<Palette> {
+        match name {
+            "dmg" => Some(Palette::dmg()),
+            "pocket" => Some(Palette::pocket()),
+            "light" => Some(Palette::light()),
+            _ => None,
+        }
+    }
+
+    /// The palette a GBC chooses when it boots a DMG game: by title hash for Nintendo games, the default otherwise.
+    pub fn gbc_boot(header: &CartridgeHeader) -> Palette {
+        let nintendo = header.old_licensee_code == 0x01
+            || (header.old_licensee_code == 0x33 && header.new_licensee_code == "01");
+        let title = header.title.as_bytes();
+        let hash = title_hash(title);
+
+        let entry = GBC_TITLE_PALETTES.iter()
+            .find(|entry| entry.hash == hash && entry.fourth_letter.is_none_or(|letter| title.get(3) == Some(&letter)));
+        let [background, object0, object1] = match entry {
+            Some(entry) if nintendo => entry.palettes,
+            _ => [GBC_DEFAULT_BACKGROUND, GBC_RED, GBC_RED],
+        };
+        Palette { name: String::from("gbc"), background, object0, object1 }
+    }
+
+    /// Parses palette files: `bg`, `obj0` and `obj1` lines of four hex colors, lightest first.
+    /// Object palettes default to the background one. Everything after a `#` is ignored.
+    ///
+    /// ```text
+    /// bg   FFFFFF 7BFF31 0063C5 000000
+    /// obj0 FFFFFF FF8484 943A3A 000000
+    /// ```
+    pub fn parse(name: &str, text: &str) -> Result<Palette, ParsePaletteError> {
+        let mut background = None;
+        let mut object0 = None;
+        let mut object1 = None;
+
+        for (idx, line) in text.lines().enumerate() {
+            let error = |message: String| ParsePaletteError { line: idx + 1, message };
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let Some(key) = words.next() else { continue };
+            let target = match key {
+                "bg" => &mut background,
+                "obj0" => &mut object0,
+                "obj1" => &mut object1,
+                _ => return Err(error(format!("unknown palette '{key}', expected bg, obj0 or obj1"))),
+            };
+
+            let colors: Vec<Rgb> = words.map(|word| Self::parse_color(word).ok_or_else(|| error(format!("invalid color '{word}'"))))
+                .collect::<Result<_, _>>()?;
+            *target = Some(colors.try_into().map_err(|_| error(String::from("expected four colors")))?);
+        }
+
+        let Some(background) = background else {
+            return Err(ParsePaletteError { line: 0, message: String::from("missing bg line") });
+        };
+        Ok(Palette {
+            name: String::from(name),
+            background,
+            object0: object0.unwrap_or(background),
+            object1: object1.unwrap_or(background),
+        })
+    }
+
+    fn parse_color(word: &str) -> Option<Rgb> {
+        if word.len() != 6 {
+            return None;
+        }
+        let value = u32::from_str_radix(word, 16).ok()?;
+        Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+    }
+
+    /// Color of `shade` drawn by `layer`.
+    pub fn rgb(&self, layer: Layer, shade: u8) -> Rgb {
+        let colors = match layer {
+            Layer::Background => &self.background,
+            Layer::Object0 => &self.object0,
+            Layer::Object1 => &self.object1,
+        };
+        colors[shade as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_palette_files() {
+        let palette = Palette::parse("custom", "# comment\nbg FFFFFF AAAAAA 555555 000000\n\nobj1 000000 111111 222222 333333 # dark\n").unwrap();
+
+        assert_eq!(palette.rgb(Layer::Background, 1), [0xAA, 0xAA, 0xAA]);
+        assert_eq!(palette.rgb(Layer::Object0, 3), BLACK);
+        assert_eq!(palette.rgb(Layer::Object1, 2), [0x22, 0x22, 0x22]);
+
+        assert_eq!(Palette::parse("custom", "bg FFFFFF 000000").unwrap_err().line, 1);
+        assert_eq!(Palette::parse("custom", "obj0 FFFFFF 000000 000000 00000G").unwrap_err().line, 1);
+        assert_eq!(Palette::parse("custom", "obj0 FFFFFF 000000 000000 000000").unwrap_err().line, 0);
+    }
+}
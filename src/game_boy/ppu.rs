@@ -1,55 +1,534 @@
 use std::cell::RefCell;
 use std::rc::Rc;
-use crate::game_boy::memory::Memory;
+use crate::game_boy::memory::{Memory, INTERRUPT_STAT, INTERRUPT_VBLANK};
+use crate::game_boy::memory::object_attribute_memory::object_attributes::DmgPalette;
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
 
-pub(crate) struct PPU {
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const DOTS_PER_LINE: i32 = 456;
+const LINES_PER_FRAME: u8 = 154;
+const MODE2_DOTS: i32 = 80;
+const MODE3_DOTS: i32 = 172;
+const MAX_OBJECTS_PER_LINE: usize = 10;
+/// RGB555 white, what a disabled CGB LCD shows.
+const WHITE: u16 = 0x7FFF;
+
+const MODE_HBLANK: u8 = 0;
+const MODE_VBLANK: u8 = 1;
+const MODE_OAM_SCAN: u8 = 2;
+const MODE_DRAWING: u8 = 3;
+
+/// What drew a pixel, which decides its colors when DMG shades are colorized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Background,
+    /// An object using OBP0.
+    Object0,
+    /// An object using OBP1.
+    Object1,
+}
+
+pub struct PPU {
     memory: Rc<RefCell<Memory>>,
     acc: i32,
-    current_scanline: u8
+    current_scanline: u8,
+    mode: u8,
+    /// Line of the window to draw next; only advances on lines where the window is visible.
+    window_line: u8,
+    stat_line: bool,
+    line_objects: Vec<usize>,
+    back_buffer: Box<[u8; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    /// Last completed frame as DMG shades, 0 being the lightest.
+    framebuffer: Box<[u8; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    layer_back_buffer: Box<[Layer; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    /// The layer each pixel of `framebuffer` comes from.
+    layers: Box<[Layer; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    color_back_buffer: Box<[u16; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    /// Last completed frame as RGB555 colors, only drawn in CGB mode.
+    color_framebuffer: Box<[u16; SCREEN_WIDTH * SCREEN_HEIGHT]>,
 }
 
 impl PPU {
-    pub fn new(memory: Rc<RefCell<Memory>>) -> PPU {
-        PPU{ memory, acc: 0, current_scanline: 0 }
+    pub(crate) fn new(memory: Rc<RefCell<Memory>>) -> PPU {
+        PPU {
+            memory,
+            acc: 0,
+            current_scanline: 0,
+            mode: MODE_OAM_SCAN,
+            window_line: 0,
+            stat_line: false,
+            line_objects: Vec::with_capacity(MAX_OBJECTS_PER_LINE),
+            back_buffer: Box::new([0; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            framebuffer: Box::new([0; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            layer_back_buffer: Box::new([Layer::Background; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            layers: Box::new([Layer::Background; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            color_back_buffer: Box::new([WHITE; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            color_framebuffer: Box::new([WHITE; SCREEN_WIDTH * SCREEN_HEIGHT]),
+        }
     }
 
-    pub fn step(&mut self, cycles: i32) {
-        self.acc += 4 * cycles;
-        if self.acc >= 456 {
-            self.acc -= 456;
-            self.draw_line();
+    /// Leaves out the buffers unless `with_picture`, for states that run a frame before anybody looks at them.
+    pub(crate) fn save_state(&self, state: &mut StateWriter, with_picture: bool) {
+        state.write_i32(self.acc);
+        state.write_bytes(&[self.current_scanline, self.mode, self.window_line]);
+        state.write_bool(self.stat_line);
+        state.write_blob(&self.line_objects.iter().map(|&idx| idx as u8).collect::<Vec<_>>());
+        if !with_picture {
+            return;
+        }
+        for (shades, layers, colors) in [
+            (&self.back_buffer, &self.layer_back_buffer, &self.color_back_buffer),
+            (&self.framebuffer, &self.layers, &self.color_framebuffer),
+        ] {
+            state.write_bytes(&shades[..]);
+            for layer in layers.iter() {
+                state.write_u8(*layer as u8);
+            }
+            for color in colors.iter() {
+                state.write_u16(*color);
+            }
         }
     }
 
-    fn draw_line(&mut self) {
-        if self.current_scanline <= 143 {
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.acc = state.read_i32()?;
+        let mut counters = [0; 3];
+        state.read_bytes(&mut counters)?;
+        [self.current_scanline, self.mode, self.window_line] = counters;
+        if self.current_scanline >= LINES_PER_FRAME || self.mode > MODE_DRAWING {
+            return Err(StateError::Invalid("PPU position"));
+        }
+        self.stat_line = state.read_bool()?;
+        let line_objects = state.read_blob()?;
+        if line_objects.len() > MAX_OBJECTS_PER_LINE || line_objects.iter().any(|&idx| idx >= 40) {
+            return Err(StateError::Invalid("list of objects on the line"));
+        }
+        self.line_objects = line_objects.iter().map(|&idx| idx as usize).collect();
+        // states without the picture keep the one on screen
+        if state.is_at_end() {
+            return Ok(());
+        }
+        for (shades, layers, colors) in [
+            (&mut self.back_buffer, &mut self.layer_back_buffer, &mut self.color_back_buffer),
+            (&mut self.framebuffer, &mut self.layers, &mut self.color_framebuffer),
+        ] {
+            state.read_bytes(&mut shades[..])?;
+            shades.iter_mut().for_each(|shade| *shade &= 0x03);
+            for layer in layers.iter_mut() {
+                *layer = match state.read_u8()? {
+                    0 => Layer::Background,
+                    1 => Layer::Object0,
+                    2 => Layer::Object1,
+                    _ => return Err(StateError::Invalid("pixel layer")),
+                };
+            }
+            for color in colors.iter_mut() {
+                *color = state.read_u16()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks up at the start of the line and mode in LY and STAT, which is all BESS keeps of the PPU.
+    pub(crate) fn import_bess(&mut self) {
+        let (ly, stat, lcd_enabled) = {
+            let memory = self.memory.borrow();
+            (memory.peek(0xFF44), memory.peek(0xFF41), memory.lcdc().is_lcd_ppu_enabled())
+        };
+        self.current_scanline = if lcd_enabled && ly < LINES_PER_FRAME { ly } else { 0 };
+        self.mode = match stat & 0x03 {
+            _ if !lcd_enabled => MODE_HBLANK,
+            _ if self.current_scanline >= SCREEN_HEIGHT as u8 => MODE_VBLANK,
+            MODE_VBLANK => MODE_OAM_SCAN,
+            mode => mode,
+        };
+        self.acc = match self.mode {
+            MODE_DRAWING => MODE2_DOTS,
+            MODE_HBLANK if lcd_enabled => MODE2_DOTS + MODE3_DOTS,
+            _ => 0,
+        };
+        if self.mode == MODE_DRAWING {
             self.mode2();
-            self.mode3();
         }
+        // the window line is not part of the state, assume the window ran on every line so far
+        let window_y = self.memory.borrow().peek(0xFF4A);
+        self.window_line = self.current_scanline.saturating_sub(window_y).min(SCREEN_HEIGHT as u8);
+
+        let mut memory = self.memory.borrow_mut();
+        memory.set_ly(self.current_scanline);
+        let ly_equals_lyc = self.current_scanline == memory.peek(0xFF45);
+        memory.set_stat(self.mode, ly_equals_lyc);
+        self.stat_line = Self::stat_line(self.mode, ly_equals_lyc, memory.peek(0xFF41));
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer[..]
+    }
+
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers[..]
+    }
+
+    pub fn color_framebuffer(&self) -> &[u16] {
+        &self.color_framebuffer[..]
+    }
+
+    /// The scanline being drawn, as exposed in LY.
+    pub fn ly(&self) -> u8 {
+        self.current_scanline
+    }
+
+    /// 0 for HBlank, 1 for VBlank, 2 while scanning OAM and 3 while drawing, as exposed in STAT.
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
+    /// Advances by `dots`, 4 per machine cycle at normal speed and 2 in double speed.
+    /// Returns true when VBlank begins and the new frame is ready.
+    pub(crate) fn step(&mut self, dots: i32) -> bool {
+        self.acc += dots;
+
+        if !self.memory.borrow().lcdc().is_lcd_ppu_enabled() {
+            return self.step_disabled();
+        }
+
+        let mut frame_completed = false;
+        loop {
+            let next_mode = match self.mode {
+                MODE_OAM_SCAN if self.acc >= MODE2_DOTS => {
+                    self.mode2();
+                    MODE_DRAWING
+                }
+                MODE_DRAWING if self.acc >= MODE2_DOTS + MODE3_DOTS => {
+                    self.mode3();
+                    self.memory.borrow_mut().request_hblank_dma();
+                    MODE_HBLANK
+                }
+                MODE_HBLANK | MODE_VBLANK if self.acc >= DOTS_PER_LINE => {
+                    self.acc -= DOTS_PER_LINE;
+                    frame_completed |= self.draw_line();
+                    if self.current_scanline < SCREEN_HEIGHT as u8 { MODE_OAM_SCAN } else { MODE_VBLANK }
+                }
+                _ => break,
+            };
+            self.mode = next_mode;
+            self.update_stat();
+        }
+
+        frame_completed
+    }
+
+    /// With the LCD off LY stays at 0 and the screen is blank, but frames keep their pace.
+    fn step_disabled(&mut self) -> bool {
+        if self.current_scanline != 0 || self.mode != MODE_HBLANK {
+            self.current_scanline = 0;
+            self.mode = MODE_HBLANK;
+            self.window_line = 0;
+            self.memory.borrow_mut().set_ly(0);
+            self.memory.borrow_mut().set_stat(MODE_HBLANK, false);
+            self.framebuffer.fill(0);
+            self.layers.fill(Layer::Background);
+            self.color_framebuffer.fill(WHITE);
+        }
+
+        let frame_dots = DOTS_PER_LINE * LINES_PER_FRAME as i32;
+        if self.acc >= frame_dots {
+            self.acc -= frame_dots;
+            // start a fresh frame from line 0 once the LCD is turned back on
+            self.mode = MODE_OAM_SCAN;
+            return true;
+        }
+        false
+    }
+
+    fn draw_line(&mut self) -> bool {
         self.current_scanline += 1;
+        if self.current_scanline == LINES_PER_FRAME {
+            self.current_scanline = 0;
+            self.window_line = 0;
+        }
         self.memory.borrow_mut().set_ly(self.current_scanline);
 
-        if self.current_scanline == 154 {
+        if self.current_scanline == SCREEN_HEIGHT as u8 {
+            self.memory.borrow_mut().request_interrupt(INTERRUPT_VBLANK);
             self.draw_frame();
-            self.current_scanline = 0;
+            return true;
         }
+        false
+    }
+
+    fn draw_frame(&mut self) {
+        std::mem::swap(&mut self.framebuffer, &mut self.back_buffer);
+        std::mem::swap(&mut self.layers, &mut self.layer_back_buffer);
+        std::mem::swap(&mut self.color_framebuffer, &mut self.color_back_buffer);
     }
 
-    pub fn draw_frame(&self) {
-        println!("drawing frame");
+    /// Updates the STAT register and requests the STAT interrupt on a rising edge of any enabled source.
+    fn update_stat(&mut self) {
+        let mut memory = self.memory.borrow_mut();
+        let ly_equals_lyc = memory.peek(0xFF44) == memory.peek(0xFF45);
+        memory.set_stat(self.mode, ly_equals_lyc);
+
+        let stat_line = Self::stat_line(self.mode, ly_equals_lyc, memory.peek(0xFF41));
+        if stat_line && !self.stat_line {
+            memory.request_interrupt(INTERRUPT_STAT);
+        }
+        self.stat_line = stat_line;
     }
-    
+
+    /// Whether any STAT interrupt source enabled in `stat` is active.
+    fn stat_line(mode: u8, ly_equals_lyc: bool, stat: u8) -> bool {
+        (ly_equals_lyc && stat & (1 << 6) != 0)
+            || (mode == MODE_OAM_SCAN && stat & (1 << 5) != 0)
+            || (mode == MODE_VBLANK && stat & (1 << 4) != 0)
+            || (mode == MODE_HBLANK && stat & (1 << 3) != 0)
+    }
+
     /// Search OBJs which overlap current line.
     /// Duration is 80 dots.
     /// Can access VRAM and CGB palettes
-    fn mode2(&self) {
+    fn mode2(&mut self) {
+        let memory = self.memory.borrow();
+        let height = memory.lcdc().obj_height() as i16;
+        let line = self.current_scanline as i16;
 
+        self.line_objects.clear();
+        for (idx, object) in memory.object_attribute_memory().objects().iter().enumerate() {
+            if (object.top()..object.top() + height).contains(&line) {
+                self.line_objects.push(idx);
+                if self.line_objects.len() == MAX_OBJECTS_PER_LINE {
+                    break;
+                }
+            }
+        }
     }
-    
+
     /// Send pixels to LCD
     /// Duration is between 172 and 289 dots
     /// can't access video memory
-    fn mode3(&self) {
+    fn mode3(&mut self) {
+        let memory = self.memory.borrow();
+        let lcdc = memory.lcdc();
+        let line = self.current_scanline;
+        let cgb_mode = memory.is_cgb_mode();
+
+        let scroll_y = memory.peek(0xFF42);
+        let scroll_x = memory.peek(0xFF43);
+        let window_y = memory.peek(0xFF4A);
+        let window_x = memory.peek(0xFF4B) as i16 - 7;
+        let bg_palette = memory.peek(0xFF47);
+        // the tile maps live in bank 0 whichever bank the CPU has selected, bank 1 holds their attributes
+        let vram = memory.video_ram(0);
+
+        // color indices before the palette is applied, needed for object priority
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        // CGB map attributes: palette in bits 0-2, tile bank in bit 3, flips in bits 5-6, priority in bit 7
+        let mut bg_attributes = [0u8; SCREEN_WIDTH];
+
+        // on CGB LCDC.0 only takes away the priority of the BG, it is still drawn
+        if lcdc.is_bg_window_enabled() || cgb_mode {
+            let window_visible = lcdc.is_window_enabled() && line >= window_y && window_x < SCREEN_WIDTH as i16;
+
+            for (x, (color, attributes)) in bg_colors.iter_mut().zip(&mut bg_attributes).enumerate() {
+                let in_window = window_visible && x as i16 >= window_x;
+                let (map_address, map_x, map_y) = if in_window {
+                    (lcdc.window_tile_map_address(), (x as i16 - window_x) as u8, self.window_line)
+                } else {
+                    (lcdc.bg_tile_map_address(), scroll_x.wrapping_add(x as u8), scroll_y.wrapping_add(line))
+                };
+
+                let tile_address = map_address + (map_y as u16 / 8) * 32 + map_x as u16 / 8;
+                if cgb_mode {
+                    *attributes = memory.video_ram(1).read(tile_address);
+                }
+                let tile_vram = memory.video_ram((*attributes >> 3 & 1) as usize);
+                let tile_idx = vram.read(tile_address);
+                let tile = if lcdc.is_unsigned_tile_data_area() {
+                    tile_vram.unsigned_tile(tile_idx)
+                } else {
+                    tile_vram.signed_tile(tile_idx)
+                };
+                let tile_row = if *attributes & 0x40 != 0 { 7 - map_y % 8 } else { map_y % 8 };
+                let tile_column = if *attributes & 0x20 != 0 { 7 - map_x % 8 } else { map_x % 8 };
+                *color = tile.row(tile_row as usize)[tile_column as usize];
+            }
+
+            if window_visible {
+                self.window_line += 1;
+            }
+        }
 
+        let pixels = line as usize * SCREEN_WIDTH..(line as usize + 1) * SCREEN_WIDTH;
+        let row = &mut self.back_buffer[pixels.clone()];
+        let layer_row = &mut self.layer_back_buffer[pixels.clone()];
+        let color_row = &mut self.color_back_buffer[pixels];
+        layer_row.fill(Layer::Background);
+        for (x, color) in bg_colors.into_iter().enumerate() {
+            row[x] = Self::apply_palette(bg_palette, color);
+            if cgb_mode {
+                color_row[x] = memory.bg_palettes().color(bg_attributes[x] & 0b111, color);
+            }
+        }
+
+        if !lcdc.is_obj_enabled() {
+            return;
+        }
+
+        let objects = memory.object_attribute_memory().objects();
+        let height = lcdc.obj_height();
+        let mut drawn: Vec<usize> = self.line_objects.clone();
+        if !cgb_mode {
+            // on DMG the object with the smaller X wins, then the one earlier in OAM; on CGB only the OAM order counts
+            drawn.sort_by_key(|&idx| (objects[idx].left(), idx));
+        }
+
+        let mut occupied = [false; SCREEN_WIDTH];
+        for idx in drawn {
+            let object = &objects[idx];
+            let mut object_row = (line as i16 - object.top()) as u8;
+            if object.y_flip() {
+                object_row = height - 1 - object_row;
+            }
+            let mut tile_idx = object.tile_index();
+            if height == 16 {
+                tile_idx = (tile_idx & 0xFE) | (object_row / 8);
+            }
+            let tile_vram = memory.video_ram(if cgb_mode { object.vram_bank() } else { 0 });
+            let colors = tile_vram.unsigned_tile(tile_idx).row((object_row % 8) as usize);
+            let (palette, layer) = match object.dmg_palette() {
+                DmgPalette::OBP0 => (memory.peek(0xFF48), Layer::Object0),
+                DmgPalette::OBP1 => (memory.peek(0xFF49), Layer::Object1),
+            };
+
+            for column in 0..8 {
+                let x = object.left() + column as i16;
+                if !(0..SCREEN_WIDTH as i16).contains(&x) || occupied[x as usize] {
+                    continue;
+                }
+                let x = x as usize;
+                let color = colors[if object.x_flip() { 7 - column } else { column }];
+                if color == 0 {
+                    continue;
+                }
+                occupied[x] = true;
+                let background_wins = bg_colors[x] != 0
+                    && (object.is_behind_background() || bg_attributes[x] & 0x80 != 0)
+                    && (lcdc.is_bg_window_enabled() || !cgb_mode);
+                if background_wins {
+                    continue;
+                }
+                row[x] = Self::apply_palette(palette, color);
+                layer_row[x] = layer;
+                if cgb_mode {
+                    color_row[x] = memory.obj_palettes().color(object.cgb_palette(), color);
+                }
+            }
+        }
     }
-}
\ No newline at end of file
+
+    fn apply_palette(palette: u8, color: u8) -> u8 {
+        (palette >> (2 * color)) & 0b11
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(memory: &Rc<RefCell<Memory>>) -> PPU {
+        // runs until the first VBlank
+        let mut ppu = PPU::new(Rc::clone(memory));
+        while !ppu.step(4) {}
+        ppu
+    }
+
+    #[test]
+    fn draws_background_tile_through_palette() {
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        {
+            let mut memory = memory.borrow_mut();
+            // tile 1: left half color 3, right half color 1
+            for row in 0..8 {
+                memory.write(0x8010 + 2 * row, 0xFF);
+                memory.write(0x8011 + 2 * row, 0xF0);
+            }
+            memory.write(0x9800, 0x01);
+            memory.write(0xFF47, 0b11_10_01_00);
+            memory.write(0xFF40, 0b1001_0001);
+        }
+
+        let ppu = frame(&memory);
+
+        assert_eq!(ppu.framebuffer()[0..9], [3, 3, 3, 3, 1, 1, 1, 1, 0]);
+        assert_eq!(ppu.framebuffer()[7 * SCREEN_WIDTH], 3);
+        assert_eq!(ppu.framebuffer()[8 * SCREEN_WIDTH], 0);
+    }
+
+    #[test]
+    fn draws_objects_over_background() {
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        {
+            let mut memory = memory.borrow_mut();
+            for row in 0..8 {
+                memory.write(0x8010 + 2 * row, 0xFF);
+                memory.write(0x8011 + 2 * row, 0xFF);
+            }
+            // object at screen position (4, 2), X flipped, using tile 1 and OBP1
+            memory.write(0xFE00, 18);
+            memory.write(0xFE01, 12);
+            memory.write(0xFE02, 0x01);
+            memory.write(0xFE03, 0b0011_0000);
+            memory.write(0xFF49, 0b01_00_00_00);
+            memory.write(0xFF40, 0b1000_0010);
+        }
+
+        let ppu = frame(&memory);
+
+        assert_eq!(ppu.framebuffer()[2 * SCREEN_WIDTH + 3], 0);
+        assert_eq!(ppu.framebuffer()[2 * SCREEN_WIDTH + 4], 1);
+        assert_eq!(ppu.framebuffer()[9 * SCREEN_WIDTH + 11], 1);
+        assert_eq!(ppu.framebuffer()[10 * SCREEN_WIDTH + 4], 0);
+    }
+
+    #[test]
+    fn draws_cgb_background_with_map_attributes() {
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        {
+            let mut memory = memory.borrow_mut();
+            memory.set_cgb_mode(true);
+            // tile 1 in bank 1: left half color 1, right half color 0
+            memory.write(0xFF4F, 1);
+            for row in 0..8 {
+                memory.write(0x8010 + 2 * row, 0xF0);
+            }
+            // map entry 0 uses tile 1 from bank 1, X flipped, with palette 2
+            memory.write(0x9800, 0b0010_1010);
+            memory.write(0xFF4F, 0);
+            memory.write(0x9800, 0x01);
+            // palette 2 color 1 is pure blue
+            memory.write(0xFF68, 0x80 | 0x12);
+            memory.write(0xFF69, 0x00);
+            memory.write(0xFF69, 0x7C);
+            memory.write(0xFF40, 0b1001_0001);
+        }
+
+        let ppu = frame(&memory);
+
+        assert_eq!(ppu.color_framebuffer()[0..8], [0, 0, 0, 0, 0x7C00, 0x7C00, 0x7C00, 0x7C00]);
+    }
+
+    #[test]
+    fn requests_vblank_interrupt() {
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        memory.borrow_mut().write(0xFF40, 0x80);
+        let mut ppu = PPU::new(Rc::clone(&memory));
+
+        ppu.step(144 * DOTS_PER_LINE - 4);
+        assert_eq!(memory.borrow().read(0xFF0F) & INTERRUPT_VBLANK, 0);
+        ppu.step(4);
+        assert_eq!(memory.borrow().read(0xFF0F) & INTERRUPT_VBLANK, INTERRUPT_VBLANK);
+        assert_eq!(memory.borrow().read(0xFF41) & 0b11, MODE_VBLANK);
+    }
+}
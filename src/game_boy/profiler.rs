@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::io::Write;
+use crate::game_boy::cpu::registers::Registers;
+use crate::game_boy::memory::Memory;
+use crate::game_boy::symbols::Symbols;
+
+/// Where code runs, as the bank it is in (None while the boot ROM is mapped in) and its address.
+type Location = (Option<usize>, u16);
+
+/// CALL, CALL cc and RST.
+const CALLS: [u8; 13] = [0xCD, 0xC4, 0xCC, 0xD4, 0xDC, 0xC7, 0xCF, 0xD7, 0xDF, 0xE7, 0xEF, 0xF7, 0xFF];
+
+/// Rows in each table of the report.
+const REPORT_ROWS: usize = 20;
+
+/// A function in the call tree, one for each chain of calls that led to it.
+struct Node {
+    function: Location,
+    parent: usize,
+    children: HashMap<Location, usize>,
+    cycles: u64,
+    halted: u64,
+}
+
+impl Node {
+    fn new(function: Location, parent: usize) -> Node {
+        Node { function, parent, children: HashMap::new(), cycles: 0, halted: 0 }
+    }
+}
+
+/// A call that has not returned, which it has once SP moves up past its return address.
+struct Frame {
+    node: usize,
+    sp: u16,
+}
+
+/// The instruction about to run.
+struct Pending {
+    location: Location,
+    opcode: u8,
+    sp: u16,
+}
+
+/// Counts the machine cycles spent at each address and under which calls, to see where the CPU time goes.
+/// Calls are followed through CALL, RST and interrupts, a call has returned once SP is above its return address.
+///
+/// When dropped, writes the call tree as folded stacks, `EntryPoint;Main;UpdateSprites 1234` on each line,
+/// which flamegraph.pl and inferno turn into flame graphs. The optional report sums up the cycles per
+/// ROM bank, per symbol and per address.
+pub struct Profiler {
+    output: Box<dyn Write>,
+    report: Option<Box<dyn Write>>,
+    symbols: Symbols,
+    by_location: HashMap<Location, u64>,
+    /// The call tree, rooted at where profiling started.
+    nodes: Vec<Node>,
+    stack: Vec<Frame>,
+    pending: Option<Pending>,
+}
+
+impl Profiler {
+    pub fn new(output: impl Write + 'static) -> Profiler {
+        Profiler {
+            output: Box::new(output),
+            report: None,
+            symbols: Symbols::default(),
+            by_location: HashMap::new(),
+            nodes: Vec::new(),
+            stack: Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// Also writes the tables of the busiest banks, symbols and addresses to `report`.
+    pub fn with_report(mut self, report: impl Write + 'static) -> Profiler {
+        self.report = Some(Box::new(report));
+        self
+    }
+
+    /// Names functions and sums up cycles per symbol with these labels.
+    pub fn with_symbols(mut self, symbols: Symbols) -> Profiler {
+        self.symbols = symbols;
+        self
+    }
+
+    pub(crate) fn before(&mut self, registers: &Registers, memory: &Memory) {
+        let pc = registers.read_pc();
+        let location = (memory.bank_at(pc), pc);
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::new(location, 0));
+        }
+        self.pending = Some(Pending { location, opcode: memory.peek(pc), sp: registers.read_sp() });
+    }
+
+    pub(crate) fn after(&mut self, registers: &Registers, memory: &Memory, cycles: i32) {
+        let Some(pending) = self.pending.take() else { return };
+        let node = self.current();
+        self.nodes[node].cycles += cycles as u64;
+        *self.by_location.entry(pending.location).or_default() += cycles as u64;
+
+        let sp = registers.read_sp();
+        self.unwind(sp);
+        if CALLS.contains(&pending.opcode) && sp == pending.sp.wrapping_sub(2) {
+            let pc = registers.read_pc();
+            self.enter((memory.bank_at(pc), pc), sp);
+        }
+    }
+
+    /// An interrupt handler was called, its dispatch counts towards the handler.
+    pub(crate) fn interrupt(&mut self, registers: &Registers, memory: &Memory, cycles: i32) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let (pc, sp) = (registers.read_pc(), registers.read_sp());
+        self.unwind(sp);
+        self.enter((memory.bank_at(pc), pc), sp);
+        let node = self.current();
+        self.nodes[node].cycles += cycles as u64;
+        *self.by_location.entry((memory.bank_at(pc), pc)).or_default() += cycles as u64;
+    }
+
+    /// Cycles the CPU sat in HALT or STOP, waiting in whatever function it halted in.
+    pub(crate) fn halted(&mut self, cycles: i32) {
+        if let Some(node) = self.nodes.get_mut(self.stack.last().map_or(0, |frame| frame.node)) {
+            node.halted += cycles as u64;
+        }
+    }
+
+    fn current(&self) -> usize {
+        self.stack.last().map_or(0, |frame| frame.node)
+    }
+
+    /// Drops the calls whose return address SP has moved past, by RET or by resetting the stack.
+    fn unwind(&mut self, sp: u16) {
+        while self.stack.last().is_some_and(|frame| frame.sp < sp) {
+            self.stack.pop();
+        }
+    }
+
+    fn enter(&mut self, function: Location, sp: u16) {
+        let parent = self.current();
+        let next = self.nodes.len();
+        let node = *self.nodes[parent].children.entry(function).or_insert(next);
+        if node == next {
+            self.nodes.push(Node::new(function, parent));
+        }
+        self.stack.push(Frame { node, sp });
+    }
+
+    fn name(&self, (bank, address): Location) -> String {
+        if let Some(name) = bank.and_then(|bank| self.symbols.describe(bank, address)) {
+            return name;
+        }
+        match (bank, address) {
+            (None, _) => format!("boot:${address:04X}"),
+            (Some(bank), 0x4000..=0x7FFF) => format!("${bank:02X}:{address:04X}"),
+            _ => format!("${address:04X}"),
+        }
+    }
+
+    /// The symbol whose code `location` is in, without its offset or local label.
+    fn function_of(&self, (bank, address): Location) -> Option<String> {
+        let name = self.symbols.describe(bank?, address)?;
+        let name = name.split('+').next().unwrap_or_default();
+        Some(String::from(name.split('.').next().unwrap_or_default()))
+    }
+
+    fn write_folded(&mut self) -> std::io::Result<()> {
+        let names: Vec<String> = self.nodes.iter().map(|node| self.name(node.function)).collect();
+        for (idx, node) in self.nodes.iter().enumerate() {
+            if node.cycles == 0 && node.halted == 0 {
+                continue;
+            }
+            let mut path = vec![names[idx].as_str()];
+            let mut parent = idx;
+            while parent != 0 {
+                parent = self.nodes[parent].parent;
+                path.push(&names[parent]);
+            }
+            path.reverse();
+            let stack = path.join(";");
+            if node.cycles > 0 {
+                writeln!(self.output, "{stack} {}", node.cycles)?;
+            }
+            if node.halted > 0 {
+                writeln!(self.output, "{stack};(halted) {}", node.halted)?;
+            }
+        }
+        self.output.flush()
+    }
+
+    fn write_report(&self, report: &mut dyn Write) -> std::io::Result<()> {
+        let busy: u64 = self.by_location.values().sum();
+        let halted: u64 = self.nodes.iter().map(|node| node.halted).sum();
+        let total = (busy + halted).max(1);
+        writeln!(report, "{} M-cycles profiled, {halted} of them halted", busy + halted)?;
+
+        let mut banks: HashMap<String, u64> = HashMap::new();
+        let mut functions: HashMap<String, u64> = HashMap::new();
+        for (&(bank, address), &cycles) in &self.by_location {
+            let region = match (bank, address) {
+                (None, _) => String::from("boot ROM"),
+                (Some(bank), 0x0000..=0x7FFF) => format!("ROM bank {bank:02X}"),
+                _ => String::from("RAM"),
+            };
+            *banks.entry(region).or_default() += cycles;
+            if let Some(function) = self.function_of((bank, address)) {
+                *functions.entry(function).or_default() += cycles;
+            }
+        }
+        let addresses = self.by_location.iter().map(|(&(bank, address), &cycles)| {
+            let text = match bank {
+                Some(bank) => format!("{bank:02X}:{address:04X}"),
+                None => format!("boot:{address:04X}"),
+            };
+            match bank.and_then(|bank| self.symbols.describe(bank, address)) {
+                Some(name) => (format!("{text} {name}"), cycles),
+                None => (text, cycles),
+            }
+        });
+
+        write_table(report, "bank", banks.into_iter(), total)?;
+        if !self.symbols.is_empty() {
+            write_table(report, "symbol", functions.into_iter(), total)?;
+        }
+        write_table(report, "address", addresses, total)?;
+        report.flush()
+    }
+}
+
+fn write_table(report: &mut dyn Write, heading: &str, rows: impl Iterator<Item = (String, u64)>, total: u64) -> std::io::Result<()> {
+    let mut rows: Vec<_> = rows.collect();
+    rows.sort_by(|(a_name, a_cycles), (b_name, b_cycles)| b_cycles.cmp(a_cycles).then_with(|| a_name.cmp(b_name)));
+    writeln!(report, "\n{:>10}  {:>6}  {heading}", "M-cycles", "share")?;
+    for (name, cycles) in rows.into_iter().take(REPORT_ROWS) {
+        writeln!(report, "{cycles:>10}  {:>5.1}%  {name}", cycles as f64 * 100.0 / total as f64)?;
+    }
+    Ok(())
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if let Err(error) = self.write_folded() {
+            eprintln!("cannot write the profile: {error}");
+        }
+        if let Some(mut report) = self.report.take()
+            && let Err(error) = self.write_report(&mut report)
+        {
+            eprintln!("cannot write the profile report: {error}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+    use crate::game_boy::GameBoy;
+    use crate::game_boy::tests::test_rom;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone()).unwrap().lines().map(String::from).collect()
+        }
+    }
+
+    #[test]
+    fn folds_cycles_into_call_stacks() {
+        // 0150: call $0157; call $015A; halt
+        // 0157: call $015A, then on into 015A: nop; ret
+        let rom = test_rom(&[0xCD, 0x57, 0x01, 0xCD, 0x5A, 0x01, 0x76, 0xCD, 0x5A, 0x01, 0x00, 0xC9]);
+        let symbols = Symbols::parse("00:0100 EntryPoint\n00:0150 Main\n00:0157 Outer\n00:015A Inner\n").unwrap();
+        let (folded, report) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut game_boy = GameBoy::new(rom).unwrap();
+        game_boy.set_profiler(Some(Profiler::new(folded.clone()).with_report(report.clone()).with_symbols(symbols)));
+        // 11 instructions up to HALT, then one cycle halted
+        for _ in 0..12 {
+            game_boy.step();
+        }
+        game_boy.set_profiler(None);
+
+        // JP takes 4 M-cycles, CALL 6, NOP 1, RET 4 and HALT 1
+        let mut lines = folded.lines();
+        lines.sort();
+        assert_eq!(lines, [
+            "EntryPoint 17",
+            "EntryPoint;(halted) 1",
+            "EntryPoint;Inner 5",
+            "EntryPoint;Outer 11",
+            "EntryPoint;Outer;Inner 5",
+        ]);
+        let report = report.lines();
+        assert_eq!(report[0], "39 M-cycles profiled, 1 of them halted");
+        assert!(report.contains(&String::from("        15   38.5%  Inner")));
+        assert!(report.contains(&String::from("        38   97.4%  ROM bank 00")));
+        assert!(report.contains(&String::from("        12   30.8%  00:015B Inner+1")));
+    }
+}
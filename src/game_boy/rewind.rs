@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+
+/// Rolling history of save states for stepping back in time. Only the newest state is kept in full,
+/// each older one as the difference to the state after it, which is mostly zeros from frame to frame.
+pub struct RewindBuffer {
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    /// Oldest first. Each delta turns the state after it back into its own.
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    /// Keeps up to `capacity` states besides the newest.
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer { capacity, newest: None, deltas: VecDeque::with_capacity(capacity) }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.newest.replace(state) {
+            if self.capacity == 0 {
+                return;
+            }
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+            let delta = encode_delta(&previous, self.newest.as_ref().unwrap());
+            self.deltas.push_back(delta);
+        }
+    }
+
+    /// Steps back one state and returns it, or None once the oldest state is reached.
+    pub fn pop(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        let newest = self.newest.as_mut().expect("deltas need a newest state");
+        apply_delta(newest, &delta);
+        Some(newest)
+    }
+
+    /// How many steps back are possible.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+    }
+
+    /// Bytes held by the states, for keeping an eye on memory use.
+    pub fn memory_usage(&self) -> usize {
+        self.newest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+/// The length of `previous`, then `previous` XOR `next` as runs of zeros and literal bytes.
+fn encode_delta(previous: &[u8], next: &[u8]) -> Vec<u8> {
+    let length = previous.len().max(next.len());
+    let byte = |data: &[u8], idx: usize| data.get(idx).copied().unwrap_or(0);
+    let xor = |idx: usize| byte(previous, idx) ^ byte(next, idx);
+
+    let mut delta = Vec::new();
+    write_varint(&mut delta, previous.len());
+    let mut idx = 0;
+    while idx < length {
+        let zeros_start = idx;
+        while idx < length && xor(idx) == 0 {
+            idx += 1;
+        }
+        let literal_start = idx;
+        // a short run of zeros costs more as a run than as part of the literal
+        while idx < length && (xor(idx) != 0 || (idx + 1 < length && xor(idx + 1) != 0)) {
+            idx += 1;
+        }
+        write_varint(&mut delta, literal_start - zeros_start);
+        write_varint(&mut delta, idx - literal_start);
+        delta.extend((literal_start..idx).map(xor));
+    }
+    delta
+}
+
+/// Turns `state` into the one `delta` was encoded against.
+fn apply_delta(state: &mut Vec<u8>, delta: &[u8]) {
+    let mut position = 0;
+    let length = read_varint(delta, &mut position);
+    state.resize(state.len().max(length), 0);
+
+    let mut idx = 0;
+    while position < delta.len() {
+        idx += read_varint(delta, &mut position);
+        let literal_length = read_varint(delta, &mut position);
+        for (target, value) in state[idx..idx + literal_length].iter_mut().zip(&delta[position..]) {
+            *target ^= value;
+        }
+        idx += literal_length;
+        position += literal_length;
+    }
+    state.truncate(length);
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::GameBoy;
+    use crate::game_boy::tests::test_rom;
+
+    #[test]
+    fn steps_back_through_states_of_any_length() {
+        let mut rewind = RewindBuffer::new(2);
+        let first = vec![7; 1000];
+        let mut second = first.clone();
+        second[500] = 1;
+        second.extend([1, 2, 3]);
+        let mut third = second.clone();
+        third.truncate(1000);
+        third[0] = 0;
+
+        rewind.push(vec![0; 5]);
+        rewind.push(first.clone());
+        rewind.push(second.clone());
+        rewind.push(third);
+        assert_eq!(rewind.len(), 2);
+        // the newest state in full and two small deltas
+        assert!(rewind.memory_usage() < 1000 + 30);
+
+        assert_eq!(rewind.pop(), Some(&second[..]));
+        assert_eq!(rewind.pop(), Some(&first[..]));
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn drops_the_oldest_states_past_capacity() {
+        let mut rewind = RewindBuffer::new(3);
+        for idx in 0..6 {
+            rewind.push(vec![idx; 100]);
+        }
+        assert_eq!(rewind.len(), 3);
+        for idx in [4, 3, 2] {
+            assert_eq!(rewind.pop(), Some(&[idx; 100][..]));
+        }
+        assert_eq!(rewind.pop(), None);
+
+        let mut nothing_to_rewind = RewindBuffer::new(0);
+        nothing_to_rewind.push(vec![1]);
+        nothing_to_rewind.push(vec![2]);
+        assert!(nothing_to_rewind.is_empty());
+    }
+
+    #[test]
+    fn restores_earlier_frames_of_a_running_game() {
+        // ld hl, $C000; inc [hl]; jr -3
+        let mut game_boy = GameBoy::new(test_rom(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD])).unwrap();
+        let mut rewind = RewindBuffer::new(60);
+        let mut frames = Vec::new();
+        for _ in 0..10 {
+            // like the frontend, the state from before each frame
+            rewind.push(game_boy.save_state_without_picture());
+            game_boy.run_frame();
+            frames.push((game_boy.memory().peek(0xC000), game_boy.frame_hash()));
+        }
+        assert_ne!(frames[6].0, frames[9].0);
+        assert!(game_boy.save_state_without_picture().len() < game_boy.save_state().len() - 2 * 160 * 144);
+
+        for _ in 0..3 {
+            game_boy.load_state(rewind.pop().unwrap()).unwrap();
+        }
+        game_boy.run_frame();
+        assert_eq!((game_boy.memory().peek(0xC000), game_boy.frame_hash()), frames[6]);
+        assert_eq!(rewind.len(), 6);
+    }
+}
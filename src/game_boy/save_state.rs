@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::game_boy::model::Model;
+
+const MAGIC: &[u8; 4] = b"GBST";
+/// Bumped whenever the layout changes. Appending fields to a section or adding sections
+/// keeps older readers working, anything else also raises `COMPATIBLE_VERSION`.
+pub const FORMAT_VERSION: u16 = 1;
+/// The oldest reader that can load states written by this version.
+const COMPATIBLE_VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// The data does not start with a save state header.
+    NotAState,
+    Truncated,
+    /// The state was written by a newer emulator in a layout this one cannot read.
+    UnsupportedVersion { format_version: u16, emulator_version: String },
+    RomMismatch { state_checksum: u16, rom_checksum: u16 },
+    ModelMismatch { state_model: String, model: Model },
+    MissingSection(String),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::UnsupportedVersion { format_version, emulator_version } => write!(
+                f, "save state has format version {format_version} from gameboy_emu {emulator_version}, \
+                this build only reads up to version {FORMAT_VERSION}"
+            ),
+            StateError::RomMismatch { state_checksum, rom_checksum } => write!(
+                f, "save state belongs to another ROM (checksum {state_checksum:04X}, this ROM has {rom_checksum:04X})"
+            ),
+            StateError::ModelMismatch { state_model, model } => {
+                write!(f, "save state was made on a {state_model}, this machine is a {model}")
+            }
+            StateError::MissingSection(tag) => write!(f, "save state lacks its {tag} section"),
+            StateError::Invalid(what) => write!(f, "save state has an invalid {what}"),
+        }
+    }
+}
+
+/// What a save state was made with, readable without loading it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct StateHeader {
+    pub format_version: u16,
+    pub emulator_version: String,
+    /// Model name as written by `Model`'s Display, kept as text so unknown models still parse.
+    pub model: String,
+    /// Global checksum of the ROM, as computed over its contents.
+    pub rom_checksum: u16,
+}
+
+impl StateHeader {
+    pub(crate) fn new(model: Model, rom_checksum: u16) -> StateHeader {
+        StateHeader {
+            format_version: FORMAT_VERSION,
+            emulator_version: String::from(env!("CARGO_PKG_VERSION")),
+            model: model.to_string(),
+            rom_checksum,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<StateHeader, StateError> {
+        Self::read(&mut StateReader::new(data))
+    }
+
+    fn read(state: &mut StateReader) -> Result<StateHeader, StateError> {
+        let mut magic = [0; 4];
+        state.read_bytes(&mut magic).map_err(|_| StateError::NotAState)?;
+        if &magic != MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let format_version = state.read_u16()?;
+        let compatible_version = state.read_u16()?;
+        let emulator_version = state.read_string()?;
+        if compatible_version > FORMAT_VERSION {
+            return Err(StateError::UnsupportedVersion { format_version, emulator_version });
+        }
+
+        Ok(StateHeader { format_version, emulator_version, model: state.read_string()?, rom_checksum: state.read_u16()? })
+    }
+
+    fn write(&self, state: &mut StateWriter) {
+        state.write_bytes(MAGIC);
+        state.write_u16(self.format_version);
+        state.write_u16(COMPATIBLE_VERSION);
+        state.write_blob(self.emulator_version.as_bytes());
+        state.write_blob(self.model.as_bytes());
+        state.write_u16(self.rom_checksum);
+    }
+}
+
+/// Builds a save state: the header, then sections of little-endian fields.
+pub(crate) struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new(header: &StateHeader) -> StateWriter {
+        let mut state = StateWriter { data: Vec::new() };
+        header.write(&mut state);
+        state
+    }
+
+    /// For formats that only borrow the section layout, like BESS blocks.
+    pub(crate) fn without_header() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Writes a section tagged with `tag`, its length lets readers skip sections they do not know.
+    pub(crate) fn section(&mut self, tag: &[u8; 4], write: impl FnOnce(&mut StateWriter)) {
+        self.write_bytes(tag);
+        let length_position = self.data.len();
+        self.write_u32(0);
+        write(self);
+        let length = (self.data.len() - length_position - 4) as u32;
+        self.data[length_position..length_position + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub(crate) fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub(crate) fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub(crate) fn write_u16(&mut self, value: u16) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub(crate) fn write_u32(&mut self, value: u32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub(crate) fn write_i32(&mut self, value: i32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub(crate) fn write_u64(&mut self, value: u64) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    /// Fixed-size data, read back with `StateReader::read_bytes`.
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Variable-size data, prefixed with its length.
+    pub(crate) fn write_blob(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// The sections of a save state. Sections this version does not know about are never asked for.
+pub(crate) struct Sections<'a>(HashMap<[u8; 4], StateReader<'a>>);
+
+impl<'a> Sections<'a> {
+    pub(crate) fn get(&mut self, tag: &[u8; 4]) -> Result<&mut StateReader<'a>, StateError> {
+        self.0.get_mut(tag).ok_or_else(|| StateError::MissingSection(String::from_utf8_lossy(tag).trim_end().to_string()))
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    /// Splits a whole state into its header and sections by tag.
+    pub(crate) fn sections(data: &'a [u8]) -> Result<(StateHeader, Sections<'a>), StateError> {
+        let mut state = StateReader::new(data);
+        let header = StateHeader::read(&mut state)?;
+
+        let mut sections = HashMap::new();
+        while state.position < data.len() {
+            let mut tag = [0; 4];
+            state.read_bytes(&mut tag)?;
+            let length = state.read_u32()? as usize;
+            let section = state.read_slice(length)?;
+            sections.insert(tag, StateReader::new(section));
+        }
+        Ok((header, Sections(sections)))
+    }
+
+    pub(crate) fn read_slice(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position.checked_add(length).filter(|&end| end <= self.data.len()).ok_or(StateError::Truncated)?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.read_slice(1)?[0])
+    }
+
+    pub(crate) fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub(crate) fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.read_slice(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.read_slice(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn read_i32(&mut self) -> Result<i32, StateError> {
+        Ok(i32::from_le_bytes(self.read_slice(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.read_slice(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        bytes.copy_from_slice(self.read_slice(bytes.len())?);
+        Ok(())
+    }
+
+    pub(crate) fn read_blob(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.read_u32()? as usize;
+        self.read_slice(length)
+    }
+
+    pub(crate) fn read_string(&mut self) -> Result<String, StateError> {
+        Ok(String::from_utf8_lossy(self.read_blob()?).into_owned())
+    }
+
+    /// Whether everything was read, for optional fields at the end of a section.
+    pub(crate) fn is_at_end(&self) -> bool {
+        self.position == self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections_skip_unknown_tags_and_trailing_fields() {
+        let mut state = StateWriter::new(&StateHeader::new(Model::Dmg, 0x1234));
+        state.section(b"NEW ", |state| state.write_u64(7));
+        state.section(b"TEST", |state| {
+            state.write_u16(0xBEEF);
+            state.write_bool(true);
+        });
+        let data = state.into_bytes();
+
+        let (header, mut sections) = StateReader::sections(&data).unwrap();
+        assert_eq!(header.model, "dmg");
+        assert_eq!(header.rom_checksum, 0x1234);
+        assert_eq!(sections.get(b"TEST").unwrap().read_u16(), Ok(0xBEEF));
+        assert_eq!(sections.get(b"OLD ").err(), Some(StateError::MissingSection(String::from("OLD"))));
+
+        assert_eq!(StateReader::sections(&data[..data.len() - 1]).err(), Some(StateError::Truncated));
+        assert_eq!(StateHeader::parse(b"RIFF").err(), Some(StateError::NotAState));
+    }
+
+    #[test]
+    fn rejects_states_from_incompatible_versions() {
+        let mut data = StateWriter::new(&StateHeader::new(Model::Cgb, 0)).into_bytes();
+        data[6..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+        assert!(matches!(StateHeader::parse(&data), Err(StateError::UnsupportedVersion { .. })));
+    }
+}
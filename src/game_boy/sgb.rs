@@ -0,0 +1,563 @@
+use crate::game_boy::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::game_boy::save_state::{StateError, StateReader, StateWriter};
+
+/// The SGB picture, border included, as sent to the TV.
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+
+/// Where the Game Boy screen sits inside the border.
+const SCREEN_LEFT: usize = 48;
+const SCREEN_TOP: usize = 40;
+
+/// The attribute map assigns one of the four palettes to each 8x8 cell of the screen.
+const CELLS_WIDE: usize = SCREEN_WIDTH / 8;
+const CELLS_HIGH: usize = SCREEN_HEIGHT / 8;
+
+const PACKET_SIZE: usize = 16;
+const ATTRIBUTE_FILE_SIZE: usize = CELLS_WIDE * CELLS_HIGH / 4;
+const ATTRIBUTE_FILES: usize = 45;
+/// *_TRN commands copy this much from the next frame, read back as 256 tiles.
+const TRANSFER_SIZE: usize = 4096;
+
+/// The palette the SGB starts with, before a game sets its own.
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mask {
+    Cancel,
+    /// Keeps showing the last frame.
+    Freeze,
+    Black,
+    /// Fills the screen with color 0.
+    Color0,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    Palettes,
+    /// Border tiles 0x00-0x7F or, when set, 0x80-0xFF.
+    BorderTiles(bool),
+    BorderMap,
+    AttributeFiles,
+}
+
+/// The Super Game Boy: command packets sent through P1, the palettes and attribute map
+/// that colorize the screen, and the border around it.
+pub(crate) struct Sgb {
+    /// P14 and P15 as last written, the pulses on them carry the packet bits.
+    joypad_lines: u8,
+    /// Bits of the current packet received so far, None while no packet is being sent.
+    packet_bits: Option<usize>,
+    packet: [u8; PACKET_SIZE],
+    /// Packets of a command spanning several of them.
+    command: Vec<u8>,
+
+    /// Palette RAM of the SNES side, filled by PAL_TRN and picked from by PAL_SET.
+    system_palettes: Box<[[u16; 4]; 512]>,
+    palettes: [[u16; 4]; 4],
+    attributes: [u8; CELLS_WIDE * CELLS_HIGH],
+    attribute_files: Box<[u8; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE]>,
+    mask: Mask,
+    pending_transfer: Option<Transfer>,
+
+    /// 256 SNES tiles, 4 bits per pixel.
+    border_tiles: Box<[u8; 256 * 32]>,
+    /// 32x28 tile entries: tile number, palette in bits 10-12, X flip in bit 14 and Y flip in bit 15.
+    border_map: Box<[u16; 32 * 28]>,
+    /// Palettes 4-7 of 16 colors each, color 0 is transparent.
+    border_palettes: [[u16; 16]; 4],
+
+    /// Joypads MLT_REQ enabled, and the one P1 currently reads.
+    players: u8,
+    player: u8,
+
+    frozen_frame: Box<[u8; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    framebuffer: Box<[u16; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT]>,
+}
+
+impl Sgb {
+    pub(crate) fn new() -> Sgb {
+        Sgb {
+            joypad_lines: 0x30,
+            packet_bits: None,
+            packet: [0; PACKET_SIZE],
+            command: Vec::new(),
+            system_palettes: Box::new([[0; 4]; 512]),
+            palettes: [DEFAULT_PALETTE; 4],
+            attributes: [0; CELLS_WIDE * CELLS_HIGH],
+            attribute_files: Box::new([0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE]),
+            mask: Mask::Cancel,
+            pending_transfer: None,
+            border_tiles: Box::new([0; 256 * 32]),
+            border_map: Box::new([0; 32 * 28]),
+            border_palettes: [[0; 16]; 4],
+            players: 1,
+            player: 0,
+            frozen_frame: Box::new([0; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            framebuffer: Box::new([DEFAULT_PALETTE[0]; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT]),
+        }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.joypad_lines);
+        state.write_u16(self.packet_bits.map_or(u16::MAX, |bits| bits as u16));
+        state.write_bytes(&self.packet);
+        state.write_blob(&self.command);
+        for color in self.system_palettes.iter().chain(&self.palettes).flatten() {
+            state.write_u16(*color);
+        }
+        state.write_bytes(&self.attributes);
+        state.write_bytes(&self.attribute_files[..]);
+        state.write_u8(self.mask as u8);
+        state.write_u8(match self.pending_transfer {
+            None => 0,
+            Some(Transfer::Palettes) => 1,
+            Some(Transfer::BorderTiles(false)) => 2,
+            Some(Transfer::BorderTiles(true)) => 3,
+            Some(Transfer::BorderMap) => 4,
+            Some(Transfer::AttributeFiles) => 5,
+        });
+        state.write_bytes(&self.border_tiles[..]);
+        for value in self.border_map.iter().chain(self.border_palettes.iter().flatten()) {
+            state.write_u16(*value);
+        }
+        state.write_u8(self.players);
+        state.write_u8(self.player);
+        state.write_bytes(&self.frozen_frame[..]);
+        for color in self.framebuffer.iter() {
+            state.write_u16(*color);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.joypad_lines = state.read_u8()? & 0x30;
+        self.packet_bits = match state.read_u16()? {
+            u16::MAX => None,
+            bits if bits as usize <= PACKET_SIZE * 8 => Some(bits as usize),
+            _ => return Err(StateError::Invalid("SGB packet position")),
+        };
+        state.read_bytes(&mut self.packet)?;
+        self.command = state.read_blob()?.to_vec();
+        for color in self.system_palettes.iter_mut().chain(&mut self.palettes).flatten() {
+            *color = state.read_u16()?;
+        }
+        state.read_bytes(&mut self.attributes)?;
+        self.attributes.iter_mut().for_each(|palette| *palette &= 0x03);
+        state.read_bytes(&mut self.attribute_files[..])?;
+        self.mask = match state.read_u8()? {
+            0 => Mask::Cancel,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            _ => return Err(StateError::Invalid("SGB screen mask")),
+        };
+        self.pending_transfer = match state.read_u8()? {
+            0 => None,
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::BorderTiles(false)),
+            3 => Some(Transfer::BorderTiles(true)),
+            4 => Some(Transfer::BorderMap),
+            5 => Some(Transfer::AttributeFiles),
+            _ => return Err(StateError::Invalid("SGB transfer")),
+        };
+        state.read_bytes(&mut self.border_tiles[..])?;
+        for value in self.border_map.iter_mut().chain(self.border_palettes.iter_mut().flatten()) {
+            *value = state.read_u16()?;
+        }
+        self.players = state.read_u8()?;
+        self.player = state.read_u8()?;
+        if !matches!(self.players, 1 | 2 | 4) || self.player >= self.players {
+            return Err(StateError::Invalid("SGB joypad selection"));
+        }
+        state.read_bytes(&mut self.frozen_frame[..])?;
+        for color in self.framebuffer.iter_mut() {
+            *color = state.read_u16()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer[..]
+    }
+
+    /// The joypad P1 reads, 0 for the Game Boy's own buttons.
+    pub(crate) fn player(&self) -> u8 {
+        self.player
+    }
+
+    /// Watches writes to P1. Pulling both lines low starts a packet, then each pulse on P15 sends a 1
+    /// and each pulse on P14 a 0, least significant bit first, followed by a 0 stop bit.
+    pub(crate) fn write_joypad(&mut self, value: u8) {
+        let lines = value & 0x30;
+        let previous = std::mem::replace(&mut self.joypad_lines, lines);
+
+        // with MLT_REQ the next joypad is selected whenever P15 goes back up
+        if self.players > 1 && previous & 0x20 == 0 && lines & 0x20 != 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+
+        if lines == 0x00 {
+            self.packet_bits = Some(0);
+            self.packet = [0; PACKET_SIZE];
+            return;
+        }
+        if previous != 0x30 || lines == 0x30 {
+            return;
+        }
+        let Some(bits) = self.packet_bits else { return };
+
+        if bits == PACKET_SIZE * 8 {
+            self.packet_bits = None;
+            self.receive_packet();
+            return;
+        }
+        if lines == 0x10 {
+            self.packet[bits / 8] |= 1 << (bits % 8);
+        }
+        self.packet_bits = Some(bits + 1);
+    }
+
+    fn receive_packet(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() >= packets * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, command: &[u8]) {
+        match command[0] >> 3 {
+            0x00 => self.set_palette_pair(command, 0, 1),
+            0x01 => self.set_palette_pair(command, 2, 3),
+            0x02 => self.set_palette_pair(command, 0, 3),
+            0x03 => self.set_palette_pair(command, 1, 2),
+            0x04 => self.attribute_blocks(command),
+            0x05 => self.attribute_lines(command),
+            0x06 => self.attribute_division(command),
+            0x07 => self.attribute_characters(command),
+            0x0A => self.set_palettes(command),
+            0x0B => self.pending_transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.players = match command[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            0x13 => self.pending_transfer = Some(Transfer::BorderTiles(command[1] & 0x01 != 0)),
+            0x14 => self.pending_transfer = Some(Transfer::BorderMap),
+            0x15 => self.pending_transfer = Some(Transfer::AttributeFiles),
+            0x16 => {
+                self.apply_attribute_file(command[1] & 0x3F);
+                if command[1] & 0x40 != 0 {
+                    self.mask = Mask::Cancel;
+                }
+            }
+            0x17 => {
+                self.mask = match command[1] & 0x03 {
+                    0 => Mask::Cancel,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                };
+            }
+            // sound, SNES program upload and the like are of no use without a SNES
+            _ => {}
+        }
+    }
+
+    fn color(command: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([command[offset], command[offset + 1]])
+    }
+
+    /// Color 0 is shared by all palettes, the last set one wins.
+    fn set_shared_color(&mut self, color: u16) {
+        for palette in &mut self.palettes {
+            palette[0] = color;
+        }
+    }
+
+    /// PAL01, PAL23, PAL03 and PAL12: color 0, then colors 1-3 of both palettes.
+    fn set_palette_pair(&mut self, command: &[u8], first: usize, second: usize) {
+        self.set_shared_color(Self::color(command, 1));
+        for color in 1..4 {
+            self.palettes[first][color] = Self::color(command, 1 + 2 * color);
+            self.palettes[second][color] = Self::color(command, 7 + 2 * color);
+        }
+    }
+
+    /// PAL_SET: picks the four palettes from the system palettes, optionally with an attribute file.
+    fn set_palettes(&mut self, command: &[u8]) {
+        for (idx, palette) in self.palettes.iter_mut().enumerate() {
+            let system_palette = Self::color(command, 1 + 2 * idx) as usize & 0x1FF;
+            *palette = self.system_palettes[system_palette];
+        }
+        self.set_shared_color(self.palettes[0][0]);
+
+        let flags = command[9];
+        if flags & 0x80 != 0 {
+            self.apply_attribute_file(flags & 0x3F);
+        }
+        if flags & 0x40 != 0 {
+            self.mask = Mask::Cancel;
+        }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        if x < CELLS_WIDE && y < CELLS_HIGH {
+            self.attributes[y * CELLS_WIDE + x] = palette & 0x03;
+        }
+    }
+
+    /// ATTR_BLK: rectangles with separate palettes for the cells inside, on and outside their border.
+    fn attribute_blocks(&mut self, command: &[u8]) {
+        let count = (command[1] as usize).min(18);
+        for block in command[2..].chunks_exact(6).take(count) {
+            let (control, palettes) = (block[0] & 0x07, block[1]);
+            let (x1, y1, x2, y2) = (block[2] as usize, block[3] as usize, block[4] as usize, block[5] as usize);
+            let inside = palettes & 0x03;
+            let outside = (palettes >> 4) & 0x03;
+            // with only the inside or only the outside changed, the border takes the same palette
+            let border = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ if control & 0x02 != 0 => Some((palettes >> 2) & 0x03),
+                _ => None,
+            };
+
+            for y in 0..CELLS_HIGH {
+                for x in 0..CELLS_WIDE {
+                    let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = if on_border {
+                        border
+                    } else if within {
+                        (control & 0x01 != 0).then_some(inside)
+                    } else {
+                        (control & 0x04 != 0).then_some(outside)
+                    };
+                    if let Some(palette) = palette {
+                        self.set_attribute(x, y, palette);
+                    }
+                }
+            }
+        }
+    }
+
+    /// ATTR_LIN: whole rows or columns of cells.
+    fn attribute_lines(&mut self, command: &[u8]) {
+        let count = command[1] as usize;
+        for &line in command[2..].iter().take(count) {
+            let (position, palette) = ((line & 0x1F) as usize, (line >> 5) & 0x03);
+            if line & 0x80 != 0 {
+                (0..CELLS_WIDE).for_each(|x| self.set_attribute(x, position, palette));
+            } else {
+                (0..CELLS_HIGH).for_each(|y| self.set_attribute(position, y, palette));
+            }
+        }
+    }
+
+    /// ATTR_DIV: splits the screen in two along a row or column of cells, which gets a palette of its own.
+    fn attribute_division(&mut self, command: &[u8]) {
+        let flags = command[1];
+        let position = command[2] as usize;
+        let (after, before, on_line) = (flags & 0x03, (flags >> 2) & 0x03, (flags >> 4) & 0x03);
+        let horizontal = flags & 0x40 != 0;
+
+        for y in 0..CELLS_HIGH {
+            for x in 0..CELLS_WIDE {
+                let coordinate = if horizontal { y } else { x };
+                let palette = match coordinate.cmp(&position) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    /// ATTR_CHR: palettes for consecutive cells, 2 bits each, starting at a given cell.
+    fn attribute_characters(&mut self, command: &[u8]) {
+        let (mut x, mut y) = (command[1] as usize, command[2] as usize);
+        let count = (u16::from_le_bytes([command[3], command[4]]) as usize).min(CELLS_WIDE * CELLS_HIGH);
+        let vertical = command[5] & 0x01 != 0;
+
+        for idx in 0..count {
+            let Some(&byte) = command.get(6 + idx / 4) else { break };
+            self.set_attribute(x, y, byte >> (6 - 2 * (idx % 4)));
+            if vertical {
+                y += 1;
+                if y == CELLS_HIGH {
+                    y = 0;
+                    x = (x + 1) % CELLS_WIDE;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_WIDE {
+                    x = 0;
+                    y = (y + 1) % CELLS_HIGH;
+                }
+            }
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: u8) {
+        let file = (file as usize).min(ATTRIBUTE_FILES - 1);
+        let data = &self.attribute_files[file * ATTRIBUTE_FILE_SIZE..(file + 1) * ATTRIBUTE_FILE_SIZE];
+        for (idx, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (data[idx / 4] >> (6 - 2 * (idx % 4))) & 0x03;
+        }
+    }
+
+    /// Called at VBlank with the new frame: runs pending transfers and redraws the SGB picture.
+    pub(crate) fn frame_completed(&mut self, shades: &[u8]) {
+        if let Some(transfer) = self.pending_transfer.take() {
+            let data = Self::transfer_data(shades);
+            match transfer {
+                Transfer::Palettes => {
+                    for (palette, colors) in self.system_palettes.iter_mut().zip(data.chunks_exact(8)) {
+                        for (color, bytes) in palette.iter_mut().zip(colors.chunks_exact(2)) {
+                            *color = u16::from_le_bytes([bytes[0], bytes[1]]);
+                        }
+                    }
+                }
+                Transfer::BorderTiles(upper) => {
+                    let offset = if upper { TRANSFER_SIZE } else { 0 };
+                    self.border_tiles[offset..offset + TRANSFER_SIZE].copy_from_slice(&data);
+                }
+                Transfer::BorderMap => {
+                    for (entry, bytes) in self.border_map.iter_mut().zip(data.chunks_exact(2)) {
+                        *entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+                    }
+                    for (palette, colors) in self.border_palettes.iter_mut().zip(data[0x800..].chunks_exact(32)) {
+                        for (color, bytes) in palette.iter_mut().zip(colors.chunks_exact(2)) {
+                            *color = u16::from_le_bytes([bytes[0], bytes[1]]);
+                        }
+                    }
+                }
+                Transfer::AttributeFiles => {
+                    let size = self.attribute_files.len();
+                    self.attribute_files.copy_from_slice(&data[..size]);
+                }
+            }
+        }
+
+        if self.mask != Mask::Freeze {
+            self.frozen_frame.copy_from_slice(shades);
+        }
+        self.render();
+    }
+
+    /// Reads the first 256 tiles of the screen back as 2-bit tile data, which is how the SGB receives VRAM.
+    fn transfer_data(shades: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; TRANSFER_SIZE];
+        for (tile, bytes) in data.chunks_exact_mut(16).enumerate() {
+            let (tile_x, tile_y) = (tile % CELLS_WIDE, tile / CELLS_WIDE);
+            for row in 0..8 {
+                let start = (tile_y * 8 + row) * SCREEN_WIDTH + tile_x * 8;
+                for (column, shade) in shades[start..start + 8].iter().enumerate() {
+                    bytes[2 * row] |= (shade & 0x01) << (7 - column);
+                    bytes[2 * row + 1] |= (shade >> 1) << (7 - column);
+                }
+            }
+        }
+        data
+    }
+
+    fn render(&mut self) {
+        let backdrop = self.palettes[0][0];
+        self.framebuffer.fill(backdrop);
+
+        for y in 0..SCREEN_HEIGHT {
+            let row = &mut self.framebuffer[(SCREEN_TOP + y) * SGB_SCREEN_WIDTH + SCREEN_LEFT..][..SCREEN_WIDTH];
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = match self.mask {
+                    Mask::Black => 0x0000,
+                    Mask::Color0 => backdrop,
+                    Mask::Cancel | Mask::Freeze => {
+                        let shade = self.frozen_frame[y * SCREEN_WIDTH + x] as usize;
+                        let palette = self.attributes[(y / 8) * CELLS_WIDE + x / 8] as usize;
+                        self.palettes[palette][shade]
+                    }
+                };
+            }
+        }
+
+        // the border is drawn on top, its transparent middle lets the screen through
+        for (idx, &entry) in self.border_map.iter().enumerate() {
+            let tile = &self.border_tiles[(entry & 0xFF) as usize * 32..][..32];
+            let palette = &self.border_palettes[((entry >> 10) & 0x03) as usize];
+            let (x_flip, y_flip) = (entry & 0x4000 != 0, entry & 0x8000 != 0);
+
+            for row in 0..8 {
+                let tile_row = if y_flip { 7 - row } else { row };
+                let planes = [tile[2 * tile_row], tile[2 * tile_row + 1], tile[16 + 2 * tile_row], tile[17 + 2 * tile_row]];
+                for column in 0..8 {
+                    let bit = if x_flip { column } else { 7 - column };
+                    let color = planes.iter().enumerate()
+                        .fold(0, |color, (plane, bits)| color | ((bits >> bit) & 0x01) << plane);
+                    if color != 0 {
+                        let (x, y) = ((idx % 32) * 8 + column, (idx / 32) * 8 + row);
+                        self.framebuffer[y * SGB_SCREEN_WIDTH + x] = palette[color as usize];
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(sgb: &mut Sgb, packet: &[u8]) {
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        for bit in 0..PACKET_SIZE * 8 {
+            sgb.write_joypad(if packet[bit / 8] & (1 << (bit % 8)) != 0 { 0x10 } else { 0x20 });
+            sgb.write_joypad(0x30);
+        }
+        sgb.write_joypad(0x20);
+        sgb.write_joypad(0x30);
+    }
+
+    #[test]
+    fn colorizes_screen_with_palettes_and_attributes() {
+        let mut sgb = Sgb::new();
+        // PAL01: color 0 white, palette 0 colors 1-3 black and palette 1 colors 1-3 red
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = 1;
+        packet[1..3].copy_from_slice(&0x7FFFu16.to_le_bytes());
+        for color in 0..3 {
+            packet[9 + 2 * color..11 + 2 * color].copy_from_slice(&0x001Fu16.to_le_bytes());
+        }
+        send(&mut sgb, &packet);
+        // ATTR_BLK: palette 1 inside and on the border of the cells (1, 0)-(2, 1)
+        send(&mut sgb, &[0x04 << 3 | 1, 1, 0x03, 0b01_01, 1, 0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        sgb.frame_completed(&[3; SCREEN_WIDTH * SCREEN_HEIGHT]);
+
+        let pixel = |x: usize, y: usize| sgb.framebuffer()[(SCREEN_TOP + y) * SGB_SCREEN_WIDTH + SCREEN_LEFT + x];
+        assert_eq!(pixel(8, 0), 0x001F);
+        assert_eq!(pixel(23, 15), 0x001F);
+        assert_eq!(pixel(24, 0), 0x0000);
+        assert_eq!(sgb.framebuffer()[0], 0x7FFF);
+    }
+
+    #[test]
+    fn multiplayer_request_cycles_joypads() {
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &[0x11 << 3 | 1, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(sgb.player(), 0);
+
+        sgb.write_joypad(0x10);
+        sgb.write_joypad(0x30);
+        assert_eq!(sgb.player(), 1);
+        sgb.write_joypad(0x10);
+        sgb.write_joypad(0x30);
+        assert_eq!(sgb.player(), 0);
+    }
+}
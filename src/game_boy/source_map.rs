@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use crate::game_boy::disassembler;
+use crate::game_boy::symbols::Symbols;
+
+/// Directives that emit no bytes, the lines after them still follow on from the instruction before.
+const NO_BYTES: [&str; 11] = ["def", "export", "purge", "assert", "static_assert", "opt", "print", "println", "warn", "endc", "endm"];
+
+/// Source lines of ROM code, worked out from where the `.sym` file puts each label and the
+/// instructions that follow it in the source. Assembling one instruction per line is assumed:
+/// after a macro, data or anything else the ROM does not match, lines stay unmapped up to the next label.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<PathBuf>,
+    /// (bank, address) to (file, 1-based line).
+    lines: BTreeMap<(usize, u16), (usize, usize)>,
+    /// The other way around.
+    addresses: BTreeMap<(usize, usize), (usize, u16)>,
+}
+
+impl SourceMap {
+    /// Maps the lines of `sources`, given as (path, text), to the code in `rom`.
+    pub fn new(rom: &[u8], symbols: &Symbols, sources: &[(PathBuf, String)]) -> SourceMap {
+        let mut map = SourceMap::default();
+        for (path, text) in sources {
+            let file = map.files.len();
+            map.files.push(path.clone());
+            map.map_file(file, rom, symbols, text);
+        }
+        map
+    }
+
+    fn map_file(&mut self, file: usize, rom: &[u8], symbols: &Symbols, text: &str) {
+        let mut scope = String::new();
+        // where the next instruction goes, while the source can be followed
+        let mut cursor: Option<(usize, u16)> = None;
+        for (idx, line) in text.lines().enumerate() {
+            let mut code = line.split(';').next().unwrap_or_default().trim();
+            if let Some((label, rest)) = split_label(code) {
+                let name = match label.strip_prefix('.') {
+                    Some(_) => format!("{scope}{label}"),
+                    None => {
+                        if let Some((parent, _)) = label.split_once('.') {
+                            scope = String::from(parent);
+                        } else {
+                            scope = String::from(label);
+                        }
+                        String::from(label)
+                    }
+                };
+                cursor = symbols.location(&name).filter(|&(_, address)| address < 0x8000);
+                code = rest;
+            }
+            if code.is_empty() {
+                continue;
+            }
+
+            let mnemonic = code.split_whitespace().next().unwrap_or_default().to_ascii_lowercase();
+            let second = code.split_whitespace().nth(1).unwrap_or_default().to_ascii_lowercase();
+            if NO_BYTES.contains(&mnemonic.as_str()) || matches!(second.as_str(), "equ" | "equs" | "=" | "set") {
+                continue;
+            }
+            let Some((bank, address)) = cursor else { continue };
+            let instruction = rom_offset(bank, address)
+                .filter(|&offset| offset < rom.len())
+                .map(|offset| disassembler::decode(std::array::from_fn(|idx| rom.get(offset + idx).copied().unwrap_or(0)), address));
+            match instruction {
+                Some(instruction) if mnemonic != "db" && family(&mnemonic) == family(instruction.text.split(' ').next().unwrap_or_default()) => {
+                    self.lines.entry((bank, address)).or_insert((file, idx + 1));
+                    self.addresses.insert((file, idx + 1), (bank, address));
+                    cursor = Some((bank, address.wrapping_add(instruction.length as u16)));
+                }
+                _ => cursor = None,
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// The file and line the code at `address` in `bank` came from.
+    pub fn line_at(&self, bank: usize, address: u16) -> Option<(&Path, usize)> {
+        let &(file, line) = self.lines.get(&(bank, address))?;
+        Some((&self.files[file], line))
+    }
+
+    /// The first line with code at or after `line` in `path`, and where that code is as (line, bank, address).
+    pub fn location_of(&self, path: &Path, line: usize) -> Option<(usize, usize, u16)> {
+        let file = self.files.iter().position(|file| file == path)?;
+        let (&(_, line), &(bank, address)) = self.addresses.range((file, line)..(file + 1, 0)).next()?;
+        Some((line, bank, address))
+    }
+}
+
+/// Splits `Label:`, `Label::`, `.local:` or a bare `.local` off the front of a line.
+fn split_label(code: &str) -> Option<(&str, &str)> {
+    let end = code.find(|char: char| !(char.is_ascii_alphanumeric() || "_.#@$".contains(char))).unwrap_or(code.len());
+    let (label, rest) = code.split_at(end);
+    if label.is_empty() || label.starts_with(|char: char| char.is_ascii_digit() || char == '$') {
+        return None;
+    }
+    match rest.strip_prefix("::").or_else(|| rest.strip_prefix(':')) {
+        Some(rest) => Some((label, rest.trim())),
+        None if label.starts_with('.') && label.len() > 1 => Some((label, rest.trim())),
+        None => None,
+    }
+}
+
+/// `ldh`, `ldi` and `ldd` assemble to `ld` as far as the disassembler is concerned.
+fn family(mnemonic: &str) -> &str {
+    match mnemonic {
+        "ldh" | "ldi" | "ldd" => "ld",
+        mnemonic => mnemonic,
+    }
+}
+
+fn rom_offset(bank: usize, address: u16) -> Option<usize> {
+    match (bank, address) {
+        (0, 0x0000..=0x3FFF) => Some(address as usize),
+        (1.., 0x4000..=0x7FFF) => Some(bank * 0x4000 + address as usize - 0x4000),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_boy::tests::test_rom;
+
+    #[test]
+    fn maps_lines_from_labels_on() {
+        // 0150: ld a, $80; ldh [$FF40], a; call $0159; jr -7; 0159: ret
+        let rom = test_rom(&[0x3E, 0x80, 0xE0, 0x40, 0xCD, 0x59, 0x01, 0x18, 0xF7, 0xC9]);
+        let symbols = Symbols::parse("00:0150 Main\n00:0154 Main.loop\n00:0159 Helper\n").unwrap();
+        let source = "\
+SECTION \"Main\", ROM0[$150]
+Main:
+    ld a, $80 ; LCD on
+    ldh [rLCDC], a
+.loop
+    call Helper
+    DEF unused EQU 3
+    jr .loop
+
+Helper: ret
+";
+        let map = SourceMap::new(&rom, &symbols, &[(PathBuf::from("main.asm"), String::from(source))]);
+        let path = Path::new("main.asm");
+        assert_eq!(map.line_at(0, 0x0150), Some((path, 3)));
+        assert_eq!(map.line_at(0, 0x0152), Some((path, 4)));
+        assert_eq!(map.line_at(0, 0x0157), Some((path, 8)));
+        assert_eq!(map.line_at(0, 0x0159), Some((path, 10)));
+        assert_eq!(map.location_of(path, 5), Some((6, 0, 0x0154)));
+        assert_eq!(map.location_of(path, 11), None);
+    }
+}
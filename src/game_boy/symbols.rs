@@ -0,0 +1,117 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SymbolError {
+    /// 1-based, like editors count.
+    pub line: usize,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {} is not BANK:ADDRESS NAME", self.line)
+    }
+}
+
+/// Labels from a `.sym` file as rgblink writes them, one `01:4000 MainLoop` per line.
+/// Locations are a bank and an address, with banks numbered per memory region like `GameBoy::bank_at` does.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    by_location: BTreeMap<(usize, u16), String>,
+    by_name: HashMap<String, (usize, u16)>,
+}
+
+impl Symbols {
+    pub fn parse(text: &str) -> Result<Symbols, SymbolError> {
+        let mut symbols = Symbols::default();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || SymbolError { line: idx + 1 };
+            let (location, name) = line.split_once(char::is_whitespace).ok_or_else(error)?;
+            let (bank, address) = location.split_once(':').ok_or_else(error)?;
+            let bank = usize::from_str_radix(bank, 16).map_err(|_| error())?;
+            let address = u16::from_str_radix(address, 16).map_err(|_| error())?;
+            symbols.insert(bank, address, name.trim());
+        }
+        Ok(symbols)
+    }
+
+    fn insert(&mut self, bank: usize, address: u16, name: &str) {
+        self.by_name.insert(String::from(name), (bank, address));
+        // of several labels at one place the first global one names it, `Main` rather than `Main.start`
+        match self.by_location.get(&(bank, address)) {
+            Some(existing) if !existing.contains('.') || name.contains('.') => {}
+            _ => {
+                self.by_location.insert((bank, address), String::from(name));
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Where `name` is, as (bank, address).
+    pub fn location(&self, name: &str) -> Option<(usize, u16)> {
+        self.by_name.get(name).copied()
+    }
+
+    /// The label right at `address` in `bank`.
+    pub fn name_at(&self, bank: usize, address: u16) -> Option<&str> {
+        self.by_location.get(&(bank, address)).map(String::as_str)
+    }
+
+    /// The closest label at or before `address` in the same bank and memory region, as `Name` or `Name+3`.
+    pub fn describe(&self, bank: usize, address: u16) -> Option<String> {
+        let ((_, start), name) = self.by_location.range((bank, region_start(address))..=(bank, address)).next_back()?;
+        Some(match address - start {
+            0 => name.clone(),
+            offset => format!("{name}+{offset}"),
+        })
+    }
+
+    /// All labels ordered by bank and address, one per location.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, u16, &str)> + '_ {
+        self.by_location.iter().map(|(&(bank, address), name)| (bank, address, name.as_str()))
+    }
+}
+
+/// Start of the memory region `address` is in, labels do not describe addresses past the end of theirs.
+fn region_start(address: u16) -> u16 {
+    match address {
+        0x0000 ..= 0x3FFF => 0x0000,
+        0x4000 ..= 0x7FFF => 0x4000,
+        0x8000 ..= 0x9FFF => 0x8000,
+        0xA000 ..= 0xBFFF => 0xA000,
+        0xC000 ..= 0xCFFF => 0xC000,
+        0xD000 ..= 0xDFFF => 0xD000,
+        0xE000 ..= 0xFDFF => 0xE000,
+        0xFE00 ..= 0xFEFF => 0xFE00,
+        0xFF00 ..= 0xFF7F => 0xFF00,
+        _ => 0xFF80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rgblink_output() {
+        let text = "; File generated by rgblink\n00:0150 Main\n00:0150 Main.start\n00:0155 Main.loop\n01:4000 Bank1Code\n00:c000 wCounter\n";
+        let symbols = Symbols::parse(text).unwrap();
+        assert_eq!(symbols.location("Main.loop"), Some((0, 0x0155)));
+        assert_eq!(symbols.name_at(0, 0x0150), Some("Main"));
+        assert_eq!(symbols.describe(0, 0x0157).as_deref(), Some("Main.loop+2"));
+        assert_eq!(symbols.describe(1, 0x4010).as_deref(), Some("Bank1Code+16"));
+        assert_eq!(symbols.describe(2, 0x4010), None);
+        // labels in ROM0 do not reach into ROMX or RAM
+        assert_eq!(symbols.describe(0, 0x4000), None);
+        assert_eq!(symbols.describe(0, 0xC001).as_deref(), Some("wCounter+1"));
+
+        assert_eq!(Symbols::parse("00:0150 Main\n0150 Broken\n").err(), Some(SymbolError { line: 2 }));
+    }
+}
@@ -0,0 +1,13 @@
+use crate::game_boy::cartridge_header::{CartridgeHeader, NINTENDO_LOGO};
+
+/// Builds a 32 KiB ROM with a valid header whose entry point jumps to `code` at 0x0150.
+pub fn test_rom(code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 32 * 1024];
+    rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+    rom[0x104..0x134].copy_from_slice(NINTENDO_LOGO);
+    rom[0x150..0x150 + code.len()].copy_from_slice(code);
+    rom[0x14D] = CartridgeHeader::calculate_checksum(&rom);
+    let global_checksum = CartridgeHeader::calculate_global_checksum(&rom);
+    rom[0x14E..0x150].copy_from_slice(&global_checksum.to_be_bytes());
+    rom
+}
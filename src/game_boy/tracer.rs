@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use crate::game_boy::cpu::registers::Registers;
+use crate::game_boy::memory::Memory;
+use crate::game_boy::symbols::Symbols;
+
+/// The CPU state before one instruction.
+#[derive(Clone, Copy)]
+struct Entry {
+    /// A, F, B, C, D, E, H and L.
+    registers: [u8; 8],
+    sp: u16,
+    pc: u16,
+    bank: Option<usize>,
+    /// The instruction and what follows it.
+    pc_memory: [u8; 4],
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, flags, b, c, d, e, h, l] = self.registers;
+        let [m0, m1, m2, m3] = self.pc_memory;
+        write!(
+            f,
+            "A:{a:02X} F:{flags:02X} B:{b:02X} C:{c:02X} D:{d:02X} E:{e:02X} H:{h:02X} L:{l:02X} \
+             SP:{:04X} PC:{:04X} PCMEM:{m0:02X},{m1:02X},{m2:02X},{m3:02X}",
+            self.sp, self.pc,
+        )
+    }
+}
+
+/// Logs the CPU state before every instruction, one line each in the format of Gameboy Doctor:
+/// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`.
+/// Those logs can be diffed against known-good ones line by line.
+pub struct Tracer {
+    output: Box<dyn Write>,
+    pc_range: Option<RangeInclusive<u16>>,
+    bank: Option<usize>,
+    /// The last instructions and how many to keep, written only when the emulator panics or the CPU locks up.
+    ring: Option<(VecDeque<Entry>, usize)>,
+    symbols: Option<Symbols>,
+    failed: bool,
+}
+
+impl Tracer {
+    pub fn new(output: impl Write + 'static) -> Tracer {
+        Tracer { output: Box::new(output), pc_range: None, bank: None, ring: None, symbols: None, failed: false }
+    }
+
+    /// Only logs instructions with their address in `range`.
+    pub fn with_pc_range(mut self, range: RangeInclusive<u16>) -> Tracer {
+        self.pc_range = Some(range);
+        self
+    }
+
+    /// Only logs instructions from cartridge ROM bank `bank`, where bank 0 is 0x0000-0x3FFF.
+    pub fn with_bank(mut self, bank: usize) -> Tracer {
+        self.bank = Some(bank);
+        self
+    }
+
+    /// Keeps the last `instructions` in memory and only writes them if the emulator panics
+    /// or the CPU locks up on an illegal opcode, to see how it got there.
+    pub fn with_ring_buffer(mut self, instructions: usize) -> Tracer {
+        self.ring = Some((VecDeque::with_capacity(instructions), instructions));
+        self
+    }
+
+    /// Writes a `Label:` line before the instructions at a label, like an assembler listing.
+    /// Gameboy Doctor does not know those lines, leave them out for logs to compare with it.
+    pub fn with_symbols(mut self, symbols: Symbols) -> Tracer {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    pub(crate) fn trace(&mut self, registers: &Registers, memory: &Memory) {
+        let pc = registers.read_pc();
+        let bank = memory.bank_at(pc);
+        if self.pc_range.as_ref().is_some_and(|range| !range.contains(&pc))
+            || self.bank.is_some_and(|wanted| pc >= 0x8000 || bank != Some(wanted))
+        {
+            return;
+        }
+
+        let [a, flags] = registers.read_af().to_be_bytes();
+        let [b, c] = registers.read_bc().to_be_bytes();
+        let [d, e] = registers.read_de().to_be_bytes();
+        let [h, l] = registers.read_hl().to_be_bytes();
+        let entry = Entry {
+            registers: [a, flags, b, c, d, e, h, l],
+            sp: registers.read_sp(),
+            pc,
+            bank,
+            pc_memory: [0, 1, 2, 3].map(|offset| memory.peek(pc.wrapping_add(offset))),
+        };
+        match &mut self.ring {
+            Some((entries, capacity)) => {
+                if entries.len() == *capacity {
+                    entries.pop_front();
+                }
+                entries.push_back(entry);
+            }
+            None => self.write(entry),
+        }
+    }
+
+    fn write(&mut self, entry: Entry) {
+        if self.failed {
+            return;
+        }
+        let label = self.symbols.as_ref().zip(entry.bank).and_then(|(symbols, bank)| symbols.name_at(bank, entry.pc));
+        let result = match label {
+            Some(label) => writeln!(self.output, "{label}:\n{entry}"),
+            None => writeln!(self.output, "{entry}"),
+        };
+        if let Err(error) = result {
+            // the emulator goes on without its trace
+            eprintln!("cannot write the trace: {error}");
+            self.failed = true;
+        }
+    }
+
+    /// Writes out what the ring buffer kept, later instructions are written as they run.
+    pub(crate) fn write_ring_buffer(&mut self) {
+        if let Some((entries, _)) = self.ring.take() {
+            for entry in entries {
+                self.write(entry);
+            }
+        }
+        let _ = self.output.flush();
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.write_ring_buffer();
+        }
+        let _ = self.output.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+    use crate::game_boy::GameBoy;
+    use crate::game_boy::cpu::registers::RegisterPair;
+    use crate::game_boy::tests::test_rom;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone()).unwrap().lines().map(String::from).collect()
+        }
+    }
+
+    #[test]
+    fn logs_in_gameboy_doctor_format() {
+        // 0150: nop; ld b, $12; db $D3
+        let rom = test_rom(&[0x00, 0x06, 0x12, 0xD3]);
+        let buffer = SharedBuffer::default();
+        let mut game_boy = GameBoy::new(rom.clone()).unwrap();
+        let symbols = Symbols::parse("00:0151 Load").unwrap();
+        game_boy.set_tracer(Some(Tracer::new(buffer.clone()).with_pc_range(0x0150..=0x01FF).with_bank(0).with_symbols(symbols)));
+        for _ in 0..3 {
+            game_boy.step();
+        }
+        assert_eq!(buffer.lines(), [
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:00,06,12,D3",
+            "Load:",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0151 PCMEM:06,12,D3,00",
+        ]);
+
+        // the ring buffer only shows up when the illegal opcode locks up the CPU
+        let buffer = SharedBuffer::default();
+        let mut game_boy = GameBoy::new(rom).unwrap();
+        game_boy.set_tracer(Some(Tracer::new(buffer.clone()).with_ring_buffer(1)));
+        for _ in 0..2 {
+            game_boy.step();
+        }
+        assert!(buffer.lines().is_empty());
+        for _ in 0..3 {
+            game_boy.step();
+        }
+        assert!(game_boy.cpu().is_locked());
+        assert_eq!(game_boy.cpu().registers().read_pc(), 0x0153);
+        assert_eq!(buffer.lines(), ["A:01 F:B0 B:12 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0153 PCMEM:D3,00,00,00"]);
+    }
+
+    #[test]
+    fn reads_pcmem_across_echo_ram() {
+        let buffer = SharedBuffer::default();
+        let mut game_boy = GameBoy::new(test_rom(&[])).unwrap();
+        // nop; nop, then the work RAM at C000 shows through echo RAM
+        for (address, value) in [(0xDFFE, 0x00), (0xDFFF, 0x00), (0xC000, 0x3C), (0xC001, 0x18)] {
+            game_boy.write_memory(address, value);
+        }
+        game_boy.write_register(RegisterPair::PC, 0xDFFE);
+        game_boy.set_tracer(Some(Tracer::new(buffer.clone())));
+        for _ in 0..3 {
+            game_boy.step();
+        }
+        assert_eq!(buffer.lines(), [
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:DFFE PCMEM:00,00,3C,18",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:DFFF PCMEM:00,3C,18,00",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:E000 PCMEM:3C,18,00,00",
+        ]);
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use gameboy_emu::game_boy::GameBoy;
+use gameboy_emu::game_boy::cpu::registers::RegisterPair;
+use gameboy_emu::game_boy::debugger::{Debugger, Stop};
+use gameboy_emu::game_boy::memory::watchpoints::{Access, Watchpoint};
+
+/// The registers in the order of `g` packets and their numbers in `p` and `P`, 16 bits each.
+const REGISTERS: [RegisterPair; 6] = [
+    RegisterPair::AF, RegisterPair::BC, RegisterPair::DE, RegisterPair::HL, RegisterPair::SP, RegisterPair::PC,
+];
+
+/// GDB knows no SM83, this tells it what the registers are.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.sm83.core">
+    <reg name="af" bitsize="16" type="int" regnum="0"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="int"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// SIGTRAP, a breakpoint or step.
+const SIGNAL_TRAP: u8 = 5;
+/// SIGINT, GDB asked to stop.
+const SIGNAL_INTERRUPT: u8 = 2;
+/// SIGILL, the CPU locked up on an illegal opcode.
+const SIGNAL_ILLEGAL: u8 = 4;
+
+/// Waits for one GDB connection on localhost `port` and serves it until it detaches or disconnects.
+pub fn listen(game_boy: &mut GameBoy, debugger: Debugger, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("waiting for GDB on 127.0.0.1:{}, attach with: target remote :{0}", listener.local_addr()?.port());
+    let (stream, _) = listener.accept()?;
+    serve(game_boy, debugger, stream)
+}
+
+/// Speaks the GDB remote serial protocol on `stream`: registers, memory, breakpoints, watchpoints, step and continue.
+pub fn serve(game_boy: &mut GameBoy, debugger: Debugger, stream: TcpStream) -> io::Result<()> {
+    let mut session = Session {
+        output: stream.try_clone()?,
+        input: BufReader::new(stream),
+        acknowledge: true,
+        debugger,
+        watchpoints: HashMap::new(),
+    };
+    while let Some(packet) = session.receive()? {
+        match session.handle(game_boy, &packet)? {
+            Some(reply) => session.send(&reply)?,
+            None => return Ok(()),
+        }
+        // the OK to it still gets acknowledged
+        if packet == b"QStartNoAckMode" {
+            session.acknowledge = false;
+        }
+    }
+    Ok(())
+}
+
+struct Session {
+    input: BufReader<TcpStream>,
+    output: TcpStream,
+    /// Until GDB turns them off with QStartNoAckMode, every packet is answered with + or -.
+    acknowledge: bool,
+    debugger: Debugger,
+    /// Watchpoint ids by the Z packet type, address and length that set them.
+    watchpoints: HashMap<(u8, u16, u16), usize>,
+}
+
+impl Session {
+    /// The next packet, or None when GDB hung up.
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // acks and interrupts while stopped are noise
+            let mut skipped = Vec::new();
+            if self.input.read_until(b'$', &mut skipped)? == 0 || skipped.last() != Some(&b'$') {
+                return Ok(None);
+            }
+            let mut packet = Vec::new();
+            if self.input.read_until(b'#', &mut packet)? == 0 || packet.pop() != Some(b'#') {
+                return Ok(None);
+            }
+            let mut checksum = [0; 2];
+            self.input.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            let valid = expected == Some(checksum_of(&packet));
+            if self.acknowledge {
+                self.output.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(unescape(&packet)));
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let packet = format!("${reply}#{:02x}", checksum_of(reply.as_bytes()));
+        loop {
+            self.output.write_all(packet.as_bytes())?;
+            if !self.acknowledge {
+                return Ok(());
+            }
+            // only take the ack, a client that skipped it may already be sending the next packet
+            let ack = self.input.fill_buf()?.first().copied();
+            if matches!(ack, Some(b'+' | b'-')) {
+                self.input.consume(1);
+            }
+            if ack != Some(b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Answers one packet, None ends the session.
+    fn handle(&mut self, game_boy: &mut GameBoy, packet: &[u8]) -> io::Result<Option<String>> {
+        let packet = String::from_utf8_lossy(packet);
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => format!("S{SIGNAL_TRAP:02x}"),
+            "g" => REGISTERS.iter().map(|&pair| hex_u16(game_boy.cpu().registers().read_pair(pair))).collect(),
+            "G" => {
+                let values: Vec<Option<u16>> = (0..REGISTERS.len()).map(|idx| arguments.get(idx * 4..idx * 4 + 4).and_then(parse_u16)).collect();
+                if values.iter().any(Option::is_none) {
+                    error(1)
+                } else {
+                    for (&pair, value) in REGISTERS.iter().zip(values) {
+                        game_boy.write_register(pair, value.unwrap());
+                    }
+                    ok()
+                }
+            }
+            "p" => match register(arguments) {
+                Some(pair) => hex_u16(game_boy.cpu().registers().read_pair(pair)),
+                None => error(1),
+            },
+            "P" => match arguments.split_once('=').and_then(|(number, value)| Some((register(number)?, parse_u16(value)?))) {
+                Some((pair, value)) => {
+                    game_boy.write_register(pair, value);
+                    ok()
+                }
+                None => error(1),
+            },
+            "m" => match memory_range(arguments) {
+                Some((address, length)) => {
+                    let memory = game_boy.memory();
+                    (0..length).map(|offset| format!("{:02x}", memory.peek(address + offset))).collect()
+                }
+                None => error(1),
+            },
+            "M" => {
+                let write = arguments.split_once(':').and_then(|(range, data)| Some((memory_range(range)?, parse_bytes(data)?)));
+                match write {
+                    Some(((address, length), bytes)) if bytes.len() == length as usize => {
+                        for (offset, byte) in bytes.into_iter().enumerate() {
+                            game_boy.write_memory(address + offset as u16, byte);
+                        }
+                        ok()
+                    }
+                    _ => error(1),
+                }
+            }
+            "Z" | "z" => self.breakpoint(game_boy, command == "Z", arguments),
+            "s" => {
+                let stop = self.debugger.step(game_boy, 1);
+                self.stop_reply(stop)
+            }
+            "c" => self.resume(game_boy)?,
+            "D" => {
+                self.send(&ok())?;
+                return Ok(None);
+            }
+            "k" => return Ok(None),
+            "H" => ok(),
+            "q" | "Q" => self.query(&packet),
+            // GDB falls back to s and c for vCont, and to M for X
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            String::from("PacketSize=4000;qXfer:features:read+;QStartNoAckMode+")
+        } else if packet == "QStartNoAckMode" {
+            ok()
+        } else if packet == "qAttached" {
+            String::from("1")
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = range.split_once(',') else { return error(1) };
+            let (Ok(offset), Ok(length)) = (usize::from_str_radix(offset, 16), usize::from_str_radix(length, 16)) else { return error(1) };
+            let chunk = TARGET_XML.get(offset.min(TARGET_XML.len())..(offset + length).min(TARGET_XML.len())).unwrap_or_default();
+            let more = offset + length < TARGET_XML.len();
+            format!("{}{}", if more { 'm' } else { 'l' }, escape(chunk))
+        } else {
+            String::new()
+        }
+    }
+
+    /// `Z0`/`Z1` breakpoints, `Z2` write, `Z3` read and `Z4` access watchpoints. Arguments are `TYPE,ADDR,KIND`.
+    fn breakpoint(&mut self, game_boy: &mut GameBoy, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.split(',');
+        let (Some(kind), Some(address), Some(length)) = (fields.next(), fields.next().and_then(parse_hex), fields.next().and_then(parse_hex)) else {
+            return error(1);
+        };
+        let length = length.max(1);
+        match (kind, insert) {
+            ("0" | "1", true) => {
+                self.debugger.add_breakpoint(address, None);
+            }
+            ("0" | "1", false) => {
+                self.debugger.remove_breakpoint(address);
+            }
+            ("2" | "3" | "4", _) => {
+                let kind = kind.as_bytes()[0];
+                let Some(end) = address.checked_add(length - 1) else { return error(1) };
+                let key = (kind, address, length);
+                if insert {
+                    let range = address..=end;
+                    let watchpoint = match kind {
+                        b'2' => Watchpoint::write(range),
+                        b'3' => Watchpoint::read(range),
+                        _ => Watchpoint::access(range),
+                    };
+                    let id = game_boy.add_watchpoint(watchpoint);
+                    if let Some(old) = self.watchpoints.insert(key, id) {
+                        game_boy.remove_watchpoint(old);
+                    }
+                } else if let Some(id) = self.watchpoints.remove(&key) {
+                    game_boy.remove_watchpoint(id);
+                }
+            }
+            _ => return String::new(),
+        }
+        ok()
+    }
+
+    /// Runs a frame at a time, looking for a Ctrl-C from GDB in between.
+    fn resume(&mut self, game_boy: &mut GameBoy) -> io::Result<String> {
+        loop {
+            match self.debugger.resume(game_boy, Some(1)) {
+                Stop::Done => {}
+                stop => return Ok(self.stop_reply(stop)),
+            }
+            if self.interrupted()? {
+                return Ok(format!("S{SIGNAL_INTERRUPT:02x}"));
+            }
+        }
+    }
+
+    /// Whether GDB sent the 0x03 interrupt byte, or hung up, without waiting for it.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if !self.input.buffer().is_empty() {
+            let byte = self.input.buffer()[0];
+            self.input.consume(1);
+            return Ok(byte == 0x03);
+        }
+        self.input.get_ref().set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.input.get_mut().read(&mut byte);
+        self.input.get_ref().set_nonblocking(false)?;
+        match result {
+            Ok(0) => Ok(true),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        let hits = match stop {
+            Stop::Watchpoint(hits) => hits,
+            Stop::Locked(_) => return format!("S{SIGNAL_ILLEGAL:02x}"),
+            _ => return format!("S{SIGNAL_TRAP:02x}"),
+        };
+        let Some(hit) = hits.first() else { return format!("S{SIGNAL_TRAP:02x}") };
+        let kind = self.watchpoints.iter().find(|&(_, &id)| id == hit.id).map(|(&(kind, _, _), _)| kind);
+        let reason = match (kind, hit.access) {
+            (Some(b'2'), _) => "watch",
+            (Some(b'3'), _) => "rwatch",
+            (_, Access::Read) => "rwatch",
+            _ => "awatch",
+        };
+        format!("T{SIGNAL_TRAP:02x}{reason}:{:04x};", hit.address)
+    }
+}
+
+fn ok() -> String {
+    String::from("OK")
+}
+
+fn error(code: u8) -> String {
+    format!("E{code:02x}")
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Undoes the `}` escaping of `#`, `$`, `}` and `*` inside packets.
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut data = data.iter();
+    while let Some(&byte) = data.next() {
+        match byte {
+            b'}' => bytes.extend(data.next().map(|byte| byte ^ 0x20)),
+            _ => bytes.push(byte),
+        }
+    }
+    bytes
+}
+
+fn escape(text: &str) -> String {
+    text.chars().fold(String::new(), |mut escaped, char| {
+        if matches!(char, '#' | '$' | '}' | '*') {
+            escaped.push('}');
+            escaped.push((char as u8 ^ 0x20) as char);
+        } else {
+            escaped.push(char);
+        }
+        escaped
+    })
+}
+
+/// Register values go over the wire little-endian.
+fn hex_u16(value: u16) -> String {
+    let [low, high] = value.to_le_bytes();
+    format!("{low:02x}{high:02x}")
+}
+
+fn parse_u16(text: &str) -> Option<u16> {
+    let bytes = parse_bytes(text)?;
+    let [low, high] = bytes[..] else { return None };
+    Some(u16::from_le_bytes([low, high]))
+}
+
+fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|idx| u8::from_str_radix(text.get(idx..idx + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+fn register(number: &str) -> Option<RegisterPair> {
+    REGISTERS.get(usize::from_str_radix(number, 16).ok()?).copied()
+}
+
+/// `ADDR,LENGTH` within the 64 KiB address space.
+fn memory_range(text: &str) -> Option<(u16, u16)> {
+    let (address, length) = text.split_once(',')?;
+    let (address, length) = (u32::from_str_radix(address, 16).ok()?, u32::from_str_radix(length, 16).ok()?);
+    (address + length <= 0x10000).then_some((address as u16, length as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use gameboy_emu::game_boy::test_rom::test_rom;
+
+    /// A client that sends each packet and collects the replies, like a GDB script would.
+    fn talk(port: u16, packets: &[&str]) -> Vec<String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut input = BufReader::new(stream.try_clone().unwrap());
+        let mut replies = Vec::new();
+        for packet in packets {
+            write!(stream, "${packet}#{:02x}", checksum_of(packet.as_bytes())).unwrap();
+            let mut ack = [0];
+            input.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            if *packet == "k" {
+                break;
+            }
+            let mut reply = Vec::new();
+            input.read_until(b'$', &mut reply).unwrap();
+            reply.clear();
+            input.read_until(b'#', &mut reply).unwrap();
+            reply.pop();
+            let mut checksum = [0; 2];
+            input.read_exact(&mut checksum).unwrap();
+            stream.write_all(b"+").unwrap();
+            replies.push(String::from_utf8(reply).unwrap());
+        }
+        replies
+    }
+
+    #[test]
+    fn serves_a_scripted_session() {
+        // 0150: ld a, $42; ld [$C000], a; jr -2
+        let mut game_boy = GameBoy::new(test_rom(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE])).unwrap();
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || talk(port, &[
+            "qSupported:multiprocess+", "?", "p5", "s", "p5", "Z0,152,1", "c", "m150,3", "Z2,c000,1", "c",
+            "P2=3412", "M8000,2:abcd", "m8000,2", "z2,c000,1", "qXfer:features:read:target.xml:0,20", "vCont?", "k",
+        ]));
+        let (stream, _) = listener.accept().unwrap();
+        serve(&mut game_boy, Debugger::new(), stream).unwrap();
+
+        assert_eq!(client.join().unwrap(), [
+            "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+", "S05", "0001", "S05", "5001", "OK", "S05", "3e42ea",
+            "OK", "T05watch:c000;", "OK", "OK", "abcd", "OK", "m<?xml version=\"1.0\"?>\n<!DOCTYPE ", "",
+        ]);
+        assert_eq!(game_boy.cpu().registers().read_de(), 0x1234);
+        assert_eq!(game_boy.cpu().registers().read_pc(), 0x0155);
+    }
+
+    #[test]
+    fn reads_across_echo_ram_and_the_unusable_range() {
+        let mut game_boy = GameBoy::new(test_rom(&[0x18, 0xFE])).unwrap();
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || talk(port, &[
+            "Mdff0,10:000102030405060708090a0b0c0d0e0f", "Mc000,10:101112131415161718191a1b1c1d1e1f", "Me008,1:ee",
+            "mdff0,20", "mfe9f,62", "Mfea0,1:12", "k",
+        ]));
+        let (stream, _) = listener.accept().unwrap();
+        serve(&mut game_boy, Debugger::new(), stream).unwrap();
+
+        assert_eq!(client.join().unwrap(), [
+            "OK", "OK", "OK",
+            "000102030405060708090a0b0c0d0e0f1011121314151617ee191a1b1c1d1e1f",
+            format!("00{}cf", "ff".repeat(0x60)).as_str(),
+            "OK",
+        ]);
+    }
+}
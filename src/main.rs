@@ -1,24 +1,451 @@
-use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs, io};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use gameboy_emu::game_boy::apu::AudioChannel;
+use gameboy_emu::game_boy::apu::wav_writer::WavWriter;
+use gameboy_emu::game_boy::cartridge_header::CartridgeHeader;
+use gameboy_emu::game_boy::coverage::Coverage;
+use gameboy_emu::game_boy::debugger::Debugger;
+use gameboy_emu::game_boy::disassembler;
 use gameboy_emu::game_boy::GameBoy;
-fn decode_ram_size(code: u8) -> String {
-    match code { 
-        0 => String::from("No RAM"),
-        1 => String::from("Unused"),
-        2 => String::from("1 bank"),
-        3 => String::from("4 banks of 8 KiB each"),
-        4 => String::from("16 banks of 8 KiB each"),
-        5 => String::from("8 banks of 8 KiB each"),
-        _ => panic!("invalid code") 
-    }
-}
-
-fn main() {
-    let content = fs::read("hello-world.gb").unwrap();
-    
-    println!("Cartridge type: {:0x}", content[0x147]);
-    println!("ROM size: 32 KiB * {}", 1 << content[0x148]);
-    println!("RAM size: {}", decode_ram_size(content[0x149]));
-
-    let mut game_boy = GameBoy::new();
-    game_boy.start(content);
-}
\ No newline at end of file
+use gameboy_emu::game_boy::model::Model;
+use gameboy_emu::game_boy::movie::{Movie, MovieError, MovieSession, MovieStart};
+use gameboy_emu::game_boy::palette::Palette;
+use gameboy_emu::game_boy::profiler::Profiler;
+use gameboy_emu::game_boy::source_map::SourceMap;
+use gameboy_emu::game_boy::symbols::Symbols;
+use gameboy_emu::game_boy::tracer::Tracer;
+use crate::cli::{Command, RunOptions};
+
+mod cli;
+mod dap_server;
+mod debugger;
+mod frontend;
+mod gdb_server;
+mod json;
+mod screenshot;
+
+const EXIT_RUNTIME_ERROR: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_UNREADABLE_ROM: u8 = 3;
+const EXIT_INVALID_ROM: u8 = 4;
+const EXIT_FRAME_HASH_MISMATCH: u8 = 5;
+
+fn main() -> ExitCode {
+    let command = match cli::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("error: {message}\n\n{}", cli::USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    let result = match command {
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        }
+        Command::Info { rom_path } => info(&rom_path),
+        Command::Disassemble { rom_path } => disassemble(&rom_path),
+        Command::Dap => dap_server::run(BufReader::new(io::stdin()), io::stdout(), load_program)
+            .map_err(|error| (EXIT_RUNTIME_ERROR, format!("DAP server failed: {error}"))),
+        Command::Run(options) => run(*options),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err((code, message)) => {
+            eprintln!("error: {message}");
+            ExitCode::from(code)
+        }
+    }
+}
+
+type CliResult<T> = Result<T, (u8, String)>;
+
+fn read_rom(rom_path: &Path) -> CliResult<(Vec<u8>, CartridgeHeader)> {
+    let content = fs::read(rom_path)
+        .map_err(|error| (EXIT_UNREADABLE_ROM, format!("cannot read {}: {error}", rom_path.display())))?;
+    let header = CartridgeHeader::parse(&content)
+        .map_err(|error| (EXIT_INVALID_ROM, format!("{}: {error}", rom_path.display())))?;
+
+    Ok((content, header))
+}
+
+fn info(rom_path: &Path) -> CliResult<()> {
+    let (content, header) = read_rom(rom_path)?;
+    let valid = |valid: bool| if valid { "ok" } else { "MISMATCH" };
+    let size = |size: Option<usize>| size.map_or(String::from("invalid"), |size| format!("{} KiB", size / 1024));
+
+    print_nintendo_logo(&content);
+    println!("Title:            {}", header.title);
+    println!("Manufacturer:     {}", header.manufacturer_code);
+    println!("Licensee:         {}", header.licensee_code());
+    println!("CGB support:      {} ({:02X})", header.cgb_support(), header.cgb_flag);
+    println!("SGB support:      {} ({:02X})", if header.supports_sgb() { "yes" } else { "no" }, header.sgb_flag);
+    println!("Cartridge type:   {} ({:02X})", header.cartridge_type_name(), header.cartridge_type);
+    println!("ROM size:         {} ({:02X}), file is {} KiB", size(header.rom_size()), header.rom_size_code, content.len() / 1024);
+    println!("RAM size:         {} ({:02X})", size(header.ram_size()), header.ram_size_code);
+    println!("Destination:      {} ({:02X})", header.destination(), header.destination_code);
+    println!("Version:          {}", header.version);
+    println!("Entry point:      {:02X?}", header.entry_point);
+    println!("Logo:             {}", valid(header.logo_valid));
+    println!("Header checksum:  {:02X} {}", header.header_checksum, valid(header.is_header_checksum_valid()));
+    println!("Global checksum:  {:04X} {}", header.global_checksum, valid(header.is_global_checksum_valid()));
+
+    // a wrong global checksum shows up above, the boot ROM and this emulator run the game anyway
+    GameBoy::check_rom(&content).map(|_| ()).map_err(|error| (EXIT_INVALID_ROM, format!("{}: {error}", rom_path.display())))
+}
+
+fn disassemble(rom_path: &Path) -> CliResult<()> {
+    let (content, _) = read_rom(rom_path)?;
+    let listing = disassembler::disassemble_rom(&content, &read_symbols(rom_path), &read_coverage(rom_path, content.len()));
+    match io::stdout().lock().write_all(listing.as_bytes()) {
+        // piping into head is fine
+        Err(error) if error.kind() != io::ErrorKind::BrokenPipe => Err((EXIT_RUNTIME_ERROR, error.to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Reads a ROM that passes the checks `GameBoy` makes.
+fn read_runnable_rom(rom_path: &Path) -> CliResult<(Vec<u8>, CartridgeHeader)> {
+    let content = fs::read(rom_path)
+        .map_err(|error| (EXIT_UNREADABLE_ROM, format!("cannot read {}: {error}", rom_path.display())))?;
+    let header = GameBoy::check_rom(&content)
+        .map_err(|error| (EXIT_INVALID_ROM, format!("{}: {error}", rom_path.display())))?;
+    Ok((content, header))
+}
+
+fn run(options: RunOptions) -> CliResult<()> {
+    let (content, header) = read_runnable_rom(&options.rom_path)?;
+
+    let playback = match &options.play_movie {
+        Some(path) => Some(read_movie(path, &content)?),
+        None => None,
+    };
+    let model = options.model
+        .or(playback.as_ref().map(Movie::model))
+        .unwrap_or_else(|| Model::for_cartridge(&header));
+    let boot_rom = match &options.boot_rom {
+        Some(path) => Some(read_boot_rom(path, model)?),
+        None => None,
+    };
+    let palettes = palettes(&options, &header, model)?;
+    let rom = options.coverage.then(|| content.clone());
+    let mut game_boy = GameBoy::with_model(content, model, boot_rom)
+        .map_err(|error| (EXIT_INVALID_ROM, format!("{}: {error}", options.rom_path.display())))?;
+    for channel in [AudioChannel::Pulse1, AudioChannel::Pulse2, AudioChannel::Wave, AudioChannel::Noise] {
+        game_boy.set_audio_channel_enabled(channel, options.audio_channels.contains(&channel));
+    }
+    let symbols = read_symbols(&options.rom_path);
+    if options.trace {
+        let tracer = tracer(&options)?;
+        game_boy.set_tracer(Some(if options.trace_labels { tracer.with_symbols(symbols.clone()) } else { tracer }));
+    }
+    if let Some(path) = &options.profile {
+        let file = File::create(path)
+            .map_err(|error| (EXIT_USAGE, format!("cannot create {}: {error}", path.display())))?;
+        game_boy.set_profiler(Some(Profiler::new(BufWriter::new(file)).with_report(io::stderr()).with_symbols(symbols.clone())));
+    }
+    if let Some(rom) = &rom {
+        game_boy.set_coverage(Some(Coverage::new(rom.len())));
+    }
+
+    let save_path = save_path(&options);
+    let movie = match playback {
+        Some(movie) => {
+            if movie.boot_rom() != options.boot_rom.is_some() {
+                let needs = if movie.boot_rom() { "needs" } else { "was recorded without" };
+                return Err((EXIT_USAGE, format!("{} {needs} --boot-rom", options.play_movie.as_ref().unwrap().display())));
+            }
+            movie.start(&mut game_boy)
+                .map_err(|error| (EXIT_USAGE, format!("{}: {error}", options.play_movie.as_ref().unwrap().display())))?;
+            Some(MovieSession::play(movie))
+        }
+        None => {
+            let mut start = MovieStart::default();
+            if game_boy.has_battery() && let Ok(data) = fs::read(&save_path) {
+                start.save_data = Some(data);
+            }
+            if let Some(path) = &options.load_state {
+                let data = fs::read(path)
+                    .map_err(|error| (EXIT_USAGE, format!("cannot read {}: {error}", path.display())))?;
+                start.state = Some(data);
+            }
+            let state_error = |error: String| (EXIT_USAGE, format!("{}: {error}", options.load_state.as_ref().unwrap().display()));
+
+            if options.record_movie.is_some() {
+                // the clock runs on emulated time from now on, so playback sees the same times
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+                let movie = Movie::new(&game_boy, options.boot_rom.is_some(), now, start);
+                movie.start(&mut game_boy).map_err(|error| state_error(error.to_string()))?;
+                Some(MovieSession::record(movie))
+            } else {
+                if let Some(data) = &start.save_data {
+                    game_boy.load_save_data(data);
+                }
+                // a save state brings its own cartridge RAM, it goes on top of the battery save
+                if let Some(data) = &start.state {
+                    game_boy.load_state(data).map_err(|error| state_error(error.to_string()))?;
+                }
+                None
+            }
+        }
+    };
+
+    let result = if options.debug {
+        // debugging sessions poke at the game, its battery save is not written
+        debugger::run(&mut game_boy, symbols.clone(), BufReader::new(io::stdin()), io::stdout())
+            .map_err(|error| (EXIT_RUNTIME_ERROR, format!("debugger failed: {error}")))
+    } else if let Some(port) = options.gdb_port {
+        gdb_server::listen(&mut game_boy, Debugger::with_symbols(symbols.clone()), port)
+            .map_err(|error| (EXIT_RUNTIME_ERROR, format!("GDB server failed: {error}")))
+    } else if options.headless {
+        run_headless(&mut game_boy, movie, &options, &save_path)
+    } else {
+        game_boy = frontend::run(game_boy, save_path, palettes, movie, &options)
+            .map_err(|error| (EXIT_RUNTIME_ERROR, format!("event loop failed: {error}")))?;
+        Ok(())
+    };
+
+    // a failed run still covered what it ran
+    let written = match &rom {
+        Some(rom) => write_coverage(&mut game_boy, rom, &options.rom_path, &symbols),
+        None => Ok(()),
+    };
+    result.and(written)
+}
+
+fn tracer(options: &RunOptions) -> CliResult<Tracer> {
+    let mut tracer = match &options.trace_file {
+        Some(path) => {
+            let file = File::create(path)
+                .map_err(|error| (EXIT_USAGE, format!("cannot create {}: {error}", path.display())))?;
+            Tracer::new(BufWriter::new(file))
+        }
+        None => Tracer::new(io::stderr()),
+    };
+    if let Some((start, end)) = options.trace_pc {
+        tracer = tracer.with_pc_range(start..=end);
+    }
+    if let Some(bank) = options.trace_bank {
+        tracer = tracer.with_bank(bank);
+    }
+    if let Some(instructions) = options.trace_last {
+        tracer = tracer.with_ring_buffer(instructions);
+    }
+    Ok(tracer)
+}
+
+/// What the DAP server launches: the ROM with its labels and the sources next to it.
+fn load_program(rom_path: &Path) -> Result<dap_server::Program, String> {
+    let (content, header) = read_runnable_rom(rom_path).map_err(|(_, message)| message)?;
+    let symbols = read_symbols(rom_path);
+    let sources = if symbols.is_empty() {
+        SourceMap::default()
+    } else {
+        let mut sources = Vec::new();
+        read_sources(rom_path.parent().unwrap_or(Path::new(".")), 0, &mut sources);
+        SourceMap::new(&content, &symbols, &sources)
+    };
+    let model = Model::for_cartridge(&header);
+    let game_boy = GameBoy::with_model(content, model, None).map_err(|error| format!("{}: {error}", rom_path.display()))?;
+    Ok(dap_server::Program { game_boy, symbols, sources })
+}
+
+/// RGBDS sources in `dir` and a few levels of directories below, skipping hidden ones.
+fn read_sources(dir: &Path, depth: usize, sources: &mut Vec<(PathBuf, String)>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let hidden = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() && depth < 3 {
+            read_sources(&path, depth + 1, sources);
+        } else if path.extension().is_some_and(|extension| ["asm", "inc", "s", "z80", "sm83"].iter().any(|source| extension.eq_ignore_ascii_case(source)))
+            && let Ok(text) = fs::read_to_string(&path)
+        {
+            sources.push((path.canonicalize().unwrap_or(path), text));
+        }
+    }
+}
+
+/// The labels rgblink wrote next to the ROM, if it did. A broken file is not worth stopping for.
+fn read_symbols(rom_path: &Path) -> Symbols {
+    let path = rom_path.with_extension("sym");
+    let Ok(text) = fs::read_to_string(&path) else {
+        return Symbols::default();
+    };
+    Symbols::parse(&text).unwrap_or_else(|error| {
+        eprintln!("warning: ignoring {}: {error}", path.display());
+        Symbols::default()
+    })
+}
+
+/// What earlier `--coverage` runs saw of the ROM, nothing if there were none. A broken file is not worth stopping for either.
+fn read_coverage(rom_path: &Path, rom_size: usize) -> Coverage {
+    let path = rom_path.with_extension("cov");
+    let Ok(text) = fs::read_to_string(&path) else {
+        return Coverage::new(rom_size);
+    };
+    Coverage::parse(&text, rom_size).unwrap_or_else(|error| {
+        eprintln!("warning: ignoring {}: {error}", path.display());
+        Coverage::new(rom_size)
+    })
+}
+
+/// Adds what this run covered to `<rom>.cov` and writes the report on all of it to `<rom>-coverage.txt`.
+fn write_coverage(game_boy: &mut GameBoy, rom: &[u8], rom_path: &Path, symbols: &Symbols) -> CliResult<()> {
+    let Some(mut coverage) = game_boy.set_coverage(None) else {
+        return Ok(());
+    };
+    coverage.merge(&read_coverage(rom_path, rom.len()));
+    let write = |path: PathBuf, text: String| {
+        fs::write(&path, text).map_err(|error| (EXIT_RUNTIME_ERROR, format!("cannot write {}: {error}", path.display())))?;
+        println!("wrote {}", path.display());
+        Ok(())
+    };
+    let stem = rom_path.file_stem().unwrap_or_default().to_string_lossy();
+    write(rom_path.with_extension("cov"), coverage.to_string())?;
+    write(rom_path.with_file_name(format!("{stem}-coverage.txt")), coverage.report(rom, symbols))
+}
+
+/// The palette chosen on the command line first, then the presets the frontend cycles through.
+fn palettes(options: &RunOptions, header: &CartridgeHeader, model: Model) -> CliResult<Vec<Palette>> {
+    let selected = match options.palette.as_deref() {
+        Some("gbc") => Palette::gbc_boot(header),
+        Some(name) => match Palette::preset(name) {
+            Some(palette) => palette,
+            None => {
+                let text = fs::read_to_string(name)
+                    .map_err(|error| (EXIT_USAGE, format!("'{name}' is no palette preset and cannot be read: {error}")))?;
+                Palette::parse(name, &text).map_err(|error| (EXIT_USAGE, format!("{name}: {error}")))?
+            }
+        },
+        None if model.is_cgb() => Palette::gbc_boot(header),
+        None => Palette::pocket(),
+    };
+
+    let mut palettes = vec![selected];
+    for palette in [Palette::dmg(), Palette::pocket(), Palette::light(), Palette::gbc_boot(header)] {
+        if palette.name != palettes[0].name {
+            palettes.push(palette);
+        }
+    }
+    Ok(palettes)
+}
+
+fn read_boot_rom(path: &Path, model: Model) -> CliResult<Vec<u8>> {
+    let boot_rom = fs::read(path)
+        .map_err(|error| (EXIT_UNREADABLE_ROM, format!("cannot read {}: {error}", path.display())))?;
+    if boot_rom.len() != model.boot_rom_size() {
+        let message = format!("{} is {} bytes, a {model} boot ROM has {}", path.display(), boot_rom.len(), model.boot_rom_size());
+        return Err((EXIT_INVALID_ROM, message));
+    }
+
+    Ok(boot_rom)
+}
+
+fn save_path(options: &RunOptions) -> PathBuf {
+    match &options.save_dir {
+        Some(save_dir) => save_dir.join(options.rom_path.with_extension("sav").file_name().unwrap_or_default()),
+        None => options.rom_path.with_extension("sav"),
+    }
+}
+
+/// Reads one of our movies, or imports one from another emulator.
+fn read_movie(path: &Path, cartridge_rom: &[u8]) -> CliResult<Movie> {
+    let data = fs::read(path).map_err(|error| (EXIT_USAGE, format!("cannot read {}: {error}", path.display())))?;
+    match Movie::parse(&data) {
+        Err(MovieError::NotAMovie) => Movie::import(&data, cartridge_rom),
+        result => result,
+    }.map_err(|error| (EXIT_USAGE, format!("{}: {error}", path.display())))
+}
+
+/// Writes a recorded movie, or the battery save unless a movie was played back, which would overwrite the real one.
+fn write_movie_and_save(game_boy: &GameBoy, movie: Option<&MovieSession>, movie_path: Option<&Path>, save_path: &Path) -> Result<(), String> {
+    if let Some(movie) = movie.filter(|movie| movie.is_recording())
+        && let Some(path) = movie_path {
+        fs::write(path, movie.movie().to_bytes()).map_err(|error| format!("cannot write {}: {error}", path.display()))?;
+        println!("recorded {} frames to {}", movie.movie().len(), path.display());
+    }
+    if movie.is_none_or(MovieSession::is_recording) && let Some(data) = game_boy.save_data() {
+        fs::write(save_path, data).map_err(|error| format!("cannot write {}: {error}", save_path.display()))?;
+    }
+    Ok(())
+}
+
+/// Runs for `--frames` frames, or until the movie being played back ends.
+fn run_headless(game_boy: &mut GameBoy, mut movie: Option<MovieSession>, options: &RunOptions, save_path: &Path) -> CliResult<()> {
+    let frames = options.frames.or(movie.as_ref().filter(|movie| !movie.is_recording()).map(|movie| movie.movie().len() as u64));
+    let mut wav = match &options.wav {
+        Some(path) => {
+            let wav = File::create(path).and_then(|file| WavWriter::new(BufWriter::new(file), game_boy.audio_sample_rate()))
+                .map_err(|error| (EXIT_USAGE, format!("cannot create {}: {error}", path.display())))?;
+            Some(wav)
+        }
+        None => None,
+    };
+    let wav_error = |error: io::Error| (EXIT_RUNTIME_ERROR, format!("cannot write {}: {error}", options.wav.as_ref().unwrap().display()));
+    let mut frame = 0;
+    while frames.is_none_or(|frames| frame < frames) {
+        if let Some(movie) = &mut movie {
+            movie.start_frame(game_boy);
+        }
+        game_boy.run_frame();
+        frame += 1;
+        // drain the audio either way instead of letting the ring fill up
+        let samples = game_boy.audio_samples();
+        if let Some(wav) = &mut wav {
+            wav.write_samples(&samples).map_err(wav_error)?;
+        }
+
+        if options.screenshot_at == Some(frame) {
+            let path = screenshot::save(game_boy, &options.rom_path, frame)
+                .map_err(|error| (EXIT_RUNTIME_ERROR, format!("cannot write screenshot: {error}")))?;
+            println!("wrote {}", path.display());
+        }
+    }
+
+    if let Some(wav) = wav {
+        wav.finish().map_err(wav_error)?;
+    }
+    write_movie_and_save(game_boy, movie.as_ref(), options.record_movie.as_deref(), save_path)
+        .map_err(|message| (EXIT_RUNTIME_ERROR, message))?;
+
+    let frame_hash = game_boy.frame_hash();
+    if options.play_movie.is_some() || options.expect_frame_hash.is_some() {
+        println!("frame {frame} hashes to {frame_hash:016X}");
+    }
+    match options.expect_frame_hash {
+        Some(expected) if expected != frame_hash => {
+            Err((EXIT_FRAME_HASH_MISMATCH, format!("expected the last frame to hash to {expected:016X}")))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn print_nintendo_logo(cartridge_rom: &[u8]) {
+    let nintendo_logo_content = &cartridge_rom[0x104..0x133 + 1];
+    print_half(&nintendo_logo_content[0..24]);
+    print_half(&nintendo_logo_content[24..48]);
+}
+
+fn print_half(bytes: &[u8]) {
+    assert_eq!(bytes.len(), 24);
+    // a row for each nimble: the high one of the first byte of a pair, its low one, then those of the second
+    for (byte_idx, shift) in [(0, 4), (0, 0), (1, 4), (1, 0)] {
+        for byte_pair in bytes.chunks(2) {
+            let nimble = byte_pair[byte_idx] >> shift;
+            for bit in (0..4).rev() {
+                print!("{}", if nimble & (1 << bit) != 0 { '▮' } else { ' ' });
+            }
+        }
+        println!();
+    }
+}
@@ -0,0 +1,109 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use gameboy_emu::game_boy::GameBoy;
+use gameboy_emu::game_boy::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Greyscale level for the four DMG shades, lightest first.
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+/// Writes the current frame next to the ROM as `<rom>-frame<N>.png` and returns its path.
+pub fn save(game_boy: &GameBoy, rom_path: &Path, frame: u64) -> io::Result<PathBuf> {
+    let stem = rom_path.file_stem().unwrap_or_default().to_string_lossy();
+    let path = rom_path.with_file_name(format!("{stem}-frame{frame}.png"));
+
+    let mut writer = BufWriter::new(File::create(&path)?);
+    write_png(&mut writer, game_boy.framebuffer())?;
+    writer.flush()?;
+
+    Ok(path)
+}
+
+/// Writes a framebuffer of DMG shades as an 8-bit greyscale PNG.
+/// The image data is stored uncompressed, which keeps the encoder tiny at about 23 KiB per screenshot.
+pub fn write_png<W: Write>(mut writer: W, framebuffer: &[u8]) -> io::Result<()> {
+    let mut scanlines = Vec::with_capacity(SCREEN_HEIGHT * (SCREEN_WIDTH + 1));
+    for row in framebuffer.chunks_exact(SCREEN_WIDTH) {
+        // filter type None
+        scanlines.push(0);
+        scanlines.extend(row.iter().map(|&shade| SHADES[shade as usize]));
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend((SCREEN_WIDTH as u32).to_be_bytes());
+    header.extend((SCREEN_HEIGHT as u32).to_be_bytes());
+    // bit depth 8, greyscale, deflate, adaptive filtering, no interlace
+    header.extend([8, 0, 0, 0, 0]);
+
+    writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+    write_chunk(&mut writer, b"IHDR", &header)?;
+    write_chunk(&mut writer, b"IDAT", &zlib_stored(&scanlines))?;
+    write_chunk(&mut writer, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = crc32(kind.iter().chain(data));
+    writer.write_all(&crc.to_be_bytes())
+}
+
+/// Wraps `data` in a zlib stream made of stored deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xFFFF;
+
+    let mut stream = vec![0x78, 0x01];
+    let blocks = data.chunks(MAX_BLOCK).collect::<Vec<_>>();
+    for (idx, block) in blocks.iter().enumerate() {
+        stream.push((idx == blocks.len() - 1) as u8);
+        let length = block.len() as u16;
+        stream.extend(length.to_le_bytes());
+        stream.extend((!length).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend(adler32(data).to_be_bytes());
+    stream
+}
+
+fn crc32<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_reference_values() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn writes_png_chunks() {
+        let mut png = Vec::new();
+        write_png(&mut png, &[3; SCREEN_WIDTH * SCREEN_HEIGHT]).unwrap();
+
+        assert_eq!(&png[0..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+    }
+}